@@ -0,0 +1,312 @@
+use std::{collections::HashMap, io::{Read, Seek, SeekFrom}, sync::RwLock};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use dbsdk_rs::{db::log, io::{FileMode, FileStream, IOError}, logfmt};
+use lazy_static::lazy_static;
+
+const PAK_MAGIC: u32 = 0x4B434150;
+const PAK_ENTRY_SIZE: u32 = 64;
+const PAK_NAME_LEN: usize = 56;
+
+lazy_static! {
+    static ref MOUNTED_ARCHIVES: RwLock<Vec<PakArchive>> = RwLock::new(Vec::new());
+}
+
+/// Enumeration of errors which can result from mounting an archive
+#[derive(Debug)]
+pub enum ArchiveError {
+    ParseError,
+    IOError(IOError)
+}
+
+struct PakEntry {
+    offset: u32,
+    length: u32,
+}
+
+/// A Quake-style PAK archive
+struct PakArchive {
+    pak_path: String,
+    mount_point: String,
+    entries: HashMap<String, PakEntry>,
+}
+
+/// A read-only window into a single entry of a mounted archive
+pub struct ArchiveFile<S = FileStream> {
+    stream: S,
+    start: u64,
+    length: u64,
+    position: u64,
+}
+
+/// A file opened through the virtual filesystem, either from a mounted archive or from a loose file
+pub enum VirtualFile {
+    Archive(ArchiveFile),
+    Loose(FileStream),
+}
+
+impl PakArchive {
+    fn new(pak_path: &str, mount_point: &str) -> Result<PakArchive, ArchiveError> {
+        let mut reader = match FileStream::open(pak_path, FileMode::Read) {
+            Ok(v) => v,
+            Err(e) => return Err(ArchiveError::IOError(e))
+        };
+
+        let entries = read_pak_directory(&mut reader)?;
+
+        Ok(PakArchive {
+            pak_path: pak_path.to_owned(),
+            mount_point: mount_point.to_owned(),
+            entries
+        })
+    }
+
+    fn find_entry(self: &Self, path: &str) -> Option<&PakEntry> {
+        let entry_name = entry_name(&self.mount_point, path)?;
+        self.entries.get(&entry_name)
+    }
+}
+
+// read the header & directory of a PAK archive, returning its entries keyed by lowercase name
+fn read_pak_directory<R: Read + Seek>(reader: &mut R) -> Result<HashMap<String, PakEntry>, ArchiveError> {
+    let magic = reader.read_u32::<LittleEndian>().map_err(|_| ArchiveError::ParseError)?;
+    if magic != PAK_MAGIC {
+        return Err(ArchiveError::ParseError);
+    }
+
+    let dir_offset = reader.read_u32::<LittleEndian>().map_err(|_| ArchiveError::ParseError)?;
+    let dir_length = reader.read_u32::<LittleEndian>().map_err(|_| ArchiveError::ParseError)?;
+
+    reader.seek(SeekFrom::Start(dir_offset as u64)).map_err(|_| ArchiveError::ParseError)?;
+
+    let num_entries = dir_length / PAK_ENTRY_SIZE;
+    let mut entries = HashMap::new();
+
+    for _ in 0..num_entries {
+        let mut name: [u8;PAK_NAME_LEN] = [0;PAK_NAME_LEN];
+        reader.read_exact(&mut name).map_err(|_| ArchiveError::ParseError)?;
+
+        let offset = reader.read_u32::<LittleEndian>().map_err(|_| ArchiveError::ParseError)?;
+        let length = reader.read_u32::<LittleEndian>().map_err(|_| ArchiveError::ParseError)?;
+
+        let name_len = name.iter().position(|x| *x == 0).unwrap_or(PAK_NAME_LEN);
+        let name = match std::str::from_utf8(&name[0..name_len]) {
+            Ok(v) => v.to_lowercase(),
+            Err(_) => return Err(ArchiveError::ParseError)
+        };
+
+        entries.insert(name, PakEntry { offset, length });
+    }
+
+    Ok(entries)
+}
+
+// get the name of the archive entry a path refers to, if it's inside the given mount point
+fn entry_name(mount_point: &str, path: &str) -> Option<String> {
+    let rest = path.strip_prefix(mount_point)?;
+
+    // the mount point must be a whole path component, so "/cd/content" doesn't match "/cd/content2/..."
+    if !rest.is_empty() && !rest.starts_with('/') && !mount_point.ends_with('/') {
+        return None;
+    }
+
+    Some(rest.trim_start_matches('/').to_lowercase())
+}
+
+impl<S: Seek> ArchiveFile<S> {
+    // wrap an open archive stream, positioned at the start of the given entry
+    fn new(mut stream: S, entry: &PakEntry) -> std::io::Result<ArchiveFile<S>> {
+        stream.seek(SeekFrom::Start(entry.offset as u64))?;
+
+        Ok(ArchiveFile {
+            stream,
+            start: entry.offset as u64,
+            length: entry.length as u64,
+            position: 0
+        })
+    }
+}
+
+impl<S: Read> Read for ArchiveFile<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = (self.length - self.position.min(self.length)) as usize;
+        let read_len = buf.len().min(remaining);
+
+        if read_len == 0 {
+            return Ok(0);
+        }
+
+        let n = self.stream.read(&mut buf[0..read_len])?;
+        self.position += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<S: Seek> Seek for ArchiveFile<S> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(v) => v as i64,
+            SeekFrom::Current(v) => self.position as i64 + v,
+            SeekFrom::End(v) => self.length as i64 + v,
+        };
+
+        if new_pos < 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before start of archive entry"));
+        }
+
+        self.stream.seek(SeekFrom::Start(self.start + new_pos as u64))?;
+        self.position = new_pos as u64;
+
+        Ok(self.position)
+    }
+}
+
+impl Read for VirtualFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            VirtualFile::Archive(v) => v.read(buf),
+            VirtualFile::Loose(v) => v.read(buf),
+        }
+    }
+}
+
+impl Seek for VirtualFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            VirtualFile::Archive(v) => v.seek(pos),
+            VirtualFile::Loose(v) => v.seek(pos),
+        }
+    }
+}
+
+/// Mount a PAK archive so that its contents are visible under the given virtual path prefix (for example, "/cd/content")
+/// Archives mounted later take priority over archives mounted earlier
+pub fn mount_pak(pak_path: &str, mount_point: &str) -> Result<(), ArchiveError> {
+    let archive = PakArchive::new(pak_path, mount_point)?;
+    logfmt!("Mounted {} at {} ({} entries)", pak_path, mount_point, archive.entries.len());
+
+    MOUNTED_ARCHIVES.write().unwrap().push(archive);
+    Ok(())
+}
+
+/// Open a file by virtual path, looking inside mounted archives first before falling back to loose files
+pub fn open_file(path: &str) -> Result<VirtualFile, IOError> {
+    let archives = MOUNTED_ARCHIVES.read().unwrap();
+
+    for archive in archives.iter().rev() {
+        if let Some(entry) = archive.find_entry(path) {
+            let stream = match FileStream::open(&archive.pak_path, FileMode::Read) {
+                Ok(v) => v,
+                Err(_) => {
+                    logfmt!("Failed opening archive {} for {}", archive.pak_path, path);
+                    continue;
+                }
+            };
+
+            match ArchiveFile::new(stream, entry) {
+                Ok(v) => return Ok(VirtualFile::Archive(v)),
+                Err(_) => {
+                    logfmt!("Failed seeking to {} in archive {}", path, archive.pak_path);
+                    continue;
+                }
+            }
+        }
+    }
+
+    match FileStream::open(path, FileMode::Read) {
+        Ok(v) => Ok(VirtualFile::Loose(v)),
+        Err(e) => Err(e)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use byteorder::WriteBytesExt;
+
+    #[test]
+    fn paths_inside_mount_point_resolve_to_entries() {
+        assert_eq!(entry_name("/cd/content", "/cd/content/maps/E1M1.bsp"), Some("maps/e1m1.bsp".to_owned()));
+        assert_eq!(entry_name("/cd/content/", "/cd/content/maps/e1m1.bsp"), Some("maps/e1m1.bsp".to_owned()));
+    }
+
+    #[test]
+    fn sibling_directories_do_not_match_mount_point() {
+        assert_eq!(entry_name("/cd/content", "/cd/content2/maps/e1m1.bsp"), None);
+        assert_eq!(entry_name("/cd/content", "/cd/contentmaps/e1m1.bsp"), None);
+        assert_eq!(entry_name("/cd/content", "/cd/other/e1m1.bsp"), None);
+    }
+
+    // build an in-memory PAK with the given entries, stored back to back after the header & followed by the directory
+    fn pak_bytes(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let data_len: usize = files.iter().map(|(_, data)| data.len()).sum();
+
+        let mut bytes = Vec::new();
+        bytes.write_u32::<LittleEndian>(PAK_MAGIC).unwrap();
+        bytes.write_u32::<LittleEndian>(12 + data_len as u32).unwrap();
+        bytes.write_u32::<LittleEndian>(files.len() as u32 * PAK_ENTRY_SIZE).unwrap();
+
+        for (_, data) in files {
+            bytes.extend_from_slice(data);
+        }
+
+        let mut offset = 12;
+        for (name, data) in files {
+            let mut name_bytes = [0;PAK_NAME_LEN];
+            name_bytes[0..name.len()].copy_from_slice(name.as_bytes());
+            bytes.extend_from_slice(&name_bytes);
+
+            bytes.write_u32::<LittleEndian>(offset).unwrap();
+            bytes.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            offset += data.len() as u32;
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn pak_entries_are_read_from_directory() {
+        let pak = pak_bytes(&[("textures/Wall01.ktx", &b"wall texture"[..]), ("maps/e1m1.bsp", &b"map"[..])]);
+        let entries = read_pak_directory(&mut Cursor::new(&pak)).unwrap();
+
+        assert_eq!(entries.len(), 2);
+
+        // names are matched case-insensitively
+        let wall = &entries["textures/wall01.ktx"];
+        assert_eq!((wall.offset, wall.length), (12, 12));
+
+        let map = &entries["maps/e1m1.bsp"];
+        assert_eq!((map.offset, map.length), (24, 3));
+
+        let mut bad_magic = pak.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(read_pak_directory(&mut Cursor::new(&bad_magic)), Err(ArchiveError::ParseError)));
+    }
+
+    #[test]
+    fn archive_file_reads_only_its_own_entry() {
+        let pak = pak_bytes(&[("textures/wall01.ktx", &b"wall texture"[..]), ("maps/e1m1.bsp", &b"map"[..])]);
+        let entries = read_pak_directory(&mut Cursor::new(&pak)).unwrap();
+
+        let mut file = ArchiveFile::new(Cursor::new(&pak), &entries["textures/wall01.ktx"]).unwrap();
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"wall texture");
+
+        // seeking is relative to the entry, not the archive
+        assert_eq!(file.seek(SeekFrom::End(-7)).unwrap(), 5);
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "texture");
+
+        assert!(file.seek(SeekFrom::Current(-100)).is_err());
+
+        let mut file = ArchiveFile::new(Cursor::new(&pak), &entries["maps/e1m1.bsp"]).unwrap();
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"map");
+    }
+}
\ No newline at end of file
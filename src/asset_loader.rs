@@ -1,26 +1,58 @@
-use std::{collections::HashMap, marker::PhantomData, path::Path, sync::{Arc, RwLock, Weak}};
+use std::{collections::HashMap, io::{Read, Seek, SeekFrom}, marker::PhantomData, path::Path, sync::{atomic::{AtomicBool, AtomicU32, Ordering}, Arc, RwLock, Weak}};
 
-use dbsdk_rs::{db::log, io::{self, IOError}, logfmt, vdp::{self, Texture}};
+use byteorder::{LittleEndian, ReadBytesExt};
+use dbsdk_rs::{audio::AudioSample, db::log, io::IOError, logfmt, vdp::{self, Texture}};
 use ktx::KtxInfo;
 use lazy_static::lazy_static;
 
-use crate::{dbanim::DBAnimationClip, dbmesh::DBMesh};
+use crate::{archive, dbanim::DBAnimationClip, dbmesh::DBMesh};
 
+const GL_RED: u32 = 0x1903;
+const GL_ALPHA: u32 = 0x1906;
 const GL_RGB: u32 = 0x1907;
 const GL_RGBA: u32 = 0x1908;
+const GL_LUMINANCE: u32 = 0x1909;
+const GL_LUMINANCE_ALPHA: u32 = 0x190A;
+const GL_BGR: u32 = 0x80E0;
+const GL_BGRA: u32 = 0x80E1;
+const GL_RG: u32 = 0x8227;
 const GL_UNSIGNED_BYTE: u32 = 0x1401;
 const GL_UNSIGNED_SHORT_5_6_5: u32 = 0x8363;
 const GL_UNSIGNED_SHORT_4_4_4_4: u32 = 0x8033;
+const GL_UNSIGNED_SHORT_5_5_5_1: u32 = 0x8034;
 const GL_COMPRESSED_RGB_S3TC_DXT1_EXT: u32 = 0x83F0;
 const GL_COMPRESSED_RGBA_S3TC_DXT1_EXT: u32 = 0x83F1;
 const GL_COMPRESSED_RGBA_S3TC_DXT3_EXT: u32 = 0x83F2;
+const GL_COMPRESSED_RGBA_S3TC_DXT5_EXT: u32 = 0x83F3;
+const GL_COMPRESSED_RED_RGTC1: u32 = 0x8DBB;
+const GL_COMPRESSED_RG_RGTC2: u32 = 0x8DBD;
+
+const WAL_NUM_MIPS: usize = 4;
+const PCX_PALETTE_MARKER: u8 = 0x0C;
+const DEFAULT_WAL_PALETTE_PATH: &str = "/cd/content/pics/colormap.pcx";
+
+const WAV_RIFF_MAGIC: u32 = 0x46464952; // "RIFF"
+const WAV_WAVE_MAGIC: u32 = 0x45564157; // "WAVE"
+const WAV_FMT_CHUNK: u32 = 0x20746D66; // "fmt "
+const WAV_DATA_CHUNK: u32 = 0x61746164; // "data"
+const WAV_FORMAT_PCM: u16 = 1;
+
+// number of recently loaded resources each cache keeps alive, even if nothing else references them
+const RECENT_RETAIN_COUNT: usize = 16;
+
+type WalPalette = [u8;768];
 
 lazy_static! {
-    static ref TEXTURE_CACHE: RwLock<TextureCache> = RwLock::new(TextureCache::new());
-    static ref MESH_CACHE: RwLock<MeshCache> = RwLock::new(MeshCache::new());
-    static ref MESH_ANIM_CACHE: RwLock<MeshAnimCache> = RwLock::new(MeshAnimCache::new());
+    static ref TEXTURE_CACHE: RwLock<TextureCache> = RwLock::new(TextureCache::with_retention(RECENT_RETAIN_COUNT));
+    static ref MESH_CACHE: RwLock<MeshCache> = RwLock::new(MeshCache::with_retention(RECENT_RETAIN_COUNT));
+    static ref MESH_ANIM_CACHE: RwLock<MeshAnimCache> = RwLock::new(MeshAnimCache::with_retention(RECENT_RETAIN_COUNT));
+    static ref SOUND_CACHE: RwLock<SoundCache> = RwLock::new(SoundCache::with_retention(RECENT_RETAIN_COUNT));
+    static ref WAL_PALETTE: RwLock<Option<Arc<WalPalette>>> = RwLock::new(None);
 }
 
+static RESOURCE_GENERATION: AtomicU32 = AtomicU32::new(0);
+static GENERATE_MIPMAPS: AtomicBool = AtomicBool::new(false);
+
 pub fn load_texture(path: &str) -> Result<Arc<Texture>, ResourceError> {
     let tex_cache = &mut TEXTURE_CACHE.write().unwrap();
     return tex_cache.load(path);
@@ -36,20 +68,719 @@ pub fn load_mesh_anim(path: &str) -> Result<Arc<DBAnimationClip>, ResourceError>
     return anim_cache.load(path);
 }
 
-pub fn load_env(env_name: &str) -> [Arc<Texture>;6] {
-    let env_ft = load_texture(format!("/cd/content/env/{}1ft.ktx", env_name).as_str()).unwrap();
-    let env_bk = load_texture(format!("/cd/content/env/{}1bk.ktx", env_name).as_str()).unwrap();
-    let env_lf = load_texture(format!("/cd/content/env/{}1lf.ktx", env_name).as_str()).unwrap();
-    let env_rt = load_texture(format!("/cd/content/env/{}1rt.ktx", env_name).as_str()).unwrap();
-    let env_up = load_texture(format!("/cd/content/env/{}1up.ktx", env_name).as_str()).unwrap();
-    let env_dn = load_texture(format!("/cd/content/env/{}1dn.ktx", env_name).as_str()).unwrap();
+pub fn load_sound(path: &str) -> Result<Arc<SoundClip>, ResourceError> {
+    let sound_cache = &mut SOUND_CACHE.write().unwrap();
+    return sound_cache.load(path);
+}
+
+/// Read just the size of a texture (.ktx, falling back to .wal like load_texture) from its header, without loading it
+pub fn load_texture_size(path: &str) -> Result<(usize, usize), ResourceError> {
+    let tex_file = match archive::open_file(path) {
+        Ok(v) => v,
+        Err(e) => {
+            let wal_path = Path::new(path).with_extension("wal");
+            let mut wal_file = match archive::open_file(wal_path.to_str().unwrap()) {
+                Ok(v) => v,
+                Err(_) => return Err(ResourceError::IOError(e))
+            };
+
+            // skip texture name
+            if wal_file.seek(SeekFrom::Start(32)).is_err() {
+                return Err(ResourceError::ParseError);
+            }
+
+            let width = wal_file.read_u32::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+            let height = wal_file.read_u32::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+
+            return Ok((width as usize, height as usize));
+        }
+    };
+
+    // the decoder only reads the header up front
+    let decoder = match ktx::Decoder::new(tex_file) {
+        Ok(v) => v,
+        Err(_) => return Err(ResourceError::ParseError)
+    };
+
+    Ok((decoder.pixel_width() as usize, decoder.pixel_height() as usize))
+}
+
+/// Set whether KTX textures which only contain a single level have the rest of their mip chain generated on load
+/// Only applies to uncompressed formats, & only to textures loaded after this is called
+pub fn set_generate_mipmaps(enabled: bool) {
+    GENERATE_MIPMAPS.store(enabled, Ordering::Relaxed);
+}
+
+/// Get the current resource generation, which is incremented every time reload_all is called
+/// Callers which want to pick up reloaded resources should hold onto the path they loaded from along with the generation,
+/// and re-fetch the resource from the cache by path whenever the generation changes
+pub fn resource_generation() -> u32 {
+    RESOURCE_GENERATION.load(Ordering::Relaxed)
+}
+
+/// Reload every live texture, mesh, animation, and sound from disk and increment the resource generation
+/// Existing references continue to point at the old data until they are re-fetched
+pub fn reload_all() {
+    logfmt!("Reloading all resources");
+
+    // textures are reloaded first so that reloaded meshes pick up the new textures
+    TEXTURE_CACHE.write().unwrap().reload_all();
+    MESH_CACHE.write().unwrap().reload_all();
+    MESH_ANIM_CACHE.write().unwrap().reload_all();
+    SOUND_CACHE.write().unwrap().reload_all();
+
+    RESOURCE_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Resources loaded ahead of time by preload
+/// The caches only hold weak references, so preloaded resources stay loaded for only as long as this is kept alive
+#[derive(Default)]
+pub struct PreloadSet {
+    pub textures: Vec<Arc<Texture>>,
+    pub meshes: Vec<Arc<DBMesh>>,
+    pub anims: Vec<Arc<DBAnimationClip>>,
+    pub sounds: Vec<Arc<SoundClip>>,
+}
+
+/// Load a list of textures (.ktx or .wal), meshes (.dbm), animations (.dba), and sounds (.wav) into their caches ahead of time,
+/// so that later loads of the same paths don't stall. Resources which fail to load are logged & skipped
+pub fn preload(paths: &[&str]) -> PreloadSet {
+    let mut set = PreloadSet::default();
+
+    for path in paths {
+        let ext = match Path::new(path).extension() {
+            Some(v) => v.to_str().unwrap_or("").to_lowercase(),
+            None => String::new()
+        };
+
+        // failures are already logged by the cache
+        match ext.as_str() {
+            "ktx" | "wal" => {
+                if let Ok(v) = load_texture(path) {
+                    set.textures.push(v);
+                }
+            }
+            "dbm" => {
+                if let Ok(v) = load_mesh(path) {
+                    set.meshes.push(v);
+                }
+            }
+            "dba" => {
+                if let Ok(v) = load_mesh_anim(path) {
+                    set.anims.push(v);
+                }
+            }
+            "wav" => {
+                if let Ok(v) = load_sound(path) {
+                    set.sounds.push(v);
+                }
+            }
+            _ => {
+                logfmt!("Don't know how to preload: {}", path);
+            }
+        };
+    }
+
+    set
+}
+
+/// Preload every resource listed in a manifest file, one path per line. Blank lines & lines starting with # are ignored
+pub fn preload_manifest(path: &str) -> Result<PreloadSet, ResourceError> {
+    let mut manifest_file = match archive::open_file(path) {
+        Ok(v) => v,
+        Err(e) => return Err(ResourceError::IOError(e))
+    };
+
+    let mut manifest = String::new();
+    if manifest_file.read_to_string(&mut manifest).is_err() {
+        return Err(ResourceError::ParseError);
+    }
+
+    let paths = manifest.lines()
+        .map(|x| x.trim())
+        .filter(|x| x.len() > 0 && !x.starts_with('#'))
+        .collect::<Vec<_>>();
+
+    Ok(preload(&paths))
+}
+
+pub fn load_env(env_name: &str) -> Result<[Arc<Texture>;6], ResourceError> {
+    let env_ft = load_texture(format!("/cd/content/env/{}1ft.ktx", env_name).as_str())?;
+    let env_bk = load_texture(format!("/cd/content/env/{}1bk.ktx", env_name).as_str())?;
+    let env_lf = load_texture(format!("/cd/content/env/{}1lf.ktx", env_name).as_str())?;
+    let env_rt = load_texture(format!("/cd/content/env/{}1rt.ktx", env_name).as_str())?;
+    let env_up = load_texture(format!("/cd/content/env/{}1up.ktx", env_name).as_str())?;
+    let env_dn = load_texture(format!("/cd/content/env/{}1dn.ktx", env_name).as_str())?;
+
+    Ok([env_ft, env_bk, env_lf, env_rt, env_up, env_dn])
+}
+
+/// Load the palette used to decode WAL textures
+/// Accepts either a PCX image with a trailing 256-color palette (such as Quake 2's colormap.pcx) or a raw 768-byte LMP palette
+/// If this is never called, the palette is loaded from /cd/content/pics/colormap.pcx the first time a WAL texture is decoded
+pub fn load_wal_palette(path: &str) -> Result<(), ResourceError> {
+    let mut palette_file = match archive::open_file(path) {
+        Ok(v) => v,
+        Err(e) => return Err(ResourceError::IOError(e))
+    };
+
+    if path.to_lowercase().ends_with(".pcx") {
+        // PCX palette is stored in the last 769 bytes of the file, prefixed with a marker byte
+        if palette_file.seek(SeekFrom::End(-769)).is_err() {
+            return Err(ResourceError::ParseError);
+        }
+
+        match palette_file.read_u8() {
+            Ok(PCX_PALETTE_MARKER) => {}
+            _ => return Err(ResourceError::ParseError)
+        };
+    }
+
+    let mut palette: WalPalette = [0;768];
+    if palette_file.read_exact(&mut palette).is_err() {
+        return Err(ResourceError::ParseError);
+    }
+
+    *WAL_PALETTE.write().unwrap() = Some(Arc::new(palette));
+    Ok(())
+}
+
+fn get_wal_palette() -> Result<Arc<WalPalette>, ResourceError> {
+    {
+        let palette = WAL_PALETTE.read().unwrap();
+        if let Some(v) = palette.as_ref() {
+            return Ok(v.clone());
+        }
+    }
+
+    load_wal_palette(DEFAULT_WAL_PALETTE_PATH)?;
+    Ok(WAL_PALETTE.read().unwrap().as_ref().unwrap().clone())
+}
+
+/// Decode a Quake 2 WAL texture into an RGB565 VDP texture with a full mip chain
+fn load_wal<R: Read + Seek>(reader: &mut R) -> Result<Texture, ResourceError> {
+    let palette = get_wal_palette()?;
+    let (width, height, levels) = decode_wal(reader, &palette)?;
+
+    let tex = match Texture::new(width as i32, height as i32, true, vdp::TextureFormat::RGB565) {
+        Ok(v) => v,
+        Err(_) => {
+            logfmt!("Failed allocating VDP texture ({}x{})", width, height);
+            return Err(ResourceError::AllocationError);
+        }
+    };
+
+    for (level, pixels) in levels.iter().enumerate() {
+        tex.set_texture_data(level as i32, pixels);
+    }
+
+    Ok(tex)
+}
+
+// decode each mip level present in a WAL file to RGB565. WAL files only store the first 4 levels, so the rest of the chain down to 1x1 is generated from the last one
+fn decode_wal<R: Read + Seek>(reader: &mut R, palette: &WalPalette) -> Result<(usize, usize, Vec<Vec<u8>>), ResourceError> {
+    // skip texture name
+    if reader.seek(SeekFrom::Start(32)).is_err() {
+        return Err(ResourceError::ParseError);
+    }
+
+    let width = reader.read_u32::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+    let height = reader.read_u32::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+
+    let mut offsets = [0;WAL_NUM_MIPS];
+    for i in 0..WAL_NUM_MIPS {
+        offsets[i] = reader.read_u32::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+    }
+
+    if width == 0 || height == 0 {
+        return Err(ResourceError::ParseError);
+    }
+
+    let mut indices: Vec<u8> = Vec::new();
+    let mut levels: Vec<Vec<u8>> = Vec::new();
+
+    for level in 0..WAL_NUM_MIPS {
+        let mip_width = (width >> level) as usize;
+        let mip_height = (height >> level) as usize;
+
+        if mip_width == 0 || mip_height == 0 || offsets[level] == 0 {
+            break;
+        }
+
+        if reader.seek(SeekFrom::Start(offsets[level] as u64)).is_err() {
+            return Err(ResourceError::ParseError);
+        }
+
+        indices.resize(mip_width * mip_height, 0);
+        if reader.read_exact(&mut indices).is_err() {
+            return Err(ResourceError::ParseError);
+        }
+
+        // convert indexed pixels to RGB565
+        let mut pixels = Vec::with_capacity(indices.len() * 2);
+        for idx in &indices {
+            let r = palette[*idx as usize * 3] as u16;
+            let g = palette[*idx as usize * 3 + 1] as u16;
+            let b = palette[*idx as usize * 3 + 2] as u16;
+            let rgb565 = ((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3);
+            pixels.extend_from_slice(&rgb565.to_le_bytes());
+        }
+
+        levels.push(pixels);
+    }
+
+    let last_level = match levels.last() {
+        Some(v) => v,
+        None => return Err(ResourceError::ParseError)
+    };
+
+    let shift = levels.len() - 1;
+    let generated = build_mipmaps(PixelLayout::RGB565, last_level, (width >> shift) as usize, (height >> shift) as usize);
+    levels.extend(generated);
+
+    Ok((width as usize, height as usize, levels))
+}
+
+/// KTX formats the VDP can't use directly, & which are converted on load
+#[derive(Clone, Copy)]
+enum KtxConversion {
+    /// Compressed formats uploaded as-is, made up of blocks of the given size (in bytes)
+    None { block_size: usize },
+    /// Uncompressed formats uploaded as-is, minus the padding KTX adds to the end of each row
+    Unpad { bytes_per_pixel: usize },
+    /// 8 bit per channel formats with fewer than 4 channels (or a different channel order), expanded to RGBA8888. Each source channel maps to an output channel (R, G, B, A), or a constant if None
+    Expand { channels: usize, swizzle: [Option<usize>;4], fill: [u8;4] },
+    /// RGBA5551 to RGBA8888
+    RGBA5551,
+    /// DXT5 to DXT3, requantizing interpolated alpha to explicit 4-bit alpha
+    DXT5,
+    /// BC4 (single channel) to RGBA8888, as greyscale
+    RGTC1,
+    /// BC5 (two channel) to RGBA8888, in red & green
+    RGTC2,
+}
+
+// KTX pads each row of uncompressed data to a multiple of 4 bytes
+fn ktx_row_stride(width: usize, bytes_per_pixel: usize) -> usize {
+    ((width * bytes_per_pixel) + 3) & !3
+}
+
+// decode a BC4 block (also used for DXT5 alpha & each BC5 channel) into 16 8-bit values
+fn decode_bc4_block(block: &[u8]) -> [u8;16] {
+    let v0 = block[0] as u32;
+    let v1 = block[1] as u32;
+
+    let mut palette = [0u8;8];
+    palette[0] = v0 as u8;
+    palette[1] = v1 as u8;
+
+    if v0 > v1 {
+        for i in 1..7 {
+            palette[i + 1] = ((((7 - i) as u32 * v0) + (i as u32 * v1)) / 7) as u8;
+        }
+    }
+    else {
+        for i in 1..5 {
+            palette[i + 1] = ((((5 - i) as u32 * v0) + (i as u32 * v1)) / 5) as u8;
+        }
+
+        palette[6] = 0;
+        palette[7] = 255;
+    }
+
+    let mut bits: u64 = 0;
+    for i in 0..6 {
+        bits |= (block[2 + i] as u64) << (i * 8);
+    }
+
+    let mut result = [0u8;16];
+    for i in 0..16 {
+        result[i] = palette[((bits >> (i * 3)) & 7) as usize];
+    }
+
+    result
+}
+
+// decode BC4 or BC5 blocks into RGBA8888, with each channel written to the given output channel & the rest filled from fill
+fn decode_rgtc(data: &[u8], width: usize, height: usize, channels: &[usize], fill: [u8;4]) -> Vec<u8> {
+    let block_size = 8 * channels.len();
+    let blocks_x = (width + 3) / 4;
 
-    [env_ft, env_bk, env_lf, env_rt, env_up, env_dn]
+    let mut result = Vec::with_capacity(width * height * 4);
+    for _ in 0..(width * height) {
+        result.extend_from_slice(&fill);
+    }
+
+    for (block_index, block) in data.chunks_exact(block_size).enumerate() {
+        let bx = (block_index % blocks_x) * 4;
+        let by = (block_index / blocks_x) * 4;
+
+        for (c, out_channel) in channels.iter().enumerate() {
+            let values = decode_bc4_block(&block[(c * 8)..(c * 8 + 8)]);
+
+            for i in 0..16 {
+                let x = bx + (i % 4);
+                let y = by + (i / 4);
+
+                if x < width && y < height {
+                    result[((x + (y * width)) * 4) + out_channel] = values[i];
+                }
+            }
+        }
+    }
+
+    result
+}
+
+// find the VDP format for a KTX image, converting formats the VDP doesn't support into the closest one it does
+fn ktx_format(gl_type: u32, gl_format: u32, gl_internal_format: u32) -> Result<(vdp::TextureFormat, KtxConversion), ResourceError> {
+    if gl_type == GL_UNSIGNED_BYTE && gl_format == GL_RGBA {
+        Ok((vdp::TextureFormat::RGBA8888, KtxConversion::Unpad { bytes_per_pixel: 4 }))
+    } else if gl_type == GL_UNSIGNED_SHORT_5_6_5 && gl_format == GL_RGB {
+        Ok((vdp::TextureFormat::RGB565, KtxConversion::Unpad { bytes_per_pixel: 2 }))
+    } else if gl_type == GL_UNSIGNED_SHORT_4_4_4_4 && gl_format == GL_RGBA {
+        Ok((vdp::TextureFormat::RGBA4444, KtxConversion::Unpad { bytes_per_pixel: 2 }))
+    } else if gl_internal_format == GL_COMPRESSED_RGB_S3TC_DXT1_EXT || gl_internal_format == GL_COMPRESSED_RGBA_S3TC_DXT1_EXT {
+        Ok((vdp::TextureFormat::DXT1, KtxConversion::None { block_size: 8 }))
+    } else if gl_internal_format == GL_COMPRESSED_RGBA_S3TC_DXT3_EXT {
+        Ok((vdp::TextureFormat::DXT3, KtxConversion::None { block_size: 16 }))
+    } else if gl_internal_format == GL_COMPRESSED_RGBA_S3TC_DXT5_EXT {
+        Ok((vdp::TextureFormat::DXT3, KtxConversion::DXT5))
+    } else if gl_internal_format == GL_COMPRESSED_RED_RGTC1 {
+        Ok((vdp::TextureFormat::RGBA8888, KtxConversion::RGTC1))
+    } else if gl_internal_format == GL_COMPRESSED_RG_RGTC2 {
+        Ok((vdp::TextureFormat::RGBA8888, KtxConversion::RGTC2))
+    } else if gl_type == GL_UNSIGNED_SHORT_5_5_5_1 && gl_format == GL_RGBA {
+        Ok((vdp::TextureFormat::RGBA8888, KtxConversion::RGBA5551))
+    } else if gl_type == GL_UNSIGNED_BYTE {
+        let expand = match gl_format {
+            GL_RGB => Some((3, [Some(0), Some(1), Some(2), None])),
+            GL_BGR => Some((3, [Some(2), Some(1), Some(0), None])),
+            GL_BGRA => Some((4, [Some(2), Some(1), Some(0), Some(3)])),
+            GL_LUMINANCE => Some((1, [Some(0), Some(0), Some(0), None])),
+            GL_LUMINANCE_ALPHA => Some((2, [Some(0), Some(0), Some(0), Some(1)])),
+            GL_ALPHA => Some((1, [None, None, None, Some(0)])),
+            GL_RED => Some((1, [Some(0), None, None, None])),
+            GL_RG => Some((2, [Some(0), Some(1), None, None])),
+            _ => None
+        };
+
+        // alpha-only textures are white, everything else is opaque black where channels are missing
+        let fill = if gl_format == GL_ALPHA { [255, 255, 255, 255] } else { [0, 0, 0, 255] };
+
+        match expand {
+            Some((channels, swizzle)) => Ok((vdp::TextureFormat::RGBA8888, KtxConversion::Expand { channels, swizzle, fill })),
+            None => {
+                logfmt!("Failed decoding KTX image: unsupported pixel format ({:#X})", gl_format);
+                Err(ResourceError::ParseError)
+            }
+        }
+    } else {
+        logfmt!("Failed decoding KTX image: unsupported pixel format ({:#X} / {:#X})", gl_type, gl_internal_format);
+        Err(ResourceError::ParseError)
+    }
+}
+
+// convert a single level of KTX image data into something the VDP can use
+// fails if the level holds less data than its dimensions call for
+fn convert_ktx_level(conversion: KtxConversion, data: Vec<u8>, width: usize, height: usize) -> Result<Vec<u8>, ResourceError> {
+    let num_blocks = ((width + 3) / 4) * ((height + 3) / 4);
+    let required_len = match conversion {
+        KtxConversion::None { block_size } => num_blocks * block_size,
+        KtxConversion::Unpad { bytes_per_pixel } => ktx_row_stride(width, bytes_per_pixel) * height,
+        KtxConversion::Expand { channels, .. } => ktx_row_stride(width, channels) * height,
+        KtxConversion::RGBA5551 => ktx_row_stride(width, 2) * height,
+        KtxConversion::DXT5 => num_blocks * 16,
+        KtxConversion::RGTC1 => num_blocks * 8,
+        KtxConversion::RGTC2 => num_blocks * 16,
+    };
+
+    if data.len() < required_len {
+        logfmt!("Failed decoding KTX image: level has {} bytes, expected {}", data.len(), required_len);
+        return Err(ResourceError::ParseError);
+    }
+
+    let result = match conversion {
+        KtxConversion::None { .. } => data,
+        KtxConversion::Unpad { bytes_per_pixel } => {
+            let stride = ktx_row_stride(width, bytes_per_pixel);
+            let row_len = width * bytes_per_pixel;
+
+            if stride == row_len {
+                data
+            }
+            else {
+                let mut result = Vec::with_capacity(row_len * height);
+
+                for y in 0..height {
+                    result.extend_from_slice(&data[(y * stride)..(y * stride) + row_len]);
+                }
+
+                result
+            }
+        }
+        KtxConversion::Expand { channels, swizzle, fill } => {
+            let stride = ktx_row_stride(width, channels);
+            let mut result = Vec::with_capacity(width * height * 4);
+
+            for y in 0..height {
+                for x in 0..width {
+                    let src = (y * stride) + (x * channels);
+
+                    for c in 0..4 {
+                        result.push(match swizzle[c] {
+                            Some(v) => data[src + v],
+                            None => fill[c]
+                        });
+                    }
+                }
+            }
+
+            result
+        }
+        KtxConversion::RGBA5551 => {
+            let stride = ktx_row_stride(width, 2);
+            let mut result = Vec::with_capacity(width * height * 4);
+
+            for y in 0..height {
+                for x in 0..width {
+                    let src = (y * stride) + (x * 2);
+                    let p = u16::from_le_bytes([data[src], data[src + 1]]);
+                    let r = ((p >> 11) & 0x1F) as u8;
+                    let g = ((p >> 6) & 0x1F) as u8;
+                    let b = ((p >> 1) & 0x1F) as u8;
+
+                    result.extend_from_slice(&[(r << 3) | (r >> 2), (g << 3) | (g >> 2), (b << 3) | (b >> 2), if p & 1 != 0 { 255 } else { 0 }]);
+                }
+            }
+
+            result
+        }
+        KtxConversion::DXT5 => {
+            let mut result = Vec::with_capacity(data.len());
+
+            for block in data.chunks_exact(16) {
+                let alpha = decode_bc4_block(&block[0..8]);
+
+                let mut bits: u64 = 0;
+                for i in 0..16 {
+                    bits |= ((alpha[i] >> 4) as u64) << (i * 4);
+                }
+
+                result.extend_from_slice(&bits.to_le_bytes());
+                result.extend_from_slice(&block[8..16]);
+            }
+
+            result
+        }
+        KtxConversion::RGTC1 => {
+            let mut result = decode_rgtc(&data, width, height, &[0], [0, 0, 0, 255]);
+
+            // greyscale, so copy red into green & blue
+            for px in result.chunks_exact_mut(4) {
+                px[1] = px[0];
+                px[2] = px[0];
+            }
+
+            result
+        }
+        KtxConversion::RGTC2 => decode_rgtc(&data, width, height, &[0, 1], [0, 0, 0, 255]),
+    };
+
+    Ok(result)
+}
+
+/// Layout of an uncompressed texture format, for generating mipmaps
+#[derive(Clone, Copy)]
+enum PixelLayout {
+    RGBA8888,
+    RGB565,
+    RGBA4444,
+}
+
+// unpack a level of an uncompressed texture into 8 bits per channel
+fn decode_pixels(layout: PixelLayout, data: &[u8]) -> Vec<[u8;4]> {
+    match layout {
+        PixelLayout::RGBA8888 => data.chunks_exact(4).map(|x| [x[0], x[1], x[2], x[3]]).collect(),
+        PixelLayout::RGB565 => data.chunks_exact(2).map(|x| {
+            let p = u16::from_le_bytes([x[0], x[1]]);
+            let r = ((p >> 11) & 0x1F) as u8;
+            let g = ((p >> 5) & 0x3F) as u8;
+            let b = (p & 0x1F) as u8;
+            [(r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2), 255]
+        }).collect(),
+        PixelLayout::RGBA4444 => data.chunks_exact(2).map(|x| {
+            let p = u16::from_le_bytes([x[0], x[1]]);
+            let r = ((p >> 12) & 0xF) as u8;
+            let g = ((p >> 8) & 0xF) as u8;
+            let b = ((p >> 4) & 0xF) as u8;
+            let a = (p & 0xF) as u8;
+            [r * 17, g * 17, b * 17, a * 17]
+        }).collect(),
+    }
+}
+
+// pack 8 bits per channel pixels back into an uncompressed texture format
+fn encode_pixels(layout: PixelLayout, pixels: &[[u8;4]]) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    for c in pixels {
+        match layout {
+            PixelLayout::RGBA8888 => {
+                data.extend_from_slice(c);
+            }
+            PixelLayout::RGB565 => {
+                let p = (((c[0] as u16) >> 3) << 11) | (((c[1] as u16) >> 2) << 5) | ((c[2] as u16) >> 3);
+                data.extend_from_slice(&p.to_le_bytes());
+            }
+            PixelLayout::RGBA4444 => {
+                let p = (((c[0] as u16) >> 4) << 12) | (((c[1] as u16) >> 4) << 8) | (((c[2] as u16) >> 4) << 4) | ((c[3] as u16) >> 4);
+                data.extend_from_slice(&p.to_le_bytes());
+            }
+        };
+    }
+
+    data
+}
+
+// box filter an image down to half size (rounding down, to a minimum of 1x1)
+fn downsample(pixels: &[[u8;4]], width: usize, height: usize) -> (Vec<[u8;4]>, usize, usize) {
+    let new_width = (width / 2).max(1);
+    let new_height = (height / 2).max(1);
+
+    let mut result = Vec::with_capacity(new_width * new_height);
+
+    for y in 0..new_height {
+        for x in 0..new_width {
+            let x0 = (x * 2).min(width - 1);
+            let x1 = (x * 2 + 1).min(width - 1);
+            let y0 = (y * 2).min(height - 1);
+            let y1 = (y * 2 + 1).min(height - 1);
+
+            let mut sum = [0u32;4];
+            for (sx, sy) in [(x0, y0), (x1, y0), (x0, y1), (x1, y1)] {
+                let p = pixels[sx + (sy * width)];
+                for c in 0..4 {
+                    sum[c] += p[c] as u32;
+                }
+            }
+
+            result.push([((sum[0] + 2) / 4) as u8, ((sum[1] + 2) / 4) as u8, ((sum[2] + 2) / 4) as u8, ((sum[3] + 2) / 4) as u8]);
+        }
+    }
+
+    (result, new_width, new_height)
+}
+
+// generate every mip level below the base level of a texture, down to 1x1
+fn build_mipmaps(layout: PixelLayout, base_level: &[u8], width: usize, height: usize) -> Vec<Vec<u8>> {
+    let mut pixels = decode_pixels(layout, base_level);
+    let mut width = width;
+    let mut height = height;
+    let mut levels = Vec::new();
+
+    while width > 1 || height > 1 {
+        let (next, next_width, next_height) = downsample(&pixels, width, height);
+        levels.push(encode_pixels(layout, &next));
+
+        pixels = next;
+        width = next_width;
+        height = next_height;
+    }
+
+    levels
+}
+
+/// Generate & upload every mip level below the base level of a texture, down to 1x1
+fn generate_mipmaps(tex: &Texture, layout: PixelLayout, base_level: &[u8], width: usize, height: usize) {
+    for (i, level) in build_mipmaps(layout, base_level, width, height).iter().enumerate() {
+        tex.set_texture_data(i as i32 + 1, level);
+    }
+}
+
+/// A mono sound effect uploaded to audio memory
+pub struct SoundClip {
+    pub sample: AudioSample,
+    pub samplerate: i32,
+    /// Length of the clip, in samples
+    pub length: usize,
+}
+
+/// Decode a PCM WAV file (8 or 16 bit) into a mono sound clip. Only the first channel of multi-channel files is kept
+fn load_wav<R: Read + Seek>(reader: &mut R) -> Result<SoundClip, ResourceError> {
+    let riff = reader.read_u32::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+    let _riff_len = reader.read_u32::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+    let wave = reader.read_u32::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+
+    if riff != WAV_RIFF_MAGIC || wave != WAV_WAVE_MAGIC {
+        return Err(ResourceError::ParseError);
+    }
+
+    let mut format = None;
+
+    // walk chunks until we find the sample data (fmt chunk must come first)
+    loop {
+        let chunk_id = reader.read_u32::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+        let chunk_len = reader.read_u32::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+
+        // chunks are padded to an even number of bytes
+        let chunk_end = reader.stream_position().map_err(|_| ResourceError::ParseError)? + ((chunk_len + (chunk_len & 1)) as u64);
+
+        match chunk_id {
+            WAV_FMT_CHUNK => {
+                let audio_format = reader.read_u16::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+                let channels = reader.read_u16::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+                let samplerate = reader.read_u32::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+                let _byte_rate = reader.read_u32::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+                let _block_align = reader.read_u16::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+                let bits = reader.read_u16::<LittleEndian>().map_err(|_| ResourceError::ParseError)?;
+
+                if audio_format != WAV_FORMAT_PCM || channels == 0 || (bits != 8 && bits != 16) {
+                    return Err(ResourceError::ParseError);
+                }
+
+                format = Some((channels as usize, samplerate as i32, bits));
+            }
+            WAV_DATA_CHUNK => {
+                let (channels, samplerate, bits) = match format {
+                    Some(v) => v,
+                    None => return Err(ResourceError::ParseError)
+                };
+
+                let mut data: Vec<u8> = vec![0;chunk_len as usize];
+                reader.read_exact(&mut data).map_err(|_| ResourceError::ParseError)?;
+
+                // 8-bit WAV samples are unsigned, 16-bit samples are signed
+                let frame_size = channels * (bits as usize / 8);
+                let samples = data.chunks_exact(frame_size).map(|frame| {
+                    if bits == 8 {
+                        ((frame[0] as i16) - 128) << 8
+                    }
+                    else {
+                        i16::from_le_bytes([frame[0], frame[1]])
+                    }
+                }).collect::<Vec<i16>>();
+
+                let sample = match AudioSample::create_s16(&samples, samplerate) {
+                    Ok(v) => v,
+                    Err(_) => return Err(ResourceError::ParseError)
+                };
+
+                return Ok(SoundClip { sample, samplerate, length: samples.len() });
+            }
+            _ => {
+            }
+        };
+
+        reader.seek(SeekFrom::Start(chunk_end)).map_err(|_| ResourceError::ParseError)?;
+    }
 }
 
 #[derive(Debug)]
 pub enum ResourceError {
     ParseError,
+    /// Not enough VDP memory for the resource
+    AllocationError,
     IOError(IOError)
 }
 
@@ -62,9 +793,16 @@ pub struct TextureLoader {
 
 impl ResourceLoader<Texture> for TextureLoader {
     fn load_resource(path: &str) -> Result<Texture, ResourceError> {    
-        let tex_file = match io::FileStream::open(path, io::FileMode::Read) {
+        let tex_file = match archive::open_file(path) {
             Ok(v) => v,
-            Err(e) => return Err(ResourceError::IOError(e))
+            Err(e) => {
+                // fall back to a Quake 2 WAL texture of the same name
+                let wal_path = Path::new(path).with_extension("wal");
+                return match archive::open_file(wal_path.to_str().unwrap()) {
+                    Ok(mut wal_file) => load_wal(&mut wal_file),
+                    Err(_) => Err(ResourceError::IOError(e))
+                };
+            }
         };
 
         // decode KTX texture
@@ -73,33 +811,42 @@ impl ResourceLoader<Texture> for TextureLoader {
             Err(_) => return Err(ResourceError::ParseError)
         };
 
-        // find appropriate VDP format
-        let tex_fmt = if decoder.gl_type() == GL_UNSIGNED_BYTE && decoder.gl_format() == GL_RGBA {
-            vdp::TextureFormat::RGBA8888
-        } else if decoder.gl_type() == GL_UNSIGNED_SHORT_5_6_5 && decoder.gl_format() == GL_RGB {
-            vdp::TextureFormat::RGB565
-        } else if decoder.gl_type() == GL_UNSIGNED_SHORT_4_4_4_4 && decoder.gl_format() == GL_RGBA {
-            vdp::TextureFormat::RGBA4444
-        } else if decoder.gl_internal_format() == GL_COMPRESSED_RGB_S3TC_DXT1_EXT || decoder.gl_internal_format() == GL_COMPRESSED_RGBA_S3TC_DXT1_EXT {
-            vdp::TextureFormat::DXT1
-        } else if decoder.gl_internal_format() == GL_COMPRESSED_RGBA_S3TC_DXT3_EXT {
-            vdp::TextureFormat::DXT3
-        } else {
-            logfmt!("Failed decoding KTX image: unsupported pixel format");
-            return Err(ResourceError::ParseError);
+        let (tex_fmt, conversion) = ktx_format(decoder.gl_type(), decoder.gl_format(), decoder.gl_internal_format())?;
+
+        // compressed formats can't easily be downsampled, so mipmaps are only generated for uncompressed ones
+        let mip_layout = match tex_fmt {
+            vdp::TextureFormat::RGBA8888 => Some(PixelLayout::RGBA8888),
+            vdp::TextureFormat::RGB565 => Some(PixelLayout::RGB565),
+            vdp::TextureFormat::RGBA4444 => Some(PixelLayout::RGBA4444),
+            _ => None
         };
 
+        let mip_layout = if decoder.mipmap_levels() <= 1 && GENERATE_MIPMAPS.load(Ordering::Relaxed) { mip_layout } else { None };
+
+        let width = decoder.pixel_width() as usize;
+        let height = decoder.pixel_height() as usize;
+
         // allocate VDP texture
-        let tex = Texture::new(
-            decoder.pixel_width() as i32,
-            decoder.pixel_height() as i32,
-            decoder.mipmap_levels() > 1, tex_fmt)
-            .expect("Failed allocating VDP texture");
+        let tex = match Texture::new(width as i32, height as i32, decoder.mipmap_levels() > 1 || mip_layout.is_some(), tex_fmt) {
+            Ok(v) => v,
+            Err(_) => {
+                logfmt!("Failed allocating VDP texture ({}x{})", width, height);
+                return Err(ResourceError::AllocationError);
+            }
+        };
 
         // upload each mip slice
         let mut level: i32 = 0;
         for tex_level in decoder.read_textures() {
+            let tex_level = convert_ktx_level(conversion, tex_level, (width >> level).max(1), (height >> level).max(1))?;
             tex.set_texture_data(level, &tex_level);
+
+            if level == 0 {
+                if let Some(layout) = mip_layout {
+                    generate_mipmaps(&tex, layout, &tex_level, width, height);
+                }
+            }
+
             level += 1;
         }
 
@@ -112,7 +859,7 @@ pub struct MeshLoader {
 
 impl ResourceLoader<DBMesh> for MeshLoader {
     fn load_resource(path: &str) -> Result<DBMesh, ResourceError> {
-        let mut mesh_file = match io::FileStream::open(path, io::FileMode::Read) {
+        let mut mesh_file = match archive::open_file(path) {
             Ok(v) => v,
             Err(e) => return Err(ResourceError::IOError(e))
         };
@@ -146,25 +893,52 @@ pub struct MeshAnimLoader {
 
 impl ResourceLoader<DBAnimationClip> for MeshAnimLoader {
     fn load_resource(path: &str) -> Result<DBAnimationClip, ResourceError> {
-        let mut anim_file = match io::FileStream::open(path, io::FileMode::Read) {
+        let mut anim_file = match archive::open_file(path) {
             Ok(v) => v,
             Err(e) => return Err(ResourceError::IOError(e))
         };
 
-        match DBAnimationClip::new(&mut anim_file) {
-            Ok(v) => Ok(v),
-            Err(_) => Err(ResourceError::ParseError)
-        }
+        // clips with out of order keyframes are rejected here, rather than playing back differently than authored
+        let clip = match DBAnimationClip::new(&mut anim_file) {
+            Ok(v) => v,
+            Err(e) => {
+                logfmt!("Failed loading animation {}: {:?}", path, e);
+                return Err(ResourceError::ParseError)
+            }
+        };
+
+        Ok(clip)
+    }
+}
+
+pub struct SoundLoader {
+}
+
+impl ResourceLoader<SoundClip> for SoundLoader {
+    fn load_resource(path: &str) -> Result<SoundClip, ResourceError> {
+        let mut wav_file = match archive::open_file(path) {
+            Ok(v) => v,
+            Err(e) => return Err(ResourceError::IOError(e))
+        };
+
+        load_wav(&mut wav_file)
     }
 }
 
 /// Implementation of a smart cache with ref counted resources
+/// Resources are keyed on their virtual path, regardless of whether they were loaded from a mounted archive or a loose file
 /// Attempts to load the same resource path more than once will return a reference to the same resource
 /// If all references to the resource are dropped, the resource will be unloaded
+/// Reloaded resources are kept alive by the cache until the next time they are loaded, so that callers can re-fetch them by path
+/// Optionally, the most recently loaded resources are also kept alive, so that loading the same path twice in quick succession doesn't unload & reload it in between
 pub struct ResourceCache<TResource, TResourceLoader>
     where TResourceLoader: ResourceLoader<TResource>
 {
     cache: HashMap<String, Weak<TResource>>,
+    reloaded: HashMap<String, Arc<TResource>>,
+    /// Recently loaded resources, least recently used first
+    recent: Vec<(String, Arc<TResource>)>,
+    retain_count: usize,
     phantom: PhantomData<TResourceLoader>
 }
 
@@ -172,19 +946,59 @@ impl<TResource, TResourceLoader> ResourceCache<TResource, TResourceLoader>
     where TResourceLoader: ResourceLoader<TResource>
 {
     pub fn new() -> ResourceCache<TResource, TResourceLoader> {
+        Self::with_retention(0)
+    }
+
+    /// Construct a cache which keeps the given number of most recently loaded resources alive
+    pub fn with_retention(retain_count: usize) -> ResourceCache<TResource, TResourceLoader> {
         ResourceCache::<TResource, TResourceLoader> {
             cache: HashMap::new(),
+            reloaded: HashMap::new(),
+            recent: Vec::with_capacity(retain_count),
+            retain_count,
             phantom: PhantomData::default()
         }
     }
 
+    /// Change how many recently loaded resources are kept alive, releasing the oldest if there are now too many
+    pub fn set_retain_count(self: &mut Self, retain_count: usize) {
+        self.retain_count = retain_count;
+
+        if self.recent.len() > retain_count {
+            self.recent.drain(0..(self.recent.len() - retain_count));
+        }
+    }
+
+    // mark a resource as the most recently loaded, evicting the least recently loaded if the list is full
+    fn retain(self: &mut Self, path: &str, res: &Arc<TResource>) {
+        if self.retain_count == 0 {
+            return;
+        }
+
+        if let Some(i) = self.recent.iter().position(|(p, _)| p == path) {
+            self.recent.remove(i);
+        }
+        else if self.recent.len() >= self.retain_count {
+            self.recent.remove(0);
+        }
+
+        self.recent.push((path.to_owned(), res.clone()));
+    }
+
     pub fn load(self: &mut Self, path: &str) -> Result<Arc<TResource>, ResourceError> {
+        // hand off a freshly reloaded resource, releasing the cache's own reference to it
+        if let Some(v) = self.reloaded.remove(path) {
+            self.retain(path, &v);
+            return Ok(v);
+        }
+
         if self.cache.contains_key(path) {
             // try and get a reference to the resource, upgraded to a new Rc
             // if that fails, the resource has been unloaded (we'll just load a new one)
             let res = self.cache[path].clone().upgrade();
             match res {
                 Some(v) => {
+                    self.retain(path, &v);
                     return Ok(v);
                 }
                 None => {
@@ -207,10 +1021,340 @@ impl<TResource, TResourceLoader> ResourceCache<TResource, TResourceLoader>
         let store = Arc::downgrade(&res.clone());
 
         self.cache.insert(path.to_owned(), store);
+        self.retain(path, &res);
         return Ok(res);
     }
+
+    /// Re-run the loader for a resource which is currently loaded, replacing the cached copy
+    /// Existing references are left untouched, and the next call to load will return the new copy
+    pub fn reload(self: &mut Self, path: &str) -> Result<(), ResourceError> {
+        let is_live = match self.cache.get(path) {
+            Some(v) => v.strong_count() > 0,
+            None => false
+        };
+
+        if !is_live {
+            self.cache.remove(path);
+            self.reloaded.remove(path);
+            return Ok(());
+        }
+
+        logfmt!("Reloading {}: {}", std::any::type_name::<TResource>(), path);
+
+        let res = match TResourceLoader::load_resource(path) {
+            Ok(v) => Arc::new(v),
+            Err(e) => {
+                logfmt!("\t FAILED: {:?}", e);
+                return Err(e);
+            }
+        };
+
+        self.cache.insert(path.to_owned(), Arc::downgrade(&res));
+        self.reloaded.insert(path.to_owned(), res);
+        Ok(())
+    }
+
+    /// Reload every resource which is currently loaded
+    pub fn reload_all(self: &mut Self) {
+        let paths = self.cache.keys().cloned().collect::<Vec<_>>();
+
+        for path in paths {
+            // failures are logged by reload, and the old copy is left in place
+            let _ = self.reload(&path);
+        }
+    }
 }
 
 pub type TextureCache = ResourceCache<Texture, TextureLoader>;
 pub type MeshCache = ResourceCache<DBMesh, MeshLoader>;
-pub type MeshAnimCache = ResourceCache<DBAnimationClip, MeshAnimLoader>;
\ No newline at end of file
+pub type MeshAnimCache = ResourceCache<DBAnimationClip, MeshAnimLoader>;
+pub type SoundCache = ResourceCache<SoundClip, SoundLoader>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use byteorder::WriteBytesExt;
+
+    thread_local! {
+        // contents of the fake resource files, & how many times they've been loaded. tests each run on their own thread
+        static FIXTURE_VERSION: Cell<u32> = Cell::new(1);
+        static FIXTURE_LOADS: Cell<usize> = Cell::new(0);
+    }
+
+    struct FixtureLoader {
+    }
+
+    impl ResourceLoader<(String, u32)> for FixtureLoader {
+        fn load_resource(path: &str) -> Result<(String, u32), ResourceError> {
+            FIXTURE_LOADS.with(|x| x.set(x.get() + 1));
+            Ok((path.to_owned(), FIXTURE_VERSION.with(|x| x.get())))
+        }
+    }
+
+    type FixtureCache = ResourceCache<(String, u32), FixtureLoader>;
+
+    fn fixture_loads() -> usize {
+        FIXTURE_LOADS.with(|x| x.get())
+    }
+
+    #[test]
+    fn single_level_texture_gains_averaged_mip_chain() {
+        // alternating red & blue columns
+        let mut base = Vec::new();
+        for _ in 0..64 {
+            for x in 0..64 {
+                base.extend_from_slice(if x % 2 == 0 { &[255, 0, 0, 255] } else { &[0, 0, 255, 255] });
+            }
+        }
+
+        let levels = build_mipmaps(PixelLayout::RGBA8888, &base, 64, 64);
+
+        // 32x32 down to 1x1
+        assert_eq!(levels.len(), 6);
+
+        for (i, level) in levels.iter().enumerate() {
+            let size = 32 >> i;
+            assert_eq!(level.len(), size * size * 4);
+
+            for px in level.chunks_exact(4) {
+                assert_eq!(px, &[128, 0, 128, 255]);
+            }
+        }
+    }
+
+    #[test]
+    fn row_padding_is_removed_from_16_bit_levels() {
+        // 3 pixels of 2 bytes each, padded to 8 bytes per row
+        let data = vec![
+            1, 2, 3, 4, 5, 6, 0, 0,
+            7, 8, 9, 10, 11, 12, 0, 0,
+        ];
+
+        let result = convert_ktx_level(KtxConversion::Unpad { bytes_per_pixel: 2 }, data, 3, 2).unwrap();
+        assert_eq!(result, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn ktx_formats_map_to_vdp_formats() {
+        assert!(matches!(ktx_format(GL_UNSIGNED_BYTE, GL_RGBA, 0), Ok((vdp::TextureFormat::RGBA8888, KtxConversion::Unpad { bytes_per_pixel: 4 }))));
+        assert!(matches!(ktx_format(GL_UNSIGNED_SHORT_5_6_5, GL_RGB, 0), Ok((vdp::TextureFormat::RGB565, KtxConversion::Unpad { bytes_per_pixel: 2 }))));
+        assert!(matches!(ktx_format(GL_UNSIGNED_SHORT_4_4_4_4, GL_RGBA, 0), Ok((vdp::TextureFormat::RGBA4444, KtxConversion::Unpad { bytes_per_pixel: 2 }))));
+        assert!(matches!(ktx_format(0, 0, GL_COMPRESSED_RGB_S3TC_DXT1_EXT), Ok((vdp::TextureFormat::DXT1, KtxConversion::None { block_size: 8 }))));
+        assert!(matches!(ktx_format(0, 0, GL_COMPRESSED_RGBA_S3TC_DXT1_EXT), Ok((vdp::TextureFormat::DXT1, KtxConversion::None { block_size: 8 }))));
+        assert!(matches!(ktx_format(0, 0, GL_COMPRESSED_RGBA_S3TC_DXT3_EXT), Ok((vdp::TextureFormat::DXT3, KtxConversion::None { block_size: 16 }))));
+        assert!(matches!(ktx_format(0, 0, GL_COMPRESSED_RGBA_S3TC_DXT5_EXT), Ok((vdp::TextureFormat::DXT3, KtxConversion::DXT5))));
+        assert!(matches!(ktx_format(0, 0, GL_COMPRESSED_RED_RGTC1), Ok((vdp::TextureFormat::RGBA8888, KtxConversion::RGTC1))));
+        assert!(matches!(ktx_format(0, 0, GL_COMPRESSED_RG_RGTC2), Ok((vdp::TextureFormat::RGBA8888, KtxConversion::RGTC2))));
+        assert!(matches!(ktx_format(GL_UNSIGNED_SHORT_5_5_5_1, GL_RGBA, 0), Ok((vdp::TextureFormat::RGBA8888, KtxConversion::RGBA5551))));
+
+        for format in [GL_RGB, GL_BGR, GL_BGRA, GL_LUMINANCE, GL_LUMINANCE_ALPHA, GL_ALPHA, GL_RED, GL_RG] {
+            assert!(matches!(ktx_format(GL_UNSIGNED_BYTE, format, 0), Ok((vdp::TextureFormat::RGBA8888, KtxConversion::Expand { .. }))));
+        }
+
+        assert!(matches!(ktx_format(GL_UNSIGNED_BYTE, 0x1234, 0), Err(ResourceError::ParseError)));
+        assert!(matches!(ktx_format(0x1234, GL_RGBA, 0x1234), Err(ResourceError::ParseError)));
+    }
+
+    fn convert(gl_type: u32, gl_format: u32, gl_internal_format: u32, data: Vec<u8>, width: usize, height: usize) -> Vec<u8> {
+        let (_, conversion) = ktx_format(gl_type, gl_format, gl_internal_format).unwrap();
+        convert_ktx_level(conversion, data, width, height).unwrap()
+    }
+
+    #[test]
+    fn expanded_formats_are_swizzled_into_rgba() {
+        // 1x1, rows padded to 4 bytes
+        assert_eq!(convert(GL_UNSIGNED_BYTE, GL_RGB, 0, vec![10, 20, 30, 0], 1, 1), vec![10, 20, 30, 255]);
+        assert_eq!(convert(GL_UNSIGNED_BYTE, GL_BGR, 0, vec![10, 20, 30, 0], 1, 1), vec![30, 20, 10, 255]);
+        assert_eq!(convert(GL_UNSIGNED_BYTE, GL_BGRA, 0, vec![10, 20, 30, 40], 1, 1), vec![30, 20, 10, 40]);
+        assert_eq!(convert(GL_UNSIGNED_BYTE, GL_LUMINANCE, 0, vec![10, 0, 0, 0], 1, 1), vec![10, 10, 10, 255]);
+        assert_eq!(convert(GL_UNSIGNED_BYTE, GL_LUMINANCE_ALPHA, 0, vec![10, 20, 0, 0], 1, 1), vec![10, 10, 10, 20]);
+        assert_eq!(convert(GL_UNSIGNED_BYTE, GL_ALPHA, 0, vec![10, 0, 0, 0], 1, 1), vec![255, 255, 255, 10]);
+        assert_eq!(convert(GL_UNSIGNED_BYTE, GL_RED, 0, vec![10, 0, 0, 0], 1, 1), vec![10, 0, 0, 255]);
+        assert_eq!(convert(GL_UNSIGNED_BYTE, GL_RG, 0, vec![10, 20, 0, 0], 1, 1), vec![10, 20, 0, 255]);
+
+        // second row starts after the first row's padding
+        assert_eq!(convert(GL_UNSIGNED_BYTE, GL_LUMINANCE, 0, vec![10, 0, 0, 0, 20, 0, 0, 0], 1, 2), vec![10, 10, 10, 255, 20, 20, 20, 255]);
+    }
+
+    #[test]
+    fn rgba5551_is_expanded_to_rgba8888() {
+        // opaque red, then transparent white
+        let red = ((0x1Fu16 << 11) | 1).to_le_bytes();
+        let white = ((0x1Fu16 << 11) | (0x1F << 6) | (0x1F << 1)).to_le_bytes();
+        let data = vec![red[0], red[1], white[0], white[1]];
+
+        assert_eq!(convert(GL_UNSIGNED_SHORT_5_5_5_1, GL_RGBA, 0, data, 2, 1), vec![255, 0, 0, 255, 255, 255, 255, 0]);
+    }
+
+    #[test]
+    fn dxt5_alpha_is_requantized_to_dxt3() {
+        // every texel uses the first alpha endpoint (0xFF), color block is passed through
+        let mut block = vec![0xFF, 0x00, 0, 0, 0, 0, 0, 0];
+        block.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let result = convert(0, 0, GL_COMPRESSED_RGBA_S3TC_DXT5_EXT, block, 4, 4);
+        assert_eq!(&result[0..8], &[0xFF;8]);
+        assert_eq!(&result[8..16], &[1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn rgtc_is_decoded_to_rgba8888() {
+        // every texel uses the first endpoint
+        let result = convert(0, 0, GL_COMPRESSED_RED_RGTC1, vec![200, 0, 0, 0, 0, 0, 0, 0], 4, 4);
+        assert_eq!(result.len(), 4 * 4 * 4);
+        assert!(result.chunks_exact(4).all(|x| x == &[200, 200, 200, 255]));
+
+        let result = convert(0, 0, GL_COMPRESSED_RG_RGTC2, vec![200, 0, 0, 0, 0, 0, 0, 0, 100, 0, 0, 0, 0, 0, 0, 0], 4, 4);
+        assert_eq!(result.len(), 4 * 4 * 4);
+        assert!(result.chunks_exact(4).all(|x| x == &[200, 100, 0, 255]));
+    }
+
+    #[test]
+    fn short_levels_are_rejected() {
+        // 3x2 RGB565 needs two 8 byte rows
+        let result = convert_ktx_level(KtxConversion::Unpad { bytes_per_pixel: 2 }, vec![0;14], 3, 2);
+        assert!(matches!(result, Err(ResourceError::ParseError)));
+
+        let result = convert_ktx_level(KtxConversion::Expand { channels: 3, swizzle: [Some(0), Some(1), Some(2), None], fill: [0, 0, 0, 255] }, vec![0;4], 2, 1);
+        assert!(matches!(result, Err(ResourceError::ParseError)));
+
+        let result = convert_ktx_level(KtxConversion::DXT5, vec![0;16], 8, 4);
+        assert!(matches!(result, Err(ResourceError::ParseError)));
+
+        let result = convert_ktx_level(KtxConversion::None { block_size: 8 }, vec![0;7], 4, 4);
+        assert!(matches!(result, Err(ResourceError::ParseError)));
+    }
+
+    // WAL file with every stored level filled with the given palette index
+    fn wal_bytes(width: u32, height: u32, index: u8) -> Vec<u8> {
+        let mut out = vec![0u8;32];
+        out.write_u32::<LittleEndian>(width).unwrap();
+        out.write_u32::<LittleEndian>(height).unwrap();
+
+        // mip offsets, then the rest of the 100 byte header
+        let mut offset = 100;
+        for level in 0..WAL_NUM_MIPS {
+            out.write_u32::<LittleEndian>(offset).unwrap();
+            offset += (width >> level) * (height >> level);
+        }
+        out.resize(100, 0);
+
+        for level in 0..WAL_NUM_MIPS {
+            out.extend(vec![index;((width >> level) * (height >> level)) as usize]);
+        }
+
+        out
+    }
+
+    #[test]
+    fn wal_levels_are_decoded_and_rest_of_mip_chain_generated() {
+        let mut palette: WalPalette = [0;768];
+        palette[3..6].copy_from_slice(&[255, 0, 0]);
+
+        let (width, height, levels) = decode_wal(&mut std::io::Cursor::new(wal_bytes(16, 16, 1)), &palette).unwrap();
+        assert_eq!((width, height), (16, 16));
+
+        // 4 stored levels from 16x16 to 2x2, plus a generated 1x1 level
+        assert_eq!(levels.len(), 5);
+
+        for (i, level) in levels.iter().enumerate() {
+            let size = 16 >> i;
+            assert_eq!(level.len(), size * size * 2);
+
+            // pure red in RGB565
+            for px in level.chunks_exact(2) {
+                assert_eq!(px, &0xF800u16.to_le_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn wal_without_levels_is_rejected() {
+        let mut data = wal_bytes(16, 16, 1);
+        data[40..44].copy_from_slice(&[0, 0, 0, 0]);
+
+        assert!(matches!(decode_wal(&mut std::io::Cursor::new(data), &[0;768]), Err(ResourceError::ParseError)));
+    }
+
+    #[test]
+    fn load_after_reload_returns_updated_resource() {
+        let mut cache = FixtureCache::new();
+        let old = cache.load("textures/wall01.ktx").unwrap();
+        assert_eq!(old.1, 1);
+
+        // edit the file on disk
+        FIXTURE_VERSION.with(|x| x.set(2));
+        cache.reload_all();
+        assert_eq!(fixture_loads(), 2);
+
+        // existing references keep the old data, while re-fetching picks up the new data
+        let new = cache.load("textures/wall01.ktx").unwrap();
+        assert_eq!(old.1, 1);
+        assert_eq!(new.1, 2);
+        assert!(!Arc::ptr_eq(&old, &new));
+
+        // reloading a resource nobody holds onto doesn't load it again
+        drop(old);
+        drop(new);
+        cache.reload("textures/wall01.ktx").unwrap();
+        assert_eq!(fixture_loads(), 2);
+    }
+
+    #[test]
+    fn held_resource_is_shared_without_reloading() {
+        let mut cache = FixtureCache::new();
+
+        // a preloaded resource stays loaded for as long as something holds onto it
+        let preloaded = cache.load("sound/door.wav").unwrap();
+        let loaded = cache.load("sound/door.wav").unwrap();
+
+        assert!(Arc::ptr_eq(&preloaded, &loaded));
+        assert_eq!(fixture_loads(), 1);
+
+        // other paths get their own resource
+        let other = cache.load("sound/step.wav").unwrap();
+        assert!(!Arc::ptr_eq(&preloaded, &other));
+        assert_eq!(fixture_loads(), 2);
+
+        // once every reference is dropped, the next load goes back to the loader
+        drop(preloaded);
+        drop(loaded);
+        cache.load("sound/door.wav").unwrap();
+        assert_eq!(fixture_loads(), 3);
+    }
+
+    #[test]
+    fn recent_loads_are_retained_until_evicted() {
+        let mut cache = FixtureCache::with_retention(2);
+
+        // back to back loads reuse the same resource, even with no other owner in between
+        let first = Arc::as_ptr(&cache.load("models/crate.dbm").unwrap());
+        let second = Arc::as_ptr(&cache.load("models/crate.dbm").unwrap());
+        assert_eq!(first, second);
+        assert_eq!(fixture_loads(), 1);
+
+        // it stays retained while another path is loaded
+        cache.load("models/barrel.dbm").unwrap();
+        assert_eq!(fixture_loads(), 2);
+        cache.load("models/crate.dbm").unwrap();
+        assert_eq!(fixture_loads(), 2);
+
+        // but loading enough other paths pushes it out of the retention list, after which it's unloaded
+        cache.load("models/barrel.dbm").unwrap();
+        cache.load("models/lamp.dbm").unwrap();
+        cache.load("models/crate.dbm").unwrap();
+        assert_eq!(fixture_loads(), 4);
+
+        // shrinking the retention list releases the oldest resources right away
+        cache.set_retain_count(0);
+        cache.load("models/lamp.dbm").unwrap();
+        assert_eq!(fixture_loads(), 5);
+
+        // without retention, nothing is kept alive
+        let mut cache = FixtureCache::new();
+        cache.load("models/crate.dbm").unwrap();
+        cache.load("models/crate.dbm").unwrap();
+        assert_eq!(fixture_loads(), 7);
+    }
+}
\ No newline at end of file
@@ -1,10 +1,22 @@
 use std::collections::HashSet;
-use dbsdk_rs::math::Vector3;
+use dbsdk_rs::{math::{Vector2, Vector3}, vdp::Color32};
 use hecs::Entity;
-use crate::bsp_file::{BspFile, MASK_SOLID};
+use crate::{bsp_file::{BspFile, TexInfo, CUSTOM_LIGHT_LAYER_END, CUSTOM_LIGHT_LAYER_START, MASK_SHOT, MASK_SOLID, NUM_CUSTOM_LIGHT_LAYERS, SURF_NOLM}, common};
 
 const DIST_EPSILON: f32 = 0.01;
 
+// how far outside of a brush's other planes a sphere's contact point may lie & still count as touching the face
+const CONTACT_EPSILON: f32 = 0.1;
+
+// bouncing objects slower than this after a bounce come to rest
+const BOUNCE_REST_SPEED: f32 = 10.0;
+
+/// Cosine of the steepest slope which counts as walkable ground for movement queries (45 degrees, the same as the character controller's default)
+pub const GROUND_SLOPE_COS_ANGLE: f32 = 0.70710678;
+
+// half-size of the initial polygon built on each brush plane before it's clipped down to the brush
+const BRUSH_WINDING_SIZE: f32 = 65536.0;
+
 #[derive(Clone, Copy)]
 pub struct Trace {
     pub all_solid: bool,
@@ -12,9 +24,130 @@ pub struct Trace {
     pub fraction: f32,
     pub end_pos: Vector3,
     pub hit_normal: Vector3,
+    /// Index of the brush side which was hit, if the trace hit map geometry
+    pub hit_side: Option<usize>,
+    /// Contents of the brush which was hit, if any
+    pub hit_contents: u32,
+    /// Index of the submodel which was hit, if the trace hit a map model rather than the world
+    pub hit_submodel: Option<usize>,
     pub entity: Option<Entity>
 }
 
+/// Shape which is swept along a trace
+#[derive(Clone, Copy)]
+pub enum TraceShape {
+    /// Axis-aligned box with the given extents (half the box's total size)
+    Box(Vector3),
+    /// Upright cylinder. Rounds off the box offsets on the XY axes, so shapes slide along angled walls instead of catching on them
+    Cylinder { radius: f32, half_height: f32 },
+    /// Sphere with the given radius. Hits against a brush's edges & corners are tested exactly, so the shape rolls past corners which a box would catch on
+    Sphere(f32),
+}
+
+impl TraceShape {
+    /// Extents of the axis-aligned box which bounds this shape
+    pub fn extents(self: &Self) -> Vector3 {
+        match self {
+            TraceShape::Box(v) => *v,
+            TraceShape::Cylinder { radius, half_height } => Vector3::new(*radius, *radius, *half_height),
+            TraceShape::Sphere(radius) => Vector3::new(*radius, *radius, *radius)
+        }
+    }
+
+    /// Distance from the shape's center to its furthest point along the negative plane normal
+    pub fn plane_offset(self: &Self, normal: &Vector3) -> f32 {
+        match self {
+            TraceShape::Box(v) => {
+                (v.x * normal.x).abs() +
+                (v.y * normal.y).abs() +
+                (v.z * normal.z).abs()
+            }
+            TraceShape::Cylinder { radius, half_height } => {
+                (radius * ((normal.x * normal.x) + (normal.y * normal.y)).sqrt()) +
+                (half_height * normal.z).abs()
+            }
+            TraceShape::Sphere(radius) => *radius
+        }
+    }
+}
+
+fn closest_point_on_segment(p: &Vector3, a: &Vector3, b: &Vector3) -> Vector3 {
+    let edge = *b - *a;
+    let s = (Vector3::dot(&(*p - *a), &edge) / Vector3::dot(&edge, &edge)).clamp(0.0, 1.0);
+    *a + (edge * s)
+}
+
+// earliest fraction along delta at which a moving sphere touches a point, if any
+fn sweep_sphere_point(start: &Vector3, delta: &Vector3, radius: f32, point: &Vector3) -> Option<f32> {
+    let m = *start - *point;
+    let a = Vector3::dot(delta, delta);
+    let b = Vector3::dot(&m, delta);
+    let c = Vector3::dot(&m, &m) - (radius * radius);
+
+    // already touching: only counts as a hit if moving closer
+    if c <= 0.0 {
+        return if b < 0.0 { Some(0.0) } else { None };
+    }
+
+    let disc = (b * b) - (a * c);
+    if a <= 0.0 || disc < 0.0 {
+        return None;
+    }
+
+    let t = (-b - disc.sqrt()) / a;
+    if t >= 0.0 && t <= 1.0 { Some(t) } else { None }
+}
+
+// earliest fraction along delta at which a moving sphere touches the segment from a to b (excluding its end points), if any
+fn sweep_sphere_segment(start: &Vector3, delta: &Vector3, radius: f32, a: &Vector3, b: &Vector3) -> Option<f32> {
+    let edge = *b - *a;
+    let edge_len_sq = Vector3::dot(&edge, &edge);
+    if edge_len_sq <= 0.0 {
+        return None;
+    }
+
+    // with motion along the edge projected out, this is a sweep against an infinite cylinder around the edge
+    let m = *start - *a;
+    let m_perp = m - (edge * (Vector3::dot(&m, &edge) / edge_len_sq));
+    let d_perp = *delta - (edge * (Vector3::dot(delta, &edge) / edge_len_sq));
+
+    let qa = Vector3::dot(&d_perp, &d_perp);
+    let qb = Vector3::dot(&m_perp, &d_perp);
+    let qc = Vector3::dot(&m_perp, &m_perp) - (radius * radius);
+
+    let t = if qc <= 0.0 {
+        if qb < 0.0 { 0.0 } else { return None; }
+    }
+    else {
+        let disc = (qb * qb) - (qa * qc);
+        if qa <= 0.0 || disc < 0.0 {
+            return None;
+        }
+
+        (-qb - disc.sqrt()) / qa
+    };
+
+    if t < 0.0 || t > 1.0 {
+        return None;
+    }
+
+    // must touch between the end points, which are handled separately
+    let s = Vector3::dot(&(*start + (*delta * t) - *a), &edge) / edge_len_sq;
+    if s >= 0.0 && s <= 1.0 { Some(t) } else { None }
+}
+
+/// Result of a successful raycast against map geometry
+#[derive(Clone, Copy)]
+pub struct RayHit<'a> {
+    pub position: Vector3,
+    pub normal: Vector3,
+    pub fraction: f32,
+    pub distance: f32,
+    pub contents: u32,
+    /// Surface info of the brush side which was hit, if it has one
+    pub tex_info: Option<&'a TexInfo>,
+}
+
 impl BspFile {
     pub fn trace_aabb(aabb_center: &Vector3, aabb_extents: &Vector3, start: &Vector3, end: &Vector3, box_extents: Option<&Vector3>, trace: &mut Trace) -> bool {
         let planes = [
@@ -99,6 +232,9 @@ impl BspFile {
 
                 trace.fraction = enterfrac;
                 trace.hit_normal = hit_normal;
+                trace.hit_side = None;
+                trace.hit_contents = 0;
+                trace.hit_submodel = None;
                 trace.end_pos = *start + ((*end - *start) * enterfrac);
 
                 return true;
@@ -108,7 +244,7 @@ impl BspFile {
         return false;
     }
 
-    fn trace_brush(self: &Self, brush_idx: usize, start: &Vector3, end: &Vector3, frac_adj: f32, box_extents: Option<&Vector3>, trace: &mut Trace) {
+    fn trace_brush(self: &Self, brush_idx: usize, start: &Vector3, end: &Vector3, frac_adj: f32, shape: Option<&TraceShape>, trace: &mut Trace) {
         let brush = &self.brush_lump.brushes[brush_idx];
 
         if brush.num_brush_sides == 0 {
@@ -116,24 +252,20 @@ impl BspFile {
         }
 
         let mut hit_normal = Vector3::zero();
+        let mut hit_side = 0;
         let mut enterfrac = f32::MIN;
         let mut exitfrac = 1.0;
         let mut startout = false;
         let mut getout = false;
 
         for i in 0..brush.num_brush_sides {
-            let side = &self.brush_side_lump.brush_sides[(brush.first_brush_side + i) as usize];
+            let side_idx = (brush.first_brush_side + i) as usize;
+            let side = &self.brush_side_lump.brush_sides[side_idx];
             let plane = &self.plane_lump.planes[side.plane as usize];
 
-            let dist = match box_extents {
+            let dist = match shape {
                 Some(v) => {
-                    let offs = Vector3::new(
-                        if plane.normal.x < 0.0 { v.x } else { -v.x },
-                        if plane.normal.y < 0.0 { v.y } else { -v.y },
-                        if plane.normal.z < 0.0 { v.z } else { -v.z }
-                    );
-
-                    plane.distance - Vector3::dot(&offs, &plane.normal)
+                    plane.distance + v.plane_offset(&plane.normal)
                 }
                 None => {
                     plane.distance
@@ -164,6 +296,7 @@ impl BspFile {
                 if f > enterfrac {
                     enterfrac = f;
                     hit_normal = plane.normal;
+                    hit_side = side_idx;
                 }
             }
             else {
@@ -189,13 +322,87 @@ impl BspFile {
                     enterfrac = 0.0;
                 }
 
+                // offsetting the planes rounds off nothing, so a sphere may only be grazing one of the brush's edges or corners
+                let (enterfrac, hit_normal) = match shape {
+                    Some(TraceShape::Sphere(radius)) => {
+                        match self.sphere_brush_contact(brush_idx, start, end, *radius, hit_side, &hit_normal, enterfrac) {
+                            Some(v) => v,
+                            None => return
+                        }
+                    }
+                    _ => (enterfrac, hit_normal)
+                };
+
+                if enterfrac >= trace.fraction {
+                    return;
+                }
+
                 trace.fraction = enterfrac + frac_adj;
                 trace.hit_normal = hit_normal;
+                trace.hit_side = Some(hit_side);
+                trace.hit_contents = brush.contents;
             }
         }
     }
 
-    fn trace_leaf(self: &Self, leaf_index: usize, checked_brush: &mut HashSet<u16>, content_mask: u32, start: &Vector3, end: &Vector3, frac_adj: f32, box_extents: Option<&Vector3>, trace: &mut Trace) {
+    // given where a sphere first crosses one of the brush's offset planes, find where it actually touches the brush.
+    // if the contact point lies on the face, that's the hit. otherwise the sphere is passing by an edge or corner of the brush, & each of those is swept against directly
+    fn sphere_brush_contact(self: &Self, brush_idx: usize, start: &Vector3, end: &Vector3, radius: f32, hit_side: usize, hit_normal: &Vector3, enterfrac: f32) -> Option<(f32, Vector3)> {
+        let brush = &self.brush_lump.brushes[brush_idx];
+        let delta = *end - *start;
+        let contact = *start + (delta * enterfrac) - (*hit_normal * radius);
+
+        let mut on_face = true;
+        for i in 0..brush.num_brush_sides {
+            let side_idx = (brush.first_brush_side + i) as usize;
+            if side_idx == hit_side {
+                continue;
+            }
+
+            let plane = &self.plane_lump.planes[self.brush_side_lump.brush_sides[side_idx].plane as usize];
+            if Vector3::dot(&contact, &plane.normal) - plane.distance > CONTACT_EPSILON {
+                on_face = false;
+                break;
+            }
+        }
+
+        if on_face {
+            return Some((enterfrac, *hit_normal));
+        }
+
+        let move_len = delta.length();
+        if move_len <= 0.0 {
+            return None;
+        }
+
+        let mut nearest: Option<(f32, Vector3)> = None;
+
+        for poly in self.brush_polygons(brush_idx) {
+            for i in 0..poly.len() {
+                let a = poly[i];
+                let b = poly[(i + 1) % poly.len()];
+
+                let hits = [
+                    sweep_sphere_point(start, &delta, radius, &a).map(|t| (t, a)),
+                    sweep_sphere_segment(start, &delta, radius, &a, &b).map(|t| {
+                        (t, closest_point_on_segment(&(*start + (delta * t)), &a, &b))
+                    })
+                ];
+
+                for (t, point) in hits.iter().flatten() {
+                    if nearest.map_or(true, |(nearest_t, _)| *t < nearest_t) {
+                        let normal = (*start + (delta * *t)) - *point;
+                        nearest = Some((*t, normal.normalized()));
+                    }
+                }
+            }
+        }
+
+        // back off slightly, the same as plane hits do
+        nearest.map(|(t, normal)| ((t - (DIST_EPSILON / move_len)).max(0.0), normal))
+    }
+
+    fn trace_leaf(self: &Self, leaf_index: usize, checked_brush: &mut HashSet<u16>, content_mask: u32, start: &Vector3, end: &Vector3, frac_adj: f32, shape: Option<&TraceShape>, trace: &mut Trace) {
         let leaf = &self.leaf_lump.leaves[leaf_index];
 
         if leaf.contents & content_mask == 0 {
@@ -218,7 +425,7 @@ impl BspFile {
                 return;
             }
 
-            self.trace_brush(brush_idx as usize, start, end, frac_adj, box_extents, trace);
+            self.trace_brush(brush_idx as usize, start, end, frac_adj, shape, trace);
 
             if trace.fraction <= 0.0 {
                 return;
@@ -226,18 +433,19 @@ impl BspFile {
         }
     }
 
-    fn recursive_trace(self: &Self, node_idx: i32, checked_brush: &mut HashSet<u16>, content_mask: u32, p1f: f32, p2f: f32, start: &Vector3, end: &Vector3, frac_adj: f32, box_extents: Option<&Vector3>, trace: &mut Trace) {
+    fn recursive_trace(self: &Self, node_idx: i32, checked_brush: &mut HashSet<u16>, content_mask: u32, p1f: f32, p2f: f32, start: &Vector3, end: &Vector3, frac_adj: f32, shape: Option<&TraceShape>, trace: &mut Trace) {
         if trace.fraction <= p1f {
             return;
         }
         
         if node_idx < 0 {
-            self.trace_leaf((-node_idx - 1) as usize, checked_brush, content_mask, start, end, frac_adj, box_extents, trace);
+            self.trace_leaf((-node_idx - 1) as usize, checked_brush, content_mask, start, end, frac_adj, shape, trace);
             return;
         }
 
         let node = &self.node_lump.nodes[node_idx as usize];
         let plane = &self.plane_lump.planes[node.plane as usize];
+        let box_extents = shape.map(|v| v.extents());
 
         let (t1, t2, offset) = if plane.plane_type == 0 {
             let t1 = start.x - plane.distance;
@@ -284,11 +492,9 @@ impl BspFile {
         else {
             let t1 = Vector3::dot(&plane.normal, start) - plane.distance;
             let t2 = Vector3::dot(&plane.normal, end) - plane.distance;
-            let offset = match box_extents {
+            let offset = match shape {
                 Some(v) => {
-                    (v.x * plane.normal.x).abs() +
-                    (v.y * plane.normal.y).abs() +
-                    (v.z * plane.normal.z).abs()
+                    v.plane_offset(&plane.normal)
                 },
                 None => {
                     0.0
@@ -299,17 +505,17 @@ impl BspFile {
         };
 
         if t1 >= offset && t2 >= offset {
-            self.recursive_trace(node.front_child, checked_brush, content_mask, p1f, p2f, start, end, frac_adj, box_extents, trace);
+            self.recursive_trace(node.front_child, checked_brush, content_mask, p1f, p2f, start, end, frac_adj, shape, trace);
             return;
         }
 
         if t1 < -offset && t2 < -offset {
-            self.recursive_trace(node.back_child, checked_brush, content_mask, p1f, p2f, start, end, frac_adj, box_extents, trace);
+            self.recursive_trace(node.back_child, checked_brush, content_mask, p1f, p2f, start, end, frac_adj, shape, trace);
             return;
         }
 
-        self.recursive_trace(node.front_child, checked_brush, content_mask, p1f, p2f, start, end, frac_adj, box_extents, trace);
-        self.recursive_trace(node.back_child, checked_brush, content_mask, p1f, p2f, start, end, frac_adj, box_extents, trace);
+        self.recursive_trace(node.front_child, checked_brush, content_mask, p1f, p2f, start, end, frac_adj, shape, trace);
+        self.recursive_trace(node.back_child, checked_brush, content_mask, p1f, p2f, start, end, frac_adj, shape, trace);
 
         /*let (side, frac2, frac) = if t1 < t2 {
             let idist = 1.0 / (t1 - t2);
@@ -341,7 +547,7 @@ impl BspFile {
         let midf = p1f + ((p2f - p1f) * frac);
         let mid = *start + ((*end - *start) * frac);
 
-        self.recursive_trace(if side { node.back_child } else { node.front_child }, checked_brush, content_mask, p1f, midf, start, &mid, frac_adj, box_extents, trace);
+        self.recursive_trace(if side { node.back_child } else { node.front_child }, checked_brush, content_mask, p1f, midf, start, &mid, frac_adj, shape, trace);
 
         // go past the node
         let frac2 = frac2.clamp(0.0, 1.0);
@@ -349,29 +555,34 @@ impl BspFile {
         let midf = p1f + ((p2f - p1f) * frac2);
         let mid = *start + ((*end - *start) * frac2);
 
-        self.recursive_trace(if side { node.front_child } else { node.back_child }, checked_brush, content_mask, midf, p2f, &mid, end, frac_adj + frac2, box_extents, trace);*/
+        self.recursive_trace(if side { node.front_child } else { node.back_child }, checked_brush, content_mask, midf, p2f, &mid, end, frac_adj + frac2, shape, trace);*/
     }
 
-    /// Checks if a given box overlaps collision shapes
-    pub fn box_check(self: &Self, content_mask: u32, start: &Vector3, box_extents: Vector3) -> bool {
-        let head_node = self.submodel_lump.submodels[0].headnode as i32;
+    /// Checks if a given box overlaps collision shapes in the world model
+    pub fn box_check(self: &Self, content_mask: u32, center: &Vector3, box_extents: Vector3) -> bool {
+        // a zero-length sweep only ever reports whether it started inside something
+        let trace = self.boxtrace(0, content_mask, center, center, box_extents);
+        trace.start_solid || trace.all_solid
+    }
 
-        let mut trace_trace = Trace {
-            all_solid: false,
-            start_solid: false,
-            fraction: 1.0,
-            end_pos: Vector3::zero(),
-            hit_normal: Vector3::zero(),
-            entity: None
-        };
+    /// Sweeps a box shape against the contents of the given submodel & returns information about what was hit and where, if any
+    pub fn boxtrace(self: &Self, model_index: usize, content_mask: u32, start: &Vector3, end: &Vector3, box_extents: Vector3) -> Trace {
+        self.shapetrace(model_index, content_mask, start, end, TraceShape::Box(box_extents))
+    }
 
-        self.recursive_trace(head_node, &mut HashSet::<u16>::new(), content_mask, 0.0, 1.0, start, start, 0.0, Some(&box_extents), &mut trace_trace);
+    /// Sweeps an upright cylinder against the contents of the given submodel & returns information about what was hit and where, if any
+    pub fn cylindertrace(self: &Self, model_index: usize, content_mask: u32, start: &Vector3, end: &Vector3, radius: f32, half_height: f32) -> Trace {
+        self.shapetrace(model_index, content_mask, start, end, TraceShape::Cylinder { radius, half_height })
+    }
 
-        trace_trace.start_solid
+    /// Sweeps a sphere against the contents of the world model & returns information about what was hit and where, if any
+    /// Intended for projectiles & thrown objects, which can bounce off using the trace's hit normal
+    pub fn spherecast(self: &Self, content_mask: u32, start: &Vector3, end: &Vector3, radius: f32) -> Trace {
+        self.shapetrace(0, content_mask, start, end, TraceShape::Sphere(radius))
     }
 
-    /// Sweeps a box shape against the contents of the given submodel & returns information about what was hit and where, if any
-    pub fn boxtrace(self: &Self, model_index: usize, content_mask: u32, start: &Vector3, end: &Vector3, box_extents: Vector3) -> Trace {
+    /// Sweeps the given shape against the contents of the given submodel & returns information about what was hit and where, if any
+    pub fn shapetrace(self: &Self, model_index: usize, content_mask: u32, start: &Vector3, end: &Vector3, shape: TraceShape) -> Trace {
         let head_node = self.submodel_lump.submodels[model_index].headnode as i32;
 
         let mut trace_trace = Trace {
@@ -380,10 +591,17 @@ impl BspFile {
             fraction: 1.0,
             end_pos: Vector3::zero(),
             hit_normal: Vector3::zero(),
+            hit_side: None,
+            hit_contents: 0,
+            hit_submodel: None,
             entity: None
         };
 
-        self.recursive_trace(head_node, &mut HashSet::<u16>::new(), content_mask, 0.0, 1.0, start, end, 0.0, Some(&box_extents), &mut trace_trace);
+        self.recursive_trace(head_node, &mut HashSet::<u16>::new(), content_mask, 0.0, 1.0, start, end, 0.0, Some(&shape), &mut trace_trace);
+
+        if model_index != 0 && (trace_trace.fraction < 1.0 || trace_trace.start_solid) {
+            trace_trace.hit_submodel = Some(model_index);
+        }
 
         if trace_trace.fraction == 1.0 {
             trace_trace.end_pos = *end;
@@ -405,11 +623,18 @@ impl BspFile {
             fraction: 1.0,
             end_pos: Vector3::zero(),
             hit_normal: Vector3::zero(),
+            hit_side: None,
+            hit_contents: 0,
+            hit_submodel: None,
             entity: None
         };
 
         self.recursive_trace(head_node, &mut HashSet::<u16>::new(), content_mask, 0.0, 1.0, start, end, 0.0, None, &mut trace_trace);
 
+        if model_index != 0 && (trace_trace.fraction < 1.0 || trace_trace.start_solid) {
+            trace_trace.hit_submodel = Some(model_index);
+        }
+
         if trace_trace.fraction == 1.0 {
             trace_trace.end_pos = *end;
         }
@@ -420,6 +645,39 @@ impl BspFile {
         trace_trace
     }
 
+    /// Cast a ray against the world & return the nearest hit within max_dist, if any
+    /// Includes the surface info of the brush side which was hit, for identifying what material was hit
+    pub fn raycast(self: &Self, start: &Vector3, dir: &Vector3, max_dist: f32, content_mask: u32) -> Option<RayHit> {
+        // a zero length direction can't be normalized
+        if dir.length() <= f32::EPSILON {
+            return None;
+        }
+
+        let end = *start + (dir.normalized() * max_dist);
+        let trace = self.linetrace(0, content_mask, start, &end);
+
+        if trace.fraction >= 1.0 {
+            return None;
+        }
+
+        let tex_info = match trace.hit_side {
+            Some(v) => {
+                let tex = self.brush_side_lump.brush_sides[v].tex as usize;
+                self.tex_info_lump.textures.get(tex)
+            }
+            None => None
+        };
+
+        Some(RayHit {
+            position: trace.end_pos,
+            normal: trace.hit_normal,
+            fraction: trace.fraction,
+            distance: trace.fraction * max_dist,
+            contents: trace.hit_contents,
+            tex_info
+        })
+    }
+
     /// Calculate the index of the leaf node which contains the given point
     pub fn calc_leaf_index(self: &Self, position: &Vector3) -> i32 {
         let mut cur_node: i32 = 0;
@@ -442,6 +700,244 @@ impl BspFile {
         return -cur_node - 1;
     }
 
+    /// Get the contents flags of the leaf which contains the given point
+    pub fn point_contents(self: &Self, position: &Vector3) -> u32 {
+        let leaf_index = self.calc_leaf_index(position);
+        return self.leaf_lump.leaves[leaf_index as usize].contents;
+    }
+
+    /// Reconstruct the faces of a brush from its side planes, by clipping a large polygon on each plane against all of the others.
+    /// Sides which are clipped away entirely (for example, bevel planes) produce no polygon
+    pub fn brush_polygons(self: &Self, brush_index: usize) -> Vec<Vec<Vector3>> {
+        let brush = &self.brush_lump.brushes[brush_index];
+        let sides = &self.brush_side_lump.brush_sides[brush.first_brush_side as usize..(brush.first_brush_side + brush.num_brush_sides) as usize];
+
+        let mut polygons = Vec::with_capacity(sides.len());
+
+        for (i, side) in sides.iter().enumerate() {
+            let plane = &self.plane_lump.planes[side.plane as usize];
+
+            // build a large quad lying on the plane
+            let up = if plane.normal.z.abs() < 0.9 { Vector3::unit_z() } else { Vector3::unit_x() };
+            let tangent = Vector3::cross(&up, &plane.normal).normalized() * BRUSH_WINDING_SIZE;
+            let bitangent = Vector3::cross(&plane.normal, &tangent).normalized() * BRUSH_WINDING_SIZE;
+            let origin = plane.normal * plane.distance;
+
+            let mut poly = vec![
+                origin - tangent + bitangent,
+                origin + tangent + bitangent,
+                origin + tangent - bitangent,
+                origin - tangent - bitangent,
+            ];
+
+            // the inside of a brush is behind each of its planes
+            for (j, other) in sides.iter().enumerate() {
+                if i == j || poly.len() < 3 {
+                    continue;
+                }
+
+                let other_plane = &self.plane_lump.planes[other.plane as usize];
+                poly = common::clip_polygon(&poly, &(other_plane.normal * -1.0), -other_plane.distance);
+            }
+
+            if poly.len() >= 3 {
+                polygons.push(poly);
+            }
+        }
+
+        polygons
+    }
+
+    /// Traces straight down from the given point to find the floor beneath it, and returns the lightmap sample at the hit point (if any)
+    /// 
+    /// # Arguments
+    /// 
+    /// * 'position' - The point to trace down from
+    /// * 'max_dist' - The maximum distance to search for a floor
+    /// * 'light_layers' - Brightness of each custom light layer, used to weight switchable lightmap styles
+    pub fn sample_lightmap(self: &Self, position: &Vector3, max_dist: f32, light_layers: &[f32;NUM_CUSTOM_LIGHT_LAYERS]) -> Option<Color32> {
+        let end = *position - (Vector3::unit_z() * max_dist);
+        let trace = self.linetrace(0, MASK_SOLID, position, &end);
+
+        if trace.fraction == 1.0 || trace.start_solid {
+            return None;
+        }
+
+        // the floor face borders the leaf just above the hit point
+        let leaf_index = self.calc_leaf_index(&(trace.end_pos + trace.hit_normal));
+        let leaf = &self.leaf_lump.leaves[leaf_index as usize];
+
+        let start_face = leaf.first_leaf_face as usize;
+        let end_face = start_face + (leaf.num_leaf_faces as usize);
+
+        for leaf_face in start_face..end_face {
+            let face_index = self.leaf_face_lump.faces[leaf_face] as usize;
+            let face = &self.face_lump.faces[face_index];
+            let tex_info = &self.tex_info_lump.textures[face.texture_info as usize];
+
+            if face.num_lightmaps == 0 || tex_info.flags & SURF_NOLM != 0 {
+                continue;
+            }
+
+            // hit point must lie on the face's plane
+            let plane = &self.plane_lump.planes[face.plane as usize];
+            if (Vector3::dot(&trace.end_pos, &plane.normal) - plane.distance).abs() > 1.0 {
+                continue;
+            }
+
+            // hit point must lie within the face's texture space extents
+            let (tex_min, tex_max) = self.face_texture_bounds(face_index);
+
+            let s = Vector3::dot(&trace.end_pos, &tex_info.u_axis) + tex_info.u_offset;
+            let t = Vector3::dot(&trace.end_pos, &tex_info.v_axis) + tex_info.v_offset;
+
+            if s < tex_min.x || s > tex_max.x || t < tex_min.y || t > tex_max.y {
+                continue;
+            }
+
+            // lightmap texels start from the texture space minimum rounded down to a multiple of 16
+            let texel = Vector2::new((s / 16.0) - (tex_min.x / 16.0).floor(), (t / 16.0) - (tex_min.y / 16.0).floor());
+            let mut sample = Color32::new(0, 0, 0, 255);
+
+            for i in 0..face.num_lightmaps {
+                let style = face.lightmap_styles[i] as usize;
+
+                // animated preset styles are taken at full brightness, since samples are cached
+                let sc = if style >= CUSTOM_LIGHT_LAYER_START && style < CUSTOM_LIGHT_LAYER_END {
+                    light_layers[style - CUSTOM_LIGHT_LAYER_START]
+                }
+                else {
+                    1.0
+                };
+
+                let src = self.sample_face_lightmap(face_index, i, texel);
+                sample.r = sample.r.saturating_add((src.r as f32 * sc).clamp(0.0, 255.0) as u8);
+                sample.g = sample.g.saturating_add((src.g as f32 * sc).clamp(0.0, 255.0) as u8);
+                sample.b = sample.b.saturating_add((src.b as f32 * sc).clamp(0.0, 255.0) as u8);
+            }
+
+            return Some(sample);
+        }
+
+        None
+    }
+
+    /// Sweeps a box up by step_height, along the given velocity (sliding along surfaces), then back down by step_height, the way a character walks up stairs.
+    /// Returns the new position, clipped velocity, & the downward trace. If the downward trace hit nothing the box walked off a ledge,
+    /// & callers should check the trace's hit normal to make sure the box landed on walkable ground
+    pub fn trace_step<TraceFn>(self: &Self, start_pos: &Vector3, velocity: &Vector3, delta: f32, content_mask: u32, box_extents: Vector3, step_height: f32, trace_fn: TraceFn) -> (Vector3, Vector3, Trace)
+        where TraceFn: Fn(u32, &Vector3, &Vector3, &Vector3) -> Trace {
+        let (box_pos, _, _) = self.trace_move(start_pos, &Vector3::new(0.0, 0.0, step_height), 1.0, false, content_mask, box_extents, &trace_fn);
+        let (box_pos, velocity, _) = self.trace_move(&box_pos, velocity, delta, true, content_mask, box_extents, &trace_fn);
+        let (box_pos, _, trace) = self.trace_move(&box_pos, &Vector3::new(0.0, 0.0, -step_height), 1.0, false, content_mask, box_extents, &trace_fn);
+
+        (box_pos, velocity, trace)
+    }
+
+    /// Checks whether a box moving along move_dir (direction times distance) is blocked by a ledge it could step up onto.
+    /// Returns the box's position after stepping up if a ledge of at most step_height blocks forward motion, or None if nothing blocks the move or the obstacle is too tall to step onto
+    pub fn can_step_up(self: &Self, pos: &Vector3, move_dir: &Vector3, box_extents: Vector3, step_height: f32) -> Option<Vector3> {
+        let trace_fn = |mask: u32, start: &Vector3, end: &Vector3, box_extents: &Vector3| {
+            self.boxtrace(0, mask, start, end, *box_extents)
+        };
+
+        // if a plain sweep isn't blocked, there's nothing to step up onto
+        let (flat_pos, _, flat_trace) = self.trace_move(pos, move_dir, 1.0, false, MASK_SOLID, box_extents, &trace_fn);
+        if flat_trace.fraction == 1.0 || flat_trace.all_solid {
+            return None;
+        }
+
+        let (step_pos, _, step_trace) = self.trace_step(pos, move_dir, 1.0, MASK_SOLID, box_extents, step_height, &trace_fn);
+
+        // must land on walkable ground above the starting height, having gotten further than the plain sweep did
+        if step_trace.fraction == 1.0 || step_trace.all_solid || step_trace.hit_normal.z < GROUND_SLOPE_COS_ANGLE {
+            return None;
+        }
+
+        let flat_dist = Vector3::new(flat_pos.x - pos.x, flat_pos.y - pos.y, 0.0).length();
+        let step_dist = Vector3::new(step_pos.x - pos.x, step_pos.y - pos.y, 0.0).length();
+
+        if step_pos.z <= pos.z + DIST_EPSILON || step_dist <= flat_dist + DIST_EPSILON {
+            return None;
+        }
+
+        Some(step_pos)
+    }
+
+    /// Checks for walkable ground at most max_snap below a box. Returns the box's position resting on the ground & the ground's normal,
+    /// or None if there's no ground in range or it's steeper than slope_cos (cosine of the steepest walkable slope, see GROUND_SLOPE_COS_ANGLE)
+    pub fn ground_snap(self: &Self, pos: &Vector3, box_extents: Vector3, max_snap: f32, slope_cos: f32) -> Option<(Vector3, Vector3)> {
+        let trace_fn = |mask: u32, start: &Vector3, end: &Vector3, box_extents: &Vector3| {
+            self.boxtrace(0, mask, start, end, *box_extents)
+        };
+
+        self.ground_snap_with(pos, box_extents, max_snap, slope_cos, MASK_SOLID, trace_fn)
+    }
+
+    /// Same as ground_snap, but sweeps with the given content mask & trace function (see trace_move)
+    pub fn ground_snap_with<TraceFn>(self: &Self, pos: &Vector3, box_extents: Vector3, max_snap: f32, slope_cos: f32, content_mask: u32, trace_fn: TraceFn) -> Option<(Vector3, Vector3)>
+        where TraceFn: Fn(u32, &Vector3, &Vector3, &Vector3) -> Trace {
+        let (new_pos, _, trace) = self.trace_move(pos, &Vector3::new(0.0, 0.0, -max_snap), 1.0, false, content_mask, box_extents, trace_fn);
+
+        if trace.fraction == 1.0 || trace.all_solid || trace.hit_normal.z < slope_cos {
+            return None;
+        }
+
+        Some((new_pos, trace.hit_normal))
+    }
+
+    /// Sweeps a sphere through the world, bouncing off any surfaces it hits and returning a new position and velocity
+    /// 
+    /// # Arguments
+    /// 
+    /// * 'start' - The current center point of the sphere
+    /// * 'velocity' - The velocity of the sphere
+    /// * 'delta' - The timestep of the movement (final sweep length is velocity times delta)
+    /// * 'radius' - The radius of the sphere
+    /// * 'restitution' - How much speed is kept after each bounce (0 stops dead, 1 keeps all of it)
+    /// * 'max_bounces' - How many times the sphere may bounce during this move, after which it stops at the last surface hit
+    /// 
+    /// If the sphere is slower than a small threshold after bouncing, it comes to rest & the returned velocity is zero
+    pub fn trace_bounce(self: &Self, start: &Vector3, velocity: &Vector3, delta: f32, radius: f32, restitution: f32, max_bounces: usize) -> (Vector3, Vector3) {
+        let mut cur_pos = *start;
+        let mut cur_velocity = *velocity;
+        let mut remaining_delta = delta;
+        let mut bounces = 0;
+
+        while remaining_delta > 0.0 {
+            let end = cur_pos + (cur_velocity * remaining_delta);
+            let trace = self.spherecast(MASK_SHOT, &cur_pos, &end, radius);
+
+            if trace.all_solid {
+                return (cur_pos, Vector3::zero());
+            }
+
+            cur_pos = trace.end_pos;
+            remaining_delta -= remaining_delta * trace.fraction;
+
+            if trace.fraction == 1.0 {
+                break;
+            }
+
+            // out of bounces, so stop dead against the last surface hit
+            if bounces == max_bounces {
+                return (cur_pos, Vector3::zero());
+            }
+
+            bounces += 1;
+
+            // reflect velocity about the hit plane, losing some speed
+            let backoff = Vector3::dot(&cur_velocity, &trace.hit_normal) * 2.0;
+            cur_velocity = (cur_velocity - (trace.hit_normal * backoff)) * restitution;
+
+            if cur_velocity.length() < BOUNCE_REST_SPEED {
+                return (cur_pos, Vector3::zero());
+            }
+        }
+
+        (cur_pos, cur_velocity)
+    }
+
     /// Attempts to sweep a box through the world, sliding along any surfaces it hits and returning a new position and velocity as well as trace hit information
     /// 
     /// # Arguments
@@ -450,8 +946,12 @@ impl BspFile {
     /// * 'velocity' - The velocity of the box shape
     /// * 'delta' - The timestep of the movement (final sweep length is velocity times delta)
     /// * 'allow_sliding' - Whether or not to allow sliding against hit surfaces
+    /// * 'content_mask' - Which contents to collide with, passed through to each trace
     /// * 'box_extents' - The extents of the box on each axis (half the box's total size)
-    pub fn trace_move<TraceFn>(self: &Self, start_pos: &Vector3, velocity: &Vector3, delta: f32, allow_sliding: bool, box_extents: Vector3, trace_fn: TraceFn) -> (Vector3, Vector3, Trace)
+    /// * 'trace_fn' - Performs each individual sweep, given a content mask, start, end, and box extents. Lets callers include submodels & entities, or collide with a different shape
+    /// 
+    /// The returned trace is the first surface hit during the move, if any
+    pub fn trace_move<TraceFn>(self: &Self, start_pos: &Vector3, velocity: &Vector3, delta: f32, allow_sliding: bool, content_mask: u32, box_extents: Vector3, trace_fn: TraceFn) -> (Vector3, Vector3, Trace)
         where TraceFn: Fn(u32, &Vector3, &Vector3, &Vector3) -> Trace {
         const NUM_ITERATIONS: usize = 8;
 
@@ -468,12 +968,15 @@ impl BspFile {
             fraction: 1.0,
             end_pos: Vector3::zero(),
             hit_normal: Vector3::zero(),
+            hit_side: None,
+            hit_contents: 0,
+            hit_submodel: None,
             entity: None
         };
 
         for _iter in 0..NUM_ITERATIONS {
             let end = cur_pos + (cur_velocity * remaining_delta);
-            let trace = trace_fn(MASK_SOLID, &cur_pos, &end, &box_extents);
+            let trace = trace_fn(content_mask, &cur_pos, &end, &box_extents);
 
             if trace.all_solid {
                 cur_velocity.z = 0.0; // don't build vertical velocity
@@ -536,4 +1039,460 @@ impl BspFile {
 
         (cur_pos, cur_velocity, ret_trace)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp_file::{CONTENTS_SOLID, CONTENTS_WATER, CONTENTS_WINDOW, MASK_OPAQUE};
+    use crate::test_map::TestMap;
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 0.1, "expected {} to be near {}", b, a);
+    }
+
+    // a block filling everything below x = 0 & y = 0, with a vertical outer edge running along the z axis
+    fn corner_map() -> BspFile {
+        let mut test_map = TestMap::new();
+        test_map.add_box(Vector3::new(-256.0, -256.0, -256.0), Vector3::new(0.0, 0.0, 256.0));
+        test_map.build()
+    }
+
+    #[test]
+    fn sphere_grazes_outer_corner_where_box_stops() {
+        let bsp = corner_map();
+
+        // passes 24 / sqrt(2) ~= 17 units from the corner's edge
+        let start = Vector3::new(-40.0, 64.0, 0.0);
+        let end = Vector3::new(64.0, -40.0, 0.0);
+
+        let sphere = bsp.spherecast(MASK_SOLID, &start, &end, 16.0);
+        assert_eq!(sphere.fraction, 1.0);
+        assert!(!sphere.start_solid);
+
+        let boxed = bsp.boxtrace(0, MASK_SOLID, &start, &end, Vector3::new(16.0, 16.0, 16.0));
+        assert!(boxed.fraction < 1.0);
+    }
+
+    #[test]
+    fn cylinder_slides_past_corner_where_box_catches() {
+        // a pillar turned 45 degrees, so its 90 degree corners point along the axes. includes axial bevel planes at its corners, like a compiled map would
+        let d = std::f32::consts::FRAC_1_SQRT_2;
+        let mut test_map = TestMap::new();
+        test_map.add_brush(&[
+            (Vector3::new(d, d, 0.0), 64.0 * d),
+            (Vector3::new(-d, d, 0.0), 64.0 * d),
+            (Vector3::new(d, -d, 0.0), 64.0 * d),
+            (Vector3::new(-d, -d, 0.0), 64.0 * d),
+            (Vector3::new(1.0, 0.0, 0.0), 64.0),
+            (Vector3::new(-1.0, 0.0, 0.0), 64.0),
+            (Vector3::new(0.0, 1.0, 0.0), 64.0),
+            (Vector3::new(0.0, -1.0, 0.0), 64.0),
+            (Vector3::new(0.0, 0.0, 1.0), 64.0),
+            (Vector3::new(0.0, 0.0, -1.0), 64.0),
+        ]);
+        let bsp = test_map.build();
+
+        // strafe past the corner at (64, 0, 0) & on along the pillar's face, 90 / sqrt(2) ~= 64 units from the pillar's center
+        let start = Vector3::new(170.0, -80.0, 0.0);
+        let end = Vector3::new(-80.0, 170.0, 0.0);
+
+        let cylinder = bsp.cylindertrace(0, MASK_SOLID, &start, &end, 16.0, 32.0);
+        assert_eq!(cylinder.fraction, 1.0);
+        assert!(!cylinder.start_solid);
+
+        // a box of the same width sticks its corner out 16 * sqrt(2) units towards the face, & catches on it
+        let boxed = bsp.boxtrace(0, MASK_SOLID, &start, &end, Vector3::new(16.0, 16.0, 32.0));
+        assert!(boxed.fraction < 1.0);
+        assert!(!boxed.start_solid);
+    }
+
+    #[test]
+    fn sphere_hits_outer_corner_edge() {
+        let bsp = corner_map();
+
+        // passes 20 / sqrt(2) ~= 14 units from the corner's edge, so it clips the edge without ever touching either face
+        let start = Vector3::new(-44.0, 64.0, 0.0);
+        let end = Vector3::new(64.0, -44.0, 0.0);
+
+        let trace = bsp.spherecast(MASK_SOLID, &start, &end, 16.0);
+        assert!(trace.fraction < 1.0);
+
+        // the sphere ends up one radius away from the edge, & is pushed straight back out from it
+        let offset = Vector3::new(trace.end_pos.x, trace.end_pos.y, 0.0);
+        assert_near(offset.length(), 16.0);
+        assert_near(trace.hit_normal.x, offset.x / offset.length());
+        assert_near(trace.hit_normal.y, offset.y / offset.length());
+        assert!(trace.hit_normal.x > 0.1 && trace.hit_normal.y > 0.1);
+    }
+
+    #[test]
+    fn sphere_stops_one_radius_from_face() {
+        let bsp = corner_map();
+
+        let start = Vector3::new(-32.0, 64.0, 0.0);
+        let end = Vector3::new(-32.0, -64.0, 0.0);
+
+        let trace = bsp.spherecast(MASK_SOLID, &start, &end, 16.0);
+        assert!(trace.fraction < 1.0);
+        assert_near(trace.end_pos.y, 16.0);
+        assert_near(trace.hit_normal.y, 1.0);
+    }
+
+    // flat floor at z = 0, with a block of the given height in front of the origin along +x
+    fn ledge_map(height: f32) -> BspFile {
+        let mut test_map = TestMap::new();
+        test_map.add_box(Vector3::new(-256.0, -256.0, -64.0), Vector3::new(256.0, 256.0, 0.0));
+        test_map.add_box(Vector3::new(32.0, -256.0, 0.0), Vector3::new(256.0, 256.0, height));
+        test_map.build()
+    }
+
+    #[test]
+    fn can_step_up_onto_low_ledge() {
+        let bsp = ledge_map(16.0);
+        let extents = Vector3::new(16.0, 16.0, 32.0);
+
+        let step_pos = bsp.can_step_up(&Vector3::new(0.0, 0.0, 33.0), &Vector3::new(32.0, 0.0, 0.0), extents, 18.0);
+        let step_pos = step_pos.expect("should step up onto a 16 unit ledge");
+
+        // box ends up standing on top of the ledge, past where a flat move would have stopped
+        assert_near(step_pos.z, 48.0);
+        assert!(step_pos.x > 16.0);
+    }
+
+    #[test]
+    fn cannot_step_up_onto_wall() {
+        let bsp = ledge_map(64.0);
+        let extents = Vector3::new(16.0, 16.0, 32.0);
+
+        assert!(bsp.can_step_up(&Vector3::new(0.0, 0.0, 33.0), &Vector3::new(32.0, 0.0, 0.0), extents, 18.0).is_none());
+    }
+
+    #[test]
+    fn unobstructed_move_does_not_step() {
+        let bsp = ledge_map(16.0);
+        let extents = Vector3::new(16.0, 16.0, 32.0);
+
+        assert!(bsp.can_step_up(&Vector3::new(0.0, 0.0, 33.0), &Vector3::new(-32.0, 0.0, 0.0), extents, 18.0).is_none());
+    }
+
+    // a wall filling everything where x + y >= 128, at 45 degrees to both axes
+    fn diagonal_wall_map() -> BspFile {
+        let n = std::f32::consts::FRAC_1_SQRT_2;
+
+        let mut test_map = TestMap::new();
+        test_map.add_brush(&[
+            (Vector3::new(-n, -n, 0.0), -128.0 * n),
+            (Vector3::new(1.0, 0.0, 0.0), 512.0),
+            (Vector3::new(0.0, 1.0, 0.0), 512.0),
+            (Vector3::new(0.0, 0.0, 1.0), 256.0),
+            (Vector3::new(0.0, 0.0, -1.0), 256.0),
+        ]);
+        test_map.build()
+    }
+
+    #[test]
+    fn bounce_reflects_off_diagonal_wall() {
+        let bsp = diagonal_wall_map();
+
+        let (end_pos, velocity) = bsp.trace_bounce(&Vector3::zero(), &Vector3::new(100.0, 0.0, 0.0), 2.0, 8.0, 1.0, 1);
+
+        // heading along +x into a 45 degree wall turns the sphere to head along -y at the same speed
+        assert_near(velocity.x, 0.0);
+        assert_near(velocity.y, -100.0);
+        assert_near(velocity.z, 0.0);
+
+        // bounced one radius away from the wall, then carried on along -y
+        assert_near(end_pos.x, 128.0 - (8.0 * std::f32::consts::SQRT_2));
+        assert!(end_pos.y < -50.0);
+    }
+
+    #[test]
+    fn bounce_stops_when_out_of_bounces() {
+        let bsp = diagonal_wall_map();
+
+        let (end_pos, velocity) = bsp.trace_bounce(&Vector3::zero(), &Vector3::new(100.0, 0.0, 0.0), 2.0, 8.0, 1.0, 0);
+
+        assert_eq!(velocity.length(), 0.0);
+        assert_near(end_pos.x, 128.0 - (8.0 * std::f32::consts::SQRT_2));
+        assert_near(end_pos.y, 0.0);
+    }
+
+    fn move_box(bsp: &BspFile, start: Vector3, velocity: Vector3, extents: Vector3) -> (Vector3, Vector3, Trace) {
+        bsp.trace_move(&start, &velocity, 1.0, true, MASK_SOLID, extents, |mask, start, end, box_extents| {
+            bsp.boxtrace(0, mask, start, end, *box_extents)
+        })
+    }
+
+    #[test]
+    fn flying_box_slides_along_wall() {
+        let bsp = ledge_map(64.0);
+
+        // no gravity, heading diagonally into the wall at x = 32
+        let (end_pos, velocity, trace) = move_box(&bsp, Vector3::new(0.0, 0.0, 40.0), Vector3::new(100.0, 100.0, 0.0), Vector3::new(15.0, 15.0, 15.0));
+
+        assert!(trace.fraction < 1.0);
+        assert_near(trace.hit_normal.x, -1.0);
+
+        // stops against the wall, keeping the motion parallel to it
+        assert!(end_pos.x <= 17.0 && end_pos.x > 16.0);
+        assert_near(end_pos.y, 100.0);
+        assert_near(end_pos.z, 40.0);
+        assert_near(velocity.x, 0.0);
+        assert_near(velocity.y, 100.0);
+    }
+
+    #[test]
+    fn grounded_box_keeps_moving_along_floor() {
+        let bsp = ledge_map(64.0);
+
+        // standing on the floor, with gravity pulling down into it
+        let (end_pos, velocity, trace) = move_box(&bsp, Vector3::new(0.0, 0.0, 33.0), Vector3::new(-100.0, 0.0, -50.0), Vector3::new(16.0, 16.0, 32.0));
+
+        assert!(trace.fraction < 1.0);
+        assert_near(trace.hit_normal.z, 1.0);
+
+        // stays on the floor, with the downward motion clipped off
+        assert!(end_pos.z >= 32.0 && end_pos.z < 33.0);
+        assert_near(end_pos.x, -100.0);
+        assert_near(velocity.x, -100.0);
+        assert_near(velocity.z, 0.0);
+    }
+
+    fn textured_wall_map() -> BspFile {
+        let mut test_map = TestMap::new();
+        let tex = test_map.add_texture("metal/floor01", 0, 0);
+        test_map.add_box_contents(Vector3::new(64.0, -256.0, -256.0), Vector3::new(128.0, 256.0, 256.0), CONTENTS_SOLID, tex);
+        test_map.build()
+    }
+
+    #[test]
+    fn raycast_reports_hit_surface() {
+        let bsp = textured_wall_map();
+
+        let hit = bsp.raycast(&Vector3::zero(), &Vector3::new(2.0, 0.0, 0.0), 256.0, MASK_SOLID).unwrap();
+
+        assert_near(hit.position.x, 64.0);
+        assert_near(hit.normal.x, -1.0);
+        assert_near(hit.distance, 64.0);
+        assert_near(hit.fraction, 0.25);
+        assert_eq!(hit.contents & CONTENTS_SOLID, CONTENTS_SOLID);
+        assert_eq!(hit.tex_info.unwrap().texture_name, "metal/floor01");
+    }
+
+    #[test]
+    fn raycast_misses_beyond_max_dist() {
+        let bsp = textured_wall_map();
+
+        assert!(bsp.raycast(&Vector3::zero(), &Vector3::new(1.0, 0.0, 0.0), 32.0, MASK_SOLID).is_none());
+        assert!(bsp.raycast(&Vector3::zero(), &Vector3::new(-1.0, 0.0, 0.0), 256.0, MASK_SOLID).is_none());
+    }
+
+    #[test]
+    fn raycast_with_zero_direction_misses() {
+        let bsp = textured_wall_map();
+
+        assert!(bsp.raycast(&Vector3::zero(), &Vector3::zero(), 256.0, MASK_SOLID).is_none());
+    }
+
+    #[test]
+    fn box_check_detects_overlap() {
+        let bsp = ledge_map(64.0);
+        let extents = Vector3::new(16.0, 16.0, 16.0);
+
+        // clear of the floor & the wall
+        assert!(!bsp.box_check(MASK_SOLID, &Vector3::new(0.0, 0.0, 32.0), extents));
+
+        // poking into the wall at x = 32 & into the floor at z = 0
+        assert!(bsp.box_check(MASK_SOLID, &Vector3::new(20.0, 0.0, 32.0), extents));
+        assert!(bsp.box_check(MASK_SOLID, &Vector3::new(0.0, 0.0, 8.0), extents));
+
+        // entirely inside the wall
+        assert!(bsp.box_check(MASK_SOLID, &Vector3::new(128.0, 0.0, 32.0), extents));
+    }
+
+    #[test]
+    fn box_check_respects_content_mask() {
+        let mut test_map = TestMap::new();
+        test_map.add_box_contents(Vector3::new(-64.0, -64.0, -64.0), Vector3::new(64.0, 64.0, 64.0), CONTENTS_WATER, u16::MAX);
+        let bsp = test_map.build();
+
+        assert!(!bsp.box_check(MASK_SOLID, &Vector3::zero(), Vector3::new(8.0, 8.0, 8.0)));
+        assert!(bsp.box_check(CONTENTS_WATER, &Vector3::zero(), Vector3::new(8.0, 8.0, 8.0)));
+    }
+
+    #[test]
+    fn traces_report_hit_submodel() {
+        let mut test_map = TestMap::new();
+        test_map.add_box(Vector3::new(-256.0, -256.0, -64.0), Vector3::new(256.0, 256.0, 0.0));
+        let model = test_map.add_model_box(Vector3::new(64.0, -32.0, 0.0), Vector3::new(96.0, 32.0, 64.0));
+        let bsp = test_map.build();
+
+        let extents = Vector3::new(8.0, 8.0, 8.0);
+
+        // sweeping into the model
+        let trace = bsp.boxtrace(model, MASK_SOLID, &Vector3::new(0.0, 0.0, 32.0), &Vector3::new(128.0, 0.0, 32.0), extents);
+        assert!(trace.fraction < 1.0);
+        assert_eq!(trace.hit_submodel, Some(model));
+
+        // starting inside the model, as when pinned by a moving door
+        let trace = bsp.boxtrace(model, MASK_SOLID, &Vector3::new(80.0, 0.0, 32.0), &Vector3::new(80.0, 0.0, 32.0), extents);
+        assert!(trace.all_solid);
+        assert_eq!(trace.hit_submodel, Some(model));
+
+        // missing the model
+        let trace = bsp.boxtrace(model, MASK_SOLID, &Vector3::new(0.0, 0.0, 32.0), &Vector3::new(0.0, 128.0, 32.0), extents);
+        assert_eq!(trace.fraction, 1.0);
+        assert_eq!(trace.hit_submodel, None);
+
+        // the world never reports a submodel
+        let trace = bsp.linetrace(0, MASK_SOLID, &Vector3::new(0.0, 0.0, 32.0), &Vector3::new(0.0, 0.0, -32.0));
+        assert!(trace.fraction < 1.0);
+        assert_eq!(trace.hit_submodel, None);
+    }
+
+    #[test]
+    fn line_of_sight_passes_through_windows() {
+        let mut test_map = TestMap::new();
+        let tex = test_map.add_texture("glass/window01", 0, 0);
+        test_map.add_box_contents(Vector3::new(-4.0, -64.0, -64.0), Vector3::new(4.0, 64.0, 64.0), CONTENTS_WINDOW, tex);
+        let bsp = test_map.build();
+
+        let start = Vector3::new(-32.0, 0.0, 0.0);
+        let end = Vector3::new(32.0, 0.0, 0.0);
+
+        let trace = bsp.linetrace(0, MASK_OPAQUE, &start, &end);
+        assert_eq!(trace.fraction, 1.0);
+
+        let trace = bsp.linetrace(0, MASK_SOLID, &start, &end);
+        assert!(trace.fraction < 1.0);
+        assert_eq!(trace.hit_contents, CONTENTS_WINDOW);
+    }
+
+    #[test]
+    fn box_brush_has_six_quads() {
+        let mins = Vector3::new(-16.0, -32.0, -8.0);
+        let maxs = Vector3::new(16.0, 32.0, 8.0);
+
+        let mut test_map = TestMap::new();
+        test_map.add_box(mins, maxs);
+        let bsp = test_map.build();
+
+        assert_eq!(bsp.brush_lump.brushes.len(), 1);
+
+        let polygons = bsp.brush_polygons(0);
+        assert_eq!(polygons.len(), 6);
+
+        for poly in &polygons {
+            assert_eq!(poly.len(), 4);
+
+            // every vertex is one of the box's corners
+            for v in poly {
+                assert!((v.x - mins.x).abs() < 0.01 || (v.x - maxs.x).abs() < 0.01, "{} {} {}", v.x, v.y, v.z);
+                assert!((v.y - mins.y).abs() < 0.01 || (v.y - maxs.y).abs() < 0.01, "{} {} {}", v.x, v.y, v.z);
+                assert!((v.z - mins.z).abs() < 0.01 || (v.z - maxs.z).abs() < 0.01, "{} {} {}", v.x, v.y, v.z);
+            }
+        }
+
+        // together the quads cover every side of the box
+        let area: f32 = polygons.iter().map(|poly| {
+            let a = poly[1] - poly[0];
+            let b = poly[3] - poly[0];
+            Vector3::cross(&a, &b).length()
+        }).sum();
+
+        assert_near(area, 2.0 * ((32.0 * 64.0) + (32.0 * 16.0) + (64.0 * 16.0)));
+    }
+
+    #[test]
+    fn ground_snap_pulls_box_down_onto_floor() {
+        let bsp = ledge_map(64.0);
+        let extents = Vector3::new(16.0, 16.0, 32.0);
+        let slope_cos = 45f32.to_radians().cos();
+
+        let (pos, normal) = bsp.ground_snap(&Vector3::new(0.0, 0.0, 40.0), extents, 20.0, slope_cos).unwrap();
+        assert!(pos.z >= 32.0 && pos.z < 33.0);
+        assert_near(pos.x, 0.0);
+        assert_near(normal.z, 1.0);
+
+        // floor is further away than the snap distance
+        assert!(bsp.ground_snap(&Vector3::new(0.0, 0.0, 60.0), extents, 20.0, slope_cos).is_none());
+    }
+
+    #[test]
+    fn ground_snap_ignores_steep_slopes() {
+        // a 60 degree slope rising along +x, where z = x * tan(60)
+        let mut test_map = TestMap::new();
+        test_map.add_brush(&[
+            (Vector3::new(-(60f32.to_radians().sin()), 0.0, 60f32.to_radians().cos()), 0.0),
+            (Vector3::new(1.0, 0.0, 0.0), 256.0),
+            (Vector3::new(0.0, 1.0, 0.0), 256.0),
+            (Vector3::new(0.0, -1.0, 0.0), 256.0),
+            (Vector3::new(0.0, 0.0, -1.0), 256.0),
+        ]);
+        let bsp = test_map.build();
+
+        // the box's lower edge at x = 80 rests on the slope at z = 138.6
+        let pos = Vector3::new(64.0, 0.0, 138.6 + 16.0 + 8.0);
+        let extents = Vector3::new(16.0, 16.0, 16.0);
+
+        assert!(bsp.ground_snap(&pos, extents, 20.0, 45f32.to_radians().cos()).is_none());
+
+        // a more lenient slope limit accepts it
+        let (pos, normal) = bsp.ground_snap(&pos, extents, 20.0, 0.4).unwrap();
+        assert!(pos.z >= 154.0 && pos.z < 155.0);
+        assert_near(normal.z, 0.5);
+    }
+
+    #[test]
+    fn ground_snap_holds_on_walkable_ramp() {
+        // a 30 degree ramp rising along +x, where z = x * tan(30)
+        let mut test_map = TestMap::new();
+        test_map.add_brush(&[
+            (Vector3::new(-(30f32.to_radians().sin()), 0.0, 30f32.to_radians().cos()), 0.0),
+            (Vector3::new(1.0, 0.0, 0.0), 256.0),
+            (Vector3::new(0.0, 1.0, 0.0), 256.0),
+            (Vector3::new(0.0, -1.0, 0.0), 256.0),
+            (Vector3::new(0.0, 0.0, -1.0), 256.0),
+        ]);
+        let bsp = test_map.build();
+
+        // the box's lower edge at x = 80 rests on the ramp at z = 46.19
+        let flush_z = (80.0 * 30f32.to_radians().tan()) + 16.0;
+        let pos = Vector3::new(64.0, 0.0, flush_z + 10.0);
+        let extents = Vector3::new(16.0, 16.0, 16.0);
+
+        let (snapped, normal) = bsp.ground_snap(&pos, extents, 20.0, GROUND_SLOPE_COS_ANGLE).unwrap();
+        assert!(snapped.z >= flush_z && snapped.z < flush_z + 0.5, "{}", snapped.z);
+        assert_near(snapped.x, 64.0);
+        assert_near(normal.z, 30f32.to_radians().cos());
+
+        // the same ramp is too steep under a 25 degree slope limit
+        assert!(bsp.ground_snap(&pos, extents, 20.0, 25f32.to_radians().cos()).is_none());
+    }
+
+    #[test]
+    fn lightmap_sample_is_brighter_under_lit_half_of_floor() {
+        let mut test_map = TestMap::new();
+        let tex = test_map.add_texture("metal/floor01", 0, 0);
+        test_map.add_box_contents(Vector3::new(-64.0, -64.0, -16.0), Vector3::new(64.0, 64.0, 0.0), CONTENTS_SOLID, tex);
+        test_map.add_room(Vector3::new(-64.0, -64.0, 0.0), Vector3::new(64.0, 64.0, 128.0), 0, 1);
+
+        // the floor spans 128 units, or 9 lightmap texels. texels at x >= 16 are lit, the rest are in shadow
+        let lightmap: Vec<Color32> = (0..81).map(|i| if i % 9 >= 5 { Color32::new(200, 180, 160, 255) } else { Color32::new(10, 10, 10, 255) }).collect();
+        test_map.add_face(&[Vector3::new(-64.0, -64.0, 0.0), Vector3::new(-64.0, 64.0, 0.0), Vector3::new(64.0, 64.0, 0.0), Vector3::new(64.0, -64.0, 0.0)], tex, Some(&lightmap));
+        let bsp = test_map.build();
+
+        assert_eq!(bsp.face_lightmap_size(0), (9, 9));
+
+        let light_layers = [0.0;NUM_CUSTOM_LIGHT_LAYERS];
+        let lit = bsp.sample_lightmap(&Vector3::new(48.0, 0.0, 64.0), 128.0, &light_layers).unwrap();
+        let shadow = bsp.sample_lightmap(&Vector3::new(-48.0, 0.0, 64.0), 128.0, &light_layers).unwrap();
+
+        assert_eq!((lit.r, lit.g, lit.b), (200, 180, 160));
+        assert_eq!((shadow.r, shadow.g, shadow.b), (10, 10, 10));
+
+        // nothing below to sample
+        assert!(bsp.sample_lightmap(&Vector3::new(48.0, 0.0, 64.0), 32.0, &light_layers).is_none());
+    }
 }
\ No newline at end of file
@@ -1,55 +1,131 @@
-use std::{collections::HashMap, io::Seek};
+use std::{collections::HashMap, io::{Cursor, Seek}};
 
 use byteorder::{LittleEndian, ReadBytesExt};
-use dbsdk_rs::{db::log, logfmt, math::Vector3, vdp::Color32};
-use regex::Regex;
+use dbsdk_rs::{db::log, logfmt, math::{Vector2, Vector3}, vdp::Color32};
 
 const BSP_MAGIC: u32 = 0x50534249;
 const BSP_VERSION: u32 = 38;
+const BSPX_MAGIC: u32 = 0x58505342;
 
-//pub const SURF_LIGHT: u32   = 0x1;
+const LGNODE_LEAF: u32 = 0x80000000;
+const LGNODE_MISSING: u32 = 0x40000000;
+
+pub const SURF_LIGHT: u32   = 0x1;
 //pub const SURF_SLICK: u32   = 0x2;
 pub const SURF_SKY: u32     = 0x4;
 pub const SURF_WARP: u32    = 0x8;
 pub const SURF_TRANS33: u32 = 0x10;
 pub const SURF_TRANS66: u32 = 0x20;
-//pub const SURF_FLOW: u32    = 0x40;
+pub const SURF_FLOW: u32    = 0x40;
 pub const SURF_NODRAW: u32  = 0x80;
 
+// engine-specific flags, for overriding how a surface's texture is sampled
+pub const SURF_CLAMP: u32   = 0x10000;
+pub const SURF_NEAREST: u32 = 0x20000;
+// engine-specific flag, for surfaces which get a detail texture modulated over them
+pub const SURF_DETAIL: u32  = 0x40000;
+
 pub const SURF_NOLM: u32    = SURF_NODRAW | SURF_SKY | SURF_WARP | SURF_TRANS33 | SURF_TRANS66;
 
+/// Number of lightmap styles which can be switched on & off at runtime (toggled by lights with a targetname), starting from CUSTOM_LIGHT_LAYER_START
+pub const NUM_CUSTOM_LIGHT_LAYERS: usize = 30;
+pub const CUSTOM_LIGHT_LAYER_START: usize = 32;
+pub const CUSTOM_LIGHT_LAYER_END: usize = CUSTOM_LIGHT_LAYER_START + NUM_CUSTOM_LIGHT_LAYERS;
+
 pub const CONTENTS_SOLID: u32       = 1;
 pub const CONTENTS_WINDOW: u32      = 2;
 //pub const CONTENTS_AUX: u32         = 4;
-//pub const CONTENTS_LAVA: u32        = 8;
-//pub const CONTENTS_SLIME: u32       = 16;
-//pub const CONTENTS_WATER: u32       = 32;
+pub const CONTENTS_LAVA: u32         = 8;
+pub const CONTENTS_SLIME: u32        = 16;
+pub const CONTENTS_WATER: u32        = 32;
 //pub const CONTENTS_MIST: u32        = 64;
+pub const CONTENTS_PLAYERCLIP: u32  = 0x10000;
 
+/// Everything which blocks movement: solid & window brushes
 pub const MASK_SOLID: u32           = CONTENTS_SOLID | CONTENTS_WINDOW;
+/// All liquids: lava, slime, & water
+pub const MASK_WATER: u32           = CONTENTS_LAVA | CONTENTS_SLIME | CONTENTS_WATER;
+/// Everything which blocks sight & light: solid brushes only, so windows & liquids let light through
+pub const MASK_OPAQUE: u32          = CONTENTS_SOLID;
+/// Everything which stops bullets & projectiles: solid & window brushes
+pub const MASK_SHOT: u32            = CONTENTS_SOLID | CONTENTS_WINDOW;
+/// Everything which blocks player movement: solid & window brushes, plus player clip brushes
+pub const MASK_PLAYERSOLID: u32     = CONTENTS_SOLID | CONTENTS_WINDOW | CONTENTS_PLAYERCLIP;
+
+/// Enumeration of errors which can result from loading a BSP file
+#[derive(Debug)]
+pub enum BspError {
+    IOError(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    LumpOutOfRange,
+}
+
+impl From<std::io::Error> for BspError {
+    fn from(value: std::io::Error) -> Self {
+        BspError::IOError(value)
+    }
+}
+
+fn read_vec3f<R: ReadBytesExt>(reader: &mut R) -> std::io::Result<Vector3> {
+    let x = reader.read_f32::<LittleEndian>()?;
+    let y = reader.read_f32::<LittleEndian>()?;
+    let z = reader.read_f32::<LittleEndian>()?;
+
+    Ok(Vector3::new(x, y, z))
+}
 
-fn read_vec3f<R: ReadBytesExt>(reader: &mut R) -> Vector3 {
-    let x = reader.read_f32::<LittleEndian>().unwrap();
-    let y = reader.read_f32::<LittleEndian>().unwrap();
-    let z = reader.read_f32::<LittleEndian>().unwrap();
+fn read_vec3s<R: ReadBytesExt>(reader: &mut R) -> std::io::Result<Vector3> {
+    let x = reader.read_i16::<LittleEndian>()? as f32;
+    let y = reader.read_i16::<LittleEndian>()? as f32;
+    let z = reader.read_i16::<LittleEndian>()? as f32;
 
-    Vector3::new(x, y, z)
+    Ok(Vector3::new(x, y, z))
 }
 
-fn read_vec3s<R: ReadBytesExt>(reader: &mut R) -> Vector3 {
-    let x = reader.read_i16::<LittleEndian>().unwrap() as f32;
-    let y = reader.read_i16::<LittleEndian>().unwrap() as f32;
-    let z = reader.read_i16::<LittleEndian>().unwrap() as f32;
+fn read_color24<R: ReadBytesExt>(reader: &mut R) -> std::io::Result<Color32> {
+    let r = reader.read_u8()?;
+    let g = reader.read_u8()?;
+    let b = reader.read_u8()?;
 
-    Vector3::new(x, y, z)
+    Ok(Color32::new(r, g, b, 255))
 }
 
-fn read_color24<R: ReadBytesExt>(reader: &mut R) -> Color32 {
-    let r = reader.read_u8().unwrap();
-    let g = reader.read_u8().unwrap();
-    let b = reader.read_u8().unwrap();
+// locate a lump in the BSPX extension directory, which follows the last of the standard lumps
+fn find_bspx_lump<R: Seek + ReadBytesExt>(reader: &mut R, bsp_lumps: &[BspLumpInfo], file_len: u64, name: &str) -> Result<Option<BspLumpInfo>, BspError> {
+    let bspx_offset = bsp_lumps.iter().map(|x| x.offset as u64 + x.length as u64).max().unwrap_or(0);
+    let bspx_offset = (bspx_offset + 3) & !3;
+
+    if bspx_offset + 8 > file_len {
+        return Ok(None);
+    }
+
+    reader.seek(std::io::SeekFrom::Start(bspx_offset))?;
+
+    if reader.read_u32::<LittleEndian>()? != BSPX_MAGIC {
+        return Ok(None);
+    }
+
+    let num_lumps = reader.read_u32::<LittleEndian>()?;
+
+    for _ in 0..num_lumps {
+        let mut lump_name = [0;24];
+        reader.read_exact(&mut lump_name)?;
+
+        let offset = reader.read_u32::<LittleEndian>()?;
+        let length = reader.read_u32::<LittleEndian>()?;
+
+        let name_len = lump_name.iter().position(|x| *x == 0).unwrap_or(lump_name.len());
+        if &lump_name[0..name_len] == name.as_bytes() {
+            if (offset as u64) + (length as u64) > file_len {
+                return Err(BspError::LumpOutOfRange);
+            }
 
-    Color32::new(r, g, b, 255)
+            return Ok(Some(BspLumpInfo { offset, length }));
+        }
+    }
+
+    Ok(None)
 }
 
 pub struct BspLumpInfo {
@@ -64,8 +140,8 @@ pub struct Edge {
 }
 
 pub struct BspFace {
-    pub _plane: u16,
-    pub _plane_side: u16,
+    pub plane: u16,
+    pub plane_side: u16,
     pub first_edge: u32,
     pub num_edges: u16,
     pub texture_info: u16,
@@ -93,7 +169,7 @@ pub struct Node {
 pub struct Leaf {
     pub contents: u32,
     pub cluster: u16,
-    pub _area: u16,
+    pub area: u16,
     pub bbox_min: Vector3,
     pub bbox_max: Vector3,
     pub first_leaf_face: u16,
@@ -108,7 +184,7 @@ pub struct TexInfo {
     pub v_axis: Vector3,
     pub v_offset: f32,
     pub flags: u32,
-    pub _value: u32,
+    pub value: u32,
     pub texture_name: String,
     pub _next_texinfo: u32,
 }
@@ -121,11 +197,14 @@ pub struct Brush {
 
 pub struct BrushSide {
     pub plane: u16,
-    pub _tex: u16,
+    /// Index into the texinfo lump, or u16::MAX if this side has no texture
+    pub tex: u16,
 }
 
 pub struct VisCluster {
-    pub vis_offset: usize
+    pub vis_offset: usize,
+    /// Offset of the cluster's potentially hearable set, or None if the map has no PHS data
+    pub phs_offset: Option<usize>,
 }
 
 pub struct SubModel {
@@ -137,6 +216,16 @@ pub struct SubModel {
     pub num_faces: u32,
 }
 
+pub struct Area {
+    pub num_areaportals: u32,
+    pub first_areaportal: u32,
+}
+
+pub struct AreaPortal {
+    pub portal_num: u32,
+    pub other_area: u32,
+}
+
 pub struct EntityLump {
     pub entities: String
 }
@@ -202,12 +291,52 @@ pub struct LightmapLump {
     pub lm: Vec<Color32>
 }
 
+/// A world face which emits light (flagged with SURF_LIGHT)
+#[derive(Clone, Copy)]
+pub struct EmissiveSurface {
+    pub center: Vector3,
+    pub normal: Vector3,
+    pub area: f32,
+    /// Light value of the surface's texinfo
+    pub intensity: f32,
+}
+
+pub struct LightGridNode {
+    pub mid: [i32;3],
+    pub children: [u32;8],
+}
+
+pub struct LightGridLeaf {
+    pub mins: [i32;3],
+    pub size: [i32;3],
+    /// Light color of each point in the leaf (style 0 only), or None if the point is inside solid geometry
+    pub samples: Vec<Option<Color32>>,
+}
+
+/// Baked light probes from the optional BSPX LIGHTGRID_OCTREE lump
+pub struct LightGridLump {
+    pub step: Vector3,
+    pub size: [i32;3],
+    pub mins: Vector3,
+    pub root_node: u32,
+    pub nodes: Vec<LightGridNode>,
+    pub leaves: Vec<LightGridLeaf>,
+}
+
+pub struct AreaLump {
+    pub areas: Vec<Area>
+}
+
+pub struct AreaPortalLump {
+    pub portals: Vec<AreaPortal>
+}
+
 impl EntityLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> EntityLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<EntityLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
         let mut data: Vec<u8> = vec![0;info.length as usize];
-        reader.read_exact(&mut data).unwrap();
+        reader.read_exact(&mut data)?;
 
         let mut len = 0;
         for val in &data {
@@ -220,96 +349,162 @@ impl EntityLump {
         let slice = &data[0..len];
         let entities = unsafe { std::str::from_utf8_unchecked(slice).to_owned() };
 
-        EntityLump {
+        Ok(EntityLump {
             entities
-        }
+        })
     }
 
+    /// Parse the entity lump, calling the given function with the key/value pairs of each entity
+    /// Keys & values borrow directly from the lump, so escape sequences are left as-is
     pub fn parse<F>(self: &Self, mut f: F) where F: FnMut(HashMap<&str, &str>) {
-        let re = Regex::new("(\"(.*)\"[ \t]+\"(.*)\")").unwrap();
-
-        // find ranges of data between { and }
-        let mut slices = Vec::new();
-        let mut start = 0;
-        for (idx, v) in self.entities.as_bytes().iter().enumerate() {
-            if *v == b'{' {
-                start = idx + 1;
-            }
-            else if *v == b'}' {
-                slices.push((start, idx - 1));
+        let tokens = tokenize_entities(&self.entities);
+        let mut iter = tokens.into_iter();
+
+        while let Some(token) = iter.next() {
+            match token {
+                EntityToken::Open => {}
+                _ => continue
+            };
+
+            // collect key value pairs until the closing brace
+            let mut map = HashMap::new();
+            loop {
+                match iter.next() {
+                    Some(EntityToken::Str(key)) => {
+                        match iter.next() {
+                            Some(EntityToken::Str(val)) => {
+                                map.insert(key, val);
+                            }
+                            _ => {
+                                logfmt!("Malformed entity lump: key {} has no value", key);
+                                return;
+                            }
+                        }
+                    }
+                    Some(EntityToken::Close) => {
+                        f(map);
+                        break;
+                    }
+                    _ => {
+                        log("Malformed entity lump: unterminated entity");
+                        return;
+                    }
+                }
             }
         }
+    }
+}
 
-        // parse key value pairs
-        for (start, end) in slices {
-            let entitydata = &self.entities[start..end];
-            
-            let mut map = HashMap::new();
-            for (_, [_, propname, propval]) in re.captures_iter(entitydata).map(|c| c.extract()) {
-                map.insert(propname, propval);
+enum EntityToken<'a> {
+    Open,
+    Close,
+    Str(&'a str),
+}
+
+// split entity lump into braces & quoted strings. quoted strings may contain braces, escaped quotes, & newlines
+fn tokenize_entities<'a>(src: &'a str) -> Vec<EntityToken<'a>> {
+    let bytes = src.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'{' => {
+                tokens.push(EntityToken::Open);
+                i += 1;
             }
+            b'}' => {
+                tokens.push(EntityToken::Close);
+                i += 1;
+            }
+            b'"' => {
+                let start = i + 1;
+                i += 1;
+
+                while i < bytes.len() && bytes[i] != b'"' {
+                    // skip over whatever is escaped
+                    if bytes[i] == b'\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
 
-            f(map);
+                let end = i.min(bytes.len());
+                tokens.push(EntityToken::Str(&src[start..end]));
+                i = end + 1;
+            }
+            b'/' if i + 1 < bytes.len() && bytes[i + 1] == b'/' => {
+                // comment, skip to end of line
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            _ => {
+                // whitespace (including CRLF), or stray characters outside of a string
+                i += 1;
+            }
         }
     }
+
+    tokens
 }
 
 impl VertexLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> VertexLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<VertexLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
         let num_vertices = (info.length / 12) as usize;
         let mut vertices: Vec<Vector3> = Vec::with_capacity(num_vertices);
 
         for _ in 0..num_vertices {
-            vertices.push(read_vec3f(reader));
+            vertices.push(read_vec3f(reader)?);
         }
 
-        VertexLump {
+        Ok(VertexLump {
             vertices
-        }
+        })
     }
 }
 
 impl EdgeLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> EdgeLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<EdgeLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
         let num_edges = (info.length / 4) as usize;
         let mut edges: Vec<Edge> = Vec::with_capacity(num_edges);
 
         for _ in 0..num_edges {
-            let a = reader.read_u16::<LittleEndian>().unwrap();
-            let b = reader.read_u16::<LittleEndian>().unwrap();
+            let a = reader.read_u16::<LittleEndian>()?;
+            let b = reader.read_u16::<LittleEndian>()?;
             edges.push(Edge {a, b});
         }
 
-        EdgeLump {
+        Ok(EdgeLump {
             edges
-        }
+        })
     }
 }
 
 impl FaceLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> FaceLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<FaceLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
         let num_faces = (info.length / 20) as usize;
         let mut faces: Vec<BspFace> = Vec::with_capacity(num_faces);
 
         for _ in 0..num_faces {
-            let plane = reader.read_u16::<LittleEndian>().unwrap();
-            let plane_side = reader.read_u16::<LittleEndian>().unwrap();
-            let first_edge = reader.read_u32::<LittleEndian>().unwrap();
-            let num_edges = reader.read_u16::<LittleEndian>().unwrap();
-            let texture_info = reader.read_u16::<LittleEndian>().unwrap();
+            let plane = reader.read_u16::<LittleEndian>()?;
+            let plane_side = reader.read_u16::<LittleEndian>()?;
+            let first_edge = reader.read_u32::<LittleEndian>()?;
+            let num_edges = reader.read_u16::<LittleEndian>()?;
+            let texture_info = reader.read_u16::<LittleEndian>()?;
             let lightmap_styles = [
-                reader.read_u8().unwrap(),
-                reader.read_u8().unwrap(),
-                reader.read_u8().unwrap(),
-                reader.read_u8().unwrap()
+                reader.read_u8()?,
+                reader.read_u8()?,
+                reader.read_u8()?,
+                reader.read_u8()?
             ];
-            let lightmap_offset = reader.read_u32::<LittleEndian>().unwrap();
+            let lightmap_offset = reader.read_u32::<LittleEndian>()?;
 
             let mut num_lightmaps = 0;
 
@@ -321,56 +516,56 @@ impl FaceLump {
             }
 
             faces.push(BspFace {
-                _plane: plane, _plane_side: plane_side, first_edge, num_edges, texture_info, lightmap_styles, num_lightmaps, lightmap_offset
+                plane, plane_side, first_edge, num_edges, texture_info, lightmap_styles, num_lightmaps, lightmap_offset
             });
         }
 
-        FaceLump {
+        Ok(FaceLump {
             faces
-        }
+        })
     }
 }
 
 impl FaceEdgeLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> FaceEdgeLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<FaceEdgeLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
         let num_edges = (info.length / 4) as usize;
         let mut edges: Vec<i32> = Vec::with_capacity(num_edges);
 
         for _ in 0..num_edges {
-            edges.push(reader.read_i32::<LittleEndian>().unwrap());
+            edges.push(reader.read_i32::<LittleEndian>()?);
         }
 
-        FaceEdgeLump {
+        Ok(FaceEdgeLump {
             edges
-        }
+        })
     }
 }
 
 impl PlaneLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> PlaneLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<PlaneLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
         let num_planes = (info.length / 20) as usize;
         let mut planes: Vec<Plane> = Vec::with_capacity(num_planes);
 
         for _ in 0..num_planes {
-            let normal = read_vec3f(reader);
-            let distance = reader.read_f32::<LittleEndian>().unwrap();
-            let plane_type = reader.read_u32::<LittleEndian>().unwrap();
+            let normal = read_vec3f(reader)?;
+            let distance = reader.read_f32::<LittleEndian>()?;
+            let plane_type = reader.read_u32::<LittleEndian>()?;
             planes.push(Plane { normal, distance, plane_type });
         }
 
-        PlaneLump {
+        Ok(PlaneLump {
             planes
-        }
+        })
     }
 }
 
 impl NodeLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> NodeLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<NodeLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
         let num_nodes = (info.length / 28) as usize;
         let mut nodes: Vec<Node> = Vec::with_capacity(num_nodes);
@@ -378,13 +573,13 @@ impl NodeLump {
         logfmt!("Num nodes in node lump: {}", num_nodes);
 
         for _ in 0..num_nodes {
-            let plane = reader.read_u32::<LittleEndian>().unwrap();
-            let front_child = reader.read_i32::<LittleEndian>().unwrap();
-            let back_child = reader.read_i32::<LittleEndian>().unwrap();
-            let bbox_min = read_vec3s(reader);
-            let bbox_max = read_vec3s(reader);
-            let first_face = reader.read_u16::<LittleEndian>().unwrap();
-            let num_faces = reader.read_u16::<LittleEndian>().unwrap();
+            let plane = reader.read_u32::<LittleEndian>()?;
+            let front_child = reader.read_i32::<LittleEndian>()?;
+            let back_child = reader.read_i32::<LittleEndian>()?;
+            let bbox_min = read_vec3s(reader)?;
+            let bbox_max = read_vec3s(reader)?;
+            let first_face = reader.read_u16::<LittleEndian>()?;
+            let num_faces = reader.read_u16::<LittleEndian>()?;
 
             nodes.push(Node {
                 plane,
@@ -397,15 +592,15 @@ impl NodeLump {
             });
         }
 
-        NodeLump {
+        Ok(NodeLump {
             nodes
-        }
+        })
     }
 }
 
 impl LeafLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> LeafLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<LeafLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
         let num_leaves = (info.length / 28) as usize;
         let mut leaves: Vec<Leaf> = Vec::with_capacity(num_leaves);
@@ -413,20 +608,20 @@ impl LeafLump {
         logfmt!("Num leaves in leaf lump: {}", num_leaves);
 
         for _ in 0..num_leaves {
-            let brush_or = reader.read_u32::<LittleEndian>().unwrap();
-            let cluster = reader.read_u16::<LittleEndian>().unwrap();
-            let area = reader.read_u16::<LittleEndian>().unwrap();
-            let bbox_min = read_vec3s(reader);
-            let bbox_max = read_vec3s(reader);
-            let first_leaf_face = reader.read_u16::<LittleEndian>().unwrap();
-            let num_leaf_faces = reader.read_u16::<LittleEndian>().unwrap();
-            let first_leaf_brush = reader.read_u16::<LittleEndian>().unwrap();
-            let num_leaf_brushes = reader.read_u16::<LittleEndian>().unwrap();
+            let brush_or = reader.read_u32::<LittleEndian>()?;
+            let cluster = reader.read_u16::<LittleEndian>()?;
+            let area = reader.read_u16::<LittleEndian>()?;
+            let bbox_min = read_vec3s(reader)?;
+            let bbox_max = read_vec3s(reader)?;
+            let first_leaf_face = reader.read_u16::<LittleEndian>()?;
+            let num_leaf_faces = reader.read_u16::<LittleEndian>()?;
+            let first_leaf_brush = reader.read_u16::<LittleEndian>()?;
+            let num_leaf_brushes = reader.read_u16::<LittleEndian>()?;
 
             leaves.push(Leaf {
                 contents: brush_or,
                 cluster,
-                _area: area,
+                area,
                 bbox_min,
                 bbox_max,
                 first_leaf_face,
@@ -436,51 +631,51 @@ impl LeafLump {
             });
         }
 
-        LeafLump {
+        Ok(LeafLump {
             leaves
-        }
+        })
     }
 }
 
 impl LeafFaceLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> LeafFaceLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<LeafFaceLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
         let num_faces = (info.length / 2) as usize;
         let mut faces: Vec<u16> = Vec::with_capacity(num_faces);
 
         for _ in 0..num_faces {
-            let a = reader.read_u16::<LittleEndian>().unwrap();
+            let a = reader.read_u16::<LittleEndian>()?;
             faces.push(a);
         }
 
-        LeafFaceLump {
+        Ok(LeafFaceLump {
             faces
-        }
+        })
     }
 }
 
 impl LeafBrushLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> LeafBrushLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<LeafBrushLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
         let num_brushes = (info.length / 2) as usize;
         let mut brushes: Vec<u16> = Vec::with_capacity(num_brushes);
 
         for _ in 0..num_brushes {
-            let a = reader.read_u16::<LittleEndian>().unwrap();
+            let a = reader.read_u16::<LittleEndian>()?;
             brushes.push(a);
         }
 
-        LeafBrushLump {
+        Ok(LeafBrushLump {
             brushes
-        }
+        })
     }
 }
 
 impl TexInfoLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> TexInfoLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<TexInfoLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
         let num_textures = (info.length / 76) as usize;
         let mut textures: Vec<TexInfo> = Vec::with_capacity(num_textures);
@@ -488,17 +683,17 @@ impl TexInfoLump {
         logfmt!("Num textures in tex info lump: {}", num_textures);
 
         for _ in 0..num_textures {
-            let u_axis = read_vec3f(reader);
-            let u_offset = reader.read_f32::<LittleEndian>().unwrap();
+            let u_axis = read_vec3f(reader)?;
+            let u_offset = reader.read_f32::<LittleEndian>()?;
 
-            let v_axis = read_vec3f(reader);
-            let v_offset = reader.read_f32::<LittleEndian>().unwrap();
+            let v_axis = read_vec3f(reader)?;
+            let v_offset = reader.read_f32::<LittleEndian>()?;
 
-            let flags = reader.read_u32::<LittleEndian>().unwrap();
-            let value = reader.read_u32::<LittleEndian>().unwrap();
+            let flags = reader.read_u32::<LittleEndian>()?;
+            let value = reader.read_u32::<LittleEndian>()?;
 
             let mut texture_name: [u8; 32] = [0; 32];
-            reader.read_exact(&mut texture_name).unwrap();
+            reader.read_exact(&mut texture_name)?;
 
             let mut name_len = 32;
             for i in 0..32 {
@@ -509,7 +704,7 @@ impl TexInfoLump {
             }
 
             let texture_name = unsafe { std::str::from_utf8_unchecked(&texture_name[0..name_len]) }.to_owned();
-            let next_texinfo = reader.read_u32::<LittleEndian>().unwrap();
+            let next_texinfo = reader.read_u32::<LittleEndian>()?;
 
             textures.push(TexInfo {
                 u_axis,
@@ -517,66 +712,162 @@ impl TexInfoLump {
                 v_axis,
                 v_offset,
                 flags,
-                _value: value,
+                value,
                 texture_name,
                 _next_texinfo: next_texinfo,
             });
         }
 
-        TexInfoLump {
+        Ok(TexInfoLump {
             textures
-        }
+        })
     }
 }
 
 impl VisLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> VisLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<VisLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
-        let num_clusters = reader.read_u32::<LittleEndian>().unwrap() as usize;
-        let hdr_size = 4 + (num_clusters * 8);
+        let num_clusters = reader.read_u32::<LittleEndian>()? as usize;
+
+        // the cluster count comes straight from the file, so make sure the header actually fits in the lump before allocating for it
+        let hdr_size = match num_clusters.checked_mul(8).and_then(|x| x.checked_add(4)) {
+            Some(v) if v <= info.length as usize => v,
+            _ => return Err(BspError::LumpOutOfRange)
+        };
+
+        let buf_len = info.length as usize - hdr_size;
 
         let mut clusters: Vec<VisCluster> = Vec::with_capacity(num_clusters);
 
         logfmt!("Num clusters in vis lump: {}", num_clusters);
 
         for _ in 0..num_clusters {
-            let pvs = reader.read_u32::<LittleEndian>().unwrap();
-            let _phs = reader.read_u32::<LittleEndian>().unwrap();
+            let pvs = reader.read_u32::<LittleEndian>()?;
+            let phs = reader.read_u32::<LittleEndian>()?;
+
+            let offs = match (pvs as usize).checked_sub(hdr_size) {
+                Some(v) if v < buf_len => v,
+                _ => return Err(BspError::LumpOutOfRange)
+            };
 
-            let offs = (pvs as usize) - hdr_size;
+            // some compilers leave the PHS region empty, in which case every cluster is treated as hearable
+            let phs_offs = (phs as usize).checked_sub(hdr_size).filter(|x| *x < buf_len);
 
             clusters.push(VisCluster {
-                vis_offset: offs
+                vis_offset: offs,
+                phs_offset: phs_offs,
             });
         }
 
         // read remainder of lump as byte array
-        let buf_len = (info.length as usize) - hdr_size;
         let mut vis_buffer: Vec<u8> = vec![0;buf_len];
-        reader.read_exact(&mut vis_buffer).unwrap();
+        reader.read_exact(&mut vis_buffer)?;
 
-        VisLump {
+        let vis_lump = VisLump {
             clusters,
             vis_buffer
+        };
+
+        // make sure every cluster's bits can be unpacked without running off the end of the buffer, so unpacking them later can't fail
+        let mut scratch = vec![false;num_clusters];
+        for cluster in &vis_lump.clusters {
+            vis_lump.unpack_bits(cluster.vis_offset, &mut scratch)?;
+
+            if let Some(phs_offset) = cluster.phs_offset {
+                vis_lump.unpack_bits(phs_offset, &mut scratch)?;
+            }
         }
+
+        Ok(vis_lump)
     }
 
     // Unpack vis info for a given cluster index
     pub fn unpack_vis(self: &VisLump, cluster_index: usize, vis_info: &mut [bool]) {
-        let mut v = self.clusters[cluster_index].vis_offset;
+        // clusters are validated when the lump is loaded
+        let _ = self.unpack_bits(self.clusters[cluster_index].vis_offset, vis_info);
+    }
+
+    // Unpack hearability info for a given cluster index
+    pub fn unpack_phs(self: &VisLump, cluster_index: usize, phs_info: &mut [bool]) {
+        match self.clusters[cluster_index].phs_offset {
+            Some(v) => {
+                let _ = self.unpack_bits(v, phs_info);
+            }
+            None => phs_info.fill(true)
+        }
+    }
+
+    // Check whether a given cluster is hearable from another cluster, without unpacking the whole PHS row
+    pub fn phs_contains(self: &VisLump, cluster_index: usize, other_index: usize) -> bool {
+        match self.clusters[cluster_index].phs_offset {
+            Some(v) => self.test_bit(v, other_index),
+            None => true
+        }
+    }
+
+    // Test a single cluster's bit in the run-length encoded cluster bits starting at the given offset into the vis buffer
+    fn test_bit(self: &VisLump, offset: usize, cluster: usize) -> bool {
+        let mut v = offset;
+        let mut c = 0;
+
+        while c < self.clusters.len() {
+            let bits = match self.vis_buffer.get(v) {
+                Some(b) => *b,
+                None => return false
+            };
+
+            if bits == 0 {
+                v += 1;
+                match self.vis_buffer.get(v) {
+                    Some(run) => c += 8 * (*run as usize),
+                    None => return false
+                }
+
+                if cluster < c {
+                    return false;
+                }
+            }
+            else {
+                if cluster < c + 8 {
+                    return (bits & (1 << (cluster - c))) != 0;
+                }
+
+                c += 8;
+            }
+
+            v += 1;
+        }
+
+        false
+    }
+
+    // Unpack run-length encoded cluster bits starting at the given offset into the vis buffer. Fails if the bits run off the end of the buffer
+    fn unpack_bits(self: &VisLump, offset: usize, info: &mut [bool]) -> Result<(), BspError> {
+        let mut v = offset;
         let mut c = 0;
 
         while c < self.clusters.len() {
-            if self.vis_buffer[v] == 0 {
+            let bits = match self.vis_buffer.get(v) {
+                Some(b) => *b,
+                None => return Err(BspError::LumpOutOfRange)
+            };
+
+            if bits == 0 {
                 v += 1;
-                c += 8 * (self.vis_buffer[v] as usize);
+                match self.vis_buffer.get(v) {
+                    Some(run) => c += 8 * (*run as usize),
+                    None => return Err(BspError::LumpOutOfRange)
+                }
             }
             else {
                 for bit in 0..8 {
                     let m = 1 << bit;
-                    if (self.vis_buffer[v] & m) != 0 {
-                        vis_info[c] = true;
+                    if (bits & m) != 0 {
+                        // the last byte may have padding bits past the final cluster
+                        if let Some(x) = info.get_mut(c) {
+                            *x = true;
+                        }
                     }
                     c += 1;
                 }
@@ -584,86 +875,209 @@ impl VisLump {
 
             v += 1;
         }
+
+        Ok(())
     }
 }
 
 impl LightmapLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> LightmapLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<LightmapLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
         let num_px = (info.length / 3) as usize;
         let mut lm: Vec<Color32> = Vec::with_capacity(num_px);
 
+        // samples are stored unscaled. brightness is applied when uploading to the lightmap atlas
         for _ in 0..num_px {
-            let mut c = read_color24(reader);
-            c.r = (c.r as i32 * 2).clamp(0, 255) as u8;
-            c.g = (c.g as i32 * 2).clamp(0, 255) as u8;
-            c.b = (c.b as i32 * 2).clamp(0, 255) as u8;
-            lm.push(c);
+            lm.push(read_color24(reader)?);
         }
 
-        LightmapLump {
+        Ok(LightmapLump {
             lm
+        })
+    }
+}
+
+impl LightGridLump {
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<LightGridLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
+
+        let step = read_vec3f(reader)?;
+        let size = [
+            reader.read_i32::<LittleEndian>()?,
+            reader.read_i32::<LittleEndian>()?,
+            reader.read_i32::<LittleEndian>()?,
+        ];
+        let mins = read_vec3f(reader)?;
+        let _num_styles = reader.read_u8()?;
+        let root_node = reader.read_u32::<LittleEndian>()?;
+
+        let num_nodes = reader.read_u32::<LittleEndian>()? as usize;
+        let mut nodes: Vec<LightGridNode> = Vec::with_capacity(num_nodes.min(info.length as usize / 44));
+
+        for _ in 0..num_nodes {
+            let mut mid = [0;3];
+            for i in 0..3 {
+                mid[i] = reader.read_i32::<LittleEndian>()?;
+            }
+
+            let mut children = [0;8];
+            for i in 0..8 {
+                children[i] = reader.read_u32::<LittleEndian>()?;
+            }
+
+            nodes.push(LightGridNode { mid, children });
+        }
+
+        let num_leaves = reader.read_u32::<LittleEndian>()? as usize;
+        let mut leaves: Vec<LightGridLeaf> = Vec::with_capacity(num_leaves.min(info.length as usize / 24));
+
+        for _ in 0..num_leaves {
+            let mut mins = [0;3];
+            for i in 0..3 {
+                mins[i] = reader.read_i32::<LittleEndian>()?;
+            }
+
+            let mut size = [0;3];
+            for i in 0..3 {
+                size[i] = reader.read_i32::<LittleEndian>()?.max(0);
+            }
+
+            // each sample is at least one byte, so a leaf can't hold more samples than the lump has bytes
+            let num_samples = size[0] as u64 * size[1] as u64 * size[2] as u64;
+            if num_samples > info.length as u64 {
+                return Err(BspError::LumpOutOfRange);
+            }
+
+            let num_samples = num_samples as usize;
+
+            let mut samples: Vec<Option<Color32>> = Vec::with_capacity(num_samples);
+
+            for _ in 0..num_samples {
+                let style_count = reader.read_u8()?;
+
+                // 0xFF marks a point which is inside solid geometry
+                if style_count == 0xFF {
+                    samples.push(None);
+                    continue;
+                }
+
+                let mut sample = Color32::new(0, 0, 0, 255);
+                for _ in 0..style_count {
+                    let style = reader.read_u8()?;
+                    let col = read_color24(reader)?;
+
+                    if style == 0 {
+                        sample = col;
+                    }
+                }
+
+                samples.push(Some(sample));
+            }
+
+            leaves.push(LightGridLeaf { mins, size, samples });
+        }
+
+        Ok(LightGridLump {
+            step,
+            size,
+            mins,
+            root_node,
+            nodes,
+            leaves
+        })
+    }
+
+    /// Look up the light grid point at the given grid coordinates, if it exists & isn't inside solid geometry
+    pub fn get_point(self: &Self, x: i32, y: i32, z: i32) -> Option<Color32> {
+        let mut node = self.root_node;
+        let mut depth = 0;
+
+        while node & LGNODE_LEAF == 0 {
+            // a well-formed octree can't be deeper than it has nodes, so anything deeper must have a cycle
+            if node & LGNODE_MISSING != 0 || depth > self.nodes.len() {
+                return None;
+            }
+
+            depth += 1;
+
+            let n = self.nodes.get(node as usize)?;
+            let child = (((x >= n.mid[0]) as usize) << 2) |
+                (((y >= n.mid[1]) as usize) << 1) |
+                ((z >= n.mid[2]) as usize);
+
+            node = n.children[child];
         }
+
+        let leaf = self.leaves.get((node & !LGNODE_LEAF) as usize)?;
+        let x = x - leaf.mins[0];
+        let y = y - leaf.mins[1];
+        let z = z - leaf.mins[2];
+
+        if x < 0 || y < 0 || z < 0 || x >= leaf.size[0] || y >= leaf.size[1] || z >= leaf.size[2] {
+            return None;
+        }
+
+        leaf.samples[(x + (y * leaf.size[0]) + (z * leaf.size[0] * leaf.size[1])) as usize]
     }
 }
 
 impl BrushLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> BrushLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<BrushLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
         let num_brushes = (info.length / 12) as usize;
         let mut brushes: Vec<Brush> = Vec::with_capacity(num_brushes);
 
         for _ in 0..num_brushes {
-            let first_brush_side = reader.read_u32::<LittleEndian>().unwrap();
-            let num_brush_sides = reader.read_u32::<LittleEndian>().unwrap();
-            let contents = reader.read_u32::<LittleEndian>().unwrap();
+            let first_brush_side = reader.read_u32::<LittleEndian>()?;
+            let num_brush_sides = reader.read_u32::<LittleEndian>()?;
+            let contents = reader.read_u32::<LittleEndian>()?;
 
             brushes.push(Brush { first_brush_side, num_brush_sides, contents });
         }
 
-        BrushLump {
+        Ok(BrushLump {
             brushes
-        }
+        })
     }
 }
 
 impl BrushSideLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> BrushSideLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<BrushSideLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
         let num_brush_sides = (info.length / 4) as usize;
         let mut brush_sides: Vec<BrushSide> = Vec::with_capacity(num_brush_sides);
 
         for _ in 0..num_brush_sides {
-            let plane = reader.read_u16::<LittleEndian>().unwrap();
-            let tex = reader.read_u16::<LittleEndian>().unwrap();
+            let plane = reader.read_u16::<LittleEndian>()?;
+            let tex = reader.read_u16::<LittleEndian>()?;
 
-            brush_sides.push(BrushSide { plane, _tex: tex });
+            brush_sides.push(BrushSide { plane, tex });
         }
 
-        BrushSideLump {
+        Ok(BrushSideLump {
             brush_sides
-        }
+        })
     }
 }
 
 impl SubModelLump {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> SubModelLump {
-        reader.seek(std::io::SeekFrom::Start(info.offset as u64)).unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<SubModelLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
 
         let num_submodels = (info.length / 48) as usize;
         let mut submodels: Vec<SubModel> = Vec::with_capacity(num_submodels);
 
         for _ in 0..num_submodels {
-            let mins = read_vec3f(reader);
-            let maxs = read_vec3f(reader);
-            let origin = read_vec3f(reader);
+            let mins = read_vec3f(reader)?;
+            let maxs = read_vec3f(reader)?;
+            let origin = read_vec3f(reader)?;
 
-            let headnode = reader.read_u32::<LittleEndian>().unwrap();
-            let first_face = reader.read_u32::<LittleEndian>().unwrap();
-            let num_faces = reader.read_u32::<LittleEndian>().unwrap();
+            let headnode = reader.read_u32::<LittleEndian>()?;
+            let first_face = reader.read_u32::<LittleEndian>()?;
+            let num_faces = reader.read_u32::<LittleEndian>()?;
 
             submodels.push(SubModel {
                 mins,
@@ -675,9 +1089,49 @@ impl SubModelLump {
             });
         }
 
-        SubModelLump {
+        Ok(SubModelLump {
             submodels
+        })
+    }
+}
+
+impl AreaLump {
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<AreaLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
+
+        let num_areas = (info.length / 8) as usize;
+        let mut areas: Vec<Area> = Vec::with_capacity(num_areas);
+
+        for _ in 0..num_areas {
+            let num_areaportals = reader.read_u32::<LittleEndian>()?;
+            let first_areaportal = reader.read_u32::<LittleEndian>()?;
+
+            areas.push(Area { num_areaportals, first_areaportal });
+        }
+
+        Ok(AreaLump {
+            areas
+        })
+    }
+}
+
+impl AreaPortalLump {
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R, info: &BspLumpInfo) -> Result<AreaPortalLump, BspError> {
+        reader.seek(std::io::SeekFrom::Start(info.offset as u64))?;
+
+        let num_portals = (info.length / 8) as usize;
+        let mut portals: Vec<AreaPortal> = Vec::with_capacity(num_portals);
+
+        for _ in 0..num_portals {
+            let portal_num = reader.read_u32::<LittleEndian>()?;
+            let other_area = reader.read_u32::<LittleEndian>()?;
+
+            portals.push(AreaPortal { portal_num, other_area });
         }
+
+        Ok(AreaPortalLump {
+            portals
+        })
     }
 }
 
@@ -698,49 +1152,74 @@ pub struct BspFile {
     pub brush_lump: BrushLump,
     pub brush_side_lump: BrushSideLump,
     pub submodel_lump: SubModelLump,
+    pub area_lump: AreaLump,
+    pub area_portal_lump: AreaPortalLump,
+    pub light_grid: Option<LightGridLump>,
 }
 
 impl BspFile {
-    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R) -> BspFile {
-        let magic = reader.read_u32::<LittleEndian>().unwrap();
+    pub fn new<R: Seek + ReadBytesExt>(reader: &mut R) -> Result<BspFile, BspError> {
+        let magic = reader.read_u32::<LittleEndian>()?;
         if magic != BSP_MAGIC {
-            panic!("Failed loading BSP: input is not valid IBSP data");
+            return Err(BspError::BadMagic);
         }
 
-        let version = reader.read_u32::<LittleEndian>().unwrap();
+        let version = reader.read_u32::<LittleEndian>()?;
         if version != BSP_VERSION {
-            panic!("Failed loading BSP: wrong IBSP file version");
+            return Err(BspError::UnsupportedVersion(version));
         }
 
         // read BSP lump info
         let mut bsp_lumps: Vec<BspLumpInfo> = Vec::with_capacity(19);
 
         for _ in 0..19 {
-            let offset = reader.read_u32::<LittleEndian>().unwrap();
-            let length = reader.read_u32::<LittleEndian>().unwrap();
+            let offset = reader.read_u32::<LittleEndian>()?;
+            let length = reader.read_u32::<LittleEndian>()?;
 
             bsp_lumps.push(BspLumpInfo { offset, length });
         }
 
+        // make sure every lump we read actually fits inside the file
+        let file_len = reader.seek(std::io::SeekFrom::End(0))?;
+        for lump in &bsp_lumps[0..19] {
+            if (lump.offset as u64) + (lump.length as u64) > file_len {
+                return Err(BspError::LumpOutOfRange);
+            }
+        }
+
         // read lumps
-        let entity_lump = EntityLump::new(reader, &bsp_lumps[0]);
-        let plane_lump = PlaneLump::new(reader, &bsp_lumps[1]);
-        let vertex_lump = VertexLump::new(reader, &bsp_lumps[2]);
-        let vis_lump = VisLump::new(reader, &bsp_lumps[3]);
-        let node_lump = NodeLump::new(reader, &bsp_lumps[4]);
-        let tex_info_lump = TexInfoLump::new(reader, &bsp_lumps[5]);
-        let face_lump = FaceLump::new(reader, &bsp_lumps[6]);
-        let lm_lump = LightmapLump::new(reader, &bsp_lumps[7]);
-        let leaf_lump = LeafLump::new(reader, &bsp_lumps[8]);
-        let leaf_face_lump = LeafFaceLump::new(reader, &bsp_lumps[9]);
-        let leaf_brush_lump = LeafBrushLump::new(reader, &bsp_lumps[10]);
-        let edge_lump = EdgeLump::new(reader, &bsp_lumps[11]);
-        let face_edge_lump = FaceEdgeLump::new(reader, &bsp_lumps[12]);
-        let submodel_lump = SubModelLump::new(reader, &bsp_lumps[13]);
-        let brush_lump = BrushLump::new(reader, &bsp_lumps[14]);
-        let brush_side_lump = BrushSideLump::new(reader, &bsp_lumps[15]);
-
-        BspFile {
+        let entity_lump = EntityLump::new(reader, &bsp_lumps[0])?;
+        let plane_lump = PlaneLump::new(reader, &bsp_lumps[1])?;
+        let vertex_lump = VertexLump::new(reader, &bsp_lumps[2])?;
+        let vis_lump = VisLump::new(reader, &bsp_lumps[3])?;
+        let node_lump = NodeLump::new(reader, &bsp_lumps[4])?;
+        let tex_info_lump = TexInfoLump::new(reader, &bsp_lumps[5])?;
+        let face_lump = FaceLump::new(reader, &bsp_lumps[6])?;
+        let lm_lump = LightmapLump::new(reader, &bsp_lumps[7])?;
+        let leaf_lump = LeafLump::new(reader, &bsp_lumps[8])?;
+        let leaf_face_lump = LeafFaceLump::new(reader, &bsp_lumps[9])?;
+        let leaf_brush_lump = LeafBrushLump::new(reader, &bsp_lumps[10])?;
+        let edge_lump = EdgeLump::new(reader, &bsp_lumps[11])?;
+        let face_edge_lump = FaceEdgeLump::new(reader, &bsp_lumps[12])?;
+        let submodel_lump = SubModelLump::new(reader, &bsp_lumps[13])?;
+        let brush_lump = BrushLump::new(reader, &bsp_lumps[14])?;
+        let brush_side_lump = BrushSideLump::new(reader, &bsp_lumps[15])?;
+        let area_lump = AreaLump::new(reader, &bsp_lumps[17])?;
+        let area_portal_lump = AreaPortalLump::new(reader, &bsp_lumps[18])?;
+
+        // optional BSPX lumps. most maps won't have these, & a broken one shouldn't prevent the map from loading
+        let light_grid = match find_bspx_lump(reader, &bsp_lumps, file_len, "LIGHTGRID_OCTREE") {
+            Ok(Some(info)) => match LightGridLump::new(reader, &info) {
+                Ok(v) => Some(v),
+                Err(_) => {
+                    log("Failed parsing BSPX light grid, ignoring");
+                    None
+                }
+            },
+            _ => None
+        };
+
+        Ok(BspFile {
             entity_lump,
             vertex_lump,
             edge_lump,
@@ -756,7 +1235,449 @@ impl BspFile {
             lm_lump,
             brush_lump,
             brush_side_lump,
-            submodel_lump
+            submodel_lump,
+            area_lump,
+            area_portal_lump,
+            light_grid
+        })
+    }
+
+    /// Get the vertices of the given face, in winding order
+    pub fn face_vertices(self: &Self, face_index: usize) -> Vec<Vector3> {
+        let face = &self.face_lump.faces[face_index];
+
+        (0..face.num_edges as u32).map(|i| {
+            let face_edge = self.face_edge_lump.edges[(face.first_edge + i) as usize];
+            let edge = &self.edge_lump.edges[face_edge.abs() as usize];
+            let vtx = if face_edge >= 0 { edge.a } else { edge.b };
+
+            self.vertex_lump.vertices[vtx as usize]
+        }).collect()
+    }
+
+    /// Get the normal of the given face, facing out from the front of the face
+    pub fn face_normal(self: &Self, face_index: usize) -> Vector3 {
+        let face = &self.face_lump.faces[face_index];
+        let plane = &self.plane_lump.planes[face.plane as usize];
+
+        if face.plane_side != 0 { plane.normal * -1.0 } else { plane.normal }
+    }
+
+    /// Get the minimum & maximum texture space coordinates of the given face
+    pub fn face_texture_bounds(self: &Self, face_index: usize) -> (Vector2, Vector2) {
+        let face = &self.face_lump.faces[face_index];
+        let tex_info = &self.tex_info_lump.textures[face.texture_info as usize];
+
+        let mut tex_min = Vector2::new(f32::INFINITY, f32::INFINITY);
+        let mut tex_max = Vector2::new(f32::NEG_INFINITY, f32::NEG_INFINITY);
+
+        for pos in self.face_vertices(face_index) {
+            let s = Vector3::dot(&pos, &tex_info.u_axis) + tex_info.u_offset;
+            let t = Vector3::dot(&pos, &tex_info.v_axis) + tex_info.v_offset;
+
+            tex_min.x = tex_min.x.min(s);
+            tex_min.y = tex_min.y.min(t);
+            tex_max.x = tex_max.x.max(s);
+            tex_max.y = tex_max.y.max(t);
+        }
+
+        (tex_min, tex_max)
+    }
+
+    /// Get the size in texels of each style of the given face's lightmap.
+    /// Lightmap texels are 16 texture units apart, starting from the face's minimum texture coordinates rounded down to a multiple of 16
+    pub fn face_lightmap_size(self: &Self, face_index: usize) -> (usize, usize) {
+        let (tex_min, tex_max) = self.face_texture_bounds(face_index);
+
+        let lm_size_x = ((tex_max.x / 16.0).ceil() - (tex_min.x / 16.0).floor() + 1.0).trunc() as usize;
+        let lm_size_y = ((tex_max.y / 16.0).ceil() - (tex_min.y / 16.0).floor() + 1.0).trunc() as usize;
+
+        (lm_size_x.clamp(1, 16), lm_size_y.clamp(1, 16))
+    }
+
+    /// Sample one style of the given face's lightmap at a position measured in texels, bilinearly filtered.
+    /// Samples are unscaled, as stored in the lightmap lump. Texels missing from the lump are black
+    pub fn sample_face_lightmap(self: &Self, face_index: usize, style_index: usize, texel: Vector2) -> Color32 {
+        let face = &self.face_lump.faces[face_index];
+        let (lm_size_x, lm_size_y) = self.face_lightmap_size(face_index);
+
+        let x = texel.x.clamp(0.0, (lm_size_x - 1) as f32);
+        let y = texel.y.clamp(0.0, (lm_size_y - 1) as f32);
+
+        let x0 = x.floor() as usize;
+        let y0 = y.floor() as usize;
+        let x1 = (x0 + 1).min(lm_size_x - 1);
+        let y1 = (y0 + 1).min(lm_size_y - 1);
+        let fx = x - x0 as f32;
+        let fy = y - y0 as f32;
+
+        let base = (face.lightmap_offset / 3) as usize + (style_index * lm_size_x * lm_size_y);
+        let sample = |tx: usize, ty: usize| {
+            match self.lm_lump.lm.get(base + (ty * lm_size_x) + tx) {
+                Some(c) => Vector3::new(c.r as f32, c.g as f32, c.b as f32),
+                None => Vector3::zero()
+            }
+        };
+
+        let top = (sample(x0, y0) * (1.0 - fx)) + (sample(x1, y0) * fx);
+        let bottom = (sample(x0, y1) * (1.0 - fx)) + (sample(x1, y1) * fx);
+        let c = (top * (1.0 - fy)) + (bottom * fy);
+
+        Color32::new(c.x as u8, c.y as u8, c.z as u8, 255)
+    }
+
+    /// Gather every face of the world model which emits light
+    pub fn emissive_surfaces(self: &Self) -> Vec<EmissiveSurface> {
+        let world_model = &self.submodel_lump.submodels[0];
+        let mut surfaces = Vec::new();
+
+        for face_idx in world_model.first_face..(world_model.first_face + world_model.num_faces) {
+            let face = &self.face_lump.faces[face_idx as usize];
+            let tex_info = &self.tex_info_lump.textures[face.texture_info as usize];
+
+            if tex_info.flags & SURF_LIGHT == 0 || tex_info.value == 0 || face.num_edges < 3 {
+                continue;
+            }
+
+            let verts = self.face_vertices(face_idx as usize);
+
+            let mut center = Vector3::zero();
+            for v in &verts {
+                center = center + *v;
+            }
+            center = center / verts.len() as f32;
+
+            // sum area of triangle fan
+            let mut area = 0.0;
+            for i in 1..(verts.len() - 1) {
+                area += Vector3::cross(&(verts[i] - verts[0]), &(verts[i + 1] - verts[0])).length() * 0.5;
+            }
+
+            let normal = self.face_normal(face_idx as usize);
+
+            surfaces.push(EmissiveSurface { center, normal, area, intensity: tex_info.value as f32 });
+        }
+
+        surfaces
+    }
+
+    /// Sample the baked light grid at the given position, returning the dominant light direction & the light color there
+    /// Returns None if the map has no light grid or the position is outside of it
+    pub fn sample_light_grid(self: &Self, position: &Vector3) -> Option<(Vector3, Color32)> {
+        let grid = match &self.light_grid {
+            Some(v) => v,
+            None => return None
+        };
+
+        let x = ((position.x - grid.mins.x) / grid.step.x).round() as i32;
+        let y = ((position.y - grid.mins.y) / grid.step.y).round() as i32;
+        let z = ((position.z - grid.mins.z) / grid.step.z).round() as i32;
+
+        let color = grid.get_point(x, y, z)?;
+
+        // the grid stores no direction, so estimate it from which way the light gets brighter.
+        // points inside of solid geometry are treated the same as the center point
+        let luma = |x: i32, y: i32, z: i32| {
+            let c = grid.get_point(x, y, z).unwrap_or(color);
+            c.r as f32 * 0.299 + c.g as f32 * 0.587 + c.b as f32 * 0.114
+        };
+
+        let dir = Vector3::new(
+            luma(x + 1, y, z) - luma(x - 1, y, z),
+            luma(x, y + 1, z) - luma(x, y - 1, z),
+            luma(x, y, z + 1) - luma(x, y, z - 1)
+        );
+
+        // fall back to light from above if the light is (nearly) even in all directions
+        let dir = if dir.length_sq() > 1.0 {
+            dir.normalized()
+        }
+        else {
+            Vector3::unit_z()
+        };
+
+        Some((dir, color))
+    }
+
+    /// Number of distinct areaportal states referenced by the map
+    pub fn num_areaportal_states(self: &Self) -> usize {
+        self.area_portal_lump.portals.iter().map(|x| x.portal_num as usize + 1).max().unwrap_or(0)
+    }
+
+    /// Check whether a sound at the given origin could be heard from the listener's position, according to the map's PHS
+    pub fn is_hearable(self: &Self, listener: &Vector3, origin: &Vector3) -> bool {
+        let listener_cluster = self.leaf_lump.leaves[self.calc_leaf_index(listener) as usize].cluster;
+        let origin_cluster = self.leaf_lump.leaves[self.calc_leaf_index(origin) as usize].cluster;
+
+        // points outside of the map aren't culled
+        if listener_cluster == u16::MAX || origin_cluster == u16::MAX {
+            return true;
+        }
+
+        if listener_cluster as usize >= self.vis_lump.clusters.len() || origin_cluster as usize >= self.vis_lump.clusters.len() {
+            return true;
+        }
+
+        self.vis_lump.phs_contains(listener_cluster as usize, origin_cluster as usize)
+    }
+
+    /// Flood fill from the given area through any open areaportals, marking each reachable area
+    pub fn flood_areas(self: &Self, start_area: usize, portal_open: &[bool], reachable_areas: &mut [bool]) {
+        reachable_areas.fill(false);
+
+        if start_area >= self.area_lump.areas.len() {
+            return;
         }
+
+        let mut stack = vec![start_area];
+        reachable_areas[start_area] = true;
+
+        while let Some(area_idx) = stack.pop() {
+            let area = &self.area_lump.areas[area_idx];
+            let num_portals = self.area_portal_lump.portals.len();
+            let start_portal = (area.first_areaportal as usize).min(num_portals);
+            let end_portal = (start_portal + (area.num_areaportals as usize)).min(num_portals);
+
+            for portal in &self.area_portal_lump.portals[start_portal..end_portal] {
+                let other_area = portal.other_area as usize;
+                let is_open = portal_open.get(portal.portal_num as usize).copied().unwrap_or(false);
+
+                if is_open && other_area < reachable_areas.len() && !reachable_areas[other_area] {
+                    reachable_areas[other_area] = true;
+                    stack.push(other_area);
+                }
+            }
+        }
+    }
+
+    /// Load a BSP file from an in-memory buffer
+    pub fn from_bytes(data: &[u8]) -> Result<BspFile, BspError> {
+        let mut reader = Cursor::new(data);
+        BspFile::new(&mut reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ByteOrder;
+    use crate::test_map::TestMap;
+
+    #[test]
+    fn loads_minimal_map_from_bytes() {
+        let mut test_map = TestMap::new().with_entities("{\n\"classname\" \"worldspawn\"\n}\n");
+        test_map.add_box(Vector3::new(-64.0, -64.0, -16.0), Vector3::new(64.0, 64.0, 0.0));
+
+        let bsp = BspFile::from_bytes(&test_map.to_bytes()).unwrap();
+
+        assert_eq!(bsp.submodel_lump.submodels.len(), 1);
+        assert_eq!(bsp.brush_lump.brushes.len(), 1);
+        assert_eq!(bsp.brush_side_lump.brush_sides.len(), 6);
+        assert!(bsp.light_grid.is_none());
+
+        let mut classnames = Vec::new();
+        bsp.entity_lump.parse(|x| classnames.push(x["classname"].to_owned()));
+        assert_eq!(classnames, vec!["worldspawn".to_owned()]);
+    }
+
+    // offset & length of a lump, read from the header of a serialized map
+    fn lump_info(bytes: &[u8], lump: usize) -> (usize, usize) {
+        let info = &bytes[8 + (lump * 8)..];
+        (LittleEndian::read_u32(&info[0..4]) as usize, LittleEndian::read_u32(&info[4..8]) as usize)
+    }
+
+    fn single_room_map() -> Vec<u8> {
+        let mut test_map = TestMap::new();
+        test_map.add_room(Vector3::new(-64.0, -64.0, -64.0), Vector3::new(64.0, 64.0, 64.0), 0, 1);
+        test_map.to_bytes()
+    }
+
+    #[test]
+    fn truncated_map_is_rejected() {
+        let bytes = single_room_map();
+
+        // last lump past the end of the file
+        let result = BspFile::from_bytes(&bytes[0..bytes.len() - 4]);
+        assert!(matches!(result, Err(BspError::LumpOutOfRange)));
+
+        // header cut off partway through
+        let result = BspFile::from_bytes(&bytes[0..20]);
+        assert!(matches!(result, Err(BspError::IOError(_))));
+    }
+
+    #[test]
+    fn wrong_version_is_rejected() {
+        let bytes = TestMap::new().with_version(BSP_VERSION + 1).to_bytes();
+        let result = BspFile::from_bytes(&bytes);
+        assert!(matches!(result, Err(BspError::UnsupportedVersion(v)) if v == BSP_VERSION + 1));
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let mut bytes = single_room_map();
+        bytes[0] = b'X';
+        assert!(matches!(BspFile::from_bytes(&bytes), Err(BspError::BadMagic)));
+    }
+
+    #[test]
+    fn vis_cluster_count_larger_than_lump_is_rejected() {
+        let mut bytes = single_room_map();
+        let (vis_offset, _) = lump_info(&bytes, 3);
+
+        LittleEndian::write_u32(&mut bytes[vis_offset..vis_offset + 4], 0x1000_0000);
+        assert!(matches!(BspFile::from_bytes(&bytes), Err(BspError::LumpOutOfRange)));
+    }
+
+    #[test]
+    fn vis_offset_past_end_of_lump_is_rejected() {
+        let mut bytes = single_room_map();
+        let (vis_offset, vis_len) = lump_info(&bytes, 3);
+
+        LittleEndian::write_u32(&mut bytes[vis_offset + 4..vis_offset + 8], vis_len as u32);
+        assert!(matches!(BspFile::from_bytes(&bytes), Err(BspError::LumpOutOfRange)));
+    }
+
+    #[test]
+    fn vis_run_past_end_of_lump_is_rejected() {
+        let mut bytes = single_room_map();
+        let (vis_offset, vis_len) = lump_info(&bytes, 3);
+
+        // point the PVS at a zero byte right at the end of the lump, whose run length is missing
+        bytes[vis_offset + vis_len - 1] = 0;
+        LittleEndian::write_u32(&mut bytes[vis_offset + 4..vis_offset + 8], (vis_len - 1) as u32);
+        assert!(matches!(BspFile::from_bytes(&bytes), Err(BspError::LumpOutOfRange)));
+    }
+
+    #[test]
+    fn phs_lookup_matches_unpacked_phs() {
+        // 20 clusters, hearable set {3, 17}: a literal byte, a run of one zero byte, then another literal byte
+        let vis = VisLump {
+            clusters: (0..20).map(|_| VisCluster { vis_offset: 0, phs_offset: Some(0) }).collect(),
+            vis_buffer: vec![0x08, 0, 1, 0x02],
+        };
+
+        let mut phs = vec![false;20];
+        vis.unpack_phs(0, &mut phs);
+
+        for other in 0..20 {
+            assert_eq!(vis.phs_contains(0, other), phs[other], "cluster {}", other);
+        }
+
+        assert!(vis.phs_contains(0, 3));
+        assert!(vis.phs_contains(0, 17));
+        assert!(!vis.phs_contains(0, 10));
+    }
+
+    #[test]
+    fn sounds_are_only_hearable_within_phs() {
+        let mut test_map = TestMap::new();
+        test_map.add_room(Vector3::new(-64.0, -64.0, -64.0), Vector3::new(64.0, 64.0, 64.0), 0, 1);
+        test_map.add_room(Vector3::new(256.0, -64.0, -64.0), Vector3::new(384.0, 64.0, 64.0), 1, 1);
+        test_map.add_room(Vector3::new(512.0, -64.0, -64.0), Vector3::new(640.0, 64.0, 64.0), 2, 1);
+        test_map.set_phs(vec![
+            vec![true, true, false],
+            vec![true, true, true],
+            vec![false, true, true],
+        ]);
+        let bsp = test_map.build();
+
+        let room_a = Vector3::new(0.0, 0.0, 0.0);
+        let room_b = Vector3::new(320.0, 0.0, 0.0);
+        let room_c = Vector3::new(576.0, 0.0, 0.0);
+
+        assert!(bsp.is_hearable(&room_a, &room_b));
+        assert!(bsp.is_hearable(&room_b, &room_c));
+        assert!(!bsp.is_hearable(&room_a, &room_c));
+        assert!(!bsp.is_hearable(&room_c, &room_a));
+    }
+
+    fn grid_leaf(mins: [i32;3], size: [i32;3], color: Color32) -> LightGridLeaf {
+        LightGridLeaf { mins, size, samples: vec![Some(color);(size[0] * size[1] * size[2]) as usize] }
+    }
+
+    #[test]
+    fn light_grid_is_sampled_through_octree() {
+        let mut bsp = BspFile::from_bytes(&single_room_map()).unwrap();
+
+        // root splits at x = 2, with a dark leaf on one side & a bright leaf on the other
+        bsp.light_grid = Some(LightGridLump {
+            step: Vector3::new(32.0, 32.0, 32.0),
+            size: [4, 1, 1],
+            mins: Vector3::zero(),
+            root_node: 0,
+            nodes: vec![LightGridNode { mid: [2, 0, 0], children: [LGNODE_LEAF, LGNODE_LEAF, LGNODE_LEAF, LGNODE_LEAF, LGNODE_LEAF | 1, LGNODE_LEAF | 1, LGNODE_LEAF | 1, LGNODE_LEAF | 1] }],
+            leaves: vec![
+                grid_leaf([0, 0, 0], [2, 1, 1], Color32::new(10, 10, 10, 255)),
+                grid_leaf([2, 0, 0], [2, 1, 1], Color32::new(200, 200, 200, 255)),
+            ],
+        });
+
+        let (dir, color) = bsp.sample_light_grid(&Vector3::new(32.0, 0.0, 0.0)).unwrap();
+        assert_eq!((color.r, color.g, color.b), (10, 10, 10));
+
+        // light comes from the brighter side
+        assert!(dir.x > 0.99);
+
+        let (_, color) = bsp.sample_light_grid(&Vector3::new(96.0, 0.0, 0.0)).unwrap();
+        assert_eq!((color.r, color.g, color.b), (200, 200, 200));
+
+        // outside of every leaf
+        assert!(bsp.sample_light_grid(&Vector3::new(0.0, 320.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn cyclic_light_grid_octree_does_not_hang() {
+        let grid = LightGridLump {
+            step: Vector3::new(32.0, 32.0, 32.0),
+            size: [1, 1, 1],
+            mins: Vector3::zero(),
+            root_node: 0,
+            nodes: vec![LightGridNode { mid: [0, 0, 0], children: [0;8] }],
+            leaves: Vec::new(),
+        };
+
+        assert!(grid.get_point(0, 0, 0).is_none());
+    }
+
+    fn parse_entities(src: &str) -> Vec<Vec<(String, String)>> {
+        let lump = EntityLump { entities: src.to_owned() };
+        let mut entities = Vec::new();
+
+        lump.parse(|map| {
+            let mut pairs = map.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Vec<_>>();
+            pairs.sort();
+            entities.push(pairs);
+        });
+
+        entities
+    }
+
+    fn pair(key: &str, val: &str) -> (String, String) {
+        (key.to_owned(), val.to_owned())
+    }
+
+    #[test]
+    fn entity_strings_may_contain_braces_and_escaped_quotes() {
+        let entities = parse_entities("{\r\n\"classname\" \"worldspawn\"\r\n\"message\" \"a {b} \\\"c\\\"\"\r\n}\r\n{ \"classname\" \"light\" }");
+
+        assert_eq!(entities.len(), 2);
+        assert_eq!(entities[0], vec![pair("classname", "worldspawn"), pair("message", "a {b} \\\"c\\\"")]);
+        assert_eq!(entities[1], vec![pair("classname", "light")]);
+    }
+
+    #[test]
+    fn entity_comments_are_skipped() {
+        let entities = parse_entities("// a comment with \"quotes\" & {braces}\n{\n\"classname\" \"info_null\" // trailing\n}");
+
+        assert_eq!(entities, vec![vec![pair("classname", "info_null")]]);
+    }
+
+    #[test]
+    fn malformed_entities_stop_parsing() {
+        // key without a value
+        assert_eq!(parse_entities("{ \"classname\" \"light\" } { \"classname\" }").len(), 1);
+
+        // missing closing brace
+        assert_eq!(parse_entities("{ \"classname\" \"light\" } { \"classname\" \"light\"").len(), 1);
     }
 }
\ No newline at end of file
@@ -1,17 +1,37 @@
 use std::{collections::HashMap, sync::Arc, vec};
 
-use dbsdk_rs::{math::{Matrix4x4, Vector2, Vector3, Vector4}, vdp::{self, Color32, Rectangle, Texture, TextureUnit, VertexSlotFormat}, vu_asm::vu_asm};
+use dbsdk_rs::{audio, db::log, math::{Matrix4x4, Vector2, Vector3, Vector4}, vdp::{self, Color32, Rectangle, Texture, TextureUnit, VertexSlotFormat}, vu_asm::vu_asm};
 use lazy_static::lazy_static;
 
-use crate::{asset_loader::load_texture, bsp_file::{BspFile, Edge, SURF_NODRAW, SURF_NOLM, SURF_SKY, SURF_TRANS33, SURF_TRANS66, SURF_WARP}, common::{self, aabb_aabb_intersects, aabb_frustum}};
-
-pub const NUM_CUSTOM_LIGHT_LAYERS: usize = 30;
-pub const CUSTOM_LIGHT_LAYER_START: usize = 32;
-pub const CUSTOM_LIGHT_LAYER_END: usize = CUSTOM_LIGHT_LAYER_START + NUM_CUSTOM_LIGHT_LAYERS;
+use crate::{asset_loader::{load_texture, load_texture_size}, bsp_file::{BspFile, Edge, CONTENTS_WATER, CUSTOM_LIGHT_LAYER_END, CUSTOM_LIGHT_LAYER_START, NUM_CUSTOM_LIGHT_LAYERS, SURF_CLAMP, SURF_DETAIL, SURF_FLOW, SURF_NEAREST, SURF_NODRAW, SURF_NOLM, SURF_SKY, SURF_TRANS33, SURF_TRANS66, SURF_WARP}, common::{self, aabb_aabb_intersects, aabb_frustum_classify, FrustumTest}};
 
 const LM_SIZE: i32 = 512;
 
-// basic VU program which multiplies input vertex positions against a transform matrix
+// number of recently visited clusters to keep unpacked visibility info for
+const VIS_CACHE_SIZE: usize = 8;
+
+// number of recently visited clusters to keep built geometry for, in addition to the geometry being drawn
+const GEO_CACHE_SIZE: usize = 4;
+
+// how fast the water sheen layer's distortion animates, relative to regular SURF_WARP surfaces
+const WATER_SHEEN_SPEED: f32 = 0.7;
+
+// when entering a new cluster, the lightmap atlas is only cleared out once it's at least this full
+const LM_ATLAS_RESET_USAGE: f32 = 0.75;
+
+// each lightmap region is surrounded by a border of this many texels, copied from its edges, so bilinear filtering never samples neighbouring regions
+const LM_PADDING: usize = 1;
+
+// largest lightmap region including padding
+const LM_MAX_PADDED: usize = 16 + (LM_PADDING * 2);
+
+// size of the fullbright region reserved for faces which don't fit in the atlas
+const LM_FALLBACK_SIZE: usize = 1 + (LM_PADDING * 2);
+
+// geometry rebuilds are spread across frames, unpacking roughly this many faces per frame
+const BUILD_FACES_PER_FRAME: usize = 256;
+
+// basic VU program which multiplies input vertex positions against a transform matrix, and blends vertex colors towards the fog color with distance
 const VU_BASIC_TRANSFORM: &[u32] = &vu_asm!{
     ld r0 0     // input position in r0
     ld r1 1     // input texcoord in r1
@@ -22,8 +42,34 @@ const VU_BASIC_TRANSFORM: &[u32] = &vu_asm!{
     ldc r6 3    // transform matrix column 3 in r6
     ldc r7 4    // ocol in r7
 
+    // view depth (clip space w) in r8
+    ld r8 0
+    dot r8 r6
+
     // transform position with MVP matrix in r3..r6
     mulm r0 r3
+
+    // fog visibility in r8: clamp(depth * linear scale + linear bias, 0, 1) * exp(depth * exp scale)
+    ldc r9 7
+    mul r9 r8
+    exp r9
+    ldc r10 5
+    mul r8 r10
+    ldc r10 6
+    add r8 r10
+    ldc r10 9
+    min r8 r10
+    ldc r11 10
+    max r8 r11
+    mul r8 r9
+
+    // fade vertex color & ocol out, and fade fog color in
+    sub r10 r8
+    ldc r11 8
+    mul r10 r11
+    mul r2 r8
+    mul r7 r8
+    add r7 r10
     
     // output
     st pos r0
@@ -32,8 +78,8 @@ const VU_BASIC_TRANSFORM: &[u32] = &vu_asm!{
     st ocol r7
 };
 
-// TODO: not sure how viable it is memory-wise to have one lightmap atlas per renderer/camera
-// For now probably not worth worrying about until splitscreen support is actually needed
+// Each renderer/camera has its own lightmap atlas, since the set of visible faces differs per camera
+// Atlas memory is reported by the debug overlay: with two players that's 2048KiB of lightmaps (1024KiB per 512x512 RGBA8888 atlas)
 
 lazy_static! {
     static ref LIGHTSTYLES: [Vec<f32>;12] = [
@@ -82,49 +128,211 @@ impl MapVertex {
     }
 }
 
+/// Per-surface parameters derived from a texinfo's value field
+#[derive(Clone, Copy)]
+pub struct SurfaceValueParams {
+    pub tint: Color32,
+    pub emissive: f32,
+    /// Ignore the lightmap & render the texture at full brightness
+    pub fullbright: bool,
+}
+
+/// Hook which maps a non-zero texinfo value to surface parameters.
+/// Surfaces with a value of zero are never passed to this hook & render unchanged
+pub type SurfaceValueFn = fn(u32) -> SurfaceValueParams;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FogMode {
+    None,
+    /// Fog fades in linearly between start & end distance
+    Linear,
+    /// Fog thickens exponentially with distance according to density
+    Exponential,
+}
+
+/// Distance fog settings, applied to map geometry, models, & meshes
+#[derive(Clone, Copy)]
+pub struct FogSettings {
+    pub mode: FogMode,
+    pub color: Vector3,
+    pub start: f32,
+    pub end: f32,
+    pub density: f32,
+}
+
+impl Default for FogSettings {
+    fn default() -> Self {
+        FogSettings { mode: FogMode::None, color: Vector3::zero(), start: 0.0, end: 1000.0, density: 0.0 }
+    }
+}
+
+/// Controls how raw lightmap samples are brightened before being uploaded to the lightmap atlas
+#[derive(Clone, Copy)]
+pub struct LightmapSettings {
+    /// Multiplier applied to lightmap samples
+    pub overbright: f32,
+    /// Gamma curve applied to lightmap samples before the overbright multiplier (1.0 leaves samples unchanged)
+    pub gamma: f32,
+}
+
+impl Default for LightmapSettings {
+    fn default() -> Self {
+        LightmapSettings { overbright: 2.0, gamma: 1.0 }
+    }
+}
+
+impl LightmapSettings {
+    /// Convert a raw lightmap sample into a linear color, with brightness settings applied
+    pub fn sample_to_color(self: &Self, sample: Color32) -> Vector3 {
+        let inv_gamma = 1.0 / self.gamma.max(0.01);
+        let remap = |c: u8| (c as f32 / 255.0).powf(inv_gamma) * self.overbright;
+
+        Vector3::new(remap(sample.r), remap(sample.g), remap(sample.b))
+    }
+
+    fn build_lut(self: &Self) -> [u8;256] {
+        let mut lut = [0;256];
+        let inv_gamma = 1.0 / self.gamma.max(0.01);
+
+        for i in 0..256 {
+            let v = (i as f32 / 255.0).powf(inv_gamma) * self.overbright;
+            lut[i] = (v * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+
+        lut
+    }
+}
+
 struct LmAtlasPacker {
     pub lm: Texture,
+    pub lut: [u8;256],
     pub cache: HashMap<usize, Rectangle>,
     pub anim_regions: Vec<usize>,
-    lm_pack_x: usize,
-    lm_pack_y: usize,
-    lm_pack_y_max: usize
+    /// Set if a region failed to fit in the atlas since the last reset
+    pub overflow: bool,
+    /// Fullbright region used by faces which failed to fit in the atlas
+    pub fallback: Rectangle,
+    /// Top edge of packed regions, as a list of (x, y, width) segments sorted left to right
+    skyline: Vec<(usize, usize, usize)>,
+    used_area: usize,
 }
 
 impl LmAtlasPacker {
-    pub fn new(size: i32) -> LmAtlasPacker {
-        LmAtlasPacker {
+    pub fn new(size: i32, settings: &LightmapSettings) -> LmAtlasPacker {
+        let mut packer = LmAtlasPacker {
             lm: Texture::new(size, size, false, vdp::TextureFormat::RGBA8888).unwrap(),
+            lut: settings.build_lut(),
             anim_regions: Vec::new(),
             cache: HashMap::new(),
-            lm_pack_x: 0,
-            lm_pack_y: 0,
-            lm_pack_y_max: 0
+            overflow: false,
+            fallback: Rectangle::new(0, 0, 0, 0),
+            skyline: vec![(0, 0, size as usize)],
+            used_area: 0,
+        };
+
+        packer.reset();
+        packer
+    }
+
+    // find the lowest position a region could be placed at, if it were placed at the start of the given skyline segment
+    fn skyline_fit(self: &Self, segment: usize, width: usize, height: usize) -> Option<usize> {
+        let x = self.skyline[segment].0;
+        if x + width > self.lm.width as usize {
+            return None;
+        }
+
+        // the region rests on the highest segment it spans
+        let mut y = 0;
+        let mut remaining = width as isize;
+        let mut i = segment;
+
+        while remaining > 0 {
+            let (_, seg_y, seg_width) = self.skyline[i];
+            y = y.max(seg_y);
+
+            if y + height > self.lm.height as usize {
+                return None;
+            }
+
+            remaining -= seg_width as isize;
+            i += 1;
+        }
+
+        Some(y)
+    }
+
+    // raise the skyline to cover a newly placed region
+    fn skyline_add(self: &mut Self, segment: usize, width: usize, height: usize, y: usize) {
+        let x = self.skyline[segment].0;
+        self.skyline.insert(segment, (x, y + height, width));
+
+        // trim or remove segments now underneath the new one
+        let i = segment + 1;
+        while i < self.skyline.len() {
+            let (seg_x, seg_y, seg_width) = self.skyline[i];
+            if seg_x >= x + width {
+                break;
+            }
+
+            let shrink = (x + width) - seg_x;
+            if shrink >= seg_width {
+                self.skyline.remove(i);
+            }
+            else {
+                self.skyline[i] = (seg_x + shrink, seg_y, seg_width - shrink);
+                break;
+            }
+        }
+
+        // merge neighbouring segments of the same height
+        let mut i = 0;
+        while i + 1 < self.skyline.len() {
+            if self.skyline[i].1 == self.skyline[i + 1].1 {
+                self.skyline[i].2 += self.skyline[i + 1].2;
+                self.skyline.remove(i + 1);
+            }
+            else {
+                i += 1;
+            }
         }
     }
 
-    pub fn pack(self: &mut Self, face_id: usize, width: usize, height: usize, anim: bool) -> (bool, Rectangle) {
+    /// Find room for a face's lightmap region, returning whether it was already packed & the region. Returns None if the atlas is out of room
+    pub fn pack(self: &mut Self, face_id: usize, width: usize, height: usize, anim: bool) -> Option<(bool, Rectangle)> {
         if self.cache.contains_key(&face_id) {
-            return (true, self.cache[&face_id]);
+            return Some((true, self.cache[&face_id]));
         }
 
-        let lm_width = self.lm.width as usize;
-        let lm_height = self.lm.height as usize;
+        // skyline bottom-left packing: place the region as low as possible, preferring whichever spot leaves the narrowest gap
+        let mut best: Option<(usize, usize, usize)> = None;
 
-        if self.lm_pack_x + width > lm_width {
-            self.lm_pack_x = 0;
-            self.lm_pack_y += self.lm_pack_y_max;
-            self.lm_pack_y_max = 0;
-        }
+        for i in 0..self.skyline.len() {
+            if let Some(y) = self.skyline_fit(i, width, height) {
+                let seg_width = self.skyline[i].2;
+                let better = match best {
+                    Some((_, best_y, best_width)) => y < best_y || (y == best_y && seg_width < best_width),
+                    None => true
+                };
 
-        if self.lm_pack_x + width > lm_width || self.lm_pack_y + height > lm_height {
-            panic!("Out of room in lightmap atlas!!");
+                if better {
+                    best = Some((i, y, seg_width));
+                }
+            }
         }
 
-        let result = Rectangle::new(self.lm_pack_x as i32, self.lm_pack_y as i32, width as i32, height as i32);
+        let (segment, y) = match best {
+            Some((segment, y, _)) => (segment, y),
+            None => {
+                // out of room. the caller is responsible for resetting & trying again, or falling back to the fullbright region
+                self.overflow = true;
+                return None;
+            }
+        };
+
+        let result = Rectangle::new(self.skyline[segment].0 as i32, y as i32, width as i32, height as i32);
 
-        self.lm_pack_x += width;
-        self.lm_pack_y_max = self.lm_pack_y_max.max(height);
+        self.skyline_add(segment, width, height, y);
+        self.used_area += width * height;
 
         self.cache.insert(face_id, result);
         
@@ -132,27 +340,147 @@ impl LmAtlasPacker {
             self.anim_regions.push(face_id);
         }
 
-        (false, result)
+        Some((false, result))
+    }
+
+    /// Apply lightmap brightness settings to a raw lightmap sample
+    pub fn remap(self: &Self, c: Color32) -> Color32 {
+        Color32::new(self.lut[c.r as usize], self.lut[c.g as usize], self.lut[c.b as usize], c.a)
+    }
+
+    /// Sample the baked lightmap of a face at the given position, from (0, 0) at the face's minimum texture coordinates to (1, 1) at its maximum.
+    /// Reads from the source lightmap data rather than the atlas texture, bilinearly filtered & with brightness settings applied like the rendered lightmap.
+    /// Faces without a lightmap are fully lit
+    pub fn sample_face(self: &Self, bsp: &BspFile, face_id: usize, uv: Vector2) -> Color32 {
+        let face = &bsp.face_lump.faces[face_id];
+        let tex_info = &bsp.tex_info_lump.textures[face.texture_info as usize];
+
+        if tex_info.flags & SURF_NOLM != 0 || face.num_lightmaps == 0 {
+            return Color32::new(255, 255, 255, 255);
+        }
+
+        let (lm_size_x, lm_size_y) = bsp.face_lightmap_size(face_id);
+        let texel = Vector2::new(uv.x.clamp(0.0, 1.0) * (lm_size_x - 1) as f32, uv.y.clamp(0.0, 1.0) * (lm_size_y - 1) as f32);
+
+        self.remap(bsp.sample_face_lightmap(face_id, 0, texel))
+    }
+
+    /// Size of the atlas texture in bytes
+    pub fn memory_size(self: &Self) -> usize {
+        self.lm.width as usize * self.lm.height as usize * 4
+    }
+
+    /// Fraction of the atlas area which has been filled so far
+    pub fn usage(self: &Self) -> f32 {
+        self.used_area as f32 / (self.lm.width * self.lm.height) as f32
     }
 
     pub fn reset(self: &mut Self) {
-        self.lm_pack_x = 0;
-        self.lm_pack_y = 0;
-        self.lm_pack_y_max = 0;
+        self.skyline.clear();
+        self.skyline.push((0, 0, self.lm.width as usize));
+        self.used_area = 0;
         self.cache.clear();
         self.anim_regions.clear();
+        self.overflow = false;
+
+        // reserve the fullbright fallback region in the corner
+        self.fallback = Rectangle::new(0, 0, LM_FALLBACK_SIZE as i32, LM_FALLBACK_SIZE as i32);
+        self.skyline_add(0, LM_FALLBACK_SIZE, LM_FALLBACK_SIZE, 0);
+        self.used_area += LM_FALLBACK_SIZE * LM_FALLBACK_SIZE;
+        self.lm.set_texture_data_region(0, Some(self.fallback), &[Color32::new(255, 255, 255, 255);LM_FALLBACK_SIZE*LM_FALLBACK_SIZE]);
     }
 }
 
+struct TransparentFace {
+    tex_idx: usize,
+    vtx_start: usize,
+    vtx_end: usize,
+    idx_start: usize,
+    idx_end: usize,
+    centroid: Vector3,
+    /// Whether this is a warping face bordering water, which gets extra water passes when enabled
+    water: bool,
+    normal: Vector3,
+}
+
 struct Model {
     geometry: Vec<(usize, Vec<MapVertex>, Vec<u16>)>
 }
 
+// geometry built for every leaf potentially visible from a cluster, with the areaportal states it was built for
+struct ClusterGeometry {
+    cluster: u16,
+    areaportal_states: Vec<bool>,
+    vertices: Vec<Vec<MapVertex>>,
+    indices: Vec<Vec<u16>>,
+    transp_faces: Vec<TransparentFace>,
+    opaque_order: Vec<usize>,
+}
+
+// geometry built for recently visited clusters, least recently used first
+struct GeometryCache {
+    entries: Vec<ClusterGeometry>,
+    capacity: usize,
+}
+
+impl GeometryCache {
+    fn new(capacity: usize) -> GeometryCache {
+        GeometryCache {
+            entries: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    // remove & return geometry built for the given cluster & areaportal states, if cached
+    fn take(self: &mut Self, cluster: u16, areaportal_states: &[bool]) -> Option<ClusterGeometry> {
+        let i = self.entries.iter().position(|x| x.cluster == cluster && x.areaportal_states.as_slice() == areaportal_states)?;
+        Some(self.entries.remove(i))
+    }
+
+    // add geometry as the most recently used, returning the least recently used geometry if it had to be evicted
+    fn insert(self: &mut Self, entry: ClusterGeometry) -> Option<ClusterGeometry> {
+        let evicted = if self.entries.len() >= self.capacity {
+            Some(self.entries.remove(0))
+        }
+        else {
+            None
+        };
+
+        self.entries.push(entry);
+        evicted
+    }
+
+    fn clear(self: &mut Self) {
+        self.entries.clear();
+    }
+}
+
 pub struct BspMapTextures {
     loaded_textures: Vec<Option<Arc<Texture>>>,
     err_tex: Texture,
     opaque_meshes: Vec<usize>,
-    transp_meshes: Vec<usize>,
+    pub surface_value_fn: Option<SurfaceValueFn>,
+    /// Filtering used for map textures, unless overridden by SURF_NEAREST
+    pub filter: vdp::TextureFilter,
+    /// Whether detail textures are drawn over SURF_DETAIL surfaces
+    pub detail_enabled: bool,
+    /// How many times detail textures repeat per repeat of the base texture
+    pub detail_scale: f32,
+    /// How fast SURF_FLOW surfaces scroll, in texture repeats per second
+    pub flow_speed: f32,
+    /// When streaming, how many frames a texture must go unseen before it is unloaded
+    pub unload_frames: u32,
+    /// Whether translucent warping faces bordering water are drawn as water: tinting what's seen through them, with an animated sheen which brightens at glancing angles
+    pub water_enabled: bool,
+    /// Color multiplied over geometry seen through water, & used for the water's sheen
+    pub water_tint: Color32,
+    detail_textures: Vec<Option<Arc<Texture>>>,
+    shared_detail_tex: Option<Option<Arc<Texture>>>,
+    tex_scale: Vec<Vector2>,
+    streaming: bool,
+    resident: Vec<bool>,
+    last_visible: Vec<u32>,
+    frame: u32,
 }
 
 pub struct BspMapModelRenderer {
@@ -164,14 +492,62 @@ pub struct BspMapModelRenderer {
 
 pub struct BspMapRenderer {
     vis: Vec<bool>,
-    prev_leaf: i32,
+    /// Unpacked visibility info for recently visited clusters, least recently used first
+    vis_cache: Vec<(u16, Vec<bool>)>,
+    prev_cluster: Option<u16>,
+    /// Camera frustum & areaportal states as of the last time visible leaves were recalculated
+    prev_frustum: Vec<Vector4>,
+    prev_areaportal_states: Vec<bool>,
     mesh_vertices: Vec<Vec<MapVertex>>,
     mesh_indices: Vec<Vec<u16>>,
+    /// Opaque texture batches sorted by their nearest vertex to the camera, as of the last build
+    opaque_order: Vec<usize>,
+    /// Cluster & areaportal states the drawn geometry was built for
+    mesh_cluster: u16,
+    mesh_areaportal_states: Vec<bool>,
+    /// Cleared when the lightmap atlas is reset, as the drawn geometry's lightmap regions are no longer valid & it mustn't be cached
+    mesh_reusable: bool,
+    /// Geometry built for recently visited clusters, reused when the camera returns to one of them
+    geo_cache: GeometryCache,
     visible_leaves: Vec<bool>,
+    visible_areas: Vec<bool>,
     lm_atlas: LmAtlasPacker,
     drawn_faces: Vec<bool>,
+    transp_faces: Vec<TransparentFace>,
+    /// Geometry being built in the background, swapped with the drawn geometry once complete
+    build_vertices: Vec<Vec<MapVertex>>,
+    build_indices: Vec<Vec<u16>>,
+    build_transp_faces: Vec<TransparentFace>,
+    /// Squared distance from the build position to the nearest vertex of each texture batch
+    build_nearest: Vec<f32>,
+    /// Leaves potentially visible from the current cluster, which the in-progress build is working from
+    build_leaves: Vec<bool>,
+    /// Next leaf to build, or None if no build is in progress
+    build_cursor: Option<usize>,
+    build_position: Vector3,
+    has_geometry: bool,
+    /// Number of times geometry has been built from scratch, rather than reused from the cache
+    build_count: usize,
+    build_time: f32,
+    face_idx_buff: Vec<u16>,
     geo_buff: Vec<MapVertex>,
     geo_buff2: Vec<MapVertex>,
+    node_tests: usize,
+}
+
+// copy a lightmap into a buffer with LM_PADDING texels of border on each side, replicating edge texels into the border
+fn pad_lightmap(src: &[Color32], width: usize, height: usize, dst: &mut [Color32]) {
+    let padded_width = width + (LM_PADDING * 2);
+    let padded_height = height + (LM_PADDING * 2);
+
+    for y in 0..padded_height {
+        let src_y = y.saturating_sub(LM_PADDING).min(height - 1);
+
+        for x in 0..padded_width {
+            let src_x = x.saturating_sub(LM_PADDING).min(width - 1);
+            dst[(y * padded_width) + x] = src[(src_y * width) + src_x];
+        }
+    }
 }
 
 fn update_lm_animation(light_layers: &[f32;NUM_CUSTOM_LIGHT_LAYERS], animation_time: f32, lm_atlas: &LmAtlasPacker, bsp: &BspFile) {
@@ -179,11 +555,14 @@ fn update_lm_animation(light_layers: &[f32;NUM_CUSTOM_LIGHT_LAYERS], animation_t
     let lightstyle_frame = (animation_time * 10.0) as usize;
 
     let mut lm_slice_buffer = [Color32::new(0, 0, 0, 255);16*16];
+    let mut lm_padded_buffer = [Color32::new(0, 0, 0, 255);LM_MAX_PADDED*LM_MAX_PADDED];
     for face_idx in &lm_atlas.anim_regions {
         let face = &bsp.face_lump.faces[*face_idx];
         let region = lm_atlas.cache[face_idx];
 
-        let slice_len = (region.width * region.height) as usize;
+        let lm_width = region.width as usize - (LM_PADDING * 2);
+        let lm_height = region.height as usize - (LM_PADDING * 2);
+        let slice_len = lm_width * lm_height;
 
         let lm_target_slice = &mut lm_slice_buffer[0..slice_len];
         lm_target_slice.fill(Color32::new(0, 0, 0, 255));
@@ -207,13 +586,17 @@ fn update_lm_animation(light_layers: &[f32;NUM_CUSTOM_LIGHT_LAYERS], animation_t
             let lm_src_slice = &bsp.lm_lump.lm[slice_start..slice_end];
 
             for j in 0..slice_len {
-                lm_target_slice[j].r = lm_target_slice[j].r.saturating_add((lm_src_slice[j].r as f32 * sc).clamp(0.0, 255.0) as u8);
-                lm_target_slice[j].g = lm_target_slice[j].g.saturating_add((lm_src_slice[j].g as f32 * sc).clamp(0.0, 255.0) as u8);
-                lm_target_slice[j].b = lm_target_slice[j].b.saturating_add((lm_src_slice[j].b as f32 * sc).clamp(0.0, 255.0) as u8);
+                let src = lm_atlas.remap(lm_src_slice[j]);
+                lm_target_slice[j].r = lm_target_slice[j].r.saturating_add((src.r as f32 * sc).clamp(0.0, 255.0) as u8);
+                lm_target_slice[j].g = lm_target_slice[j].g.saturating_add((src.g as f32 * sc).clamp(0.0, 255.0) as u8);
+                lm_target_slice[j].b = lm_target_slice[j].b.saturating_add((src.b as f32 * sc).clamp(0.0, 255.0) as u8);
             }
         }
 
-        lm_atlas.lm.set_texture_data_region(0, Some(region), lm_target_slice);
+        let padded_slice = &mut lm_padded_buffer[0..(region.width * region.height) as usize];
+        pad_lightmap(lm_target_slice, lm_width, lm_height, padded_slice);
+
+        lm_atlas.lm.set_texture_data_region(0, Some(region), padded_slice);
     }
 }
 
@@ -295,28 +678,47 @@ fn unpack_face(bsp: &BspFile, textures: &BspMapTextures, face_idx: usize, edge_b
     let lm_size_y = lm_size_y.clamp(1, 16);
 
     // upload region to lightmap atlas
-    let (lm_region_offset, lm_region_scale) = if tex_info.flags & SURF_NOLM == 0 {
-        let (in_cache, lm_region) = lm.pack(face_idx, lm_size_x, lm_size_y, face.num_lightmaps > 1);
+    let mut lm_fallback_uv = None;
+    let lm_region = if tex_info.flags & SURF_NOLM == 0 {
+        let (in_cache, lm_region) = match lm.pack(face_idx, lm_size_x + (LM_PADDING * 2), lm_size_y + (LM_PADDING * 2), face.num_lightmaps > 1) {
+            Some(v) => v,
+            None => {
+                // out of room, so every vertex samples the middle of the fullbright region instead
+                let r = lm.fallback;
+                lm_fallback_uv = Some(Vector2::new(
+                    (r.x as f32 + (r.width as f32 * 0.5)) / lm.lm.width as f32,
+                    (r.y as f32 + (r.height as f32 * 0.5)) / lm.lm.height as f32
+                ));
+                (true, r)
+            }
+        };
 
         if !in_cache {
             let slice_start = (face.lightmap_offset / 3) as usize;
             let slice_end = slice_start + (lm_size_x * lm_size_y);
             let lm_slice = &bsp.lm_lump.lm[slice_start..slice_end];
+
+            let mut lm_slice_buffer = [Color32::new(0, 0, 0, 255);16*16];
+            for (dst, src) in lm_slice_buffer.iter_mut().zip(lm_slice) {
+                *dst = lm.remap(*src);
+            }
+
+            let mut lm_padded_buffer = [Color32::new(0, 0, 0, 255);LM_MAX_PADDED*LM_MAX_PADDED];
+            let padded_slice = &mut lm_padded_buffer[0..(lm_region.width * lm_region.height) as usize];
+            pad_lightmap(&lm_slice_buffer[0..lm_slice.len()], lm_size_x, lm_size_y, padded_slice);
     
-            lm.lm.set_texture_data_region(0, Some(lm_region), lm_slice);
+            lm.lm.set_texture_data_region(0, Some(lm_region), padded_slice);
         }
 
-        // hack: scale lightmap UVs inwards to avoid bilinear sampling artifacts on borders
-        // todo: should probably be padding these instead
-        let lm_region_offset = Vector2::new((lm_region.x as f32 + 0.5) / lm.lm.width as f32, (lm_region.y as f32 + 0.5) / lm.lm.height as f32);
-        let lm_region_scale = Vector2::new((lm_region.width as f32 - 1.0) / lm.lm.width as f32, (lm_region.height as f32 - 1.0) / lm.lm.height as f32);
-
-        (lm_region_offset, lm_region_scale)
+        Some(lm_region)
     }
     else {
-        (Vector2::zero(), Vector2::zero())
+        None
     };
 
+    // lightmap texels are 16 texture units apart, starting from the texture space minimum rounded down to a multiple of 16
+    let lm_tex_origin = Vector2::new((tex_min.x / 16.0).floor() * 16.0, (tex_min.y / 16.0).floor() * 16.0);
+
     // build triangle fan out of edges (note: clockwise winding)
     let idx_start = geo.len();
 
@@ -329,19 +731,17 @@ fn unpack_face(bsp: &BspFile, textures: &BspMapTextures, face_idx: usize, edge_b
             Vector3::dot(&pos, &tex_info.v_axis) + tex_info.v_offset
         );
 
-        let lm = (((tex - tex_min) / (tex_max - tex_min)) * lm_region_scale) + lm_region_offset;
-
-        match &textures.loaded_textures[tex_idx] {
-            Some(v) => {
-                let sc = Vector2::new(1.0 / v.width as f32, 1.0 / v.height as f32);
-                tex = tex * sc;
-            }
-            None => {
-                let sc = Vector2::new(1.0 / 64.0, 1.0 / 64.0);
-                tex = tex * sc;
-            }
+        let lm = match (lm_fallback_uv, lm_region) {
+            (Some(uv), _) => uv,
+            (None, Some(r)) => Vector2::new(
+                (r.x as f32 + LM_PADDING as f32 + ((tex.x - lm_tex_origin.x) / 16.0) + 0.5) / lm.lm.width as f32,
+                (r.y as f32 + LM_PADDING as f32 + ((tex.y - lm_tex_origin.y) / 16.0) + 0.5) / lm.lm.height as f32
+            ),
+            (None, None) => Vector2::zero()
         };
 
+        tex = tex * textures.tex_scale[tex_idx];
+
         let pos = Vector4::new(pos.x, pos.y, pos.z, 1.0);
 
         let vtx = MapVertex::new(pos, tex, lm, col);
@@ -370,6 +770,36 @@ fn apply_warp(warp_time: f32, geo_buff: &mut Vec<MapVertex>) {
     }
 }
 
+fn apply_flow(flow_time: f32, flow_speed: f32, geo_buff: &mut Vec<MapVertex>) {
+    // scroll along the texture's U axis, wrapped to a single repeat to avoid losing precision over time
+    let offset = -(flow_time * flow_speed).fract();
+
+    for vtx in geo_buff {
+        vtx.texcoord0.x += offset;
+    }
+}
+
+fn apply_uv_animation(flags: u32, animation_time: f32, flow_speed: f32, geo_buff: &mut Vec<MapVertex>) {
+    // flow & warp both offset UVs, so they can be combined
+    if flags & SURF_FLOW != 0 {
+        apply_flow(animation_time, flow_speed, geo_buff);
+    }
+
+    if flags & SURF_WARP != 0 {
+        apply_warp(animation_time, geo_buff);
+    }
+}
+
+fn apply_tint(tint: Color32, geo_buff: &mut Vec<MapVertex>) {
+    for vtx in geo_buff {
+        vtx.color.r = ((vtx.color.r as u32 * tint.r as u32) / 255) as u8;
+        vtx.color.g = ((vtx.color.g as u32 * tint.g as u32) / 255) as u8;
+        vtx.color.b = ((vtx.color.b as u32 * tint.b as u32) / 255) as u8;
+        vtx.color.a = ((vtx.color.a as u32 * tint.a as u32) / 255) as u8;
+    }
+}
+
+/// Set up the map VU program & layout. Fog starts out disabled, call load_cdata_fog(5, ..) afterwards to enable it
 pub fn setup_vu() {
     // set up VU program
     vdp::upload_vu_program(VU_BASIC_TRANSFORM);
@@ -379,6 +809,28 @@ pub fn setup_vu() {
     vdp::set_vu_layout(0, 0, VertexSlotFormat::FLOAT4);
     vdp::set_vu_layout(1, 16, VertexSlotFormat::FLOAT4);
     vdp::set_vu_layout(2, 32, VertexSlotFormat::UNORM4);
+
+    load_cdata_fog(5, &FogSettings::default());
+}
+
+/// Load fog parameters into six consecutive cdata slots, starting at the given slot
+pub fn load_cdata_fog(slot: usize, fog: &FogSettings) {
+    let (lin_scale, lin_bias, exp_scale) = match fog.mode {
+        FogMode::None => (0.0, 1.0, 0.0),
+        FogMode::Linear => {
+            let range = (fog.end - fog.start).max(f32::EPSILON);
+            (-1.0 / range, fog.end / range, 0.0)
+        }
+        FogMode::Exponential => (0.0, 1.0, -fog.density)
+    };
+
+    // note: w lanes are chosen so that visibility is always 1 in alpha, which leaves vertex alpha untouched
+    vdp::set_vu_cdata(slot + 0, &Vector4::new(lin_scale, lin_scale, lin_scale, 0.0));
+    vdp::set_vu_cdata(slot + 1, &Vector4::new(lin_bias, lin_bias, lin_bias, 1.0));
+    vdp::set_vu_cdata(slot + 2, &Vector4::new(exp_scale, exp_scale, exp_scale, 0.0));
+    vdp::set_vu_cdata(slot + 3, &Vector4::new(fog.color.x, fog.color.y, fog.color.z, 0.0));
+    vdp::set_vu_cdata(slot + 4, &Vector4::new(1.0, 1.0, 1.0, 1.0));
+    vdp::set_vu_cdata(slot + 5, &Vector4::zero());
 }
 
 pub fn load_cdata_matrix(slot: usize, trs: &Matrix4x4) {
@@ -407,23 +859,25 @@ fn draw_opaque_geom_setup(model: &Matrix4x4, camera_view: &Matrix4x4, camera_pro
     vdp::set_vu_cdata(4, &Vector4::zero());
 }
 
+// color a point light adds to a vertex, with linear falloff to zero at the light's radius & scaled by N.L
+fn dynamic_light_color(light_pos: &Vector3, light_color: &Vector3, light_radius: f32, normal: &Vector3, pos: &Vector3) -> Color32 {
+    let to_light = *light_pos - *pos;
+    let dist = to_light.length();
+    let falloff = (1.0 - (dist / light_radius)).max(0.0);
+    let ndotl = if dist > 0.0 { Vector3::dot(normal, &(to_light / dist)).max(0.0) } else { 1.0 };
+    let c = *light_color * (falloff * ndotl * 255.0);
+
+    Color32::new(c.x.clamp(0.0, 255.0) as u8, c.y.clamp(0.0, 255.0) as u8, c.z.clamp(0.0, 255.0) as u8, 255)
+}
+
 fn unpack_indexed(src: &[MapVertex], dst: &mut [MapVertex], idx: &[u16]) {
     for (i, v) in idx.iter().enumerate() {
         dst[i] = src[*v as usize];
     }
 }
 
-fn draw_geom(bsp: &BspFile, animation_time: f32, textures: &BspMapTextures, texture_index: usize, geo_buff: &mut Vec<MapVertex>, geo_buff2: &mut Vec<MapVertex>, m: &Vec<MapVertex>, idx: &Vec<u16>, lm: &LmAtlasPacker) {
-    match &textures.loaded_textures[texture_index] {
-        Some(v) => {
-            vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, Some(v));
-            vdp::set_sample_params_slot(TextureUnit::TU0, vdp::TextureFilter::Linear, vdp::TextureWrap::Repeat, vdp::TextureWrap::Repeat);
-        }
-        None => {
-            vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, Some(&textures.err_tex));
-            vdp::set_sample_params_slot(TextureUnit::TU0, vdp::TextureFilter::Nearest, vdp::TextureWrap::Repeat, vdp::TextureWrap::Repeat);
-        }
-    };
+fn draw_geom(bsp: &BspFile, animation_time: f32, textures: &BspMapTextures, texture_index: usize, geo_buff: &mut Vec<MapVertex>, geo_buff2: &mut Vec<MapVertex>, m: &[MapVertex], idx: &[u16], lm: &LmAtlasPacker) {
+    textures.bind_texture(bsp, texture_index);
 
     if m.len() > 0 {
         geo_buff.clear();
@@ -433,20 +887,157 @@ fn draw_geom(bsp: &BspFile, animation_time: f32, textures: &BspMapTextures, text
         geo_buff2.reserve(idx.len());
         unsafe { geo_buff2.set_len(idx.len()) };
 
-        if bsp.tex_info_lump.textures[texture_index].flags & SURF_WARP != 0 {
-            apply_warp(animation_time, geo_buff);
+        apply_uv_animation(bsp.tex_info_lump.textures[texture_index].flags, animation_time, textures.flow_speed, geo_buff);
+
+        // apply per-surface parameters from texinfo value, if any
+        let surface_value = bsp.tex_info_lump.textures[texture_index].value;
+        let surface_params = match textures.surface_value_fn {
+            Some(f) if surface_value != 0 => Some(f(surface_value)),
+            _ => None
+        };
+
+        if let Some(params) = surface_params {
+            apply_tint(params.tint, geo_buff);
+            vdp::set_vu_cdata(4, &Vector4::new(params.emissive, params.emissive, params.emissive, 0.0));
         }
 
-        if bsp.tex_info_lump.textures[texture_index].flags & SURF_NOLM == 0 {
-            vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, Some(&lm.lm));
+        // surfaces without a lightmap (or flagged fullbright) pass the texture through unmodulated, rather than being multiplied by a missing lightmap
+        let fullbright = bsp.tex_info_lump.textures[texture_index].flags & SURF_NOLM != 0 || match surface_params {
+            Some(params) => params.fullbright,
+            None => false
+        };
+
+        if fullbright {
+            vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, None);
+            vdp::set_tex_combine(vdp::TexCombine::None, vdp::TexCombine::Mul);
         }
         else {
-            vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, None);
+            vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, Some(&lm.lm));
         }
 
         unpack_indexed(geo_buff, geo_buff2, idx);
         vdp::submit_vu(vdp::Topology::TriangleList, &geo_buff2);
+
+        if fullbright {
+            vdp::set_tex_combine(vdp::TexCombine::Mul, vdp::TexCombine::Mul);
+        }
+
+        if surface_params.is_some() {
+            vdp::set_vu_cdata(4, &Vector4::zero());
+        }
+
+        draw_detail(bsp, textures, texture_index, geo_buff2);
+    }
+}
+
+// modulate a detail texture over already-lit opaque geometry, tiled at a higher frequency than the base texture
+fn draw_detail(bsp: &BspFile, textures: &BspMapTextures, texture_index: usize, geo_buff: &mut Vec<MapVertex>) {
+    let detail_tex = match detail_texture(bsp, textures, texture_index) {
+        Some(v) => v,
+        None => return
+    };
+
+    build_detail_geom(textures.detail_scale, geo_buff);
+
+    vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, Some(detail_tex));
+    vdp::set_sample_params_slot(TextureUnit::TU0, textures.filter, vdp::TextureWrap::Repeat, vdp::TextureWrap::Repeat);
+    vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, None);
+    vdp::set_tex_combine(vdp::TexCombine::None, vdp::TexCombine::Mul);
+
+    // 2x modulate (dst * src * 2), so mid-grey in the detail texture leaves the surface unchanged
+    vdp::depth_write(false);
+    vdp::blend_func(vdp::BlendFactor::DstColor, vdp::BlendFactor::SrcColor);
+
+    vdp::submit_vu(vdp::Topology::TriangleList, &geo_buff);
+
+    vdp::depth_write(true);
+    vdp::blend_func(vdp::BlendFactor::One, vdp::BlendFactor::Zero);
+    vdp::set_tex_combine(vdp::TexCombine::Mul, vdp::TexCombine::Mul);
+}
+
+// detail texture to draw over a texture's faces, if any. translucent surfaces never get detail, since modulating them would darken what's behind them too
+fn detail_texture<'a>(bsp: &BspFile, textures: &'a BspMapTextures, texture_index: usize) -> Option<&'a Arc<Texture>> {
+    if !textures.detail_enabled || bsp.tex_info_lump.textures[texture_index].flags & (SURF_TRANS33 | SURF_TRANS66) != 0 {
+        return None;
+    }
+
+    textures.detail_textures[texture_index].as_ref()
+}
+
+// retile already-unpacked geometry for the detail pass, with white vertex colors so only the detail texture modulates the surface
+fn build_detail_geom(detail_scale: f32, geo_buff: &mut Vec<MapVertex>) {
+    for vtx in geo_buff.iter_mut() {
+        vtx.texcoord0 = vtx.texcoord0 * detail_scale;
+        vtx.color = Color32::new(255, 255, 255, 255);
+    }
+}
+
+// unpack a water face's triangles with every vertex colored by the water tint
+fn build_water_tint_geom(tint: Color32, geo_buff: &mut Vec<MapVertex>, m: &[MapVertex], idx: &[u16]) {
+    geo_buff.clear();
+    geo_buff.extend(idx.iter().map(|i| {
+        let mut vtx = m[*i as usize];
+        vtx.color = tint;
+        vtx
+    }));
+}
+
+// multiply whatever's behind a water face by the water tint, before the water itself is drawn over it
+fn draw_water_tint(textures: &BspMapTextures, geo_buff: &mut Vec<MapVertex>, m: &[MapVertex], idx: &[u16]) {
+    build_water_tint_geom(textures.water_tint, geo_buff, m, idx);
+
+    vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, None);
+    vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, None);
+    vdp::blend_func(vdp::BlendFactor::DstColor, vdp::BlendFactor::Zero);
+
+    vdp::submit_vu(vdp::Topology::TriangleList, &geo_buff);
+
+    vdp::blend_func(vdp::BlendFactor::SrcAlpha, vdp::BlendFactor::OneMinusSrcAlpha);
+}
+
+// add a second, differently distorted layer of the water texture on top of a water face. the layer fades in as the view gets closer to edge-on, approximating fresnel reflection
+fn draw_water_sheen(bsp: &BspFile, textures: &BspMapTextures, texture_index: usize, animation_time: f32, camera_pos: &Vector3, normal: &Vector3, geo_buff: &mut Vec<MapVertex>, geo_buff2: &mut Vec<MapVertex>, m: &[MapVertex], idx: &[u16]) {
+    let sheen_time = animation_time * WATER_SHEEN_SPEED;
+    let tint = textures.water_tint;
+
+    geo_buff.clear();
+    geo_buff.extend_from_slice(m);
+
+    for vtx in geo_buff.iter_mut() {
+        let pos = Vector3::new(vtx.position.x, vtx.position.y, vtx.position.z);
+
+        // warp at a different scale & phase than the base layer, & drift slowly so the two layers never line up
+        let os = pos.x * 0.03;
+        let ot = pos.y * 0.03;
+
+        vtx.texcoord0.x += ((sheen_time * 1.3) + ot).sin() * 0.15 + (sheen_time * 0.05).fract();
+        vtx.texcoord0.y += (sheen_time + os).cos() * 0.15 + (sheen_time * 0.03).fract();
+
+        let mut to_eye = *camera_pos - pos;
+        if to_eye.length_sq() > f32::EPSILON {
+            to_eye.normalize();
+        }
+
+        let facing = 1.0 - Vector3::dot(&to_eye, normal).abs();
+        let fresnel = (facing * facing).min(1.0);
+
+        vtx.color = Color32::new(tint.r, tint.g, tint.b, (fresnel * 255.0) as u8);
     }
+
+    geo_buff2.clear();
+    geo_buff2.reserve(idx.len());
+    unsafe { geo_buff2.set_len(idx.len()) };
+    unpack_indexed(geo_buff, geo_buff2, idx);
+
+    textures.bind_texture(bsp, texture_index);
+    vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, None);
+    vdp::set_tex_combine(vdp::TexCombine::None, vdp::TexCombine::Mul);
+    vdp::blend_func(vdp::BlendFactor::SrcAlpha, vdp::BlendFactor::One);
+
+    vdp::submit_vu(vdp::Topology::TriangleList, &geo_buff2);
+
+    vdp::blend_func(vdp::BlendFactor::SrcAlpha, vdp::BlendFactor::OneMinusSrcAlpha);
+    vdp::set_tex_combine(vdp::TexCombine::Mul, vdp::TexCombine::Mul);
 }
 
 fn draw_transparent_geom_setup(model: &Matrix4x4, camera_view: &Matrix4x4, camera_proj: &Matrix4x4) {
@@ -468,12 +1059,39 @@ fn draw_transparent_geom_setup(model: &Matrix4x4, camera_view: &Matrix4x4, camer
 }
 
 impl BspMapTextures {
+    /// Load every texture used by the map up front
     pub fn new(bsp_file: &BspFile) -> BspMapTextures {
-        // load unique textures
-        let mut loaded_textures: Vec<Option<Arc<Texture>>> = Vec::new();
+        Self::new_immediate(bsp_file, false)
+    }
+
+    /// Load textures the first time they become visible & unload them once they've been out of view for a while, to bound texture memory on large maps.
+    /// Surfaces are drawn with the error texture until their texture is loaded
+    pub fn new_streaming(bsp_file: &BspFile) -> BspMapTextures {
+        Self::new_immediate(bsp_file, true)
+    }
+
+    // run every load step at once
+    fn new_immediate(bsp_file: &BspFile, streaming: bool) -> BspMapTextures {
+        let mut textures = Self::new_deferred(bsp_file, streaming);
+        for i in 0..bsp_file.tex_info_lump.textures.len() {
+            textures.load_step(bsp_file, i);
+        }
+
+        textures
+    }
+
+    /// Create without loading any textures, so that loading can be spread out over several frames. Call load_step for each texinfo before use
+    pub fn new_deferred(bsp_file: &BspFile, streaming: bool) -> BspMapTextures {
+        let num_textures = bsp_file.tex_info_lump.textures.len();
 
         let mut opaque_meshes: Vec<usize> = Vec::new();
-        let mut transp_meshes: Vec<usize> = Vec::new();
+
+        for (i, tex_info) in bsp_file.tex_info_lump.textures.iter().enumerate() {
+            // transparent faces are sorted & drawn individually rather than batched per texture
+            if tex_info.flags & SURF_TRANS33 == 0 && tex_info.flags & SURF_TRANS66 == 0 {
+                opaque_meshes.push(i);
+            }
+        }
 
         let err_tex = Texture::new(2, 2, false, vdp::TextureFormat::RGBA8888).unwrap();
         err_tex.set_texture_data(0, &[
@@ -481,75 +1099,234 @@ impl BspMapTextures {
             Color32::new(0, 0, 0, 255), Color32::new(255, 0, 255, 255)
         ]);
 
-        for (i, tex_info) in bsp_file.tex_info_lump.textures.iter().enumerate() {
-            if tex_info.flags & SURF_TRANS33 != 0 || tex_info.flags & SURF_TRANS66 != 0 {
-                transp_meshes.push(i);
-            }
-            else {
-                opaque_meshes.push(i);
-            }
-
-            let tex = match load_texture(format!("/cd/content/textures/{}.ktx", &tex_info.texture_name).as_str()) {
-                Ok(v) => Some(v),
-                Err(_) => None
-            };
-
-            loaded_textures.push(tex);
-        }
-
         BspMapTextures {
-            loaded_textures,
+            loaded_textures: vec![None;num_textures],
             err_tex,
             opaque_meshes,
-            transp_meshes
+            surface_value_fn: None,
+            filter: vdp::TextureFilter::Linear,
+            detail_enabled: false,
+            detail_scale: 4.0,
+            flow_speed: 0.5,
+            unload_frames: 300,
+            water_enabled: false,
+            water_tint: Color32::new(128, 192, 200, 255),
+            detail_textures: vec![None;num_textures],
+            shared_detail_tex: None,
+            tex_scale: vec![Vector2::new(1.0 / 64.0, 1.0 / 64.0);num_textures],
+            streaming,
+            resident: vec![false;num_textures],
+            last_visible: vec![0;num_textures],
+            frame: 0,
         }
     }
-}
-
-impl BspMapModelRenderer {
-    pub fn new(bsp_file: &BspFile, textures: &BspMapTextures) -> BspMapModelRenderer {
-        let mut lm_atlas = LmAtlasPacker::new(LM_SIZE);
 
-        // build models
-        let mut models = Vec::new();
-        let mut edges = Vec::new();
-        for i in 1..bsp_file.submodel_lump.submodels.len() {
-            let model = &bsp_file.submodel_lump.submodels[i];
-            let mut model_geom = Vec::new();
+    /// Load the texture for a single texinfo, as part of a deferred load.
+    /// When streaming, only the texture's size is read (which is needed to build geometry), & the texture itself is loaded the first time it becomes visible
+    pub fn load_step(self: &mut Self, bsp_file: &BspFile, texture_index: usize) {
+        if !self.streaming {
+            self.load(bsp_file, texture_index);
+            return;
+        }
 
-            let start_face_idx = model.first_face as usize;
-            let end_face_idx: usize = start_face_idx + (model.num_faces as usize);
+        let tex_info = &bsp_file.tex_info_lump.textures[texture_index];
+        if let Ok((width, height)) = load_texture_size(format!("/cd/content/textures/{}.ktx", &tex_info.texture_name).as_str()) {
+            self.tex_scale[texture_index] = Vector2::new(1.0 / width as f32, 1.0 / height as f32);
+        }
+    }
 
-            for face_idx in start_face_idx..end_face_idx {
-                let mut geom = Vec::new();
-                let mut idx = Vec::new();
+    // load the base & detail textures for the given texinfo
+    fn load(self: &mut Self, bsp_file: &BspFile, texture_index: usize) {
+        let tex_info = &bsp_file.tex_info_lump.textures[texture_index];
 
-                let face = &bsp_file.face_lump.faces[face_idx];
-                let tex_idx = face.texture_info as usize;
+        let tex = match load_texture(format!("/cd/content/textures/{}.ktx", &tex_info.texture_name).as_str()) {
+            Ok(v) => Some(v),
+            Err(_) => None
+        };
 
-                unpack_face(bsp_file, textures, face_idx, &mut edges, &mut geom, &mut idx, &mut lm_atlas);
+        if let Some(v) = &tex {
+            self.tex_scale[texture_index] = Vector2::new(1.0 / v.width as f32, 1.0 / v.height as f32);
+        }
 
-                model_geom.push((tex_idx, geom, idx));
+        // use the texture's own detail map if it has one, otherwise fall back to a shared one
+        let detail_tex = if tex_info.flags & SURF_DETAIL != 0 {
+            match load_texture(format!("/cd/content/textures/{}_detail.ktx", &tex_info.texture_name).as_str()) {
+                Ok(v) => Some(v),
+                Err(_) => self.shared_detail_tex.get_or_insert_with(|| {
+                    match load_texture("/cd/content/textures/detail.ktx") {
+                        Ok(v) => Some(v),
+                        Err(_) => None
+                    }
+                }).clone()
             }
-
-            models.push(Model {
-                geometry: model_geom
-            });
         }
+        else {
+            None
+        };
 
-        BspMapModelRenderer { models, lm_atlas, geo_buff: Vec::with_capacity(1024), geo_buff2: Vec::with_capacity(1024) }
+        self.loaded_textures[texture_index] = tex;
+        self.detail_textures[texture_index] = detail_tex;
+        self.resident[texture_index] = true;
     }
 
-    /// Call each frame before rendering. Updates lightmap animation
-    pub fn update(self: &BspMapModelRenderer, light_layers: &[f32;NUM_CUSTOM_LIGHT_LAYERS], bsp: &BspFile, animation_time: f32) {
-        update_lm_animation(light_layers, animation_time, &self.lm_atlas, bsp);
+    // drop this map's references to the base & detail textures for the given texinfo. the texture is freed once nothing else references it
+    fn unload(self: &mut Self, texture_index: usize) {
+        self.loaded_textures[texture_index] = None;
+        self.detail_textures[texture_index] = None;
+        self.resident[texture_index] = false;
     }
 
-    /// Draw the opaque parts of a given map model
-    pub fn draw_model_opaque(self: &mut Self, bsp: &BspFile, animation_time: f32, textures: &BspMapTextures, model_idx: usize, model_transform: &Matrix4x4, camera_view: &Matrix4x4, camera_proj: &Matrix4x4) {
-        let model = &self.models[model_idx];
+    // record that a texture is visible this frame, loading it if needed
+    fn touch(self: &mut Self, bsp_file: &BspFile, texture_index: usize) {
+        self.last_visible[texture_index] = self.frame;
 
-        draw_opaque_geom_setup(model_transform, camera_view, camera_proj);
+        if !self.resident[texture_index] {
+            self.load(bsp_file, texture_index);
+        }
+    }
+
+    /// Whether textures are streamed in & out as they become visible, rather than all loaded up front
+    pub fn is_streaming(self: &Self) -> bool {
+        self.streaming
+    }
+
+    /// Number of texinfos whose textures are currently loaded
+    pub fn resident_count(self: &Self) -> usize {
+        self.resident.iter().filter(|x| **x).count()
+    }
+
+    /// When streaming, mark textures used by the renderer's current geometry as visible, loading any which aren't loaded yet. Call after updating the renderer
+    pub fn mark_visible(self: &mut Self, bsp_file: &BspFile, renderer: &BspMapRenderer) {
+        if !self.streaming {
+            return;
+        }
+
+        for i in 0..renderer.mesh_vertices.len() {
+            if renderer.mesh_vertices[i].len() > 0 {
+                self.touch(bsp_file, i);
+            }
+        }
+    }
+
+    /// When streaming, mark textures used by the given map model as visible, loading any which aren't loaded yet
+    pub fn mark_model_visible(self: &mut Self, bsp_file: &BspFile, models: &BspMapModelRenderer, model_idx: usize) {
+        if !self.streaming {
+            return;
+        }
+
+        for (tex_idx, _, _) in &models.models[model_idx].geometry {
+            self.touch(bsp_file, *tex_idx);
+        }
+    }
+
+    /// When streaming, call once per frame after all cameras have been drawn. Unloads textures which haven't been visible for unload_frames frames
+    pub fn end_frame(self: &mut Self) {
+        if !self.streaming {
+            return;
+        }
+
+        for i in 0..self.resident.len() {
+            if self.resident[i] && self.frame.wrapping_sub(self.last_visible[i]) >= self.unload_frames {
+                self.unload(i);
+            }
+        }
+
+        self.frame = self.frame.wrapping_add(1);
+    }
+
+    /// Bind the given texture to TU0, with sample params chosen by the global filter preference & the surface's flags
+    fn bind_texture(self: &Self, bsp: &BspFile, texture_index: usize) {
+        let flags = bsp.tex_info_lump.textures[texture_index].flags;
+
+        let filter = if flags & SURF_NEAREST != 0 { vdp::TextureFilter::Nearest } else { self.filter };
+        let wrap = if flags & SURF_CLAMP != 0 { vdp::TextureWrap::Clamp } else { vdp::TextureWrap::Repeat };
+
+        match &self.loaded_textures[texture_index] {
+            Some(v) => {
+                vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, Some(v));
+                vdp::set_sample_params_slot(TextureUnit::TU0, filter, wrap, wrap);
+            }
+            None => {
+                vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, Some(&self.err_tex));
+                vdp::set_sample_params_slot(TextureUnit::TU0, vdp::TextureFilter::Nearest, vdp::TextureWrap::Repeat, vdp::TextureWrap::Repeat);
+            }
+        };
+    }
+}
+
+impl BspMapModelRenderer {
+    pub fn new(bsp_file: &BspFile, textures: &BspMapTextures, lm_settings: &LightmapSettings) -> BspMapModelRenderer {
+        let mut renderer = Self::new_deferred(lm_settings);
+        while renderer.build_step(bsp_file, textures) {
+        }
+
+        renderer
+    }
+
+    /// Create without building any models, so that building can be spread out over several frames. Call build_step until it returns false before use
+    pub fn new_deferred(lm_settings: &LightmapSettings) -> BspMapModelRenderer {
+        BspMapModelRenderer {
+            models: Vec::new(),
+            lm_atlas: LmAtlasPacker::new(LM_SIZE, lm_settings),
+            geo_buff: Vec::with_capacity(1024),
+            geo_buff2: Vec::with_capacity(1024)
+        }
+    }
+
+    /// Build the next model, as part of a deferred build. Returns false once all models have been built
+    pub fn build_step(self: &mut Self, bsp_file: &BspFile, textures: &BspMapTextures) -> bool {
+        // submodel 0 is the world, which is drawn by BspMapRenderer instead
+        let i = self.models.len() + 1;
+        if i >= bsp_file.submodel_lump.submodels.len() {
+            return false;
+        }
+
+        let model = &bsp_file.submodel_lump.submodels[i];
+        let mut model_geom = Vec::new();
+        let mut edges = Vec::new();
+
+        let start_face_idx = model.first_face as usize;
+        let end_face_idx: usize = start_face_idx + (model.num_faces as usize);
+
+        for face_idx in start_face_idx..end_face_idx {
+            let mut geom = Vec::new();
+            let mut idx = Vec::new();
+
+            let face = &bsp_file.face_lump.faces[face_idx];
+            let tex_idx = face.texture_info as usize;
+
+            unpack_face(bsp_file, textures, face_idx, &mut edges, &mut geom, &mut idx, &mut self.lm_atlas);
+
+            model_geom.push((tex_idx, geom, idx));
+        }
+
+        if self.lm_atlas.overflow {
+            log("Out of room in model lightmap atlas, some model faces will be drawn fullbright");
+            self.lm_atlas.overflow = false;
+        }
+
+        self.models.push(Model {
+            geometry: model_geom
+        });
+
+        true
+    }
+
+    /// Number of models built so far
+    pub fn model_count(self: &Self) -> usize {
+        self.models.len()
+    }
+
+    /// Call each frame before rendering. Updates lightmap animation
+    pub fn update(self: &BspMapModelRenderer, light_layers: &[f32;NUM_CUSTOM_LIGHT_LAYERS], bsp: &BspFile, animation_time: f32) {
+        update_lm_animation(light_layers, animation_time, &self.lm_atlas, bsp);
+    }
+
+    /// Draw the opaque parts of a given map model
+    pub fn draw_model_opaque(self: &mut Self, bsp: &BspFile, animation_time: f32, textures: &BspMapTextures, model_idx: usize, model_transform: &Matrix4x4, camera_view: &Matrix4x4, camera_proj: &Matrix4x4) {
+        let model = &self.models[model_idx];
+
+        draw_opaque_geom_setup(model_transform, camera_view, camera_proj);
 
         for (i, m, idx) in &model.geometry {
             let tex_info = &bsp.tex_info_lump.textures[*i];
@@ -577,112 +1354,406 @@ impl BspMapModelRenderer {
 }
 
 impl BspMapRenderer {
-    pub fn new(bsp_file: &BspFile) -> BspMapRenderer {
+    pub fn new(bsp_file: &BspFile, lm_settings: &LightmapSettings) -> BspMapRenderer {
         let num_clusters = bsp_file.vis_lump.clusters.len();
         let num_leaves = bsp_file.leaf_lump.leaves.len();
         let num_textures = bsp_file.tex_info_lump.textures.len();
         let num_faces = bsp_file.face_lump.faces.len();
+        let num_areas = bsp_file.area_lump.areas.len();
 
-        let lm_atlas = LmAtlasPacker::new(LM_SIZE);
+        let lm_atlas = LmAtlasPacker::new(LM_SIZE, lm_settings);
 
         BspMapRenderer {
             vis: vec![false;num_clusters],
             visible_leaves: vec![false;num_leaves],
+            visible_areas: vec![true;num_areas],
             mesh_vertices: vec![Vec::new();num_textures],
             mesh_indices: vec![Vec::new();num_textures],
             drawn_faces: vec![false;num_faces],
-            prev_leaf: -1,
+            transp_faces: Vec::new(),
+            build_vertices: vec![Vec::new();num_textures],
+            build_indices: vec![Vec::new();num_textures],
+            build_transp_faces: Vec::new(),
+            build_nearest: vec![f32::INFINITY;num_textures],
+            opaque_order: Vec::new(),
+            mesh_cluster: u16::MAX,
+            mesh_areaportal_states: Vec::new(),
+            mesh_reusable: false,
+            geo_cache: GeometryCache::new(GEO_CACHE_SIZE),
+            build_leaves: vec![false;num_leaves],
+            build_cursor: None,
+            build_position: Vector3::zero(),
+            has_geometry: false,
+            build_count: 0,
+            build_time: 0.0,
+            face_idx_buff: Vec::new(),
+            vis_cache: Vec::with_capacity(VIS_CACHE_SIZE),
+            prev_cluster: None,
+            prev_frustum: Vec::new(),
+            prev_areaportal_states: Vec::new(),
             lm_atlas,
             geo_buff: Vec::with_capacity(1024),
             geo_buff2: Vec::with_capacity(1024),
+            node_tests: 0,
         }
     }
 
-    fn update_leaf(bsp: &BspFile, leaf_index: usize, visible_clusters: &[bool], visible_leaves: &mut [bool]) {
+    fn update_leaf(bsp: &BspFile, leaf_index: usize, visible_clusters: &[bool], visible_areas: &[bool], visible_leaves: &mut [bool]) {
         let leaf = &bsp.leaf_lump.leaves[leaf_index];
         if leaf.cluster == u16::MAX {
             return;
         }
 
-        if visible_clusters[leaf.cluster as usize] {
+        // leaves in areas cut off by closed areaportals are not visible, even if they are in the PVS
+        let area_visible = visible_areas.get(leaf.area as usize).copied().unwrap_or(true);
+
+        if visible_clusters[leaf.cluster as usize] && area_visible {
             visible_leaves[leaf_index] = true;
         }
     }
 
-    fn update_recursive(bsp: &BspFile, cur_node: i32, frustum: &[Vector4], visible_clusters: &[bool], visible_leaves: &mut [bool]) {
+    // returns the number of nodes which were tested against the frustum
+    fn update_recursive(bsp: &BspFile, cur_node: i32, frustum: &[Vector4], fully_inside: bool, visible_clusters: &[bool], visible_areas: &[bool], visible_leaves: &mut [bool]) -> usize {
         if cur_node < 0 {
-            Self::update_leaf(bsp, (-cur_node - 1) as usize, visible_clusters, visible_leaves);
-            return;
+            Self::update_leaf(bsp, (-cur_node - 1) as usize, visible_clusters, visible_areas, visible_leaves);
+            return 0;
         }
 
         let node = &bsp.node_lump.nodes[cur_node as usize];
 
-        if !aabb_frustum(node._bbox_min, node._bbox_max, frustum) {
-            return;
+        // once a node is entirely inside the frustum, so are all of its children & they don't need to be tested
+        let (fully_inside, node_tests) = if fully_inside {
+            (true, 0)
         }
+        else {
+            match aabb_frustum_classify(node._bbox_min, node._bbox_max, frustum) {
+                FrustumTest::Outside => return 1,
+                FrustumTest::Intersect => (false, 1),
+                FrustumTest::Inside => (true, 1),
+            }
+        };
 
-        Self::update_recursive(bsp, node.front_child, frustum, visible_clusters, visible_leaves);
-        Self::update_recursive(bsp, node.back_child, frustum, visible_clusters, visible_leaves);
+        node_tests
+            + Self::update_recursive(bsp, node.front_child, frustum, fully_inside, visible_clusters, visible_areas, visible_leaves)
+            + Self::update_recursive(bsp, node.back_child, frustum, fully_inside, visible_clusters, visible_areas, visible_leaves)
     }
 
-    /// Call each frame before rendering. Recalculates visible leaves, rebuilds geometry and lightmap atlas, & updates lightmap animation
-    pub fn update(self: &mut Self, frustum: &[Vector4], anim_time: f32, light_layers: &[f32;NUM_CUSTOM_LIGHT_LAYERS], bsp: &BspFile, textures: &BspMapTextures, position: &Vector3) {
+    /// Call each frame before rendering. Recalculates visible leaves, rebuilds geometry and lightmap atlas (if the camera's cluster or areaportal states have changed), & updates lightmap animation
+    /// 
+    /// Geometry covers everything potentially visible from the camera's cluster, & is cached for recently visited clusters so that going back & forth between them doesn't rebuild it.
+    /// Otherwise it is rebuilt incrementally over several frames, and the previous geometry is drawn until the new geometry is ready
+    pub fn update(self: &mut Self, frustum: &[Vector4], anim_time: f32, light_layers: &[f32;NUM_CUSTOM_LIGHT_LAYERS], areaportal_states: &[bool], bsp: &BspFile, textures: &BspMapTextures, position: &Vector3) {
         let leaf_index = bsp.calc_leaf_index(position);
         let leaf = &bsp.leaf_lump.leaves[leaf_index as usize];
 
-        // if camera enters a new cluster, unpack new cluster's visibility info
-        if leaf_index != self.prev_leaf {
-            self.prev_leaf = leaf_index;
-            
-            self.vis.fill(false);
-            if leaf.cluster != u16::MAX {
-                bsp.vis_lump.unpack_vis(leaf.cluster as usize, &mut self.vis);
-            }
+        self.build_time = 0.0;
+
+        // if the camera hasn't moved or rotated, the cluster is the same, & no areaportals have changed, the visible leaves & geometry from last frame can be reused as-is
+        let frustum_changed = self.prev_frustum.len() != frustum.len() || self.prev_frustum.iter().zip(frustum)
+            .any(|(a, b)| a.x != b.x || a.y != b.y || a.z != b.z || a.w != b.w);
+        let cluster_changed = self.prev_cluster != Some(leaf.cluster);
+        let areaportals_changed = self.prev_areaportal_states.as_slice() != areaportal_states;
+        let dirty = frustum_changed || cluster_changed || areaportals_changed;
+
+        if !dirty {
+            self.node_tests = 0;
+            self.step_build(bsp, textures, position);
+            update_lm_animation(light_layers, anim_time, &self.lm_atlas, bsp);
+            return;
+        }
+
+        self.prev_frustum.clear();
+        self.prev_frustum.extend_from_slice(frustum);
+        self.prev_areaportal_states.clear();
+        self.prev_areaportal_states.extend_from_slice(areaportal_states);
+
+        // if camera enters a new cluster, fetch new cluster's visibility info
+        if cluster_changed {
+            self.prev_cluster = Some(leaf.cluster);
+            self.update_vis(bsp, leaf.cluster);
+        }
 
-            // clear lightmap cache
-            self.lm_atlas.reset();
+        // flood fill areas reachable from the camera through open areaportals
+        // area 0 is outside the map, in which case don't cull by area at all
+        if leaf.area == 0 {
+            self.visible_areas.fill(true);
+        }
+        else {
+            bsp.flood_areas(leaf.area as usize, areaportal_states, &mut self.visible_areas);
         }
 
         self.visible_leaves.fill(false);
-        Self::update_recursive(bsp, 0, frustum, &self.vis, &mut self.visible_leaves);
+        self.node_tests = Self::update_recursive(bsp, 0, frustum, false, &self.vis, &self.visible_areas, &mut self.visible_leaves);
+
+        // geometry isn't culled by the frustum, so only needs replacing if the camera's cluster or areaportals change
+        if cluster_changed || areaportals_changed || !self.has_geometry {
+            self.select_geometry(bsp, textures, leaf.cluster, areaportal_states, position);
+        }
+        else {
+            self.step_build(bsp, textures, position);
+        }
+
+        update_lm_animation(light_layers, anim_time, &self.lm_atlas, bsp);
+    }
+
+    // unpack visibility info for the given cluster, reusing cached info if the cluster was visited recently
+    fn update_vis(self: &mut Self, bsp: &BspFile, cluster: u16) {
+        match self.vis_cache.iter().position(|(c, _)| *c == cluster) {
+            Some(i) => {
+                let entry = self.vis_cache.remove(i);
+                self.vis.copy_from_slice(&entry.1);
+                self.vis_cache.push(entry);
+            }
+            None => {
+                self.vis.fill(false);
+                if cluster != u16::MAX {
+                    bsp.vis_lump.unpack_vis(cluster as usize, &mut self.vis);
+                }
+
+                // evict least recently used cluster
+                if self.vis_cache.len() >= VIS_CACHE_SIZE {
+                    self.vis_cache.remove(0);
+                }
+
+                self.vis_cache.push((cluster, self.vis.clone()));
+            }
+        }
+    }
+
+    // switch to geometry for the given cluster & areaportal states, reusing it from the cache if possible & otherwise starting a build
+    fn select_geometry(self: &mut Self, bsp: &BspFile, textures: &BspMapTextures, cluster: u16, areaportal_states: &[bool], position: &Vector3) {
+        // the camera returned to the view being drawn before the build for another view finished
+        if self.has_geometry && self.mesh_reusable && self.mesh_cluster == cluster && self.mesh_areaportal_states.as_slice() == areaportal_states {
+            self.build_cursor = None;
+            return;
+        }
+
+        if let Some(entry) = self.geo_cache.take(cluster, areaportal_states) {
+            self.build_cursor = None;
+            self.swap_geometry(entry);
+            return;
+        }
 
-        // build geometry for visible leaves
-        for m in &mut self.mesh_vertices {
+        if !self.has_geometry {
+            self.build_geometry(bsp, textures, position);
+        }
+        else if self.lm_atlas.usage() >= LM_ATLAS_RESET_USAGE {
+            // lightmap regions packed for cached geometry are kept around until the atlas starts running out of room.
+            // the drawn geometry's regions must stay valid until it's replaced, so resetting the atlas forces a full rebuild this frame
+            self.reset_atlas();
+            self.build_geometry(bsp, textures, position);
+        }
+        else {
+            self.begin_build(bsp, position);
+            self.step_build(bsp, textures, position);
+        }
+    }
+
+    // replace the drawn geometry, caching the old geometry if it's still usable. returns geometry whose buffers can be reused, if any
+    fn swap_geometry(self: &mut Self, entry: ClusterGeometry) -> Option<ClusterGeometry> {
+        let prev = ClusterGeometry {
+            cluster: self.mesh_cluster,
+            areaportal_states: std::mem::replace(&mut self.mesh_areaportal_states, entry.areaportal_states),
+            vertices: std::mem::replace(&mut self.mesh_vertices, entry.vertices),
+            indices: std::mem::replace(&mut self.mesh_indices, entry.indices),
+            transp_faces: std::mem::replace(&mut self.transp_faces, entry.transp_faces),
+            opaque_order: std::mem::replace(&mut self.opaque_order, entry.opaque_order),
+        };
+
+        let prev_reusable = self.has_geometry && self.mesh_reusable;
+
+        self.mesh_cluster = entry.cluster;
+        self.mesh_reusable = true;
+        self.has_geometry = true;
+
+        if prev_reusable {
+            self.geo_cache.insert(prev)
+        }
+        else {
+            Some(prev)
+        }
+    }
+
+    // clear out the lightmap atlas, which invalidates all built geometry
+    fn reset_atlas(self: &mut Self) {
+        self.lm_atlas.reset();
+        self.geo_cache.clear();
+        self.mesh_reusable = false;
+    }
+
+    // synchronously build geometry & lightmap atlas regions for all potentially visible leaves
+    fn build_geometry(self: &mut Self, bsp: &BspFile, textures: &BspMapTextures, position: &Vector3) {
+        self.begin_build(bsp, position);
+        self.continue_build(bsp, textures, usize::MAX);
+
+        // if the atlas filled up partway through, clear it out & rebuild everything into the empty atlas
+        if self.lm_atlas.overflow {
+            self.reset_atlas();
+            self.begin_build(bsp, position);
+            self.continue_build(bsp, textures, usize::MAX);
+
+            // still too many lightmaps to fit at once. faces which didn't fit sample the atlas' fullbright region instead
+            if self.lm_atlas.overflow {
+                log("Out of room in lightmap atlas, some faces will be drawn fullbright");
+                self.lm_atlas.overflow = false;
+            }
+        }
+    }
+
+    // start building geometry for the leaves potentially visible from the current cluster, discarding any build in progress
+    fn begin_build(self: &mut Self, bsp: &BspFile, position: &Vector3) {
+        for m in &mut self.build_vertices {
             m.clear();
         }
 
-        for idx in &mut self.mesh_indices {
+        for idx in &mut self.build_indices {
             idx.clear();
         }
 
-        let mut edges: Vec<Edge> = Vec::new();
+        self.build_nearest.fill(f32::INFINITY);
 
         // faces might be shared by multiple leaves. keep track of them so we don't draw them more than once
         self.drawn_faces.fill(false);
+        self.build_transp_faces.clear();
 
-        for i in 0..self.visible_leaves.len() {
-            if self.visible_leaves[i] {
-                let leaf = &bsp.leaf_lump.leaves[i];
-                let start_face_idx = leaf.first_leaf_face as usize;
-                let end_face_idx: usize = start_face_idx + (leaf.num_leaf_faces as usize);
+        self.build_leaves.fill(false);
+        for i in 0..self.build_leaves.len() {
+            Self::update_leaf(bsp, i, &self.vis, &self.visible_areas, &mut self.build_leaves);
+        }
 
-                for leaf_face in start_face_idx..end_face_idx {
-                    let face_idx = bsp.leaf_face_lump.faces[leaf_face] as usize;
+        self.build_cursor = Some(0);
+        self.build_position = *position;
+        self.build_count += 1;
+    }
 
-                    if self.drawn_faces[face_idx] {
-                        continue;
-                    }
+    // advance the build in progress (if any) by a frame's worth of work
+    fn step_build(self: &mut Self, bsp: &BspFile, textures: &BspMapTextures, position: &Vector3) {
+        if self.build_cursor.is_none() {
+            return;
+        }
+
+        self.continue_build(bsp, textures, BUILD_FACES_PER_FRAME);
+
+        // regions from the drawn geometry can't be evicted, so if the atlas fills up just rebuild everything at once
+        if self.lm_atlas.overflow {
+            self.reset_atlas();
+            self.build_geometry(bsp, textures, position);
+        }
+    }
+
+    // unpack faces of visible leaves from the build cursor onwards until roughly face_budget faces have been unpacked. swaps in the new geometry once complete
+    fn continue_build(self: &mut Self, bsp: &BspFile, textures: &BspMapTextures, face_budget: usize) {
+        let mut cursor = match self.build_cursor {
+            Some(v) => v,
+            None => return
+        };
+
+        let start_time = audio::get_time();
+        let mut edges: Vec<Edge> = Vec::new();
+        let mut num_faces = 0;
+
+        while cursor < self.build_leaves.len() && num_faces < face_budget {
+            let i = cursor;
+            cursor += 1;
+
+            if !self.build_leaves[i] {
+                continue;
+            }
+
+            let leaf = &bsp.leaf_lump.leaves[i];
+            let start_face_idx = leaf.first_leaf_face as usize;
+            let end_face_idx: usize = start_face_idx + (leaf.num_leaf_faces as usize);
+
+            for leaf_face in start_face_idx..end_face_idx {
+                let face_idx = bsp.leaf_face_lump.faces[leaf_face] as usize;
+
+                if self.drawn_faces[face_idx] {
+                    continue;
+                }
+
+                self.drawn_faces[face_idx] = true;
+                num_faces += 1;
 
-                    self.drawn_faces[face_idx] = true;
+                let face = &bsp.face_lump.faces[face_idx];
+                let tex_idx = face.texture_info as usize;
+
+                let vtx_start = self.build_vertices[tex_idx].len();
+                let idx_start = self.build_indices[tex_idx].len();
+
+                unpack_face(bsp, textures, face_idx, &mut edges, &mut self.build_vertices[tex_idx], &mut self.build_indices[tex_idx], &mut self.lm_atlas);
+
+                let vtx_end = self.build_vertices[tex_idx].len();
+                let idx_end = self.build_indices[tex_idx].len();
+
+                for v in &self.build_vertices[tex_idx][vtx_start..vtx_end] {
+                    let dist_sq = (Vector3::new(v.position.x, v.position.y, v.position.z) - self.build_position).length_sq();
+                    self.build_nearest[tex_idx] = self.build_nearest[tex_idx].min(dist_sq);
+                }
 
-                    let face = &bsp.face_lump.faces[face_idx];
-                    let tex_idx = face.texture_info as usize;
-                    unpack_face(bsp, textures, face_idx, &mut edges, &mut self.mesh_vertices[tex_idx], &mut self.mesh_indices[tex_idx], &mut self.lm_atlas);
+                // keep a per-face list of transparent faces so they can be sorted
+                let flags = bsp.tex_info_lump.textures[tex_idx].flags;
+                if (flags & SURF_TRANS33 != 0 || flags & SURF_TRANS66 != 0) && vtx_end > vtx_start {
+                    let mut centroid = Vector3::zero();
+                    for v in &self.build_vertices[tex_idx][vtx_start..vtx_end] {
+                        centroid = centroid + Vector3::new(v.position.x, v.position.y, v.position.z);
+                    }
+                    centroid = centroid / (vtx_end - vtx_start) as f32;
+
+                    // water surfaces are warping faces with water on either side
+                    let normal = bsp.face_normal(face_idx);
+                    let water = flags & SURF_WARP != 0 && (bsp.point_contents(&(centroid + (normal * 2.0))) | bsp.point_contents(&(centroid - (normal * 2.0)))) & CONTENTS_WATER != 0;
+
+                    self.build_transp_faces.push(TransparentFace {
+                        tex_idx,
+                        vtx_start,
+                        vtx_end,
+                        idx_start,
+                        idx_end,
+                        centroid,
+                        water,
+                        normal,
+                    });
                 }
             }
         }
 
-        update_lm_animation(light_layers, anim_time, &self.lm_atlas, bsp);
+        self.build_time += (audio::get_time() - start_time) as f32;
+
+        if cursor < self.build_leaves.len() {
+            self.build_cursor = Some(cursor);
+            return;
+        }
+
+        // sort opaque batches front to back (only used if requested when drawing)
+        let indices = &self.build_indices;
+        let nearest = &self.build_nearest;
+        let mut opaque_order: Vec<usize> = textures.opaque_meshes.iter().copied().filter(|i| indices[*i].len() > 0).collect();
+        opaque_order.sort_by(|a, b| nearest[*a].total_cmp(&nearest[*b]));
+
+        // build complete, swap in the new geometry. builds are always for the current view, as changing it starts over
+        let entry = ClusterGeometry {
+            cluster: self.prev_cluster.unwrap_or(u16::MAX),
+            areaportal_states: self.prev_areaportal_states.clone(),
+            vertices: std::mem::take(&mut self.build_vertices),
+            indices: std::mem::take(&mut self.build_indices),
+            transp_faces: std::mem::take(&mut self.build_transp_faces),
+            opaque_order,
+        };
+
+        // reuse buffers from geometry which was evicted from the cache for the next build
+        match self.swap_geometry(entry) {
+            Some(recycled) => {
+                self.build_vertices = recycled.vertices;
+                self.build_indices = recycled.indices;
+                self.build_transp_faces = recycled.transp_faces;
+            }
+            None => {
+                self.build_vertices = vec![Vec::new();self.mesh_vertices.len()];
+                self.build_indices = vec![Vec::new();self.mesh_indices.len()];
+            }
+        }
+
+        self.build_cursor = None;
     }
 
     fn get_bounds_corners(center: Vector3, extents: Vector3) -> [Vector3;8] {
@@ -754,18 +1825,61 @@ impl BspMapRenderer {
         return self.check_vis_recursive(bsp, 0, center, extents, &corners);
     }
 
+    /// Sample the baked lightmap of a face, see LmAtlasPacker::sample_face
+    pub fn sample_face_lightmap(self: &Self, bsp: &BspFile, face_index: usize, uv: Vector2) -> Color32 {
+        self.lm_atlas.sample_face(bsp, face_index, uv)
+    }
+
     pub fn is_leaf_visible(self: &Self, leaf_index: usize) -> bool {
         return self.visible_leaves[leaf_index];
     }
 
+    /// Number of leaves found visible by the last update
+    pub fn visible_leaf_count(self: &Self) -> usize {
+        self.visible_leaves.iter().filter(|x| **x).count()
+    }
+
+    /// Number of BSP nodes tested against the frustum by the last update
+    pub fn node_test_count(self: &Self) -> usize {
+        self.node_tests
+    }
+
+    /// Number of map triangles (opaque + transparent) built by the last update
+    pub fn triangle_count(self: &Self) -> usize {
+        self.mesh_indices.iter().map(|x| x.len()).sum::<usize>() / 3
+    }
+
+    /// Fraction of this renderer's lightmap atlas in use
+    pub fn lightmap_usage(self: &Self) -> f32 {
+        self.lm_atlas.usage()
+    }
+
+    /// Texture memory used by this renderer's lightmap atlas, in bytes
+    pub fn lightmap_memory(self: &Self) -> usize {
+        self.lm_atlas.memory_size()
+    }
+
+    /// Time in seconds spent building geometry during the last update
+    pub fn build_time(self: &Self) -> f32 {
+        self.build_time
+    }
+
+    /// Number of times map geometry has been built from scratch, rather than reused from the per-cluster cache
+    pub fn build_count(self: &Self) -> usize {
+        self.build_count
+    }
+
     /// After updating a map, call this to render opaque geometry
-    pub fn draw_opaque(self: &mut Self, bsp: &BspFile, textures: &BspMapTextures, animation_time: f32, camera_view: &Matrix4x4, camera_proj: &Matrix4x4) {
+    /// Draw opaque map geometry. Batches are drawn in texture order, or if front_to_back is set, ordered by how close they come to the camera so that the depth test can reject hidden surfaces sooner
+    pub fn draw_opaque(self: &mut Self, bsp: &BspFile, textures: &BspMapTextures, animation_time: f32, front_to_back: bool, camera_view: &Matrix4x4, camera_proj: &Matrix4x4) {
         draw_opaque_geom_setup(&Matrix4x4::identity(), camera_view, camera_proj);
 
         // bind lightmap texture
         vdp::bind_texture_slot(TextureUnit::TU1, Some(&self.lm_atlas.lm));
 
-        for i in &textures.opaque_meshes {
+        let order = if front_to_back { &self.opaque_order } else { &textures.opaque_meshes };
+
+        for i in order {
             let m = &self.mesh_vertices[*i];
             let idx = &self.mesh_indices[*i];
 
@@ -773,15 +1887,524 @@ impl BspMapRenderer {
         }
     }
 
+    /// After drawing opaque geometry, call this to additively blend dynamic point lights (position, color, radius) onto visible map surfaces
+    pub fn draw_dynamic_lights(self: &mut Self, bsp: &BspFile, textures: &BspMapTextures, lights: &[(Vector3, Vector3, f32)], camera_view: &Matrix4x4, camera_proj: &Matrix4x4) {
+        if lights.len() == 0 {
+            return;
+        }
+
+        draw_opaque_geom_setup(&Matrix4x4::identity(), camera_view, camera_proj);
+
+        // accumulate on top of the base + lightmap pass
+        vdp::depth_write(false);
+        vdp::blend_func(vdp::BlendFactor::One, vdp::BlendFactor::One);
+        vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, None);
+        vdp::set_tex_combine(vdp::TexCombine::None, vdp::TexCombine::Mul);
+
+        for (light_pos, light_color, light_radius) in lights {
+            let light_min = *light_pos - Vector3::new(*light_radius, *light_radius, *light_radius);
+            let light_max = *light_pos + Vector3::new(*light_radius, *light_radius, *light_radius);
+
+            // only meshes with visible faces this frame
+            for i in &self.opaque_order {
+                // surfaces without lightmaps (warp, nodraw, etc) don't receive dynamic lights either
+                if bsp.tex_info_lump.textures[*i].flags & SURF_NOLM != 0 {
+                    continue;
+                }
+
+                let m = &self.mesh_vertices[*i];
+                let idx = &self.mesh_indices[*i];
+
+                self.geo_buff2.clear();
+
+                for tri in idx.chunks_exact(3) {
+                    let v0 = m[tri[0] as usize];
+                    let v1 = m[tri[1] as usize];
+                    let v2 = m[tri[2] as usize];
+
+                    let p0 = Vector3::new(v0.position.x, v0.position.y, v0.position.z);
+                    let p1 = Vector3::new(v1.position.x, v1.position.y, v1.position.z);
+                    let p2 = Vector3::new(v2.position.x, v2.position.y, v2.position.z);
+
+                    let tri_min = Vector3::new(p0.x.min(p1.x).min(p2.x), p0.y.min(p1.y).min(p2.y), p0.z.min(p1.z).min(p2.z));
+                    let tri_max = Vector3::new(p0.x.max(p1.x).max(p2.x), p0.y.max(p1.y).max(p2.y), p0.z.max(p1.z).max(p2.z));
+
+                    if !aabb_aabb_intersects(light_min, light_max, tri_min, tri_max) {
+                        continue;
+                    }
+
+                    // faces are wound clockwise, so the front-facing normal is (p2 - p0) x (p1 - p0)
+                    let normal = Vector3::cross(&(p2 - p0), &(p1 - p0)).normalized();
+
+                    if Vector3::dot(&normal, &(*light_pos - p0)) <= 0.0 {
+                        continue;
+                    }
+
+                    for (vtx, pos) in [(v0, p0), (v1, p1), (v2, p2)] {
+                        let mut vtx = vtx;
+                        vtx.color = dynamic_light_color(light_pos, light_color, *light_radius, &normal, &pos);
+                        self.geo_buff2.push(vtx);
+                    }
+                }
+
+                if self.geo_buff2.len() == 0 {
+                    continue;
+                }
+
+                textures.bind_texture(bsp, *i);
+
+                vdp::submit_vu(vdp::Topology::TriangleList, &self.geo_buff2);
+            }
+        }
+
+        vdp::depth_write(true);
+        vdp::blend_func(vdp::BlendFactor::One, vdp::BlendFactor::Zero);
+        vdp::set_tex_combine(vdp::TexCombine::Mul, vdp::TexCombine::Mul);
+    }
+
     /// After updating a map, call this to render transparent geometry
-    pub fn draw_transparent(self: &mut Self, bsp: &BspFile, textures: &BspMapTextures, animation_time: f32, camera_view: &Matrix4x4, camera_proj: &Matrix4x4) {
+    pub fn draw_transparent(self: &mut Self, bsp: &BspFile, textures: &BspMapTextures, animation_time: f32, camera_pos: &Vector3, camera_view: &Matrix4x4, camera_proj: &Matrix4x4) {
         draw_transparent_geom_setup(&Matrix4x4::identity(), camera_view, camera_proj);
 
-        for i in &textures.transp_meshes {
-            let m = &self.mesh_vertices[*i];
-            let idx = &self.mesh_indices[*i];
+        // sort faces back to front from where the camera is now, since geometry is reused while the camera moves around
+        self.transp_faces.sort_by(|a, b| {
+            let dist_a = (a.centroid - *camera_pos).length_sq();
+            let dist_b = (b.centroid - *camera_pos).length_sq();
+            dist_b.total_cmp(&dist_a)
+        });
 
-            draw_geom(bsp, animation_time, textures, *i, &mut self.geo_buff, &mut self.geo_buff2, &m, &idx, &self.lm_atlas);
+        // draw them one at a time
+        for face in &self.transp_faces {
+            let m = &self.mesh_vertices[face.tex_idx][face.vtx_start..face.vtx_end];
+            let idx = &self.mesh_indices[face.tex_idx][face.idx_start..face.idx_end];
+
+            // rebase face indices to the start of its vertex range
+            self.face_idx_buff.clear();
+            self.face_idx_buff.extend(idx.iter().map(|x| *x - face.vtx_start as u16));
+
+            let water = face.water && textures.water_enabled;
+
+            if water {
+                draw_water_tint(textures, &mut self.geo_buff2, m, &self.face_idx_buff);
+            }
+
+            draw_geom(bsp, animation_time, textures, face.tex_idx, &mut self.geo_buff, &mut self.geo_buff2, m, &self.face_idx_buff, &self.lm_atlas);
+
+            if water {
+                draw_water_sheen(bsp, textures, face.tex_idx, animation_time, camera_pos, &face.normal, &mut self.geo_buff, &mut self.geo_buff2, m, &self.face_idx_buff);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster_geometry(cluster: u16, areaportal_states: &[bool]) -> ClusterGeometry {
+        ClusterGeometry {
+            cluster,
+            areaportal_states: areaportal_states.to_vec(),
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            transp_faces: Vec::new(),
+            opaque_order: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn back_and_forth_across_cluster_boundary_builds_once() {
+        let mut test_map = crate::test_map::TestMap::new();
+        test_map.add_room(Vector3::new(-64.0, -64.0, -64.0), Vector3::new(64.0, 64.0, 64.0), 0, 1);
+        test_map.add_room(Vector3::new(64.0, -64.0, -64.0), Vector3::new(192.0, 64.0, 64.0), 1, 1);
+        let bsp = test_map.build();
+
+        let textures = BspMapTextures::new_deferred(&bsp, false);
+        let mut renderer = BspMapRenderer::new(&bsp, &LightmapSettings::default());
+        let light_layers = [0.0;NUM_CUSTOM_LIGHT_LAYERS];
+
+        let positions = [Vector3::new(0.0, 0.0, 0.0), Vector3::new(128.0, 0.0, 0.0)];
+        assert_ne!(bsp.calc_leaf_index(&positions[0]), bsp.calc_leaf_index(&positions[1]));
+
+        for i in 0..10 {
+            renderer.update(&[], 0.0, &light_layers, &[], &bsp, &textures, &positions[i % 2]);
+        }
+
+        // each cluster is built the first time the camera enters it, & swapped back in from the cache afterwards
+        assert_eq!(renderer.build_count(), 2);
+        assert_eq!(renderer.mesh_cluster, 1);
+    }
+
+    #[test]
+    fn cached_geometry_is_keyed_by_areaportal_states() {
+        let mut cache = GeometryCache::new(GEO_CACHE_SIZE);
+        cache.insert(cluster_geometry(1, &[true, false]));
+
+        assert!(cache.take(1, &[true, true]).is_none());
+        assert!(cache.take(1, &[true, false]).is_some());
+        assert!(cache.take(1, &[true, false]).is_none());
+    }
+
+    #[test]
+    fn least_recently_used_geometry_is_evicted() {
+        let mut cache = GeometryCache::new(2);
+        assert!(cache.insert(cluster_geometry(1, &[])).is_none());
+        assert!(cache.insert(cluster_geometry(2, &[])).is_none());
+
+        // taking & reinserting cluster 1 makes cluster 2 the least recently used
+        let entry = cache.take(1, &[]).unwrap();
+        assert!(cache.insert(entry).is_none());
+
+        let evicted = cache.insert(cluster_geometry(3, &[])).unwrap();
+        assert_eq!(evicted.cluster, 2);
+        assert_eq!(cache.entries.len(), 2);
+    }
+
+    #[test]
+    fn closed_areaportal_culls_far_room() {
+        let mut test_map = crate::test_map::TestMap::new();
+        test_map.add_room(Vector3::new(-64.0, -64.0, -64.0), Vector3::new(64.0, 64.0, 64.0), 0, 1);
+        test_map.add_room(Vector3::new(64.0, -64.0, -64.0), Vector3::new(192.0, 64.0, 64.0), 1, 2);
+        test_map.add_area_portal(0, 1, 2);
+        let bsp = test_map.build();
+
+        let near_leaf = bsp.calc_leaf_index(&Vector3::new(0.0, 0.0, 0.0)) as usize;
+        let far_leaf = bsp.calc_leaf_index(&Vector3::new(128.0, 0.0, 0.0)) as usize;
+        let start_area = bsp.leaf_lump.leaves[near_leaf].area as usize;
+
+        // both rooms are in each other's PVS, so only the areaportal can cull the far room
+        let visible_clusters = vec![true;bsp.vis_lump.clusters.len()];
+        let mut visible_areas = vec![false;bsp.area_lump.areas.len()];
+
+        let visible_leaves = |visible_areas: &[bool]| {
+            let mut visible_leaves = vec![false;bsp.leaf_lump.leaves.len()];
+            for i in 0..visible_leaves.len() {
+                BspMapRenderer::update_leaf(&bsp, i, &visible_clusters, visible_areas, &mut visible_leaves);
+            }
+            visible_leaves
+        };
+
+        bsp.flood_areas(start_area, &[true], &mut visible_areas);
+        let open = visible_leaves(&visible_areas);
+        assert!(open[near_leaf] && open[far_leaf]);
+
+        bsp.flood_areas(start_area, &[false], &mut visible_areas);
+        let closed = visible_leaves(&visible_areas);
+        assert!(closed[near_leaf] && !closed[far_leaf]);
+    }
+
+    #[test]
+    fn dynamic_light_falls_off_with_distance_and_angle() {
+        let up = Vector3::unit_z();
+        let light_pos = Vector3::new(0.0, 0.0, 50.0);
+        let color = Vector3::new(1.0, 0.5, 0.0);
+
+        // directly beneath, halfway to the radius
+        let c = dynamic_light_color(&light_pos, &color, 100.0, &up, &Vector3::zero());
+        assert_eq!((c.r, c.g, c.b, c.a), (127, 63, 0, 255));
+
+        // at 45 degrees, the same distance away
+        let d = 50.0 * std::f32::consts::FRAC_1_SQRT_2;
+        let c = dynamic_light_color(&Vector3::new(d, 0.0, d), &color, 100.0, &up, &Vector3::zero());
+        assert_eq!(c.r, (0.5 * std::f32::consts::FRAC_1_SQRT_2 * 255.0) as u8);
+
+        // out of range, or behind the surface
+        let c = dynamic_light_color(&light_pos, &color, 40.0, &up, &Vector3::zero());
+        assert_eq!((c.r, c.g, c.b), (0, 0, 0));
+        let c = dynamic_light_color(&light_pos, &color, 100.0, &(up * -1.0), &Vector3::zero());
+        assert_eq!((c.r, c.g, c.b), (0, 0, 0));
+
+        // bright lights saturate rather than wrapping
+        let c = dynamic_light_color(&light_pos, &Vector3::new(4.0, 4.0, 4.0), 100.0, &up, &Vector3::zero());
+        assert_eq!((c.r, c.g, c.b), (255, 255, 255));
+    }
+
+    #[test]
+    fn streamed_texture_is_dropped_out_of_view_and_reloaded_in_view() {
+        let mut test_map = crate::test_map::TestMap::new();
+        let near_tex = test_map.add_texture("near", SURF_NOLM, 0);
+        let far_tex = test_map.add_texture("far", SURF_NOLM, 0);
+        test_map.add_room(Vector3::new(-64.0, -64.0, -64.0), Vector3::new(64.0, 64.0, 64.0), 0, 1);
+        test_map.add_room(Vector3::new(64.0, -64.0, -64.0), Vector3::new(192.0, 64.0, 64.0), 1, 1);
+        test_map.add_face(&[Vector3::new(-64.0, -64.0, -64.0), Vector3::new(-64.0, 64.0, -64.0), Vector3::new(64.0, 64.0, -64.0), Vector3::new(64.0, -64.0, -64.0)], near_tex, None);
+        test_map.add_face(&[Vector3::new(64.0, -64.0, -64.0), Vector3::new(64.0, 64.0, -64.0), Vector3::new(192.0, 64.0, -64.0), Vector3::new(192.0, -64.0, -64.0)], far_tex, None);
+
+        // neither room can see the other's floor
+        test_map.set_pvs(vec![vec![true, false], vec![false, true]]);
+        let bsp = test_map.build();
+
+        let mut textures = BspMapTextures::new_streaming(&bsp);
+        textures.unload_frames = 2;
+        let mut renderer = BspMapRenderer::new(&bsp, &LightmapSettings::default());
+
+        fn frame(renderer: &mut BspMapRenderer, textures: &mut BspMapTextures, bsp: &BspFile, position: Vector3) {
+            renderer.update(&[], 0.0, &[0.0;NUM_CUSTOM_LIGHT_LAYERS], &[], bsp, textures, &position);
+            textures.mark_visible(bsp, renderer);
+            textures.end_frame();
+        }
+
+        // nothing is loaded until it's seen
+        assert_eq!(textures.resident_count(), 0);
+
+        frame(&mut renderer, &mut textures, &bsp, Vector3::new(0.0, 0.0, 0.0));
+        assert!(textures.resident[near_tex as usize] && !textures.resident[far_tex as usize]);
+
+        for _ in 0..3 {
+            frame(&mut renderer, &mut textures, &bsp, Vector3::new(128.0, 0.0, 0.0));
+        }
+        assert!(!textures.resident[near_tex as usize] && textures.resident[far_tex as usize]);
+
+        frame(&mut renderer, &mut textures, &bsp, Vector3::new(0.0, 0.0, 0.0));
+        assert!(textures.resident[near_tex as usize]);
+    }
+
+    #[test]
+    fn stationary_camera_skips_node_tests() {
+        let mut test_map = crate::test_map::TestMap::new();
+        test_map.add_room(Vector3::new(-64.0, -64.0, -64.0), Vector3::new(64.0, 64.0, 64.0), 0, 1);
+        test_map.add_room(Vector3::new(64.0, -64.0, -64.0), Vector3::new(192.0, 64.0, 64.0), 1, 1);
+        let bsp = test_map.build();
+
+        let textures = BspMapTextures::new_deferred(&bsp, false);
+        let mut renderer = BspMapRenderer::new(&bsp, &LightmapSettings::default());
+        let light_layers = [0.0;NUM_CUSTOM_LIGHT_LAYERS];
+
+        let frustum = [Vector4::new(1.0, 0.0, 0.0, 256.0), Vector4::new(-1.0, 0.0, 0.0, 256.0)];
+        let position = Vector3::new(0.0, 0.0, 0.0);
+
+        renderer.update(&frustum, 0.0, &light_layers, &[], &bsp, &textures, &position);
+        assert!(renderer.node_test_count() > 0);
+        let visible = renderer.visible_leaf_count();
+
+        // nothing changed, so last frame's visible leaves are reused without walking the tree
+        renderer.update(&frustum, 0.1, &light_layers, &[], &bsp, &textures, &position);
+        assert_eq!(renderer.node_test_count(), 0);
+        assert_eq!(renderer.visible_leaf_count(), visible);
+
+        // turning the camera walks the tree again
+        let turned = [Vector4::new(0.0, 1.0, 0.0, 256.0), Vector4::new(0.0, -1.0, 0.0, 256.0)];
+        renderer.update(&turned, 0.2, &light_layers, &[], &bsp, &textures, &position);
+        assert!(renderer.node_test_count() > 0);
+    }
+
+    #[test]
+    fn time_sliced_build_matches_one_shot_build() {
+        let mut test_map = crate::test_map::TestMap::new();
+        let tex = test_map.add_texture("floor", SURF_NOLM, 0);
+        test_map.add_room(Vector3::new(-64.0, -64.0, -64.0), Vector3::new(64.0, 64.0, 64.0), 0, 1);
+        test_map.add_room(Vector3::new(64.0, -64.0, -64.0), Vector3::new(192.0, 64.0, 64.0), 1, 1);
+
+        // more small floor tiles in the first room than are built in a single frame, plus a few in the second room
+        let tile = |x: f32, y: f32| [Vector3::new(x, y, -64.0), Vector3::new(x, y + 4.0, -64.0), Vector3::new(x + 4.0, y + 4.0, -64.0), Vector3::new(x + 4.0, y, -64.0)];
+        for i in 0..20 {
+            for j in 0..15 {
+                test_map.add_face(&tile(-60.0 + (i as f32 * 6.0), -60.0 + (j as f32 * 8.0)), tex, None);
+            }
+        }
+        for i in 0..10 {
+            test_map.add_face(&tile(70.0 + (i as f32 * 8.0), 0.0), tex, None);
+        }
+        let bsp = test_map.build();
+
+        let textures = BspMapTextures::new_deferred(&bsp, false);
+        let light_layers = [0.0;NUM_CUSTOM_LIGHT_LAYERS];
+        let near = Vector3::new(0.0, 0.0, 0.0);
+        let far = Vector3::new(128.0, 0.0, 0.0);
+
+        // entering the second room rebuilds its geometry over several frames, drawing the first room's geometry until it's done
+        let mut sliced = BspMapRenderer::new(&bsp, &LightmapSettings::default());
+        sliced.update(&[], 0.0, &light_layers, &[], &bsp, &textures, &near);
+        sliced.update(&[], 0.0, &light_layers, &[], &bsp, &textures, &far);
+        assert!(sliced.build_cursor.is_some());
+        assert_eq!(sliced.mesh_cluster, 0);
+
+        sliced.update(&[], 0.0, &light_layers, &[], &bsp, &textures, &far);
+        assert!(sliced.build_cursor.is_none());
+        assert_eq!(sliced.mesh_cluster, 1);
+
+        // starting in the second room builds everything at once
+        let mut one_shot = BspMapRenderer::new(&bsp, &LightmapSettings::default());
+        one_shot.update(&[], 0.0, &light_layers, &[], &bsp, &textures, &far);
+
+        assert_eq!(sliced.triangle_count(), 310 * 2);
+        assert_eq!(sliced.opaque_order, one_shot.opaque_order);
+
+        for t in 0..bsp.tex_info_lump.textures.len() {
+            assert_eq!(sliced.mesh_indices[t], one_shot.mesh_indices[t]);
+            assert_eq!(sliced.mesh_vertices[t].len(), one_shot.mesh_vertices[t].len());
+
+            for (a, b) in sliced.mesh_vertices[t].iter().zip(&one_shot.mesh_vertices[t]) {
+                assert_eq!((a.position.x, a.position.y, a.position.z), (b.position.x, b.position.y, b.position.z));
+                assert_eq!((a.texcoord0.x, a.texcoord0.y), (b.texcoord0.x, b.texcoord0.y));
+            }
+        }
+    }
+
+    #[test]
+    fn packed_lightmap_regions_never_overlap() {
+        let mut packer = LmAtlasPacker::new(128, &LightmapSettings::default());
+        let mut regions = vec![packer.fallback];
+
+        // pseudo-random region sizes, as large as a padded lightmap can be. keep going well past the first failure to fit
+        let mut seed: u32 = 12345;
+        let mut next_size = || {
+            seed = (seed.wrapping_mul(1103515245).wrapping_add(12345)) & 0x7FFFFFFF;
+            3 + ((seed >> 16) as usize % 16)
+        };
+
+        for face_id in 0..300 {
+            let width = next_size();
+            let height = next_size();
+
+            if let Some((in_cache, r)) = packer.pack(face_id, width, height, false) {
+                assert!(!in_cache);
+                assert_eq!((r.width as usize, r.height as usize), (width, height));
+                regions.push(r);
+            }
+        }
+
+        assert!(packer.overflow);
+
+        for (i, a) in regions.iter().enumerate() {
+            assert!(a.x >= 0 && a.y >= 0 && a.x + a.width <= 128 && a.y + a.height <= 128);
+
+            for b in &regions[(i + 1)..] {
+                let overlap = a.x < b.x + b.width && b.x < a.x + a.width && a.y < b.y + b.height && b.y < a.y + a.height;
+                assert!(!overlap, "({}, {}, {}, {}) overlaps ({}, {}, {}, {})", a.x, a.y, a.width, a.height, b.x, b.y, b.width, b.height);
+            }
+        }
+
+        // usage is tracked exactly, & skyline packing leaves little wasted space
+        let area: i32 = regions.iter().map(|r| r.width * r.height).sum();
+        assert_eq!(packer.usage(), area as f32 / (128.0 * 128.0));
+        assert!(packer.usage() > 0.85, "{}", packer.usage());
+    }
+
+    #[test]
+    fn lightmap_padding_replicates_edge_texels() {
+        let a = Color32::new(10, 0, 0, 255);
+        let b = Color32::new(20, 0, 0, 255);
+        let c = Color32::new(30, 0, 0, 255);
+        let d = Color32::new(40, 0, 0, 255);
+        let src = [a, b, c, d];
+
+        let mut dst = [Color32::new(0, 0, 0, 0);4*4];
+        pad_lightmap(&src, 2, 2, &mut dst);
+
+        // every padded texel should hold the nearest source texel, so bilinear filtering at the edge never reads a neighbour
+        let expected = [
+            a, a, b, b,
+            a, a, b, b,
+            c, c, d, d,
+            c, c, d, d,
+        ];
+
+        for i in 0..expected.len() {
+            assert_eq!(dst[i].r, expected[i].r, "texel {}", i);
+        }
+    }
+
+    #[test]
+    fn lightmap_settings_brighten_mid_gray() {
+        let mid_gray = Color32::new(64, 64, 64, 255);
+
+        // default settings double lightmap samples, clamping at white
+        let lut = LightmapSettings::default().build_lut();
+        assert_eq!(lut[0], 0);
+        assert_eq!(lut[64], 128);
+        assert_eq!(lut[128], 255);
+        assert_eq!(lut[255], 255);
+
+        let c = LightmapSettings::default().sample_to_color(mid_gray);
+        assert!((c.x - (128.0 / 255.0)).abs() < 0.001);
+
+        let lut = LightmapSettings { overbright: 1.5, gamma: 1.0 }.build_lut();
+        assert_eq!(lut[128], 192);
+
+        // gamma is applied before overbright
+        let lut = LightmapSettings { overbright: 1.0, gamma: 2.0 }.build_lut();
+        assert_eq!(lut[64], 128);
+
+        // the atlas applies the same curve to every color channel, but leaves alpha alone
+        let packer = LmAtlasPacker::new(16, &LightmapSettings::default());
+        let c = packer.remap(Color32::new(64, 32, 0, 100));
+        assert_eq!((c.r, c.g, c.b, c.a), (128, 64, 0, 100));
+    }
+
+    #[test]
+    fn flowing_surface_uvs_scroll_over_time() {
+        let vtx = MapVertex::new(Vector4::new(0.0, 0.0, 0.0, 1.0), Vector2::new(0.5, 0.5), Vector2::zero(), Color32::new(255, 255, 255, 255));
+        let uvs_at = |flags: u32, time: f32| {
+            let mut geo_buff = vec![vtx];
+            apply_uv_animation(flags, time, 0.5, &mut geo_buff);
+            (geo_buff[0].texcoord0.x, geo_buff[0].texcoord0.y)
+        };
+
+        // a normal face's UVs never move
+        assert_eq!(uvs_at(0, 0.0), (0.5, 0.5));
+        assert_eq!(uvs_at(0, 1.5), (0.5, 0.5));
+
+        // flowing faces scroll along U at flow_speed repeats per second, wrapped to a single repeat
+        assert_eq!(uvs_at(SURF_FLOW, 0.0), (0.5, 0.5));
+        assert_eq!(uvs_at(SURF_FLOW, 0.5), (0.25, 0.5));
+        assert_eq!(uvs_at(SURF_FLOW, 1.0), (0.0, 0.5));
+        assert_eq!(uvs_at(SURF_FLOW, 2.5), (0.25, 0.5));
+
+        // combined with warp, the flow offset is added on top of the warp offset
+        let (warp_u, warp_v) = uvs_at(SURF_WARP, 0.5);
+        let (both_u, both_v) = uvs_at(SURF_FLOW | SURF_WARP, 0.5);
+        assert!((both_u - (warp_u - 0.25)).abs() < 0.0001);
+        assert_eq!(both_v, warp_v);
+    }
+
+    #[test]
+    fn translucent_surfaces_skip_detail() {
+        let mut test_map = crate::test_map::TestMap::new();
+        let opaque_tex = test_map.add_texture("opaque", SURF_DETAIL, 0);
+        let glass_tex = test_map.add_texture("glass", SURF_DETAIL | SURF_TRANS66, 0);
+        let bsp = test_map.build();
+
+        let mut textures = BspMapTextures::new_deferred(&bsp, false);
+        for i in [opaque_tex, glass_tex] {
+            textures.detail_textures[i as usize] = Some(Arc::new(Texture::new(2, 2, false, vdp::TextureFormat::RGBA8888).unwrap()));
+        }
+
+        // detail is off by default
+        assert!(detail_texture(&bsp, &textures, opaque_tex as usize).is_none());
+
+        textures.detail_enabled = true;
+        assert!(detail_texture(&bsp, &textures, opaque_tex as usize).is_some());
+        assert!(detail_texture(&bsp, &textures, glass_tex as usize).is_none());
+
+        // the detail pass tiles at detail_scale times the base texture's frequency
+        let mut geo_buff = vec![MapVertex::new(Vector4::new(0.0, 0.0, 0.0, 1.0), Vector2::new(0.25, 0.5), Vector2::zero(), Color32::new(64, 64, 64, 128))];
+        build_detail_geom(textures.detail_scale, &mut geo_buff);
+
+        assert_eq!((geo_buff[0].texcoord0.x, geo_buff[0].texcoord0.y), (1.0, 2.0));
+        assert_eq!((geo_buff[0].color.r, geo_buff[0].color.g, geo_buff[0].color.b, geo_buff[0].color.a), (255, 255, 255, 255));
+    }
+
+    #[test]
+    fn water_tint_colors_every_unpacked_vertex() {
+        let white = Color32::new(255, 255, 255, 255);
+        let m = [
+            MapVertex::new(Vector4::new(0.0, 0.0, 0.0, 1.0), Vector2::zero(), Vector2::zero(), white),
+            MapVertex::new(Vector4::new(1.0, 0.0, 0.0, 1.0), Vector2::zero(), Vector2::zero(), white),
+            MapVertex::new(Vector4::new(1.0, 1.0, 0.0, 1.0), Vector2::zero(), Vector2::zero(), white),
+            MapVertex::new(Vector4::new(0.0, 1.0, 0.0, 1.0), Vector2::zero(), Vector2::zero(), white),
+        ];
+        let idx = [0, 1, 2, 0, 2, 3];
+
+        let tint = Color32::new(128, 192, 200, 255);
+        let mut geo_buff = Vec::new();
+        build_water_tint_geom(tint, &mut geo_buff, &m, &idx);
+
+        assert_eq!(geo_buff.len(), idx.len());
+
+        for (vtx, i) in geo_buff.iter().zip(&idx) {
+            assert_eq!(vtx.position.x, m[*i as usize].position.x);
+            assert_eq!(vtx.position.y, m[*i as usize].position.y);
+            assert_eq!((vtx.color.r, vtx.color.g, vtx.color.b, vtx.color.a), (128, 192, 200, 255));
         }
     }
 }
\ No newline at end of file
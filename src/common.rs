@@ -1,4 +1,6 @@
-use dbsdk_rs::math::{Matrix4x4, Vector3, Vector4};
+use dbsdk_rs::math::{Matrix4x4, Quaternion, Vector3, Vector4};
+
+use crate::dbanim::Lerp;
 
 pub fn coord_space_transform() -> Matrix4x4 {
     // Quake coordinate system:
@@ -25,37 +27,105 @@ pub fn aabb_aabb_intersects(min_a: Vector3, max_a: Vector3, min_b: Vector3, max_
             min_a.z <= max_b.z && max_a.z >= min_b.z;
 }
 
+/// Result of testing a bounding box against a frustum
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FrustumTest {
+    Inside,
+    Intersect,
+    Outside,
+}
+
+/// Extract frustum planes from a view-projection matrix. Planes are normalized, so dotting a plane with a point gives the signed distance to that plane
 pub fn extract_frustum(viewproj: &Matrix4x4) -> [Vector4;6] {
     let row1 = Vector4::new(viewproj.m[0][0], viewproj.m[1][0], viewproj.m[2][0], viewproj.m[3][0]);
     let row2 = Vector4::new(viewproj.m[0][1], viewproj.m[1][1], viewproj.m[2][1], viewproj.m[3][1]);
     let row3 = Vector4::new(viewproj.m[0][2], viewproj.m[1][2], viewproj.m[2][2], viewproj.m[3][2]);
     let row4 = Vector4::new(viewproj.m[0][3], viewproj.m[1][3], viewproj.m[2][3], viewproj.m[3][3]);
 
+    let normalize_plane = |plane: Vector4| {
+        let len = Vector3::new(plane.x, plane.y, plane.z).length();
+        if len > f32::EPSILON { Vector4::new(plane.x / len, plane.y / len, plane.z / len, plane.w / len) } else { plane }
+    };
+
     [
-        row4 + row1,
-        row4 - row1,
-        row4 + row2,
-        row4 - row2,
-        row4 + row3,
-        row4 - row3,
+        normalize_plane(row4 + row1),
+        normalize_plane(row4 - row1),
+        normalize_plane(row4 + row2),
+        normalize_plane(row4 - row2),
+        normalize_plane(row4 + row3),
+        normalize_plane(row4 - row3),
     ]
 }
 
-pub fn aabb_frustum(min: Vector3, max: Vector3, frustum: &[Vector4]) -> bool {
+/// Classify a bounding box as entirely inside, partially inside, or entirely outside of a frustum
+pub fn aabb_frustum_classify(min: Vector3, max: Vector3, frustum: &[Vector4]) -> FrustumTest {
+    let center = (min + max) * 0.5;
+    let extents = (max - min) * 0.5;
+
+    let mut result = FrustumTest::Inside;
+
     for plane in frustum {
-        if Vector4::dot(&plane, &Vector4::new(min.x, min.y, min.z, 1.0)) <= 0.0 &&
-            Vector4::dot(&plane, &Vector4::new(max.x, min.y, min.z, 1.0)) <= 0.0 &&
-            Vector4::dot(&plane, &Vector4::new(min.x, max.y, min.z, 1.0)) <= 0.0 &&
-            Vector4::dot(&plane, &Vector4::new(max.x, max.y, min.z, 1.0)) <= 0.0 &&
-            Vector4::dot(&plane, &Vector4::new(min.x, min.y, max.z, 1.0)) <= 0.0 &&
-            Vector4::dot(&plane, &Vector4::new(max.x, min.y, max.z, 1.0)) <= 0.0 &&
-            Vector4::dot(&plane, &Vector4::new(min.x, max.y, max.z, 1.0)) <= 0.0 &&
-            Vector4::dot(&plane, &Vector4::new(max.x, max.y, max.z, 1.0)) <= 0.0 {
-            return false;
+        // distance from box center to plane, vs projected radius of box onto plane normal
+        let dist = Vector4::dot(&plane, &Vector4::new(center.x, center.y, center.z, 1.0));
+        let radius = (plane.x.abs() * extents.x) + (plane.y.abs() * extents.y) + (plane.z.abs() * extents.z);
+
+        if dist + radius <= 0.0 {
+            return FrustumTest::Outside;
+        }
+        else if dist - radius < 0.0 {
+            result = FrustumTest::Intersect;
         }
     }
 
-    return true;
+    return result;
+}
+
+pub fn aabb_frustum(min: Vector3, max: Vector3, frustum: &[Vector4]) -> bool {
+    return aabb_frustum_classify(min, max, frustum) != FrustumTest::Outside;
+}
+
+/// Decompose an affine transform matrix into position, rotation, and scale
+pub fn decompose_matrix(mat: &Matrix4x4) -> (Vector3, Quaternion, Vector3) {
+    let pos = *mat * Vector4::new(0.0, 0.0, 0.0, 1.0);
+    let x_axis = *mat * Vector4::new(1.0, 0.0, 0.0, 0.0);
+    let y_axis = *mat * Vector4::new(0.0, 1.0, 0.0, 0.0);
+    let z_axis = *mat * Vector4::new(0.0, 0.0, 1.0, 0.0);
+
+    let x_axis = Vector3::new(x_axis.x, x_axis.y, x_axis.z);
+    let y_axis = Vector3::new(y_axis.x, y_axis.y, y_axis.z);
+    let z_axis = Vector3::new(z_axis.x, z_axis.y, z_axis.z);
+
+    let scale = Vector3::new(x_axis.length(), y_axis.length(), z_axis.length());
+
+    if scale.x <= f32::EPSILON || scale.y <= f32::EPSILON || scale.z <= f32::EPSILON {
+        return (Vector3::new(pos.x, pos.y, pos.z), Quaternion::identity(), scale);
+    }
+
+    // normalized basis vectors form the columns of the rotation matrix
+    let x = x_axis / scale.x;
+    let y = y_axis / scale.y;
+    let z = z_axis / scale.z;
+
+    let trace = x.x + y.y + z.z;
+
+    let rot = if trace > 0.0 {
+        let s = (trace + 1.0).sqrt() * 2.0;
+        Quaternion::new((y.z - z.y) / s, (z.x - x.z) / s, (x.y - y.x) / s, 0.25 * s)
+    }
+    else if x.x > y.y && x.x > z.z {
+        let s = (1.0 + x.x - y.y - z.z).sqrt() * 2.0;
+        Quaternion::new(0.25 * s, (y.x + x.y) / s, (z.x + x.z) / s, (y.z - z.y) / s)
+    }
+    else if y.y > z.z {
+        let s = (1.0 + y.y - x.x - z.z).sqrt() * 2.0;
+        Quaternion::new((y.x + x.y) / s, 0.25 * s, (z.y + y.z) / s, (z.x - x.z) / s)
+    }
+    else {
+        let s = (1.0 + z.z - x.x - y.y).sqrt() * 2.0;
+        Quaternion::new((z.x + x.z) / s, (z.y + y.z) / s, 0.25 * s, (x.y - y.x) / s)
+    };
+
+    (Vector3::new(pos.x, pos.y, pos.z), rot, scale)
 }
 
 /// Transform an AABB from local space into world space, returning center + extents
@@ -88,4 +158,90 @@ pub fn transform_aabb(offset: Vector3, extents: Vector3, local2world: &Matrix4x4
     }
 
     ((max + min) * 0.5, (max - min) * 0.5)
+}
+
+/// Move a point toward a target point, moving no further than max_delta
+pub fn move_toward(current: Vector3, target: Vector3, max_delta: f32) -> Vector3 {
+    let delta = target - current;
+
+    if delta.length_sq() <= max_delta * max_delta {
+        return target;
+    }
+
+    current + (delta.normalized() * max_delta)
+}
+
+/// Rotate a quaternion toward a target rotation, rotating no further than max_radians
+pub fn rotate_toward(current: Quaternion, target: Quaternion, max_radians: f32) -> Quaternion {
+    let d = (current.x * target.x) +
+        (current.y * target.y) +
+        (current.z * target.z) +
+        (current.w * target.w);
+
+    let angle = 2.0 * d.abs().min(1.0).acos();
+
+    if angle <= max_radians || angle <= f32::EPSILON {
+        return target;
+    }
+
+    Quaternion::lerp(current, target, max_radians / angle)
+}
+
+/// Clip a convex polygon against a plane, keeping the part in front of it
+pub fn clip_polygon(verts: &[Vector3], normal: &Vector3, dist: f32) -> Vec<Vector3> {
+    let mut result = Vec::with_capacity(verts.len() + 1);
+
+    for i in 0..verts.len() {
+        let a = verts[i];
+        let b = verts[(i + 1) % verts.len()];
+
+        let da = Vector3::dot(&a, normal) - dist;
+        let db = Vector3::dot(&b, normal) - dist;
+
+        if da >= 0.0 {
+            result.push(a);
+        }
+
+        if (da >= 0.0) != (db >= 0.0) {
+            let t = da / (da - db);
+            result.push(a + ((b - a) * t));
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 0.001, "expected {} to be near {}", a, b);
+    }
+
+    #[test]
+    fn move_toward_clamps_step() {
+        let result = move_toward(Vector3::zero(), Vector3::new(10.0, 0.0, 0.0), 4.0);
+        assert_near(result.x, 4.0);
+        assert_near(result.y, 0.0);
+
+        // doesn't overshoot when the target is within reach
+        let result = move_toward(Vector3::zero(), Vector3::new(0.0, 3.0, 0.0), 4.0);
+        assert_eq!(result.y, 3.0);
+    }
+
+    #[test]
+    fn rotate_toward_clamps_angle() {
+        let half = std::f32::consts::FRAC_PI_4;
+        let target = Quaternion::new(0.0, 0.0, half.sin(), half.cos());
+
+        // a quarter turn, limited to 30 degrees
+        let result = rotate_toward(Quaternion::identity(), target, 30f32.to_radians());
+        assert_near(2.0 * result.w.acos(), 30f32.to_radians());
+        assert_near(result.x, 0.0);
+        assert_near(result.y, 0.0);
+
+        let result = rotate_toward(Quaternion::identity(), target, 120f32.to_radians());
+        assert_eq!((result.x, result.y, result.z, result.w), (target.x, target.y, target.z, target.w));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,65 @@
+use std::sync::Arc;
+
+use dbsdk_rs::math::Vector3;
+
+use crate::asset_loader::SoundClip;
+
+/// Distance at which a target_speaker with an attenuation of 1 fades out completely
+pub const SPEAKER_RADIUS: f32 = 1000.0;
+
+/// A sound emitted from a fixed point in the world (placed by target_speaker entities)
+pub struct AmbientSound {
+    pub sample: Arc<SoundClip>,
+    pub origin: Vector3,
+    /// Distance at which the sound fades out completely. Infinite radius sounds are heard everywhere at full volume
+    pub radius: f32,
+    pub volume: f32,
+    pub looping: bool,
+    /// Whether looping sounds play before being triggered. Triggering the speaker toggles it
+    pub start_on: bool,
+    /// Looping voice currently assigned to this sound, if it's playing
+    pub voice: Option<usize>,
+    /// Trigger state as of last update, used to fire one-shot sounds when triggered
+    pub prev_triggered: bool,
+}
+
+impl AmbientSound {
+    pub fn new(sample: Arc<SoundClip>, origin: Vector3, radius: f32, volume: f32, looping: bool, start_on: bool) -> AmbientSound {
+        AmbientSound {
+            sample,
+            origin,
+            radius,
+            volume,
+            looping,
+            start_on,
+            voice: None,
+            prev_triggered: false,
+        }
+    }
+
+    /// Calculate a target_speaker's radius from its attenuation key. -1 means the sound is heard everywhere, & 0 means the default attenuation of 1
+    pub fn radius_from_attenuation(attenuation: f32) -> f32 {
+        if attenuation < 0.0 {
+            f32::INFINITY
+        }
+        else if attenuation == 0.0 {
+            SPEAKER_RADIUS
+        }
+        else {
+            SPEAKER_RADIUS / attenuation
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attenuation_keys_map_to_radius() {
+        assert_eq!(AmbientSound::radius_from_attenuation(-1.0), f32::INFINITY);
+        assert_eq!(AmbientSound::radius_from_attenuation(0.0), SPEAKER_RADIUS);
+        assert_eq!(AmbientSound::radius_from_attenuation(1.0), SPEAKER_RADIUS);
+        assert_eq!(AmbientSound::radius_from_attenuation(2.0), SPEAKER_RADIUS / 2.0);
+    }
+}
\ No newline at end of file
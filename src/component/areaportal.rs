@@ -0,0 +1,5 @@
+/// Marks an entity which opens & closes one of the map's areaportals based on its trigger state
+#[derive(Clone, Copy)]
+pub struct AreaPortal {
+    pub portal_num: usize,
+}
@@ -0,0 +1,21 @@
+use hecs::Entity;
+
+use super::transform3d::Transform3D;
+
+/// Mounts an entity to a bone of a parent entity's animated skeleton
+#[derive(Clone, Copy)]
+pub struct Attachment {
+    pub parent: Entity,
+    pub bone_index: u8,
+    pub local_offset: Transform3D,
+}
+
+impl Attachment {
+    pub fn new(parent: Entity, bone_index: u8) -> Attachment {
+        Attachment {
+            parent,
+            bone_index,
+            local_offset: Transform3D::default()
+        }
+    }
+}
\ No newline at end of file
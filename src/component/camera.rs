@@ -1,12 +1,19 @@
-use dbsdk_rs::vdp::Rectangle;
+use std::sync::Arc;
+
+use dbsdk_rs::{math::{Quaternion, Vector3}, vdp::{Color32, Rectangle, Texture}};
 use hecs::Entity;
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct Camera {
     pub fov: f32,
     pub near: f32,
     pub far: f32,
-    pub viewport_rect: Option<Rectangle>
+    pub viewport_rect: Option<Rectangle>,
+    /// Color the camera's viewport is filled with before drawing
+    pub clear_color: Color32,
+    pub draw_sky: bool,
+    /// If set, the camera renders into this texture instead of to the screen
+    pub render_target: Option<Arc<Texture>>,
 }
 
 impl Camera {
@@ -15,7 +22,10 @@ impl Camera {
             fov: 60.0,
             near: 10.0,
             far: 10000.0,
-            viewport_rect: None
+            viewport_rect: None,
+            clear_color: Color32::new(0, 0, 0, 255),
+            draw_sky: true,
+            render_target: None,
         }
     }
 }
@@ -31,4 +41,44 @@ impl FPCamera {
             follow_entity
         }
     }
+}
+
+#[derive(Clone, Copy)]
+pub struct ThirdPersonCamera {
+    pub follow_entity: Entity,
+    pub distance: f32,
+    pub height: f32,
+    pub pitch_offset: f32,
+}
+
+impl ThirdPersonCamera {
+    pub fn new(follow_entity: Entity, distance: f32, height: f32) -> ThirdPersonCamera {
+        ThirdPersonCamera {
+            follow_entity,
+            distance,
+            height,
+            pitch_offset: 0.0
+        }
+    }
+}
+
+/// Shakes a camera by an amount proportional to trauma squared. Trauma decays by the given amount per second
+#[derive(Clone, Copy)]
+pub struct CameraShake {
+    pub trauma: f32,
+    pub decay: f32,
+    /// Camera transform before shake was applied this frame, restored after rendering
+    pub base_position: Vector3,
+    pub base_rotation: Quaternion,
+}
+
+impl CameraShake {
+    pub fn new(trauma: f32, decay: f32) -> CameraShake {
+        CameraShake {
+            trauma,
+            decay,
+            base_position: Vector3::zero(),
+            base_rotation: Quaternion::identity()
+        }
+    }
 }
\ No newline at end of file
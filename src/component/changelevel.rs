@@ -0,0 +1,10 @@
+use dbsdk_rs::math::Vector3;
+
+/// Marks a trigger volume which sends players to another map when entered
+pub struct ChangeLevel {
+    pub next_map: String,
+    /// Targetname of the info_player_start to spawn at in the next map (empty for the default start)
+    pub spawnpoint: String,
+    pub mins: Vector3,
+    pub maxs: Vector3,
+}
\ No newline at end of file
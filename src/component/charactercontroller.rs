@@ -1,13 +1,25 @@
 use dbsdk_rs::math::Vector3;
+use hecs::Entity;
 
 #[derive(Clone, Copy)]
 pub struct CharacterController {
     pub radius: f32,
     pub height_offset: f32,
     pub move_speed: f32,
+    pub walk_speed: f32,
     pub jump_force: f32,
     pub main_height: f32,
     pub crouch_height: f32,
+    pub step_height: f32,
+    /// Steepest slope (in degrees) the character can stand on
+    pub ground_slope_angle: f32,
+    /// Multiplier applied to the map's gravity
+    pub gravity_scale: f32,
+    pub friction: f32,
+    pub max_accel: f32,
+    pub air_accel: f32,
+    /// Collide as an upright cylinder instead of a box, which slides along angled walls more smoothly
+    pub cylinder: bool,
 }
 
 #[derive(Clone, Copy)]
@@ -16,13 +28,25 @@ pub struct CharacterState {
     pub velocity: Vector3,
     pub grounded: bool,
     pub crouched: bool,
+    pub climbing: bool,
+    /// How deep the character is submerged: 0 = not in water, 1 = feet, 2 = waist, 3 = head
+    pub water_level: u8,
+    /// Time remaining in which the character may still jump after leaving the ground
+    pub coyote_timer: f32,
+    /// Time remaining in which a jump press will still fire upon landing
+    pub jump_buffer_timer: f32,
+    pub jump_held: bool,
+    /// Map model entity the character is stuck inside of this frame, if any (for example, a door pushing it into a wall)
+    pub pinned_by: Option<Entity>,
 }
 
 #[derive(Clone, Copy)]
 pub struct CharacterInputState {
     pub input_move_dir: Vector3,
+    pub input_swim_dir: Vector3,
     pub input_crouch: bool,
     pub input_jump: bool,
+    pub input_walk: bool,
 }
 
 impl CharacterController {
@@ -33,7 +57,15 @@ impl CharacterController {
             crouch_height: 16.0,
             height_offset: 24.0,
             move_speed: 200.0,
+            walk_speed: 100.0,
             jump_force: 150.0,
+            step_height: 20.0,
+            ground_slope_angle: 45.0,
+            gravity_scale: 1.0,
+            friction: 0.2,
+            max_accel: 10.0,
+            air_accel: 1.0,
+            cylinder: false,
         }
     }
 }
@@ -45,6 +77,12 @@ impl CharacterState {
             velocity: Vector3::zero(),
             grounded: false,
             crouched: false,
+            climbing: false,
+            water_level: 0,
+            coyote_timer: 0.0,
+            jump_buffer_timer: 0.0,
+            jump_held: false,
+            pinned_by: None,
         }
     }
 }
@@ -53,8 +91,10 @@ impl CharacterInputState {
     pub fn default() -> CharacterInputState {
         CharacterInputState {
             input_move_dir: Vector3::zero(),
+            input_swim_dir: Vector3::zero(),
             input_crouch: false,
             input_jump: false,
+            input_walk: false,
         }
     }
 }
\ No newline at end of file
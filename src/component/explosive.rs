@@ -0,0 +1,7 @@
+/// A brush entity which is destroyed when triggered, or when its health runs out
+#[derive(Clone, Copy)]
+pub struct Explosive {
+    pub health: f32,
+    /// Explosives spawned with no health can only be destroyed by a trigger
+    pub damageable: bool,
+}
\ No newline at end of file
@@ -1,3 +1,16 @@
-#[derive(Clone, Copy, Default)]
+use crate::bsp_file::MASK_SOLID;
+
+#[derive(Clone, Copy)]
 pub struct FlyCam {
+    /// Contents the flycam collides with. If zero, the flycam ignores map collision entirely
+    pub content_mask: u32,
+}
+
+impl Default for FlyCam {
+    /// A flycam which collides with solid map geometry
+    fn default() -> Self {
+        FlyCam {
+            content_mask: MASK_SOLID,
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,16 @@
+/// Plays footstep sounds matching the floor surface while a grounded character is moving
+#[derive(Clone, Copy)]
+pub struct Footsteps {
+    /// Distance travelled since the last footstep
+    pub stride_progress: f32,
+    pub step_index: usize,
+}
+
+impl Footsteps {
+    pub fn new() -> Footsteps {
+        Footsteps {
+            stride_progress: 0.0,
+            step_index: 0,
+        }
+    }
+}
\ No newline at end of file
@@ -3,6 +3,8 @@ pub struct FPView {
     pub yaw: f32,
     pub pitch: f32,
     pub eye_offset: f32,
+    /// Vertical offset which smooths out the eye position when stepping up stairs. Decays back towards zero
+    pub step_offset: f32,
 }
 
 impl FPView {
@@ -11,6 +13,7 @@ impl FPView {
             yaw: 0.0,
             pitch: 0.0,
             eye_offset: 0.0,
+            step_offset: 0.0,
         }
     }
 
@@ -19,6 +22,7 @@ impl FPView {
             yaw,
             pitch,
             eye_offset,
+            step_offset: 0.0,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,9 @@
+use dbsdk_rs::math::Vector3;
+
+/// A volume which scales the map's gravity for characters inside of it
+#[derive(Clone, Copy)]
+pub struct GravityVolume {
+    pub mins: Vector3,
+    pub maxs: Vector3,
+    pub gravity: f32,
+}
\ No newline at end of file
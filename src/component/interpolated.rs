@@ -0,0 +1,24 @@
+use dbsdk_rs::math::{Quaternion, Vector3};
+
+use super::transform3d::Transform3D;
+
+/// Marks an entity whose transform is smoothed between simulation steps when rendering
+#[derive(Clone, Copy)]
+pub struct Interpolated {
+    pub prev_position: Vector3,
+    pub prev_rotation: Quaternion,
+    /// Simulated transform stashed while the interpolated transform is applied for rendering
+    pub sim_position: Vector3,
+    pub sim_rotation: Quaternion,
+}
+
+impl Interpolated {
+    pub fn new(transform: &Transform3D) -> Interpolated {
+        Interpolated {
+            prev_position: transform.position,
+            prev_rotation: transform.rotation,
+            sim_position: transform.position,
+            sim_rotation: transform.rotation,
+        }
+    }
+}
\ No newline at end of file
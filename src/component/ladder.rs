@@ -0,0 +1,8 @@
+use dbsdk_rs::math::Vector3;
+
+/// A volume which characters can climb while inside of
+#[derive(Clone, Copy)]
+pub struct LadderVolume {
+    pub mins: Vector3,
+    pub maxs: Vector3,
+}
\ No newline at end of file
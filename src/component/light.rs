@@ -4,4 +4,15 @@ use dbsdk_rs::math::Vector3;
 pub struct Light {
     pub color: Vector3,
     pub max_radius: f32,
-}
\ No newline at end of file
+    /// Dynamic lights are additionally projected onto map surfaces, since they are not baked into the lightmaps
+    pub dynamic: bool,
+}
+
+/// Marks a light entity which can be switched on and off by a trigger, driving one of the custom light layers
+#[derive(Clone, Copy)]
+pub struct LightSwitch {
+    pub layer: usize,
+    pub start_on: bool,
+    /// Whether the switch last turned its layer on or off, or None if it hasn't touched the layer yet
+    pub applied: Option<bool>,
+}
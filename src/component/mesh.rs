@@ -1,6 +1,7 @@
 use std::sync::Arc;
 
-use dbsdk_rs::math::{Matrix4x4, Vector3};
+use dbsdk_rs::math::{Matrix4x4, Quaternion, Vector3};
+use hecs::Entity;
 
 use crate::{dbanim::{AnimationCurveLoopMode, DBAnimationClip}, dbmesh::DBMesh};
 
@@ -8,6 +9,47 @@ pub struct Mesh {
     pub mesh: Arc<DBMesh>,
     pub bounds_offset: Vector3,
     pub bounds_extents: Vector3,
+    /// Level of detail selected by the renderer last frame, for each camera which drew the mesh
+    pub lods: Vec<(Entity, usize)>,
+}
+
+impl Mesh {
+    /// Level of detail last selected when the given camera drew this mesh
+    pub fn lod_for(self: &Self, camera: Entity) -> usize {
+        match self.lods.iter().find(|(e, _)| *e == camera) {
+            Some((_, lod)) => *lod,
+            None => 0
+        }
+    }
+
+    /// Record the level of detail selected when the given camera drew this mesh
+    pub fn set_lod_for(self: &mut Self, camera: Entity, lod: usize) {
+        match self.lods.iter_mut().find(|(e, _)| *e == camera) {
+            Some(v) => v.1 = lod,
+            None => self.lods.push((camera, lod))
+        }
+    }
+
+    /// Grow the mesh's bounds by a margin on every side (for example, to cover a skinned mesh's animation)
+    pub fn with_bounds_margin(mut self, margin: f32) -> Mesh {
+        self.bounds_extents = self.bounds_extents + Vector3::new(margin, margin, margin);
+        self
+    }
+}
+
+impl From<Arc<DBMesh>> for Mesh {
+    /// Construct a mesh component whose bounds are calculated from the mesh's vertices
+    fn from(mesh: Arc<DBMesh>) -> Self {
+        let bounds_offset = (mesh.bounds_min + mesh.bounds_max) * 0.5;
+        let bounds_extents = (mesh.bounds_max - mesh.bounds_min) * 0.5;
+
+        Mesh {
+            mesh,
+            bounds_offset,
+            bounds_extents,
+            lods: Vec::new(),
+        }
+    }
 }
 
 pub struct FPMesh {
@@ -18,8 +60,144 @@ pub struct MeshAnim {
     pub anim: Arc<DBAnimationClip>,
     pub loop_mode: AnimationCurveLoopMode,
     pub time: f32,
+    pub speed: f32,
+    pub paused: bool,
+    /// Names of animation events passed during playback, oldest first. Gameplay systems should drain this each frame
+    pub events: Vec<String>,
+    /// Move the entity by the root bone's animated translation & rotation, rather than letting the root bone move the mesh away from the entity
+    pub extract_root_motion: bool,
+    /// World space translation extracted from the root bone during the last animation update
+    pub root_motion_translation: Vector3,
+    /// Rotation extracted from the root bone during the last animation update
+    pub root_motion_rotation: Quaternion,
+}
+
+impl MeshAnim {
+    pub fn new(anim: Arc<DBAnimationClip>, loop_mode: AnimationCurveLoopMode) -> MeshAnim {
+        MeshAnim {
+            anim,
+            loop_mode,
+            time: 0.0,
+            speed: 1.0,
+            paused: false,
+            events: Vec::new(),
+            extract_root_motion: false,
+            root_motion_translation: Vector3::zero(),
+            root_motion_rotation: Quaternion::identity(),
+        }
+    }
+
+    /// Advance playback by the given timestep, scaled by speed (negative speeds play in reverse). Does nothing while paused
+    pub fn advance(self: &mut Self, delta_time: f32) {
+        if !self.paused {
+            self.time += delta_time * self.speed;
+        }
+    }
+
+    /// Current playback position as a fraction of the clip's duration, from 0 to 1, after applying the loop mode
+    pub fn normalized_time(self: &Self) -> f32 {
+        let duration = self.anim.duration();
+        if duration <= 0.0 {
+            return 0.0;
+        }
+
+        self.loop_mode.fold_time(self.time, duration) / duration
+    }
+}
+
+/// An animation layered on top of a skinned mesh's MeshAnim, for overlays such as aim offsets or flinches
+/// Each bone is offset by how far the clip's pose at base_pose_time differs from its first frame, scaled by weight
+pub struct MeshAnimAdditive {
+    pub clip: Arc<DBAnimationClip>,
+    /// Time within the additive clip to sample the offset pose from
+    pub base_pose_time: f32,
+    /// How much of the offset to apply, where 0 leaves the base animation unchanged
+    pub weight: f32,
+}
+
+impl MeshAnimAdditive {
+    pub fn new(clip: Arc<DBAnimationClip>) -> MeshAnimAdditive {
+        MeshAnimAdditive {
+            clip,
+            base_pose_time: 0.0,
+            weight: 1.0,
+        }
+    }
+}
+
+// the animation inputs a pose was last sampled with
+struct SampledPose {
+    clip: Arc<DBAnimationClip>,
+    time: f32,
+    loop_mode: AnimationCurveLoopMode,
+    extract_root_motion: bool,
+    additive: Option<(Arc<DBAnimationClip>, f32, f32)>,
 }
 
 pub struct SkeletalPoseState {
-    pub bone_palette: Vec<Matrix4x4>
+    pub bone_palette: Vec<Matrix4x4>,
+    pub bone_matrices: Vec<Matrix4x4>,
+    /// Force the pose to be resampled on the next animation update, even if the animation hasn't changed
+    pub dirty: bool,
+    sampled: Option<SampledPose>,
+}
+
+impl SkeletalPoseState {
+    pub fn new(bone_count: usize) -> SkeletalPoseState {
+        SkeletalPoseState {
+            bone_palette: vec![Matrix4x4::identity();bone_count],
+            bone_matrices: vec![Matrix4x4::identity();bone_count],
+            dirty: true,
+            sampled: None,
+        }
+    }
+
+    /// Check whether the given animation would produce a different pose than the one last sampled
+    pub fn needs_update(self: &Self, anim: &MeshAnim, additive: Option<&MeshAnimAdditive>) -> bool {
+        if self.dirty {
+            return true;
+        }
+
+        let sampled = match &self.sampled {
+            Some(v) => v,
+            None => return true
+        };
+
+        let additive_changed = match (&sampled.additive, additive) {
+            (None, None) => false,
+            (Some((clip, base_pose_time, weight)), Some(additive)) => {
+                !Arc::ptr_eq(clip, &additive.clip) || *base_pose_time != additive.base_pose_time || *weight != additive.weight
+            }
+            _ => true
+        };
+
+        !Arc::ptr_eq(&sampled.clip, &anim.anim)
+            || sampled.time != anim.time
+            || sampled.loop_mode != anim.loop_mode
+            || sampled.extract_root_motion != anim.extract_root_motion
+            || additive_changed
+    }
+
+    /// Record the animation inputs the pose was just sampled with
+    pub fn mark_sampled(self: &mut Self, anim: &MeshAnim, additive: Option<&MeshAnimAdditive>) {
+        self.dirty = false;
+        self.sampled = Some(SampledPose {
+            clip: anim.anim.clone(),
+            time: anim.time,
+            loop_mode: anim.loop_mode,
+            extract_root_motion: anim.extract_root_motion,
+            additive: match additive {
+                Some(v) => Some((v.clip.clone(), v.base_pose_time, v.weight)),
+                None => None
+            },
+        });
+    }
+
+    /// Get the accumulated object-space transform of the given bone, as of the last animation update
+    pub fn bone_world_matrix(self: &Self, bone_index: u8) -> Matrix4x4 {
+        match self.bone_matrices.get(bone_index as usize) {
+            Some(v) => *v,
+            None => Matrix4x4::identity()
+        }
+    }
 }
\ No newline at end of file
@@ -10,4 +10,14 @@ pub mod door;
 pub mod triggerable;
 pub mod mesh;
 pub mod collider;
-pub mod light;
\ No newline at end of file
+pub mod light;
+pub mod attachment;
+pub mod areaportal;
+pub mod changelevel;
+pub mod interpolated;
+pub mod footsteps;
+pub mod explosive;
+pub mod ladder;
+pub mod ambientsound;
+pub mod gravityvolume;
+pub mod portal;
\ No newline at end of file
@@ -1,10 +1,13 @@
 #[derive(Clone, Copy)]
 pub struct PlayerInput {
+    /// Index of the gamepad this player reads input from
+    pub slot: usize,
 }
 
 impl PlayerInput {
-    pub fn new() -> PlayerInput {
+    pub fn new(slot: usize) -> PlayerInput {
         PlayerInput {
+            slot
         }
     }
 }
\ No newline at end of file
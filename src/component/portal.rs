@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use dbsdk_rs::{math::Vector3, vdp::Texture};
+use hecs::Entity;
+
+/// Renders the scene from a secondary viewpoint into a texture every frame, for security screens & the like.
+/// The texture can then be applied to any surface. Only a couple of portals render at once (see portal_system)
+pub struct Portal {
+    pub view_origin: Vector3,
+    /// Pitch, yaw, & roll of the view, in degrees
+    pub view_angles: Vector3,
+    pub target_texture: Arc<Texture>,
+    pub fov: f32,
+    /// Camera entity which renders this portal's view, managed by the portal system
+    pub camera: Option<Entity>,
+}
+
+impl Portal {
+    pub fn new(view_origin: Vector3, view_angles: Vector3, target_texture: Arc<Texture>) -> Portal {
+        Portal {
+            view_origin,
+            view_angles,
+            target_texture,
+            fov: 60.0,
+            camera: None,
+        }
+    }
+}
+
+/// Marks a camera spawned to render a portal's view
+#[derive(Clone, Copy)]
+pub struct PortalCamera {
+    pub portal: Entity,
+}
\ No newline at end of file
@@ -5,5 +5,19 @@ pub struct TriggerState {
 }
 
 pub struct TriggerLink {
-    pub target: Entity
+    /// Every entity sharing the link's target name
+    pub targets: Vec<Entity>,
+    /// Seconds to wait before passing a change in trigger state on to the target
+    pub delay: f32,
+    pub prev_triggered: bool,
+}
+
+/// Opts a target in to following its source's trigger state, so that it is also untriggered when the source is (for example, an areaportal behind a door)
+pub struct TriggerFollow;
+
+/// A change in trigger state which has been scheduled to be applied to its target at a later time
+pub struct DelayedTrigger {
+    pub target: Entity,
+    pub triggered: bool,
+    pub fire_time: f32,
 }
\ No newline at end of file
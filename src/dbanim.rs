@@ -5,10 +5,34 @@ use dbsdk_rs::{math::{Vector2, Vector3, Vector4, Quaternion}, db::log};
 
 const DBA_VER: u32 = 1;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum AnimationCurveLoopMode {
-    _Clamp,
+    Clamp,
     Repeat,
+    PingPong,
+}
+
+impl AnimationCurveLoopMode {
+    /// Fold a playback time into the 0..duration range according to this loop mode
+    pub fn fold_time(self, time: f32, duration: f32) -> f32 {
+        if duration <= 0.0 {
+            return 0.0;
+        }
+
+        match self {
+            AnimationCurveLoopMode::Clamp => {
+                time.clamp(0.0, duration)
+            }
+            AnimationCurveLoopMode::Repeat => {
+                time.rem_euclid(duration)
+            }
+            AnimationCurveLoopMode::PingPong => {
+                // reflect back at each end of the clip, so t and (2 * duration - t) sample the same pose
+                let t = time.rem_euclid(duration * 2.0);
+                if t > duration { (duration * 2.0) - t } else { t }
+            }
+        }
+    }
 }
 
 pub trait Lerp<T> where T : Clone + Copy {
@@ -120,24 +144,19 @@ impl<T> AnimationCurve<T> where T : Clone + Copy + Lerp<T> {
             return Ok(self.keyframes[0].value);
         }
 
-        if time < self.keyframes[0].time {
+        if self.duration <= 0.0 {
             return Ok(self.keyframes[0].value);
         }
 
-        let mut sample_time = time;
+        // fold time into clip bounds (also wraps negative time, for reverse playback)
+        let sample_time = loop_mode.fold_time(time, self.duration);
+
+        if sample_time < self.keyframes[0].time {
+            return Ok(self.keyframes[0].value);
+        }
 
         if sample_time >= self.duration {
-            match loop_mode {
-                AnimationCurveLoopMode::_Clamp => {
-                    return Ok(self.keyframes.last().unwrap().value);
-                },
-                AnimationCurveLoopMode::Repeat => {
-                    // loop within bounds
-                    while sample_time >= self.duration {
-                        sample_time -= self.duration;
-                    }
-                }
-            };
+            return Ok(self.keyframes.last().unwrap().value);
         }
 
         for i in 0..self.keyframes.len() {
@@ -160,6 +179,13 @@ pub struct DBAnimationChannel<T> where T : Clone + Copy + Lerp<T> {
     pub curve: AnimationCurve<T>,
 }
 
+/// A named cue at a point in time during an animation clip (for example, a footstep or a sound)
+#[derive(Clone)]
+pub struct DBAnimationEvent {
+    pub name: String,
+    pub time: f32,
+}
+
 /// Represents an animation clip loaded from a DBA file
 pub struct DBAnimationClip {
     pub channels_f32: Vec<DBAnimationChannel<f32>>,
@@ -167,7 +193,29 @@ pub struct DBAnimationClip {
     pub channels_vec3: Vec<DBAnimationChannel<Vector3>>,
     pub channels_vec4: Vec<DBAnimationChannel<Vector4>>,
     pub channels_quat: Vec<DBAnimationChannel<Quaternion>>,
-    _duration: f32,
+    /// Events sorted by time, from the optional EVNT chunk
+    pub events: Vec<DBAnimationEvent>,
+    duration: f32,
+}
+
+/// The type of value an animation channel animates
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DBAnimationChannelType {
+    F32,
+    Vec2,
+    Vec3,
+    Vec4,
+    Quat,
+}
+
+/// Describes a single channel of an animation clip, see DBAnimationClip::iter_channels
+#[derive(Clone, Copy, Debug)]
+pub struct DBAnimationChannelInfo {
+    pub channel_id: u32,
+    pub binding_id: u32,
+    pub channel_type: DBAnimationChannelType,
+    pub keyframe_count: usize,
+    pub duration: f32,
 }
 
 /// Enumeration of errors which can result from parsing a DBA animation file
@@ -175,7 +223,58 @@ pub struct DBAnimationClip {
 pub enum DBAnimationError {
     ParseError,
     VersionError,
-    IOError(std::io::Error)
+    IOError(std::io::Error),
+    /// A channel's keyframe times are out of order, or out of the clip's bounds. Contains the offending channel & binding id
+    InvalidKeyframes(u32, u32),
+}
+
+fn channel_info<T>(channel: &DBAnimationChannel<T>, channel_type: DBAnimationChannelType) -> DBAnimationChannelInfo where T : Clone + Copy + Lerp<T> {
+    DBAnimationChannelInfo {
+        channel_id: channel.channelid,
+        binding_id: channel.bindingid,
+        channel_type,
+        keyframe_count: channel.curve.keyframes.len(),
+        duration: channel.curve.duration(),
+    }
+}
+
+// count how many times playback moving from t0 to t1 passes over the given time, where the time repeats every period (if any)
+fn count_crossings(t0: f32, t1: f32, at: f32, period: Option<f32>) -> u32 {
+    let (min, max) = if t0 <= t1 { (t0, t1) } else { (t1, t0) };
+
+    match period {
+        Some(p) => {
+            (((max - at) / p).floor() - ((min - at) / p).floor()).max(0.0) as u32
+        }
+        None => {
+            if min < at && at <= max { 1 } else { 0 }
+        }
+    }
+}
+
+// keyframes must be stored in strictly increasing time order. this has to be checked as they're read, since inserting them into a curve sorts them
+fn check_keyframe_time(time: f32, prev_time: &mut f32, channel_id: u32, binding_id: u32) -> Result<(), DBAnimationError> {
+    if !time.is_finite() || time < 0.0 || time <= *prev_time {
+        return Err(DBAnimationError::InvalidKeyframes(channel_id, binding_id));
+    }
+
+    *prev_time = time;
+    Ok(())
+}
+
+// check that keyframe times are finite, strictly increasing, & within 0..duration
+fn validate_channel<T>(channel: &DBAnimationChannel<T>, duration: f32) -> Result<(), DBAnimationError> where T : Clone + Copy + Lerp<T> {
+    let mut prev_time = f32::MIN;
+
+    for keyframe in &channel.curve.keyframes {
+        if !keyframe.time.is_finite() || keyframe.time < 0.0 || keyframe.time > duration || keyframe.time <= prev_time {
+            return Err(DBAnimationError::InvalidKeyframes(channel.channelid, channel.bindingid));
+        }
+
+        prev_time = keyframe.time;
+    }
+
+    Ok(())
 }
 
 impl DBAnimationClip {
@@ -216,6 +315,8 @@ impl DBAnimationClip {
         let mut channels_vec4: Vec<DBAnimationChannel<Vector4>> = Vec::new();
         let mut channels_quat: Vec<DBAnimationChannel<Quaternion>> = Vec::new();
 
+        let mut events: Vec<DBAnimationEvent> = Vec::new();
+
         let mut duration: f32 = 0.0;
 
         // scan chunks
@@ -264,6 +365,7 @@ impl DBAnimationClip {
                     } as usize;
 
                     let mut anim_curve: AnimationCurve<f32> = AnimationCurve::new();
+                    let mut prev_time = f32::MIN;
 
                     for _ in 0..key_cnt {
                         let time = match reader.read_f32::<LittleEndian>() {
@@ -273,6 +375,8 @@ impl DBAnimationClip {
                             }
                         };
 
+                        check_keyframe_time(time, &mut prev_time, channel_id, binding_id)?;
+
                         let val = match reader.read_f32::<LittleEndian>() {
                             Ok(v) => { v },
                             Err(e) => {
@@ -313,6 +417,7 @@ impl DBAnimationClip {
                     } as usize;
 
                     let mut anim_curve: AnimationCurve<Vector2> = AnimationCurve::new();
+                    let mut prev_time = f32::MIN;
 
                     for _ in 0..key_cnt {
                         let time = match reader.read_f32::<LittleEndian>() {
@@ -322,6 +427,8 @@ impl DBAnimationClip {
                             }
                         };
 
+                        check_keyframe_time(time, &mut prev_time, channel_id, binding_id)?;
+
                         let vx = match reader.read_f32::<LittleEndian>() {
                             Ok(v) => { v },
                             Err(e) => {
@@ -369,6 +476,7 @@ impl DBAnimationClip {
                     } as usize;
 
                     let mut anim_curve: AnimationCurve<Vector3> = AnimationCurve::new();
+                    let mut prev_time = f32::MIN;
 
                     for _ in 0..key_cnt {
                         let time = match reader.read_f32::<LittleEndian>() {
@@ -378,6 +486,8 @@ impl DBAnimationClip {
                             }
                         };
 
+                        check_keyframe_time(time, &mut prev_time, channel_id, binding_id)?;
+
                         let vx = match reader.read_f32::<LittleEndian>() {
                             Ok(v) => { v },
                             Err(e) => {
@@ -432,6 +542,7 @@ impl DBAnimationClip {
                     } as usize;
 
                     let mut anim_curve: AnimationCurve<Vector4> = AnimationCurve::new();
+                    let mut prev_time = f32::MIN;
 
                     for _ in 0..key_cnt {
                         let time = match reader.read_f32::<LittleEndian>() {
@@ -441,6 +552,8 @@ impl DBAnimationClip {
                             }
                         };
 
+                        check_keyframe_time(time, &mut prev_time, channel_id, binding_id)?;
+
                         let vx = match reader.read_f32::<LittleEndian>() {
                             Ok(v) => { v },
                             Err(e) => {
@@ -502,6 +615,7 @@ impl DBAnimationClip {
                     } as usize;
 
                     let mut anim_curve: AnimationCurve<Quaternion> = AnimationCurve::new();
+                    let mut prev_time = f32::MIN;
 
                     for _ in 0..key_cnt {
                         let time = match reader.read_f32::<LittleEndian>() {
@@ -511,6 +625,8 @@ impl DBAnimationClip {
                             }
                         };
 
+                        check_keyframe_time(time, &mut prev_time, channel_id, binding_id)?;
+
                         let vx = match reader.read_f32::<LittleEndian>() {
                             Ok(v) => { v },
                             Err(e) => {
@@ -549,6 +665,51 @@ impl DBAnimationClip {
                         curve: anim_curve
                     });
                 },
+                Ok("EVNT") => {
+                    let event_cnt = match reader.read_u32::<LittleEndian>() {
+                        Ok(v) => { v },
+                        Err(e) => {
+                            return Err(DBAnimationError::IOError(e));
+                        }
+                    } as usize;
+
+                    for _ in 0..event_cnt {
+                        let time = match reader.read_f32::<LittleEndian>() {
+                            Ok(v) => { v },
+                            Err(e) => {
+                                return Err(DBAnimationError::IOError(e));
+                            }
+                        };
+
+                        let name_len = match reader.read_u32::<LittleEndian>() {
+                            Ok(v) => { v },
+                            Err(e) => {
+                                return Err(DBAnimationError::IOError(e));
+                            }
+                        } as usize;
+
+                        let mut name_bytes: Vec<u8> = vec![0;name_len];
+                        match reader.read_exact(&mut name_bytes) {
+                            Ok(_) => {
+                            },
+                            Err(e) => {
+                                return Err(DBAnimationError::IOError(e));
+                            }
+                        };
+
+                        let name = match String::from_utf8(name_bytes) {
+                            Ok(v) => { v },
+                            Err(_) => {
+                                return Err(DBAnimationError::ParseError);
+                            }
+                        };
+
+                        events.push(DBAnimationEvent {
+                            name: name,
+                            time: time
+                        });
+                    }
+                },
                 _ => {
                     // unknown chunk ID, skip
                     match reader.seek(std::io::SeekFrom::Current(chunk_size as i64)) {
@@ -562,8 +723,11 @@ impl DBAnimationClip {
             }
         }
 
+        events.sort_by(|a, b| a.time.total_cmp(&b.time));
+
         return Ok(DBAnimationClip {
-            _duration: duration,
+            events: events,
+            duration: duration,
             channels_f32: channels_f32,
             channels_vec2: channels_vec2,
             channels_vec3: channels_vec3,
@@ -572,9 +736,87 @@ impl DBAnimationClip {
         });
     }
 
-    /// Get the total duration of this animation clip
-    pub fn _duration(&self) -> f32 {
-        return self._duration;
+    /// Get the total duration of this animation clip (the duration of its longest channel)
+    pub fn duration(&self) -> f32 {
+        return self.duration;
+    }
+
+    /// Get the total number of channels in this animation clip
+    pub fn channel_count(&self) -> usize {
+        return self.channels_f32.len() + self.channels_vec2.len() + self.channels_vec3.len() + self.channels_vec4.len() + self.channels_quat.len();
+    }
+
+    /// Iterate over a description of each channel in this animation clip
+    pub fn iter_channels(&self) -> impl Iterator<Item = DBAnimationChannelInfo> + '_ {
+        self.channels_f32.iter().map(|x| channel_info(x, DBAnimationChannelType::F32))
+            .chain(self.channels_vec2.iter().map(|x| channel_info(x, DBAnimationChannelType::Vec2)))
+            .chain(self.channels_vec3.iter().map(|x| channel_info(x, DBAnimationChannelType::Vec3)))
+            .chain(self.channels_vec4.iter().map(|x| channel_info(x, DBAnimationChannelType::Vec4)))
+            .chain(self.channels_quat.iter().map(|x| channel_info(x, DBAnimationChannelType::Quat)))
+    }
+
+    /// Call the given function for each event which playback passes over when moving from time t0 to t1 (unfolded, as stored in MeshAnim::time)
+    /// Looping clips fire each event once per loop, & ping-pong clips fire each event once in each direction
+    pub fn for_each_event_crossed<F>(&self, t0: f32, t1: f32, loop_mode: AnimationCurveLoopMode, mut f: F) where F : FnMut(&DBAnimationEvent) {
+        if self.events.len() == 0 || t0 == t1 {
+            return;
+        }
+
+        let duration = self.duration.max(self.events.last().unwrap().time);
+
+        for event in &self.events {
+            let count = match loop_mode {
+                AnimationCurveLoopMode::Clamp => {
+                    count_crossings(t0.clamp(0.0, duration), t1.clamp(0.0, duration), event.time, None)
+                }
+                AnimationCurveLoopMode::Repeat if duration > 0.0 => {
+                    count_crossings(t0, t1, event.time, Some(duration))
+                }
+                AnimationCurveLoopMode::PingPong if duration > 0.0 => {
+                    // on the way back, the event is passed at the mirrored time. events at either end of the clip are only passed once per bounce
+                    let forward = count_crossings(t0, t1, event.time, Some(duration * 2.0));
+                    let mirrored = (duration * 2.0) - event.time;
+
+                    if event.time > 0.0 && event.time < duration {
+                        forward + count_crossings(t0, t1, mirrored, Some(duration * 2.0))
+                    }
+                    else {
+                        forward
+                    }
+                }
+                _ => 0
+            };
+
+            for _ in 0..count {
+                f(event);
+            }
+        }
+    }
+
+    /// Check that every channel's keyframe times are strictly increasing & within the clip's duration.
+    /// Clips loaded with new() have already had their keyframe order checked, so this is mostly useful for clips built by hand
+    pub fn validate(&self) -> Result<(), DBAnimationError> {
+        for channel in &self.channels_f32 {
+            validate_channel(channel, self.duration)?;
+        }
+
+        for channel in &self.channels_vec2 {
+            validate_channel(channel, self.duration)?;
+        }
+
+        for channel in &self.channels_vec3 {
+            validate_channel(channel, self.duration)?;
+        }
+
+        for channel in &self.channels_vec4 {
+            validate_channel(channel, self.duration)?;
+        }
+
+        for channel in &self.channels_quat {
+            validate_channel(channel, self.duration)?;
+        }
+
+        Ok(())
     }
 
     /// Get the f32 animation channel with the given channel & binding id, or none
@@ -626,4 +868,138 @@ impl DBAnimationClip {
         }
         return None;
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use byteorder::WriteBytesExt;
+
+    use super::*;
+
+    // build a DBA file containing a single f32 channel with the given (time, value) keyframes, in the given order
+    fn f32_clip_bytes(keyframes: &[(f32, f32)]) -> Vec<u8> {
+        let mut chunk = Vec::new();
+        chunk.write_u32::<LittleEndian>(0).unwrap();
+        chunk.write_u32::<LittleEndian>(7).unwrap();
+        chunk.write_u32::<LittleEndian>(keyframes.len() as u32).unwrap();
+
+        for (time, value) in keyframes {
+            chunk.write_f32::<LittleEndian>(*time).unwrap();
+            chunk.write_f32::<LittleEndian>(*value).unwrap();
+        }
+
+        let mut data = Vec::new();
+        data.extend_from_slice(b"DBA\0");
+        data.write_u32::<LittleEndian>(DBA_VER).unwrap();
+        data.extend_from_slice(b"F32\0");
+        data.write_u32::<LittleEndian>(chunk.len() as u32).unwrap();
+        data.extend(chunk);
+
+        data
+    }
+
+    #[test]
+    fn loaded_clip_reports_duration() {
+        let clip = DBAnimationClip::new(&mut Cursor::new(f32_clip_bytes(&[(0.0, 0.0), (0.5, 1.0), (1.25, 2.0)]))).unwrap();
+
+        assert_eq!(clip.duration(), 1.25);
+        assert_eq!(clip.channel_count(), 1);
+        assert!(clip.validate().is_ok());
+
+        let channels: Vec<DBAnimationChannelInfo> = clip.iter_channels().collect();
+        assert_eq!(channels[0].binding_id, 7);
+        assert_eq!(channels[0].channel_type, DBAnimationChannelType::F32);
+        assert_eq!(channels[0].keyframe_count, 3);
+    }
+
+    #[test]
+    fn rejects_out_of_order_keyframes() {
+        let result = DBAnimationClip::new(&mut Cursor::new(f32_clip_bytes(&[(0.0, 0.0), (1.0, 1.0), (0.5, 2.0)])));
+
+        match result {
+            Err(DBAnimationError::InvalidKeyframes(0, 7)) => {}
+            _ => panic!("expected out of order keyframes to be rejected")
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_keyframe_times() {
+        let result = DBAnimationClip::new(&mut Cursor::new(f32_clip_bytes(&[(0.0, 0.0), (0.5, 1.0), (0.5, 2.0)])));
+        assert!(matches!(result, Err(DBAnimationError::InvalidKeyframes(_, _))));
+    }
+
+    #[test]
+    fn reverse_playback_samples_earlier_pose() {
+        let clip = std::sync::Arc::new(DBAnimationClip::new(&mut Cursor::new(f32_clip_bytes(&[(0.0, 0.0), (1.0, 1.0)]))).unwrap());
+        let curve = &clip.channels_f32[0].curve;
+
+        let mut anim = crate::component::mesh::MeshAnim::new(clip.clone(), AnimationCurveLoopMode::Repeat);
+        anim.time = 0.75;
+        anim.speed = -1.0;
+
+        anim.advance(0.5);
+        assert_eq!(anim.time, 0.25);
+        assert_eq!(curve.sample(anim.time, anim.loop_mode), Ok(0.25));
+
+        // playing backwards past the start wraps around to the end of the clip
+        anim.advance(0.5);
+        assert_eq!(curve.sample(anim.time, anim.loop_mode), Ok(0.75));
+
+        // paused animations hold their time
+        anim.paused = true;
+        anim.advance(0.5);
+        assert_eq!(anim.time, -0.25);
+    }
+
+    #[test]
+    fn ping_pong_reflects_at_clip_ends() {
+        let clip = DBAnimationClip::new(&mut Cursor::new(f32_clip_bytes(&[(0.0, 0.0), (0.5, 2.0), (2.0, 1.0)]))).unwrap();
+        let curve = &clip.channels_f32[0].curve;
+        let duration = clip.duration();
+
+        for t in [0.0, 0.25, 0.5, 1.3, 2.0] {
+            let forward = curve.sample(t, AnimationCurveLoopMode::PingPong).unwrap();
+            let back = curve.sample((2.0 * duration) - t, AnimationCurveLoopMode::PingPong).unwrap();
+            assert!((forward - back).abs() < 0.0001, "t = {}: {} != {}", t, forward, back);
+        }
+
+        // continuous across the fold points
+        let eps = 0.001;
+        for fold in [duration, 2.0 * duration] {
+            let before = curve.sample(fold - eps, AnimationCurveLoopMode::PingPong).unwrap();
+            let after = curve.sample(fold + eps, AnimationCurveLoopMode::PingPong).unwrap();
+            assert!((before - after).abs() < 0.01, "snap at {}: {} != {}", fold, before, after);
+        }
+    }
+
+    // names of the events passed moving from t0 to t1, in the order they're fired
+    fn events_crossed(clip: &DBAnimationClip, t0: f32, t1: f32, loop_mode: AnimationCurveLoopMode) -> Vec<String> {
+        let mut events = Vec::new();
+        clip.for_each_event_crossed(t0, t1, loop_mode, |event| events.push(event.name.clone()));
+        events
+    }
+
+    #[test]
+    fn each_event_passed_fires_once() {
+        let mut clip = DBAnimationClip::new(&mut Cursor::new(f32_clip_bytes(&[(0.0, 0.0), (1.0, 1.0)]))).unwrap();
+        clip.events = vec![
+            DBAnimationEvent { name: "step_l".to_owned(), time: 0.25 },
+            DBAnimationEvent { name: "step_r".to_owned(), time: 0.75 },
+        ];
+
+        assert_eq!(events_crossed(&clip, 0.1, 0.3, AnimationCurveLoopMode::Repeat), vec!["step_l"]);
+        assert!(events_crossed(&clip, 0.3, 0.3, AnimationCurveLoopMode::Repeat).is_empty());
+        assert!(events_crossed(&clip, 0.3, 0.7, AnimationCurveLoopMode::Repeat).is_empty());
+
+        // wrapping around the end of the loop passes the first event again
+        assert_eq!(events_crossed(&clip, 0.9, 1.3, AnimationCurveLoopMode::Repeat), vec!["step_l"]);
+
+        // a long frame can pass an event once per loop
+        assert_eq!(events_crossed(&clip, 0.8, 2.3, AnimationCurveLoopMode::Repeat), vec!["step_l", "step_l", "step_r"]);
+
+        // clamped clips stop at the end instead of wrapping
+        assert!(events_crossed(&clip, 0.9, 1.3, AnimationCurveLoopMode::Clamp).is_empty());
+    }
 }
\ No newline at end of file
@@ -1,12 +1,26 @@
 use std::{io::{Read, Seek, ErrorKind}, ffi::CStr, str::FromStr, sync::Arc};
 
 use byteorder::{ReadBytesExt, LittleEndian};
-use dbsdk_rs::{io::IOError, math::{Matrix4x4, Quaternion, Vector3, Vector4}, vdp::Texture};
+use dbsdk_rs::{io::IOError, math::{Matrix4x4, Quaternion, Vector2, Vector3, Vector4}, vdp::{Color32, Texture, TextureFilter, TextureWrap}};
 use half::f16;
 
 use crate::asset_loader::ResourceError;
 
-const DBM_VER: u32 = 1;
+const DBM_VER: u32 = 3;
+
+// older version of the format which has no texture sampling flags in materials
+const DBM_VER_NO_SAMPLER: u32 = 2;
+
+// older version of the format which only stores two bone influences per vertex
+const DBM_VER_2WEIGHT: u32 = 1;
+
+// material sampler flags
+const SAMPLER_NEAREST: u8 = 1;
+const SAMPLER_CLAMP_U: u8 = 2;
+const SAMPLER_CLAMP_V: u8 = 4;
+
+/// Maximum number of bones which may influence a single vertex
+pub const MAX_BONE_INFLUENCES: usize = 4;
 
 /// Represents a skeleton loaded from DBM mesh file
 pub struct DBSkeleton {
@@ -29,8 +43,46 @@ pub struct DBMeshVertex {
     pub nrm: [f16;3],
     pub col: [u8;4],
     pub tex: [f16;2],
-    pub bweight: [u8;2],
-    pub bidx: [u8;2],
+    pub bweight: [u8;MAX_BONE_INFLUENCES],
+    pub bidx: [u8;MAX_BONE_INFLUENCES],
+}
+
+/// A mesh vertex unpacked into the layout consumed by the lit mesh VU program
+#[derive(Clone, Copy)]
+pub struct ModelVertex {
+    pub position: Vector4,
+    pub normal: Vector4,
+    pub texcoord: Vector2,
+    pub color: Color32
+}
+
+impl ModelVertex {
+    pub fn new(position: Vector4, normal: Vector4, texcoord: Vector2, color: Color32) -> ModelVertex {
+        ModelVertex { position, normal, texcoord, color }
+    }
+
+    /// Convert a packed DBM vertex into a GPU vertex
+    pub fn unpack(vertex: &DBMeshVertex) -> ModelVertex {
+        let vtx = Vector4::new(vertex.pos[0].to_f32(), vertex.pos[1].to_f32(), vertex.pos[2].to_f32(), 1.0);
+        let nrm = Vector4::new(vertex.nrm[0].to_f32(), vertex.nrm[1].to_f32(), vertex.nrm[2].to_f32(), 1.0);
+
+        ModelVertex::new(
+            vtx,
+            nrm,
+            Vector2::new(vertex.tex[0].to_f32(), vertex.tex[1].to_f32()),
+            Color32::new(vertex.col[0], vertex.col[1], vertex.col[2], vertex.col[3]))
+    }
+}
+
+/// Lighting model used to shade a material
+#[derive(Clone, Copy, PartialEq)]
+pub enum MaterialShading {
+    /// Ignore lighting, drawing with just the diffuse color
+    Unlit,
+    /// Diffuse lighting only
+    Flat,
+    /// Diffuse lighting plus a specular highlight from the dominant light
+    Specular,
 }
 
 /// Represents a material loaded from DBM mesh file
@@ -42,20 +94,41 @@ pub struct DBMaterialInfo {
     pub diffuse_color: Vector4,
     pub spec_color: Vector3,
     pub roughness: f32,
+    /// Lighting model to draw with. Materials with a specular color default to Specular, others to Flat
+    pub shading: MaterialShading,
+    /// Texture sampling parameters (linear filtering & repeat wrapping unless the material says otherwise)
+    pub filter: TextureFilter,
+    pub wrap_u: TextureWrap,
+    pub wrap_v: TextureWrap,
 }
 
 /// Represents a mesh part loaded from DBM mesh file
+/// If indices is non-empty, vertices is a pool of unique vertices referenced by indices (three per triangle)
+/// Otherwise, vertices is a plain triangle list
 pub struct DBMeshPart {
     pub name: String,
+    /// Level of detail this part belongs to (0 = full detail)
+    pub lod: usize,
     pub transform: Matrix4x4,
     pub material: DBMaterialInfo,
     pub vertices: Vec<DBMeshVertex>,
+    /// Vertices unpacked into GPU format at load time, so static meshes don't need to convert them every frame
+    pub gpu_vertices: Vec<ModelVertex>,
+    pub indices: Vec<u16>,
+    /// Mesh-space bounding box of this part's vertices (in bind pose, for skinned meshes)
+    pub bounds_min: Vector3,
+    pub bounds_max: Vector3,
 }
 
 /// A mesh loaded from DBM mesh file
 pub struct DBMesh {
     pub mesh_parts: Vec<DBMeshPart>,
     pub skeleton: Option<DBSkeleton>,
+    /// Number of levels of detail present in the mesh (always at least 1)
+    pub lod_count: usize,
+    /// Local-space bounding box of all mesh parts (in bind pose, for skinned meshes)
+    pub bounds_min: Vector3,
+    pub bounds_max: Vector3,
 }
 
 /// Enumeration of errors which can result from parsing a DBM mesh file
@@ -79,6 +152,30 @@ unsafe fn str_from_null_terminated_utf8(s: &[u8]) -> &str {
     CStr::from_ptr(s.as_ptr() as *const _).to_str().unwrap()
 }
 
+// rescale bone weights so that they sum to 255
+fn normalize_weights(weights: &mut [u8;MAX_BONE_INFLUENCES]) {
+    let sum: u32 = weights.iter().map(|w| *w as u32).sum();
+
+    if sum == 0 || sum == 255 {
+        return;
+    }
+
+    let mut total: u32 = 0;
+    let mut largest = 0;
+
+    for i in 0..MAX_BONE_INFLUENCES {
+        weights[i] = ((weights[i] as u32 * 255) / sum) as u8;
+        total += weights[i] as u32;
+
+        if weights[i] > weights[largest] {
+            largest = i;
+        }
+    }
+
+    // give any rounding error to the most influential bone
+    weights[largest] = (weights[largest] as u32 + (255 - total)) as u8;
+}
+
 fn read_skel_node<R>(reader: &mut R) -> Result<Option<DBSkelNode>,DBMeshError> where R : Read {
     // read inverse bind mat
     let mut inv_bind_mat = Matrix4x4::identity();
@@ -146,6 +243,23 @@ fn read_skel_node<R>(reader: &mut R) -> Result<Option<DBSkelNode>,DBMeshError> w
     return Ok(Some(DBSkelNode { bone_index: bone_index, inv_bind_pose: inv_bind_mat, local_rest_pose: local_rest_mat, children: children }));
 }
 
+fn validate_skel_node(node: &DBSkelNode, seen: &mut [bool]) -> Result<(), DBMeshError> {
+    match seen.get_mut(node.bone_index as usize) {
+        Some(v) if !*v => {
+            *v = true;
+        }
+        _ => {
+            return Err(DBMeshError::ParseError);
+        }
+    };
+
+    for child in &node.children {
+        validate_skel_node(child, seen)?;
+    }
+
+    return Ok(());
+}
+
 impl DBMesh {
     pub fn new<R,TL>(reader: &mut R, tex_load_fn: TL) -> Result<DBMesh, DBMeshError>
         where R : Read + Seek,
@@ -176,15 +290,21 @@ impl DBMesh {
             }
         };
 
-        if ver != DBM_VER {
+        if ver != DBM_VER && ver != DBM_VER_NO_SAMPLER && ver != DBM_VER_2WEIGHT {
             return Err(DBMeshError::VersionError);
         }
 
         let mut mesh = DBMesh {
             mesh_parts: Vec::new(),
             skeleton: None,
+            lod_count: 1,
+            bounds_min: Vector3::zero(),
+            bounds_max: Vector3::zero(),
         };
 
+        // LOD level which subsequent mesh parts belong to
+        let mut cur_lod = 0;
+
         // scan chunks
         loop {
             let mut chunk_id: [u8;4] = [0;4];
@@ -237,6 +357,29 @@ impl DBMesh {
 
                     mesh.skeleton = Some(skeleton);
                 },
+                Ok("LOD ") => {
+                    // all mesh parts following this chunk belong to the given LOD level
+                    if chunk_size < 4 {
+                        return Err(DBMeshError::ParseError);
+                    }
+
+                    cur_lod = match reader.read_u32::<LittleEndian>() {
+                        Ok(v) => { v as usize },
+                        Err(_) => {
+                            return Err(DBMeshError::ParseError);
+                        }
+                    };
+
+                    match reader.seek(std::io::SeekFrom::Current(chunk_size as i64 - 4)) {
+                        Ok(_) => {
+                        },
+                        Err(_) => {
+                            return Err(DBMeshError::ParseError);
+                        }
+                    }
+
+                    mesh.lod_count = mesh.lod_count.max(cur_lod.saturating_add(1));
+                },
                 Ok("MESH") => {
                     // append a new mesh part from chunk
                     let mut mesh_name: [u8;32] = [0;32];
@@ -374,6 +517,17 @@ impl DBMesh {
                         }
                     };
 
+                    let sampler_flags = if ver == DBM_VER {
+                        match reader.read_u8() {
+                            Ok(v) => { v }
+                            Err(_) => {
+                                return Err(DBMeshError::ParseError);
+                            }
+                        }
+                    } else {
+                        0
+                    };
+
                     let mat_name = String::from_str(str_from_null_terminated_utf8_safe(&mat_name)).unwrap();
 
                     let texture: Option<Arc<Texture>> = if mat_has_texture {
@@ -397,6 +551,8 @@ impl DBMesh {
                         None
                     };
 
+                    let shading = if spec_color.iter().any(|&c| c > 0) { MaterialShading::Specular } else { MaterialShading::Flat };
+
                     let mat_info = DBMaterialInfo {
                         name: mat_name,
                         texture: texture,
@@ -405,6 +561,10 @@ impl DBMesh {
                         diffuse_color: Vector4::new((diffuse_color[0] as f32) / 255.0, (diffuse_color[1] as f32) / 255.0, (diffuse_color[2] as f32) / 255.0, (diffuse_color[3] as f32) / 255.0),
                         spec_color: Vector3::new((spec_color[0] as f32) / 255.0, (spec_color[1] as f32) / 255.0, (spec_color[2] as f32) / 255.0),
                         roughness: (roughness as f32) / 255.0,
+                        shading: shading,
+                        filter: if sampler_flags & SAMPLER_NEAREST != 0 { TextureFilter::Nearest } else { TextureFilter::Linear },
+                        wrap_u: if sampler_flags & SAMPLER_CLAMP_U != 0 { TextureWrap::Clamp } else { TextureWrap::Repeat },
+                        wrap_v: if sampler_flags & SAMPLER_CLAMP_V != 0 { TextureWrap::Clamp } else { TextureWrap::Repeat },
                     };
 
                     let mut mesh_vertices: Vec<DBMeshVertex> = Vec::new();
@@ -474,21 +634,26 @@ impl DBMesh {
                                 return Err(DBMeshError::ParseError);
                             }
                         };
-                        let mut bw: [u8;2] = [0;2];
-                        match reader.read_exact(&mut bw) {
+                        // v1 meshes store two bone influences per vertex, v2 meshes store four
+                        let num_influences = if ver == DBM_VER_2WEIGHT { 2 } else { MAX_BONE_INFLUENCES };
+
+                        let mut bw: [u8;MAX_BONE_INFLUENCES] = [0;MAX_BONE_INFLUENCES];
+                        match reader.read_exact(&mut bw[0..num_influences]) {
                             Ok(_) => {},
                             Err(_) => {
                                 return Err(DBMeshError::ParseError);
                             }
                         };
-                        let mut bi: [u8;2] = [0;2];
-                        match reader.read_exact(&mut bi) {
+                        let mut bi: [u8;MAX_BONE_INFLUENCES] = [0;MAX_BONE_INFLUENCES];
+                        match reader.read_exact(&mut bi[0..num_influences]) {
                             Ok(_) => {},
                             Err(_) => {
                                 return Err(DBMeshError::ParseError);
                             }
                         };
 
+                        normalize_weights(&mut bw);
+
                         mesh_vertices.push(DBMeshVertex {
                             pos: [px, py, pz],
                             nrm: [nx, ny, nz],
@@ -501,13 +666,56 @@ impl DBMesh {
 
                     let mesh_part = DBMeshPart {
                         name: String::from_str(str_from_null_terminated_utf8_safe(&mesh_name)).unwrap(),
+                        lod: cur_lod,
                         transform: transform,
                         material: mat_info,
-                        vertices: mesh_vertices
+                        gpu_vertices: mesh_vertices.iter().map(ModelVertex::unpack).collect(),
+                        vertices: mesh_vertices,
+                        indices: Vec::new(),
+                        bounds_min: Vector3::zero(),
+                        bounds_max: Vector3::zero(),
                     };
 
                     mesh.mesh_parts.push(mesh_part);
                 },
+                Ok("IDX ") => {
+                    // optional index list for the preceding mesh part
+                    let mesh_part = match mesh.mesh_parts.last_mut() {
+                        Some(v) => v,
+                        None => {
+                            return Err(DBMeshError::ParseError);
+                        }
+                    };
+
+                    // an odd size would leave a stray byte which misaligns every chunk after this one
+                    if chunk_size % 2 != 0 {
+                        return Err(DBMeshError::ParseError);
+                    }
+
+                    let idx_count = (chunk_size / 2) as usize;
+                    let mut indices: Vec<u16> = Vec::with_capacity(idx_count);
+
+                    for _ in 0..idx_count {
+                        let idx = match reader.read_u16::<LittleEndian>() {
+                            Ok(v) => { v },
+                            Err(_) => {
+                                return Err(DBMeshError::ParseError);
+                            }
+                        };
+
+                        if idx as usize >= mesh_part.vertices.len() {
+                            return Err(DBMeshError::ParseError);
+                        }
+
+                        indices.push(idx);
+                    }
+
+                    if indices.len() % 3 != 0 {
+                        return Err(DBMeshError::ParseError);
+                    }
+
+                    mesh_part.indices = indices;
+                },
                 _ => {
                     // unknown chunk ID, skip
                     match reader.seek(std::io::SeekFrom::Current(chunk_size as i64)) {
@@ -521,6 +729,334 @@ impl DBMesh {
             };
         }
 
+        // every LOD level up to the last one must have at least one mesh part, otherwise selecting it would draw nothing
+        if mesh.lod_count > 1 {
+            if mesh.lod_count > mesh.mesh_parts.len() {
+                return Err(DBMeshError::ParseError);
+            }
+
+            for lod in 0..mesh.lod_count {
+                if !mesh.mesh_parts.iter().any(|x| x.lod == lod) {
+                    return Err(DBMeshError::ParseError);
+                }
+            }
+        }
+
+        mesh.validate()?;
+        mesh.calc_bounds();
+
         return Ok(mesh);
     }
+
+    // make sure bone indices can't reach outside of the bone palette at draw time
+    fn validate(self: &Self) -> Result<(), DBMeshError> {
+        let skeleton = match &self.skeleton {
+            Some(v) => v,
+            None => {
+                return Ok(());
+            }
+        };
+
+        // each bone must be referenced by the node tree at most once
+        let mut seen: Vec<bool> = vec![false;skeleton.bone_count as usize];
+        for node in &skeleton.nodes {
+            validate_skel_node(node, &mut seen)?;
+        }
+
+        for part in &self.mesh_parts {
+            for vertex in &part.vertices {
+                if vertex.bidx.iter().any(|&bi| bi as u32 >= skeleton.bone_count) {
+                    return Err(DBMeshError::ParseError);
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    // calculate a tight bounding box around the vertices of each mesh part, & around all mesh parts
+    fn calc_bounds(self: &mut Self) {
+        let mut bounds_min = Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+        let mut bounds_max = Vector3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+
+        for part in &mut self.mesh_parts {
+            let mut part_min = Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+            let mut part_max = Vector3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+
+            for vertex in &part.gpu_vertices {
+                let pos = part.transform * vertex.position;
+
+                part_min = Vector3::new(part_min.x.min(pos.x), part_min.y.min(pos.y), part_min.z.min(pos.z));
+                part_max = Vector3::new(part_max.x.max(pos.x), part_max.y.max(pos.y), part_max.z.max(pos.z));
+            }
+
+            if part_min.x > part_max.x {
+                part_min = Vector3::zero();
+                part_max = Vector3::zero();
+            }
+            else {
+                bounds_min = Vector3::new(bounds_min.x.min(part_min.x), bounds_min.y.min(part_min.y), bounds_min.z.min(part_min.z));
+                bounds_max = Vector3::new(bounds_max.x.max(part_max.x), bounds_max.y.max(part_max.y), bounds_max.z.max(part_max.z));
+            }
+
+            part.bounds_min = part_min;
+            part.bounds_max = part_max;
+        }
+
+        // empty meshes get empty bounds
+        if bounds_min.x > bounds_max.x {
+            bounds_min = Vector3::zero();
+            bounds_max = Vector3::zero();
+        }
+
+        self.bounds_min = bounds_min;
+        self.bounds_max = bounds_max;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::component::mesh::Mesh;
+
+    // serialize a mesh from a list of chunks
+    fn dbm_bytes(chunks: &[(&[u8;4], Vec<u8>)]) -> Vec<u8> {
+        dbm_bytes_ver(DBM_VER, chunks)
+    }
+
+    fn dbm_bytes_ver(ver: u32, chunks: &[(&[u8;4], Vec<u8>)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(b"DBM\0");
+        out.extend_from_slice(&ver.to_le_bytes());
+
+        for (id, data) in chunks {
+            out.extend_from_slice(*id);
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            out.extend_from_slice(data);
+        }
+
+        out
+    }
+
+    // an untextured mesh part with the given number of triangles, whose vertices are all weighted to the given bone
+    fn mesh_chunk(tri_count: u16, bone: u8) -> Vec<u8> {
+        mesh_chunk_weights(tri_count, &[255, 0, 0, 0], &[bone, 0, 0, 0])
+    }
+
+    // an untextured mesh part with the given number of triangles, whose vertices all have the given bone weights & indices (as many as the file version stores)
+    fn mesh_chunk_weights(tri_count: u16, weights: &[u8], bones: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&[0;32]);
+
+        // translation, rotation, scale
+        for v in [0.0f32, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+
+        // material name, has texture, blend, culling, diffuse, specular, roughness, sampler flags
+        out.extend_from_slice(&[0;32]);
+        out.extend_from_slice(&[0, 0, 1]);
+        out.extend_from_slice(&[255, 255, 255, 255]);
+        out.extend_from_slice(&[0, 0, 0]);
+        out.extend_from_slice(&[0, 0]);
+
+        out.extend_from_slice(&tri_count.to_le_bytes());
+        for i in 0..(tri_count as usize * 3) {
+            let pos = [i as f32, (i % 3) as f32, 0.0];
+            for v in pos.iter().chain([0.0f32, 0.0, 1.0].iter()) {
+                out.extend_from_slice(&f16::from_f32(*v).to_bits().to_le_bytes());
+            }
+            out.extend_from_slice(&[255, 255, 255, 255]);
+            out.extend_from_slice(&f16::from_f32(0.0).to_bits().to_le_bytes());
+            out.extend_from_slice(&f16::from_f32(0.0).to_bits().to_le_bytes());
+            out.extend_from_slice(weights);
+            out.extend_from_slice(bones);
+        }
+
+        out
+    }
+
+    fn idx_chunk(indices: &[u16]) -> Vec<u8> {
+        indices.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    fn load(bytes: &[u8]) -> Result<DBMesh, DBMeshError> {
+        let mut reader = std::io::Cursor::new(bytes);
+        DBMesh::new(&mut reader, |_| Err(ResourceError::ParseError))
+    }
+
+    #[test]
+    fn index_chunk_is_read_into_preceding_part() {
+        let bytes = dbm_bytes(&[(b"MESH", mesh_chunk(2, 0)), (b"IDX ", idx_chunk(&[0, 1, 2, 2, 1, 0]))]);
+        let mesh = load(&bytes).unwrap();
+
+        assert_eq!(mesh.mesh_parts.len(), 1);
+        assert_eq!(mesh.mesh_parts[0].indices, vec![0, 1, 2, 2, 1, 0]);
+    }
+
+    #[test]
+    fn odd_sized_index_chunk_is_rejected() {
+        let mut indices = idx_chunk(&[0, 1, 2]);
+        indices.push(0);
+
+        let bytes = dbm_bytes(&[(b"MESH", mesh_chunk(1, 0)), (b"IDX ", indices)]);
+        assert!(matches!(load(&bytes), Err(DBMeshError::ParseError)));
+    }
+
+    #[test]
+    fn lod_chunks_assign_following_parts() {
+        let bytes = dbm_bytes(&[
+            (b"MESH", mesh_chunk(2, 0)),
+            (b"LOD ", 1u32.to_le_bytes().to_vec()),
+            (b"MESH", mesh_chunk(1, 0)),
+        ]);
+        let mesh = load(&bytes).unwrap();
+
+        assert_eq!(mesh.lod_count, 2);
+        assert_eq!(mesh.mesh_parts.iter().map(|x| x.lod).collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    #[test]
+    fn missing_lod_level_is_rejected() {
+        let bytes = dbm_bytes(&[
+            (b"MESH", mesh_chunk(2, 0)),
+            (b"LOD ", 2u32.to_le_bytes().to_vec()),
+            (b"MESH", mesh_chunk(1, 0)),
+        ]);
+        assert!(matches!(load(&bytes), Err(DBMeshError::ParseError)));
+
+        let bytes = dbm_bytes(&[
+            (b"MESH", mesh_chunk(2, 0)),
+            (b"LOD ", u32::MAX.to_le_bytes().to_vec()),
+            (b"MESH", mesh_chunk(1, 0)),
+        ]);
+        assert!(matches!(load(&bytes), Err(DBMeshError::ParseError)));
+    }
+
+    // a skeleton chunk with a root node (identity matrices, no children) for each of the given bone indices
+    fn skel_chunk(bones: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        for bone in bones {
+            for _ in 0..2 {
+                for j in 0..4 {
+                    for i in 0..4 {
+                        out.extend_from_slice(&(if i == j { 1.0f32 } else { 0.0 }).to_le_bytes());
+                    }
+                }
+            }
+
+            out.extend_from_slice(&[*bone, 0]);
+        }
+
+        out
+    }
+
+    #[test]
+    fn skinned_mesh_with_valid_bone_indices_loads() {
+        let bytes = dbm_bytes(&[(b"SKEL", skel_chunk(&[0, 1])), (b"MESH", mesh_chunk(1, 1))]);
+        let mesh = load(&bytes).unwrap();
+
+        assert_eq!(mesh.skeleton.as_ref().unwrap().bone_count, 2);
+    }
+
+    #[test]
+    fn out_of_range_bone_index_is_rejected() {
+        let bytes = dbm_bytes(&[(b"SKEL", skel_chunk(&[0, 1])), (b"MESH", mesh_chunk(1, 2))]);
+        assert!(matches!(load(&bytes), Err(DBMeshError::ParseError)));
+    }
+
+    #[test]
+    fn bone_referenced_twice_by_skeleton_is_rejected() {
+        let bytes = dbm_bytes(&[(b"SKEL", skel_chunk(&[0, 0])), (b"MESH", mesh_chunk(1, 0))]);
+        assert!(matches!(load(&bytes), Err(DBMeshError::ParseError)));
+    }
+
+    #[test]
+    fn bone_weights_are_normalized_to_255() {
+        let mut weights = [100, 100, 0, 0];
+        normalize_weights(&mut weights);
+        assert_eq!(weights, [128, 127, 0, 0]);
+
+        // rounding error goes to the most influential bone
+        let mut weights = [25, 50, 25, 0];
+        normalize_weights(&mut weights);
+        assert_eq!(weights, [63, 129, 63, 0]);
+
+        // unweighted vertices are left alone
+        let mut weights = [0, 0, 0, 0];
+        normalize_weights(&mut weights);
+        assert_eq!(weights, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn current_version_reads_four_influences() {
+        let bytes = dbm_bytes(&[(b"SKEL", skel_chunk(&[0, 1, 2, 3])), (b"MESH", mesh_chunk_weights(1, &[64, 64, 64, 63], &[0, 1, 2, 3]))]);
+        let mesh = load(&bytes).unwrap();
+
+        let vertex = &mesh.mesh_parts[0].vertices[0];
+        assert_eq!(vertex.bweight, [64, 64, 64, 63]);
+        assert_eq!(vertex.bidx, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn two_weight_version_reads_two_influences() {
+        let bytes = dbm_bytes_ver(DBM_VER_2WEIGHT, &[(b"SKEL", skel_chunk(&[0, 1])), (b"MESH", mesh_chunk_weights(1, &[128, 127], &[1, 0]))]);
+        let mesh = load(&bytes).unwrap();
+
+        assert_eq!(mesh.mesh_parts[0].vertices.len(), 3);
+
+        let vertex = &mesh.mesh_parts[0].vertices[0];
+        assert_eq!(vertex.bweight, [128, 127, 0, 0]);
+        assert_eq!(vertex.bidx, [1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn bounds_enclose_all_vertices() {
+        let bytes = dbm_bytes(&[(b"MESH", mesh_chunk(2, 0)), (b"MESH", mesh_chunk(1, 0))]);
+        let mesh = load(&bytes).unwrap();
+
+        assert_eq!((mesh.bounds_min.x, mesh.bounds_min.y, mesh.bounds_min.z), (0.0, 0.0, 0.0));
+        assert_eq!((mesh.bounds_max.x, mesh.bounds_max.y, mesh.bounds_max.z), (5.0, 2.0, 0.0));
+    }
+
+    #[test]
+    fn empty_mesh_has_empty_bounds() {
+        let mesh = load(&dbm_bytes(&[])).unwrap();
+
+        assert_eq!((mesh.bounds_min.x, mesh.bounds_min.y, mesh.bounds_min.z), (0.0, 0.0, 0.0));
+        assert_eq!((mesh.bounds_max.x, mesh.bounds_max.y, mesh.bounds_max.z), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn mesh_component_is_centered_on_bounds() {
+        let bytes = dbm_bytes(&[(b"MESH", mesh_chunk(2, 0))]);
+        let mesh = Mesh::from(Arc::new(load(&bytes).unwrap())).with_bounds_margin(1.0);
+
+        assert_eq!((mesh.bounds_offset.x, mesh.bounds_offset.y, mesh.bounds_offset.z), (2.5, 1.0, 0.0));
+        assert_eq!((mesh.bounds_extents.x, mesh.bounds_extents.y, mesh.bounds_extents.z), (3.5, 2.0, 1.0));
+    }
+
+    #[test]
+    fn gpu_vertices_match_unpacked_vertices() {
+        let bytes = dbm_bytes(&[(b"MESH", mesh_chunk(2, 0))]);
+        let mesh = load(&bytes).unwrap();
+        let part = &mesh.mesh_parts[0];
+
+        assert_eq!(part.gpu_vertices.len(), part.vertices.len());
+
+        for (i, (vertex, gpu_vertex)) in part.vertices.iter().zip(&part.gpu_vertices).enumerate() {
+            // same result as unpacking each vertex when it's drawn
+            let unpacked = ModelVertex::unpack(vertex);
+            let p = gpu_vertex.position;
+            let n = gpu_vertex.normal;
+
+            assert_eq!((p.x, p.y, p.z, p.w), (unpacked.position.x, unpacked.position.y, unpacked.position.z, unpacked.position.w));
+            assert_eq!((n.x, n.y, n.z, n.w), (unpacked.normal.x, unpacked.normal.y, unpacked.normal.z, unpacked.normal.w));
+            assert_eq!((gpu_vertex.texcoord.x, gpu_vertex.texcoord.y), (unpacked.texcoord.x, unpacked.texcoord.y));
+            assert_eq!((gpu_vertex.color.r, gpu_vertex.color.g, gpu_vertex.color.b, gpu_vertex.color.a), (unpacked.color.r, unpacked.color.g, unpacked.color.b, unpacked.color.a));
+
+            assert_eq!((p.x, p.y, p.z, p.w), (i as f32, (i % 3) as f32, 0.0, 1.0));
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,237 @@
+use dbsdk_rs::{math::{Matrix4x4, Vector2, Vector3, Vector4}, vdp::{self, Color32, Texture, TextureUnit}};
+
+use crate::{bsp_file::{BspFile, MASK_SOLID}, bsp_renderer::{self, BspMapRenderer, MapVertex}, common};
+
+const CIRCLE_SEGMENTS: usize = 24;
+
+// only brushes in leaves within this distance of the camera are reconstructed & drawn
+const BRUSH_DRAW_RADIUS: f32 = 256.0;
+
+/// Debug visualization of map, lighting, & collision data, drawn on top of each camera's view
+pub struct DebugDraw {
+    /// Draw the edges of every face in visible leaves
+    pub wireframe: bool,
+    /// Draw the bounding box of every visible leaf
+    pub leaf_bounds: bool,
+    /// Draw the radius of every visible light
+    pub light_radii: bool,
+    /// Draw translucent solid brushes near the camera, as seen by collision traces
+    pub collision_brushes: bool,
+    geo_buff: Vec<MapVertex>,
+    tri_buff: Vec<MapVertex>,
+    drawn_faces: Vec<bool>,
+    drawn_brushes: Vec<bool>,
+}
+
+fn push_line(geo: &mut Vec<MapVertex>, a: &Vector3, b: &Vector3, col: Color32) {
+    geo.push(MapVertex::new(Vector4::new(a.x, a.y, a.z, 1.0), Vector2::zero(), Vector2::zero(), col));
+    geo.push(MapVertex::new(Vector4::new(b.x, b.y, b.z, 1.0), Vector2::zero(), Vector2::zero(), col));
+}
+
+fn push_box(geo: &mut Vec<MapVertex>, min: &Vector3, max: &Vector3, col: Color32) {
+    let c = [
+        Vector3::new(min.x, min.y, min.z),
+        Vector3::new(max.x, min.y, min.z),
+        Vector3::new(min.x, max.y, min.z),
+        Vector3::new(max.x, max.y, min.z),
+        Vector3::new(min.x, min.y, max.z),
+        Vector3::new(max.x, min.y, max.z),
+        Vector3::new(min.x, max.y, max.z),
+        Vector3::new(max.x, max.y, max.z),
+    ];
+
+    for (a, b) in [(0, 1), (2, 3), (0, 2), (1, 3), (4, 5), (6, 7), (4, 6), (5, 7), (0, 4), (1, 5), (2, 6), (3, 7)] {
+        push_line(geo, &c[a], &c[b], col);
+    }
+}
+
+// pick a stable, distinct color for each brush
+fn brush_color(brush_index: usize) -> Color32 {
+    let h = (brush_index as u32).wrapping_mul(2654435761);
+    Color32::new(((h >> 24) as u8) | 64, ((h >> 16) as u8) | 64, ((h >> 8) as u8) | 64, 96)
+}
+
+fn push_circle(geo: &mut Vec<MapVertex>, center: &Vector3, axis_a: &Vector3, axis_b: &Vector3, radius: f32, col: Color32) {
+    for i in 0..CIRCLE_SEGMENTS {
+        let a0 = (i as f32 / CIRCLE_SEGMENTS as f32) * std::f32::consts::PI * 2.0;
+        let a1 = ((i + 1) as f32 / CIRCLE_SEGMENTS as f32) * std::f32::consts::PI * 2.0;
+
+        let p0 = *center + (*axis_a * (a0.cos() * radius)) + (*axis_b * (a0.sin() * radius));
+        let p1 = *center + (*axis_a * (a1.cos() * radius)) + (*axis_b * (a1.sin() * radius));
+
+        push_line(geo, &p0, &p1, col);
+    }
+}
+
+impl DebugDraw {
+    pub fn new() -> DebugDraw {
+        DebugDraw {
+            wireframe: false,
+            leaf_bounds: false,
+            light_radii: false,
+            collision_brushes: false,
+            geo_buff: Vec::with_capacity(1024),
+            tri_buff: Vec::with_capacity(1024),
+            drawn_faces: Vec::new(),
+            drawn_brushes: Vec::new(),
+        }
+    }
+
+    pub fn any_enabled(self: &Self) -> bool {
+        self.wireframe || self.leaf_bounds || self.light_radii || self.collision_brushes
+    }
+
+    /// Step to the next visualization: off, wireframe, leaf bounds, light radii, collision brushes, then back to off
+    pub fn cycle(self: &mut Self) {
+        let (wireframe, leaf_bounds, light_radii, collision_brushes) = if !self.any_enabled() {
+            (true, false, false, false)
+        }
+        else if self.wireframe {
+            (false, true, false, false)
+        }
+        else if self.leaf_bounds {
+            (false, false, true, false)
+        }
+        else if self.light_radii {
+            (false, false, false, true)
+        }
+        else {
+            (false, false, false, false)
+        };
+
+        self.wireframe = wireframe;
+        self.leaf_bounds = leaf_bounds;
+        self.light_radii = light_radii;
+        self.collision_brushes = collision_brushes;
+    }
+
+    /// Draw enabled visualizations for the given camera. Sets up its own VU program & render state, so it is safe to call regardless of what was drawn before
+    pub fn draw(self: &mut Self, bsp: &BspFile, renderer: &BspMapRenderer, lights: &[(Vector3, Vector3, f32)], camera_pos: &Vector3, camera_view: &Matrix4x4, camera_proj: &Matrix4x4) {
+        if !self.any_enabled() {
+            return;
+        }
+
+        let geo = &mut self.geo_buff;
+        geo.clear();
+
+        let tris = &mut self.tri_buff;
+        tris.clear();
+
+        if self.wireframe {
+            // faces can be shared between several leaves, so only draw each one once
+            self.drawn_faces.clear();
+            self.drawn_faces.resize(bsp.face_lump.faces.len(), false);
+
+            for (leaf_index, leaf) in bsp.leaf_lump.leaves.iter().enumerate() {
+                if !renderer.is_leaf_visible(leaf_index) {
+                    continue;
+                }
+
+                for leaf_face in (leaf.first_leaf_face as usize)..((leaf.first_leaf_face + leaf.num_leaf_faces) as usize) {
+                    let face_index = bsp.leaf_face_lump.faces[leaf_face] as usize;
+                    if self.drawn_faces[face_index] {
+                        continue;
+                    }
+
+                    self.drawn_faces[face_index] = true;
+
+                    let verts = bsp.face_vertices(face_index);
+                    for i in 0..verts.len() {
+                        push_line(geo, &verts[i], &verts[(i + 1) % verts.len()], Color32::new(255, 255, 255, 255));
+                    }
+                }
+            }
+        }
+
+        if self.leaf_bounds {
+            for (leaf_index, leaf) in bsp.leaf_lump.leaves.iter().enumerate() {
+                if renderer.is_leaf_visible(leaf_index) {
+                    push_box(geo, &leaf.bbox_min, &leaf.bbox_max, Color32::new(0, 255, 255, 255));
+                }
+            }
+        }
+
+        if self.light_radii {
+            for (light_pos, light_color, light_radius) in lights {
+                let col = Color32::new((light_color.x.clamp(0.0, 1.0) * 255.0) as u8, (light_color.y.clamp(0.0, 1.0) * 255.0) as u8, (light_color.z.clamp(0.0, 1.0) * 255.0) as u8, 255);
+
+                push_circle(geo, light_pos, &Vector3::unit_x(), &Vector3::unit_y(), *light_radius, col);
+                push_circle(geo, light_pos, &Vector3::unit_x(), &Vector3::unit_z(), *light_radius, col);
+                push_circle(geo, light_pos, &Vector3::unit_y(), &Vector3::unit_z(), *light_radius, col);
+            }
+        }
+
+        if self.collision_brushes {
+            // brushes can be shared between several leaves, so only draw each one once
+            self.drawn_brushes.clear();
+            self.drawn_brushes.resize(bsp.brush_lump.brushes.len(), false);
+
+            let radius = Vector3::new(BRUSH_DRAW_RADIUS, BRUSH_DRAW_RADIUS, BRUSH_DRAW_RADIUS);
+            let min = *camera_pos - radius;
+            let max = *camera_pos + radius;
+
+            for leaf in &bsp.leaf_lump.leaves {
+                if !common::aabb_aabb_intersects(min, max, leaf.bbox_min, leaf.bbox_max) {
+                    continue;
+                }
+
+                for leaf_brush in (leaf.first_leaf_brush as usize)..((leaf.first_leaf_brush + leaf.num_leaf_brushes) as usize) {
+                    let brush_index = bsp.leaf_brush_lump.brushes[leaf_brush] as usize;
+                    if self.drawn_brushes[brush_index] || bsp.brush_lump.brushes[brush_index].contents & MASK_SOLID == 0 {
+                        continue;
+                    }
+
+                    self.drawn_brushes[brush_index] = true;
+
+                    let col = brush_color(brush_index);
+                    let edge_col = Color32::new(col.r, col.g, col.b, 255);
+
+                    for poly in bsp.brush_polygons(brush_index) {
+                        for i in 0..poly.len() {
+                            push_line(geo, &poly[i], &poly[(i + 1) % poly.len()], edge_col);
+                        }
+
+                        for i in 1..(poly.len() - 1) {
+                            for p in [poly[0], poly[i], poly[i + 1]] {
+                                tris.push(MapVertex::new(Vector4::new(p.x, p.y, p.z, 1.0), Vector2::zero(), Vector2::zero(), col));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if geo.len() == 0 && tris.len() == 0 {
+            return;
+        }
+
+        bsp_renderer::setup_vu();
+
+        let trs = (*camera_view) * common::coord_space_transform() * (*camera_proj);
+        bsp_renderer::load_cdata_matrix(0, &trs);
+        vdp::set_vu_cdata(4, &Vector4::zero());
+
+        // lines are drawn through geometry, so hidden culling problems are visible too
+        vdp::set_culling(false);
+        vdp::depth_func(vdp::Compare::Always);
+        vdp::depth_write(false);
+        vdp::blend_equation(vdp::BlendEquation::Add);
+        vdp::blend_func(vdp::BlendFactor::One, vdp::BlendFactor::Zero);
+        vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, None);
+        vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, None);
+
+        // brush faces are depth tested, so it's clear which surfaces of the world they belong to
+        if tris.len() > 0 {
+            vdp::depth_func(vdp::Compare::LessOrEqual);
+            vdp::blend_func(vdp::BlendFactor::SrcAlpha, vdp::BlendFactor::OneMinusSrcAlpha);
+            vdp::submit_vu(vdp::Topology::TriangleList, tris.as_slice());
+
+            vdp::depth_func(vdp::Compare::Always);
+            vdp::blend_func(vdp::BlendFactor::One, vdp::BlendFactor::Zero);
+        }
+
+        if geo.len() > 0 {
+            vdp::submit_vu(vdp::Topology::LineList, geo.as_slice());
+        }
+    }
+}
\ No newline at end of file
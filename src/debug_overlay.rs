@@ -0,0 +1,150 @@
+use dbsdk_rs::{audio, db::log, logfmt, math::{Matrix4x4, Vector2, Vector4}, vdp::{self, Color32, Rectangle, Texture, TextureUnit}};
+
+use crate::bsp_renderer::{self, MapVertex};
+
+const FRAME_HISTORY: usize = 64;
+const TARGET_FRAME_TIME: f32 = 1.0 / 60.0;
+const TRIANGLE_BUDGET: usize = 8192;
+const LOG_INTERVAL: f32 = 1.0;
+
+/// Rendering statistics for a single frame, accumulated across all cameras
+#[derive(Default, Clone, Copy)]
+pub struct FrameStats {
+    pub visible_leaves: usize,
+    pub total_leaves: usize,
+    pub node_tests: usize,
+    pub triangles: usize,
+    pub lightmap_atlases: usize,
+    /// Texture memory used by all lightmap atlases, in bytes
+    pub lightmap_memory: usize,
+    /// Fill fraction of the fullest lightmap atlas
+    pub lightmap_usage: f32,
+    /// Longest time any camera spent building map geometry this frame, in seconds
+    pub build_time: f32,
+    /// Number of static mesh instances drawn, & the number of batches they were grouped into
+    pub mesh_instances: usize,
+    pub mesh_batches: usize,
+}
+
+/// On-screen frame timing & rendering stats, for profiling on-device
+pub struct DebugOverlay {
+    pub enabled: bool,
+    frame_times: [f32;FRAME_HISTORY],
+    frame_index: usize,
+    last_time: f64,
+    log_timer: f32,
+    /// Worst single-frame geometry build time since the last log
+    max_build_time: f32,
+    geo_buff: Vec<MapVertex>,
+}
+
+// convert a rectangle in screen pixels into a pair of clip space triangles
+fn push_rect(geo: &mut Vec<MapVertex>, x: f32, y: f32, w: f32, h: f32, col: Color32) {
+    let x0 = (x / 320.0) - 1.0;
+    let x1 = ((x + w) / 320.0) - 1.0;
+    let y0 = 1.0 - (y / 240.0);
+    let y1 = 1.0 - ((y + h) / 240.0);
+
+    let v0 = MapVertex::new(Vector4::new(x0, y0, 0.0, 1.0), Vector2::zero(), Vector2::zero(), col);
+    let v1 = MapVertex::new(Vector4::new(x1, y0, 0.0, 1.0), Vector2::zero(), Vector2::zero(), col);
+    let v2 = MapVertex::new(Vector4::new(x0, y1, 0.0, 1.0), Vector2::zero(), Vector2::zero(), col);
+    let v3 = MapVertex::new(Vector4::new(x1, y1, 0.0, 1.0), Vector2::zero(), Vector2::zero(), col);
+
+    geo.extend_from_slice(&[v0, v1, v2, v2, v1, v3]);
+}
+
+impl DebugOverlay {
+    pub fn new() -> DebugOverlay {
+        DebugOverlay {
+            enabled: false,
+            frame_times: [0.0;FRAME_HISTORY],
+            frame_index: 0,
+            last_time: audio::get_time(),
+            log_timer: 0.0,
+            max_build_time: 0.0,
+            geo_buff: Vec::with_capacity(1024),
+        }
+    }
+
+    /// Call once per frame to record the real time elapsed since the previous frame
+    pub fn update(self: &mut Self) {
+        let now = audio::get_time();
+        let frame_time = (now - self.last_time) as f32;
+        self.last_time = now;
+
+        self.frame_times[self.frame_index] = frame_time;
+        self.frame_index = (self.frame_index + 1) % FRAME_HISTORY;
+    }
+
+    /// Rolling average of recent frame times, in seconds
+    pub fn avg_frame_time(self: &Self) -> f32 {
+        self.frame_times.iter().sum::<f32>() / FRAME_HISTORY as f32
+    }
+
+    /// Draw the overlay over the whole screen. Sets up its own VU program & render state, so can be called after any other pass
+    pub fn draw(self: &mut Self, stats: &FrameStats) {
+        if !self.enabled {
+            return;
+        }
+
+        let avg_frame_time = self.avg_frame_time();
+
+        // the HUD has no text, so periodically log the exact numbers too
+        self.log_timer += avg_frame_time.max(TARGET_FRAME_TIME);
+        self.max_build_time = self.max_build_time.max(stats.build_time);
+        if self.log_timer >= LOG_INTERVAL {
+            self.log_timer = 0.0;
+            logfmt!("frame: {:.2}ms ({:.1} fps) | leaves: {}/{} | node tests: {} | tris: {} | lm atlases: {} ({}KiB, {:.0}% full) | meshes: {} in {} batches | worst geometry build: {:.2}ms",
+                avg_frame_time * 1000.0, 1.0 / avg_frame_time.max(f32::EPSILON),
+                stats.visible_leaves, stats.total_leaves,
+                stats.node_tests,
+                stats.triangles,
+                stats.lightmap_atlases, stats.lightmap_memory / 1024, stats.lightmap_usage * 100.0,
+                stats.mesh_instances, stats.mesh_batches,
+                self.max_build_time * 1000.0);
+            self.max_build_time = 0.0;
+        }
+
+        let geo = &mut self.geo_buff;
+        geo.clear();
+
+        // background panel
+        push_rect(geo, 4.0, 4.0, (FRAME_HISTORY * 3) as f32 + 8.0, 104.0, Color32::new(0, 0, 0, 160));
+
+        // frame time history, oldest on the left. the white line marks the 60Hz frame budget
+        for i in 0..FRAME_HISTORY {
+            let frame_time = self.frame_times[(self.frame_index + i) % FRAME_HISTORY];
+            let h = ((frame_time / TARGET_FRAME_TIME) * 32.0).min(64.0);
+            let col = if frame_time <= TARGET_FRAME_TIME * 1.05 { Color32::new(0, 255, 0, 255) } else { Color32::new(255, 0, 0, 255) };
+
+            push_rect(geo, 8.0 + (i * 3) as f32, 72.0 - h, 2.0, h, col);
+        }
+
+        push_rect(geo, 8.0, 40.0, (FRAME_HISTORY * 3) as f32, 1.0, Color32::new(255, 255, 255, 255));
+
+        // stat bars: visible leaves, triangles vs budget, fullest lightmap atlas
+        let bar_width = (FRAME_HISTORY * 3) as f32;
+        let leaf_frac = stats.visible_leaves as f32 / stats.total_leaves.max(1) as f32;
+        let tri_frac = stats.triangles as f32 / TRIANGLE_BUDGET as f32;
+
+        push_rect(geo, 8.0, 78.0, bar_width * leaf_frac.min(1.0), 6.0, Color32::new(0, 128, 255, 255));
+        push_rect(geo, 8.0, 88.0, bar_width * tri_frac.min(1.0), 6.0, if tri_frac <= 1.0 { Color32::new(255, 255, 0, 255) } else { Color32::new(255, 0, 0, 255) });
+        push_rect(geo, 8.0, 98.0, bar_width * stats.lightmap_usage.min(1.0), 6.0, Color32::new(255, 0, 255, 255));
+
+        // vertices are already in clip space, so just use the map VU program with an identity transform
+        bsp_renderer::setup_vu();
+        bsp_renderer::load_cdata_matrix(0, &Matrix4x4::identity());
+        vdp::set_vu_cdata(4, &Vector4::zero());
+
+        vdp::viewport(Rectangle::new(0, 0, 640, 480));
+        vdp::set_culling(false);
+        vdp::depth_func(vdp::Compare::Always);
+        vdp::depth_write(false);
+        vdp::blend_equation(vdp::BlendEquation::Add);
+        vdp::blend_func(vdp::BlendFactor::SrcAlpha, vdp::BlendFactor::OneMinusSrcAlpha);
+        vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, None);
+        vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, None);
+
+        vdp::submit_vu(vdp::Topology::TriangleList, geo.as_slice());
+    }
+}
@@ -0,0 +1,249 @@
+use std::sync::Arc;
+
+use dbsdk_rs::{math::{Matrix4x4, Vector2, Vector3, Vector4}, vdp::{self, Color32, Texture, TextureUnit}};
+
+use crate::{bsp_file::{BspFile, MASK_SOLID, SURF_NODRAW, SURF_SKY}, bsp_renderer::{self, MapVertex}, common};
+
+const MAX_DECALS: usize = 64;
+const DECAL_LIFETIME: f32 = 20.0;
+const DECAL_FADE_TIME: f32 = 2.0;
+
+// how far decals are pushed off of the surface they're projected onto, to avoid z-fighting
+const DECAL_OFFSET: f32 = 0.1;
+
+/// A decal which has been clipped against map geometry
+pub struct Decal {
+    pub texture: Arc<Texture>,
+    pub vertices: Vec<MapVertex>,
+    pub spawn_time: f32,
+}
+
+/// Ring buffer of recently spawned decals. Once full, the oldest decal is replaced
+pub struct DecalBuffer {
+    decals: Vec<Decal>,
+    next: usize,
+    geo_buff: Vec<MapVertex>,
+}
+
+impl DecalBuffer {
+    pub fn new() -> DecalBuffer {
+        DecalBuffer {
+            decals: Vec::with_capacity(MAX_DECALS),
+            next: 0,
+            geo_buff: Vec::new(),
+        }
+    }
+
+    /// Remove all decals
+    pub fn clear(self: &mut Self) {
+        self.decals.clear();
+        self.next = 0;
+    }
+
+    /// Project a square decal of the given size onto the map at the given position, facing along the given normal.
+    /// The decal is clipped against each face it touches, so it can wrap around corners
+    pub fn spawn(self: &mut Self, bsp: &BspFile, position: &Vector3, normal: &Vector3, size: f32, texture: &Arc<Texture>, time: f32) {
+        let normal = normal.normalized();
+        let half_size = size * 0.5;
+
+        // find the surface behind the decal
+        let trace = bsp.linetrace(0, MASK_SOLID, &(*position + (normal * half_size)), &(*position - (normal * half_size)));
+        if trace.fraction >= 1.0 {
+            return;
+        }
+
+        let center = trace.end_pos;
+
+        // build decal axes
+        let up = if normal.z.abs() < 0.9 { Vector3::unit_z() } else { Vector3::unit_x() };
+        let tangent = Vector3::cross(&up, &normal).normalized();
+        let bitangent = Vector3::cross(&normal, &tangent);
+
+        // faces touching the decal are found in the leaf just in front of the hit surface
+        let leaf_index = bsp.calc_leaf_index(&(center + (normal * DECAL_OFFSET))) as usize;
+        let leaf = &bsp.leaf_lump.leaves[leaf_index];
+
+        let mut vertices = Vec::new();
+
+        for leaf_face in (leaf.first_leaf_face as usize)..((leaf.first_leaf_face + leaf.num_leaf_faces) as usize) {
+            let face_index = bsp.leaf_face_lump.faces[leaf_face] as usize;
+            let face = &bsp.face_lump.faces[face_index];
+            let tex_info = &bsp.tex_info_lump.textures[face.texture_info as usize];
+
+            if tex_info.flags & (SURF_NODRAW | SURF_SKY) != 0 {
+                continue;
+            }
+
+            // only project onto faces pointing towards the decal
+            let face_normal = bsp.face_normal(face_index);
+            if Vector3::dot(&face_normal, &normal) <= 0.1 {
+                continue;
+            }
+
+            let poly = clip_to_decal(bsp.face_vertices(face_index), &center, &tangent, &bitangent, &normal, half_size);
+            if poly.len() < 3 {
+                continue;
+            }
+
+            let to_vertex = |p: &Vector3| {
+                let local = *p - center;
+                let uv = Vector2::new(
+                    (Vector3::dot(&local, &tangent) / size) + 0.5,
+                    0.5 - (Vector3::dot(&local, &bitangent) / size)
+                );
+
+                let p = *p + (face_normal * DECAL_OFFSET);
+                MapVertex::new(Vector4::new(p.x, p.y, p.z, 1.0), uv, Vector2::zero(), Color32::new(255, 255, 255, 255))
+            };
+
+            // triangulate as a fan
+            for i in 1..(poly.len() - 1) {
+                vertices.push(to_vertex(&poly[0]));
+                vertices.push(to_vertex(&poly[i]));
+                vertices.push(to_vertex(&poly[i + 1]));
+            }
+        }
+
+        if vertices.len() == 0 {
+            return;
+        }
+
+        let decal = Decal {
+            texture: texture.clone(),
+            vertices,
+            spawn_time: time,
+        };
+
+        if self.decals.len() < MAX_DECALS {
+            self.decals.push(decal);
+        }
+        else {
+            self.decals[self.next] = decal;
+        }
+
+        self.next = (self.next + 1) % MAX_DECALS;
+    }
+
+    /// Draw all decals which haven't expired yet. Decals fade out before they expire. Expects the map VU program to already be set up
+    pub fn draw(self: &mut Self, time: f32, camera_view: &Matrix4x4, camera_proj: &Matrix4x4) {
+        if self.decals.len() == 0 {
+            return;
+        }
+
+        let trs = (*camera_view) * common::coord_space_transform() * (*camera_proj);
+        bsp_renderer::load_cdata_matrix(0, &trs);
+        vdp::set_vu_cdata(4, &Vector4::zero());
+
+        vdp::set_winding(vdp::WindingOrder::Clockwise);
+        vdp::set_culling(false);
+        vdp::depth_func(vdp::Compare::LessOrEqual);
+        vdp::depth_write(false);
+        vdp::blend_equation(vdp::BlendEquation::Add);
+        vdp::blend_func(vdp::BlendFactor::SrcAlpha, vdp::BlendFactor::OneMinusSrcAlpha);
+        vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, None);
+        vdp::set_tex_combine(vdp::TexCombine::None, vdp::TexCombine::Mul);
+
+        for decal in &self.decals {
+            let age = time - decal.spawn_time;
+            if age >= DECAL_LIFETIME {
+                continue;
+            }
+
+            let alpha = ((DECAL_LIFETIME - age) / DECAL_FADE_TIME).min(1.0);
+            let alpha = (alpha * 255.0) as u8;
+
+            self.geo_buff.clear();
+            self.geo_buff.extend(decal.vertices.iter().map(|v| {
+                let mut v = *v;
+                v.color.a = alpha;
+                v
+            }));
+
+            vdp::bind_texture_slot(TextureUnit::TU0, Some(decal.texture.as_ref()));
+            vdp::set_sample_params_slot(TextureUnit::TU0, vdp::TextureFilter::Linear, vdp::TextureWrap::Clamp, vdp::TextureWrap::Clamp);
+            vdp::submit_vu(vdp::Topology::TriangleList, &self.geo_buff);
+        }
+
+        vdp::blend_func(vdp::BlendFactor::One, vdp::BlendFactor::Zero);
+        vdp::set_tex_combine(vdp::TexCombine::Mul, vdp::TexCombine::Mul);
+    }
+}
+
+// clip a face polygon against each side of a decal's box. Returns fewer than 3 vertices if the face is outside of it
+fn clip_to_decal(poly: Vec<Vector3>, center: &Vector3, tangent: &Vector3, bitangent: &Vector3, normal: &Vector3, half_size: f32) -> Vec<Vector3> {
+    let bounds = [
+        (*tangent, Vector3::dot(center, tangent) - half_size),
+        (*tangent * -1.0, -Vector3::dot(center, tangent) - half_size),
+        (*bitangent, Vector3::dot(center, bitangent) - half_size),
+        (*bitangent * -1.0, -Vector3::dot(center, bitangent) - half_size),
+        (*normal, Vector3::dot(center, normal) - half_size),
+        (*normal * -1.0, -Vector3::dot(center, normal) - half_size),
+    ];
+
+    let mut poly = poly;
+    for (n, d) in &bounds {
+        if poly.len() < 3 {
+            break;
+        }
+
+        poly = common::clip_polygon(&poly, n, *d);
+    }
+
+    poly
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a 64x64 floor face at z = 0, wound clockwise when seen from above
+    fn floor_face() -> Vec<Vector3> {
+        vec![
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(0.0, 64.0, 0.0),
+            Vector3::new(64.0, 64.0, 0.0),
+            Vector3::new(64.0, 0.0, 0.0),
+        ]
+    }
+
+    fn bounds_of(poly: &[Vector3]) -> (Vector3, Vector3) {
+        let mut mins = poly[0];
+        let mut maxs = poly[0];
+        for p in poly {
+            mins = Vector3::new(mins.x.min(p.x), mins.y.min(p.y), mins.z.min(p.z));
+            maxs = Vector3::new(maxs.x.max(p.x), maxs.y.max(p.y), maxs.z.max(p.z));
+        }
+        (mins, maxs)
+    }
+
+    #[test]
+    fn decal_over_face_corner_keeps_the_overlap() {
+        let poly = clip_to_decal(floor_face(), &Vector3::new(56.0, 56.0, 0.0), &Vector3::unit_x(), &Vector3::unit_y(), &Vector3::unit_z(), 16.0);
+
+        // the face is coplanar with the decal, so it's only trimmed at the decal's edges & the face's own edges
+        assert_eq!(poly.len(), 4);
+        let (mins, maxs) = bounds_of(&poly);
+        assert_eq!((mins.x, mins.y, mins.z), (40.0, 40.0, 0.0));
+        assert_eq!((maxs.x, maxs.y, maxs.z), (64.0, 64.0, 0.0));
+    }
+
+    #[test]
+    fn decal_inside_face_is_clipped_to_decal_square() {
+        let poly = clip_to_decal(floor_face(), &Vector3::new(32.0, 32.0, 0.0), &Vector3::unit_x(), &Vector3::unit_y(), &Vector3::unit_z(), 8.0);
+
+        let (mins, maxs) = bounds_of(&poly);
+        assert_eq!((mins.x, mins.y), (24.0, 24.0));
+        assert_eq!((maxs.x, maxs.y), (40.0, 40.0));
+    }
+
+    #[test]
+    fn face_outside_decal_is_discarded() {
+        // beside the decal
+        let poly = clip_to_decal(floor_face(), &Vector3::new(128.0, 32.0, 0.0), &Vector3::unit_x(), &Vector3::unit_y(), &Vector3::unit_z(), 16.0);
+        assert!(poly.len() < 3);
+
+        // too far in front of the decal
+        let poly = clip_to_decal(floor_face(), &Vector3::new(32.0, 32.0, 32.0), &Vector3::unit_x(), &Vector3::unit_y(), &Vector3::unit_z(), 16.0);
+        assert!(poly.len() < 3);
+    }
+}
\ No newline at end of file
@@ -3,35 +3,51 @@ extern crate byteorder;
 extern crate lazy_static;
 extern crate ktx;
 extern crate hecs;
-extern crate regex;
 extern crate half;
 extern crate qoaudio;
 
 use std::{collections::HashMap, sync::{Arc, Mutex}};
 
-use asset_loader::{load_env, load_mesh, load_mesh_anim};
-use bsp_file::BspFile;
-use bsp_renderer::{BspMapModelRenderer, BspMapRenderer, BspMapTextures, NUM_CUSTOM_LIGHT_LAYERS};
+use asset_loader::{load_env, load_mesh, load_mesh_anim, load_sound, preload_manifest, reload_all, set_generate_mipmaps, PreloadSet, ResourceError};
+use bsp_file::{BspError, BspFile, EmissiveSurface, SubModel, CUSTOM_LIGHT_LAYER_END, CUSTOM_LIGHT_LAYER_START, NUM_CUSTOM_LIGHT_LAYERS};
+use bsp_renderer::{BspMapModelRenderer, BspMapRenderer, BspMapTextures, FogSettings, LightmapSettings};
 use common::aabb_aabb_intersects;
-use component::{camera::{Camera, FPCamera}, charactercontroller::CharacterController, collider::ColliderBounds, door::{Door, DoorLink, DoorOpener}, fpview::FPView, light::Light, mapmodel::MapModel, mesh::{Mesh, MeshAnim}, playerinput::PlayerInput, rotator::Rotator, transform3d::Transform3D, triggerable::{TriggerLink, TriggerState}};
+use component::{ambientsound::AmbientSound, areaportal::AreaPortal, camera::{Camera, FPCamera}, changelevel::ChangeLevel, charactercontroller::CharacterController, collider::ColliderBounds, door::{Door, DoorLink, DoorOpener}, explosive::Explosive, footsteps::Footsteps, fpview::FPView, gravityvolume::GravityVolume, interpolated::Interpolated, ladder::LadderVolume, light::{Light, LightSwitch}, mapmodel::MapModel, mesh::{Mesh, MeshAnim}, playerinput::PlayerInput, rotator::Rotator, transform3d::Transform3D, triggerable::{TriggerFollow, TriggerLink, TriggerState}};
 use dbanim::AnimationCurveLoopMode;
-use hecs::{CommandBuffer, World};
+use debug_draw::DebugDraw;
+use debug_overlay::DebugOverlay;
+use decal::DecalBuffer;
+use hecs::{CommandBuffer, Entity, World};
 use lazy_static::lazy_static;
-use dbsdk_rs::{db::{self, log}, gamepad::{self, Gamepad}, io::{FileMode, FileStream}, logfmt, math::{Quaternion, Vector3}, vdp::{self, Texture}};
+use map_loader::{MapLoader, MapSettings};
+use dbsdk_rs::{audio, db::{self, log}, gamepad::{self, Gamepad}, io::{FileMode, FileStream, IOError}, logfmt, math::{Quaternion, Vector3}, vdp::{self, Rectangle, Texture}};
 use music_player::MusicPlayer;
-use system::{anim_system::sk_anim_system_update, character_system::{character_apply_input_update, character_init, character_input_update, character_rotation_update, character_update}, door_system::door_system_update, flycam_system::flycam_system_update, fpcam_system::fpcam_update, fpview_system::{fpview_eye_update, fpview_input_system_update}, render_system::render_system, rotator_system::rotator_system_update, triggerable_system::trigger_link_system_update};
+use post_process::PostProcess;
+use savegame::{SaveData, SaveError};
+use system::{ambient_sound_system::ambient_sound_system_update, anim_system::sk_anim_system_update, areaportal_system::areaportal_system_update, attachment_system::attachment_system_update, camera_shake_system::{camera_shake_apply, camera_shake_decay, camera_shake_restore}, changelevel_system::changelevel_system_update, character_system::{character_apply_input_update, character_init, character_input_update, character_rotation_update, character_update}, door_system::door_system_update, explosive_system::explosive_system_update, flycam_system::{flycam_system_update, flycam_toggle_noclip}, footstep_system::{footstep_system_update, FootstepSounds}, fpcam_system::fpcam_update, fpview_system::{fpview_eye_update, fpview_input_system_update}, interpolation_system::{interpolation_apply, interpolation_restore, interpolation_snapshot}, light_switch_system::light_switch_system_update, portal_system::portal_system_update, render_system::render_system, rotator_system::rotator_system_update, tpcam_system::{tpcam_toggle, tpcam_update}, triggerable_system::{delayed_trigger_system_update, trigger_link_system_update}};
 
 use crate::component::mesh::FPMesh;
 
 pub mod common;
 pub mod dbanim;
 pub mod dbmesh;
+pub mod debug_draw;
+pub mod debug_overlay;
+pub mod decal;
 pub mod sh;
 pub mod bsp_file;
 pub mod bsp_renderer;
 pub mod bsp_collision;
+pub mod archive;
 pub mod asset_loader;
+pub mod map_loader;
 pub mod parse_utils;
+pub mod post_process;
+pub mod savegame;
+pub mod sfx;
+
+#[cfg(test)]
+mod test_map;
 
 pub mod component;
 pub mod system;
@@ -41,6 +57,19 @@ lazy_static! {
     static ref GAME_STATE: Mutex<GameState> = Mutex::new(GameState::new());
 }
 
+const DEFAULT_SKY: &str = "sky";
+const DEFAULT_GRAVITY: f32 = 300.0;
+const QUICKSAVE_PATH: &str = "/ma/quicksave.sav";
+
+/// Number of local players. Each player gets their own gamepad slot & a slice of the screen. Splitscreen is opt-in, by building with the splitscreen feature
+#[cfg(not(feature = "splitscreen"))]
+const NUM_PLAYERS: usize = 1;
+#[cfg(feature = "splitscreen")]
+const NUM_PLAYERS: usize = 2;
+
+/// Maximum number of fixed simulation steps to run in a single frame
+const MAX_SIM_STEPS: u32 = 4;
+
 #[derive(Default)]
 pub struct InputState {
     pub move_x: f32,
@@ -49,14 +78,56 @@ pub struct InputState {
     pub look_y: f32,
     pub crouch: bool,
     pub jump: bool,
+    pub walk: bool,
 }
 
 pub struct MapData {
+    pub map_name: String,
     pub map: BspFile,
     pub map_textures: BspMapTextures,
     pub map_models: BspMapModelRenderer,
     pub map_renderers: Vec<BspMapRenderer>,
     pub light_layers: [f32;NUM_CUSTOM_LIGHT_LAYERS],
+    pub light_layer_pulses: [LightLayerPulse;NUM_CUSTOM_LIGHT_LAYERS],
+    pub lightmap_settings: LightmapSettings,
+    pub sky_name: String,
+    pub sky_rotate: f32,
+    pub sky_axis: Vector3,
+    pub areaportal_states: Vec<bool>,
+    /// Baked lighting sampled from the floor beneath dynamic meshes, cached per-leaf
+    pub leaf_ambient: Vec<Option<Vector3>>,
+    /// Light layer values the leaf ambient cache was sampled with
+    pub leaf_ambient_layers: [f32;NUM_CUSTOM_LIGHT_LAYERS],
+    pub gravity: f32,
+    pub fog: FogSettings,
+    /// Draw opaque map geometry front to back rather than in texture order, trading texture batching for less overdraw
+    pub sort_opaque_front_to_back: bool,
+    /// Light-emitting faces of the world, used to light dynamic meshes
+    pub emissive_surfaces: Vec<EmissiveSurface>,
+    pub spawn_points: Vec<SpawnPoint>,
+    next_spawn: usize,
+    /// Spawned entities, keyed by targetname
+    pub target_index: HashMap<String, Vec<Entity>>,
+    /// Recently spawned decals
+    pub decals: DecalBuffer,
+    /// Resources listed in the map's preload manifest, kept loaded for as long as the map is
+    pub preloaded: PreloadSet,
+}
+
+/// A location players can spawn at, from an info_player_start or info_player_deathmatch
+#[derive(Clone)]
+pub struct SpawnPoint {
+    pub target_name: String,
+    pub position: Vector3,
+    pub yaw: f32,
+    pub deathmatch: bool,
+}
+
+/// Describes a custom light layer which oscillates between zero and a given amplitude over time
+#[derive(Clone, Copy, Default)]
+pub struct LightLayerPulse {
+    pub amplitude: f32,
+    pub hz: f32,
 }
 
 #[derive(Default)]
@@ -66,49 +137,269 @@ pub struct TimeData {
 }
 
 struct GameState {
-    gamepad: Gamepad,
+    gamepads: Vec<Gamepad>,
     world: World,
     time_data: TimeData,
     map_data: Option<MapData>,
     env: Option<[Arc<Texture>;6]>,
     music_player: Option<MusicPlayer>,
+    reload_combo_held: bool,
+    noclip_combo_held: bool,
+    save_combo_held: bool,
+    load_combo_held: bool,
+    overlay_combo_held: bool,
+    debug_draw_combo_held: bool,
+    tpcam_combo_held: bool,
+    debug_overlay: DebugOverlay,
+    debug_draw: DebugDraw,
+    post_process: PostProcess,
+    /// Map currently being loaded in the background, along with the spawnpoint to use once it's loaded
+    map_loader: Option<(MapLoader, String)>,
+    last_frame_time: f64,
+    sim_accumulator: f32,
+    footstep_sounds: FootstepSounds,
+}
+
+/// Enumeration of errors which can result from loading a map
+#[derive(Debug)]
+pub enum MapLoadError {
+    IOError(IOError),
+    BspError(BspError),
+    /// The loader was stepped again after it had already returned the finished map
+    AlreadyLoaded,
+}
+
+// index of the spawn point to use, cycling through deathmatch spawns with next_spawn
+fn choose_spawn_index(spawn_points: &[SpawnPoint], next_spawn: &mut usize, target_name: &str, deathmatch: bool) -> Option<usize> {
+    if target_name != "" {
+        match spawn_points.iter().position(|x| x.target_name == target_name) {
+            Some(idx) => return Some(idx),
+            None => {
+                logfmt!("Couldn't find spawn point: {}", target_name);
+            }
+        };
+    }
+
+    if deathmatch {
+        let dm_spawns = spawn_points.iter().enumerate()
+            .filter(|(_, x)| x.deathmatch)
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+
+        if dm_spawns.len() > 0 {
+            let idx = dm_spawns[*next_spawn % dm_spawns.len()];
+            *next_spawn += 1;
+            return Some(idx);
+        }
+    }
+
+    spawn_points.iter().position(|x| !x.deathmatch && x.target_name == "")
+        .or_else(|| spawn_points.iter().position(|x| !x.deathmatch))
+        .or_else(|| if spawn_points.is_empty() { None } else { Some(0) })
 }
 
 impl MapData {
-    pub fn load_map(map_name: &str) -> MapData {
-        logfmt!("Loading map: {}", map_name);
-        let mut bsp_file = FileStream::open(format!("/cd/content/maps/{}.bsp", map_name).as_str(), FileMode::Read).unwrap();
-        let bsp = BspFile::new(&mut bsp_file);
-        let bsp_textures = BspMapTextures::new(&bsp);
-        let bsp_models = BspMapModelRenderer::new(&bsp, &bsp_textures);
-        logfmt!("Map loaded");
+    /// Load a map all at once. See MapLoader to spread loading out over several frames instead
+    pub fn load_map(map_name: &str) -> Result<MapData, MapLoadError> {
+        MapLoader::new(map_name).finish()
+    }
+
+    /// Construct map data from an already-loaded BSP file, loading any textures it references
+    pub fn from_bsp(bsp: BspFile) -> MapData {
+        // the BSP file is the only thing which can fail to load, so this can't fail
+        MapLoader::from_bsp(bsp).finish().unwrap()
+    }
+
+    /// Construct map data from its already-loaded parts
+    pub fn from_parts(bsp: BspFile, settings: MapSettings, bsp_textures: BspMapTextures, bsp_models: BspMapModelRenderer) -> MapData {
+        // areaportals start closed, and are opened by whatever targets them
+        let areaportal_states = vec![false;bsp.num_areaportal_states()];
+        let leaf_ambient = vec![None;bsp.leaf_lump.leaves.len()];
+        let emissive_surfaces = bsp.emissive_surfaces();
 
         MapData {
+            map_name: String::new(),
             map: bsp,
             map_textures: bsp_textures,
             map_models: bsp_models,
             map_renderers: Vec::new(),
-            light_layers: [0.0;NUM_CUSTOM_LIGHT_LAYERS]
+            light_layers: [0.0;NUM_CUSTOM_LIGHT_LAYERS],
+            light_layer_pulses: [LightLayerPulse::default();NUM_CUSTOM_LIGHT_LAYERS],
+            lightmap_settings: settings.lightmap_settings,
+            sky_name: settings.sky_name,
+            sky_rotate: settings.sky_rotate,
+            sky_axis: settings.sky_axis,
+            areaportal_states,
+            leaf_ambient,
+            leaf_ambient_layers: [0.0;NUM_CUSTOM_LIGHT_LAYERS],
+            gravity: settings.gravity,
+            fog: settings.fog,
+            sort_opaque_front_to_back: settings.sort_opaque_front_to_back,
+            emissive_surfaces,
+            spawn_points: Vec::new(),
+            next_spawn: 0,
+            target_index: HashMap::new(),
+            decals: DecalBuffer::new(),
+            preloaded: PreloadSet::default(),
+        }
+    }
+
+    /// Look up all entities spawned with the given targetname
+    pub fn entities_named(self: &Self, target_name: &str) -> &[Entity] {
+        match self.target_index.get(target_name) {
+            Some(v) => v.as_slice(),
+            None => &[]
+        }
+    }
+
+    /// Pick a spawn point. If a target name is given, the spawn point with that targetname is used if it exists.
+    /// Otherwise single-player uses the default (unnamed) info_player_start, & deathmatch cycles through each info_player_deathmatch in turn
+    pub fn choose_spawn(self: &mut Self, target_name: &str, deathmatch: bool) -> Option<&SpawnPoint> {
+        let idx = choose_spawn_index(&self.spawn_points, &mut self.next_spawn, target_name, deathmatch)?;
+        Some(&self.spawn_points[idx])
+    }
+
+    /// Change lightmap brightness settings. Lightmap atlases are rebuilt to apply the new settings
+    pub fn set_lightmap_settings(self: &mut Self, settings: LightmapSettings) {
+        self.lightmap_settings = settings;
+        self.map_models = BspMapModelRenderer::new(&self.map, &self.map_textures, &self.lightmap_settings);
+        self.map_renderers.clear();
+        self.leaf_ambient.fill(None);
+    }
+
+    /// Set the brightness of a custom light layer, cancelling any pulse active on that layer
+    pub fn set_light_layer(self: &mut Self, index: usize, value: f32) {
+        if index >= NUM_CUSTOM_LIGHT_LAYERS {
+            logfmt!("Light layer index out of range: {}", index);
+            return;
+        }
+
+        self.light_layers[index] = value;
+        self.light_layer_pulses[index] = LightLayerPulse::default();
+    }
+
+    /// Make a custom light layer pulse between zero and the given amplitude at the given frequency
+    pub fn pulse_light_layer(self: &mut Self, index: usize, amplitude: f32, hz: f32) {
+        if index >= NUM_CUSTOM_LIGHT_LAYERS {
+            logfmt!("Light layer index out of range: {}", index);
+            return;
+        }
+
+        self.light_layer_pulses[index] = LightLayerPulse { amplitude, hz };
+    }
+
+    /// Advance any pulsing light layers
+    pub fn update_light_layers(self: &mut Self, total_time: f32) {
+        for (layer, pulse) in self.light_layers.iter_mut().zip(self.light_layer_pulses.iter()) {
+            if pulse.hz != 0.0 {
+                let phase = (total_time * pulse.hz * std::f32::consts::PI * 2.0).sin();
+                *layer = pulse.amplitude * (0.5 + (phase * 0.5));
+            }
         }
     }
 
     pub fn update_renderer_cache(self: &mut Self, index: usize) {
         while self.map_renderers.len() <= index {
             logfmt!("Allocating map renderer for camera {}", index);
-            self.map_renderers.push(BspMapRenderer::new(&self.map));
+            self.map_renderers.push(BspMapRenderer::new(&self.map, &self.lightmap_settings));
         }
     }
 }
 
 impl GameState {
     pub fn new() -> GameState {
+        // mount content archive if present (falls back to loose files otherwise)
+        match archive::mount_pak("/cd/content.pak", "/cd/content") {
+            Ok(_) => {}
+            Err(e) => {
+                logfmt!("Content archive not mounted: {:?}", e);
+            }
+        };
+
+        // most map textures ship with a single level, which aliases badly in the distance
+        set_generate_mipmaps(true);
+
+        // let music_player = MusicPlayer::new("/cd/content/mus/b8d_toys.qoa", false).unwrap();
+
+        let mut state = GameState {
+            gamepads: open_gamepads(NUM_PLAYERS),
+            world: World::new(),
+            time_data: TimeData::default(),
+            map_data: None,
+            env: None,
+            music_player: None, //Some(music_player),
+            reload_combo_held: false,
+            noclip_combo_held: false,
+            save_combo_held: false,
+            load_combo_held: false,
+            overlay_combo_held: false,
+            debug_draw_combo_held: false,
+            tpcam_combo_held: false,
+            debug_overlay: DebugOverlay::new(),
+            debug_draw: DebugDraw::new(),
+            post_process: PostProcess::new(),
+            map_loader: None,
+            last_frame_time: audio::get_time(),
+            sim_accumulator: 0.0,
+            footstep_sounds: FootstepSounds::new(),
+        };
+
+        // leave the world empty rather than bringing down the whole program
+        match state.change_map("demo1", "") {
+            Ok(_) => {}
+            Err(e) => {
+                logfmt!("Failed loading map: {:?}", e);
+            }
+        };
+
+        state
+    }
+
+    /// Tear down the current world & load a new map, spawning entities & players from the map's entity data
+    /// Players spawn at the info_player_start whose targetname matches the given spawnpoint, or the default start if empty
+    pub fn change_map(self: &mut Self, map_name: &str, spawnpoint: &str) -> Result<(), MapLoadError> {
+        // the previous map & world are kept alive until the new one has loaded,
+        // so that any assets shared between them are pulled from the cache instead of reloaded
+        let map_data = MapData::load_map(map_name)?;
+        self.enter_map(map_data, spawnpoint);
+
+        // this supersedes any map still loading in the background
+        self.map_loader = None;
+
+        Ok(())
+    }
+
+    /// Tear down the current world & replace it with an already-loaded map, spawning entities & players from the map's entity data
+    fn enter_map(self: &mut Self, mut map_data: MapData, spawnpoint: &str) {
         let mut world = World::new();
 
-        let map_data = MapData::load_map("demo1");
-        let env = load_env("sky");
+        // warm up the resource caches with anything the map lists in its (optional) preload manifest, so entities spawned below & the first frames of gameplay don't stall on loading
+        map_data.preloaded = match preload_manifest(format!("/cd/content/maps/{}.preload", map_data.map_name).as_str()) {
+            Ok(v) => v,
+            Err(ResourceError::IOError(_)) => PreloadSet::default(),
+            Err(e) => {
+                logfmt!("Failed reading preload manifest for {}: {:?}", map_data.map_name, e);
+                PreloadSet::default()
+            }
+        };
 
-        let mut player_start_pos = Vector3::zero();
-        let mut player_start_rot = 0.0;
+        let env = match load_env(&map_data.sky_name) {
+            Ok(v) => Some(v),
+            Err(_) => {
+                logfmt!("Failed loading sky {}, falling back to default", &map_data.sky_name);
+
+                // render without a sky rather than failing to enter the map
+                match load_env(DEFAULT_SKY) {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        logfmt!("Failed loading default sky: {:?}", e);
+                        None
+                    }
+                }
+            }
+        };
+
+        let mut spawn_points = Vec::new();
 
         let mut targetmap = HashMap::new();
         let mut pending_resolve_targets = Vec::new();
@@ -118,9 +409,17 @@ impl GameState {
         // spawn entities
         map_data.map.entity_lump.parse(|entity_data| {
             match entity_data["classname"] {
-                "info_player_start" => {
-                    player_start_pos = parse_utils::parse_prop_vec3(&entity_data, "origin", Vector3::zero());
-                    player_start_rot = parse_utils::parse_prop::<f32>(&entity_data, "angle", 0.0) + 180.0;
+                "info_player_start" | "info_player_deathmatch" => {
+                    let target_name = parse_utils::get_prop_str(&entity_data, "targetname", "");
+                    let start_pos = parse_utils::parse_prop_vec3(&entity_data, "origin", Vector3::zero());
+                    let start_rot = parse_utils::parse_prop::<f32>(&entity_data, "angle", 0.0) + 180.0;
+
+                    spawn_points.push(SpawnPoint {
+                        target_name: target_name.to_owned(),
+                        position: start_pos,
+                        yaw: start_rot,
+                        deathmatch: entity_data["classname"] == "info_player_deathmatch"
+                    });
                 }
                 "worldspawn" => {
                     for (key, val) in entity_data {
@@ -130,16 +429,34 @@ impl GameState {
                 "light" => {
                     let light_pos = parse_utils::parse_prop_vec3(&entity_data, "origin", Vector3::zero());
                     let light_intensity = parse_utils::parse_prop::<f32>(&entity_data, "light", 300.0);
-                    let light_color = parse_utils::parse_prop_vec3(&entity_data, "_color", Vector3::new(1.0, 1.0, 1.0));
+                    let light_color = parse_utils::parse_prop_color(&entity_data, "_color", Vector3::new(1.0, 1.0, 1.0));
 
-                    world.spawn((
+                    // lights marked "_dynamic" are expected to be left out of the lightmaps (e.g. via "_nostaticlight"), and are projected onto map surfaces at runtime instead
+                    let light_dynamic = parse_utils::parse_prop::<i32>(&entity_data, "_dynamic", 0) != 0;
+
+                    let e = world.spawn((
                         Transform3D::default().with_position(light_pos),
-                        Light { color: light_color, max_radius: light_intensity }
+                        Light { color: light_color, max_radius: light_intensity, dynamic: light_dynamic }
                     ));
+
+                    // targeted lights are assigned a switchable style by the light compiler
+                    let target_name = parse_utils::get_prop_str(&entity_data, "targetname", "");
+                    let style = parse_utils::parse_prop::<usize>(&entity_data, "style", 0);
+
+                    if target_name != "" && style >= CUSTOM_LIGHT_LAYER_START && style < CUSTOM_LIGHT_LAYER_END {
+                        world.insert(e, (
+                            LightSwitch { layer: style - CUSTOM_LIGHT_LAYER_START, start_on: !parse_utils::has_spawnflag(&entity_data, 1), applied: None },
+                            TriggerState { triggered: false }
+                        )).unwrap();
+
+                        targetmap.entry(target_name.to_owned()).or_insert_with(Vec::new).push(e);
+                    }
                 }
                 "func_door" => {
-                    let model_idx = parse_utils::parse_prop_modelindex(&entity_data, "model", usize::MAX);
-                    let submodel = &map_data.map.submodel_lump.submodels[model_idx + 1];
+                    let (model_idx, submodel) = match entity_submodel(&map_data.map, &entity_data) {
+                        Some(v) => v,
+                        None => return
+                    };
                     let pos = submodel.origin;
                     let size = submodel.maxs - submodel.mins;
 
@@ -152,8 +469,6 @@ impl GameState {
                     let speed = parse_utils::parse_prop::<f32>(&entity_data, "speed", 100.0);
                     let lip = parse_utils::parse_prop::<f32>(&entity_data, "lip", 0.0);
 
-                    let spawn_flags = parse_utils::parse_prop::<u32>(&entity_data, "spawnflags", 0);
-
                     let move_dir = if angle == -1 {
                         Vector3::new(0.0, 0.0, 1.0)
                     }
@@ -177,37 +492,157 @@ impl GameState {
 
                     let e = world.spawn((
                         Transform3D::default().with_position(pos),
+                        Interpolated::new(&Transform3D::default().with_position(pos)),
                         Door { auto_open, open_pos, close_pos: pos, move_speed: speed },
                         TriggerState { triggered: false },
                         MapModel { model_idx }
                     ));
 
                     if target != "" {
-                        pending_resolve_targets.push((e, target.to_owned()));
+                        pending_resolve_targets.push((e, target.to_owned(), parse_utils::parse_prop::<f32>(&entity_data, "delay", 0.0)));
                     }
 
                     if target_name != "" {
-                        targetmap.insert(target_name.to_owned(), e);
+                        targetmap.entry(target_name.to_owned()).or_insert_with(Vec::new).push(e);
                     }
 
                     // don't link doors if they have the "don't link" spawn flag set
-                    if spawn_flags & 4 == 0 {
+                    if !parse_utils::has_spawnflag(&entity_data, 4) {
                         doors.push((e, submodel));
                     }
                 }
+                "func_areaportal" => {
+                    let portal_num = parse_utils::parse_prop::<usize>(&entity_data, "style", 0);
+                    let target_name = parse_utils::get_prop_str(&entity_data, "targetname", "");
+
+                    let e = world.spawn((
+                        AreaPortal { portal_num },
+                        TriggerState { triggered: false },
+                        TriggerFollow
+                    ));
+
+                    if target_name != "" {
+                        targetmap.entry(target_name.to_owned()).or_insert_with(Vec::new).push(e);
+                    }
+                }
+                "trigger_relay" => {
+                    let target_name = parse_utils::get_prop_str(&entity_data, "targetname", "");
+                    let target = parse_utils::get_prop_str(&entity_data, "target", "");
+
+                    // relays simply forward their trigger state to their target
+                    let e = world.spawn((
+                        TriggerState { triggered: false },
+                    ));
+
+                    if target != "" {
+                        pending_resolve_targets.push((e, target.to_owned(), parse_utils::parse_prop::<f32>(&entity_data, "delay", 0.0)));
+                    }
+
+                    if target_name != "" {
+                        targetmap.entry(target_name.to_owned()).or_insert_with(Vec::new).push(e);
+                    }
+                }
+                "trigger_changelevel" => {
+                    let (_, submodel) = match entity_submodel(&map_data.map, &entity_data) {
+                        Some(v) => v,
+                        None => return
+                    };
+                    let next_map = parse_utils::get_prop_str(&entity_data, "map", "");
+                    let spawnpoint = parse_utils::get_prop_str(&entity_data, "spawnpoint", "");
+
+                    if next_map != "" {
+                        world.spawn((
+                            ChangeLevel { next_map: next_map.to_owned(), spawnpoint: spawnpoint.to_owned(), mins: submodel.mins, maxs: submodel.maxs },
+                        ));
+                    }
+                    else {
+                        logfmt!("trigger_changelevel is missing a map key, ignoring");
+                    }
+                }
+                "target_speaker" => {
+                    let origin = parse_utils::parse_prop_vec3(&entity_data, "origin", Vector3::zero());
+                    let noise = parse_utils::get_prop_str(&entity_data, "noise", "");
+                    let volume = parse_utils::parse_prop::<f32>(&entity_data, "volume", 1.0);
+                    let attenuation = parse_utils::parse_prop::<f32>(&entity_data, "attenuation", 1.0);
+                    let spawn_flags = parse_utils::parse_prop::<u32>(&entity_data, "spawnflags", 0);
+                    let target_name = parse_utils::get_prop_str(&entity_data, "targetname", "");
+
+                    // spawnflag 1 = looped on, 2 = looped off
+                    let looping = spawn_flags & 3 != 0;
+                    let start_on = spawn_flags & 1 != 0;
+
+                    let radius = AmbientSound::radius_from_attenuation(attenuation);
+
+                    let path = if noise.ends_with(".wav") { format!("/cd/content/sound/{}", noise) } else { format!("/cd/content/sound/{}.wav", noise) };
+
+                    match load_sound(&path) {
+                        Ok(sample) => {
+                            let e = world.spawn((
+                                AmbientSound::new(sample, origin, radius, volume, looping, start_on),
+                                TriggerState { triggered: false }
+                            ));
+
+                            if target_name != "" {
+                                targetmap.entry(target_name.to_owned()).or_insert_with(Vec::new).push(e);
+                            }
+                        }
+                        Err(_) => {
+                            logfmt!("Failed loading speaker sound: {}", path);
+                        }
+                    }
+                }
+                "trigger_gravity" => {
+                    let (_, submodel) = match entity_submodel(&map_data.map, &entity_data) {
+                        Some(v) => v,
+                        None => return
+                    };
+                    let gravity = parse_utils::parse_prop::<f32>(&entity_data, "gravity", 1.0);
+
+                    world.spawn((
+                        GravityVolume { mins: submodel.mins, maxs: submodel.maxs, gravity },
+                    ));
+                }
+                "func_ladder" => {
+                    let (_, submodel) = match entity_submodel(&map_data.map, &entity_data) {
+                        Some(v) => v,
+                        None => return
+                    };
+
+                    world.spawn((
+                        LadderVolume { mins: submodel.mins, maxs: submodel.maxs },
+                    ));
+                }
                 "func_explosive" => {
-                    let model_idx = parse_utils::parse_prop_modelindex(&entity_data, "model", usize::MAX);
-                    let submodel = &map_data.map.submodel_lump.submodels[model_idx + 1];
+                    let (model_idx, submodel) = match entity_submodel(&map_data.map, &entity_data) {
+                        Some(v) => v,
+                        None => return
+                    };
                     let pos = submodel.origin;
+                    let health = parse_utils::parse_prop::<f32>(&entity_data, "health", 0.0);
+
+                    let target_name = parse_utils::get_prop_str(&entity_data, "targetname", "");
+                    let target = parse_utils::get_prop_str(&entity_data, "target", "");
                     
-                    world.spawn((
+                    let e = world.spawn((
                         Transform3D::default().with_position(pos),
+                        Explosive { health, damageable: health > 0.0 },
+                        TriggerState { triggered: false },
                         MapModel { model_idx }
                     ));
+
+                    if target != "" {
+                        pending_resolve_targets.push((e, target.to_owned(), parse_utils::parse_prop::<f32>(&entity_data, "delay", 0.0)));
+                    }
+
+                    if target_name != "" {
+                        targetmap.entry(target_name.to_owned()).or_insert_with(Vec::new).push(e);
+                    }
                 }
                 "func_wall" => {
-                    let model_idx = parse_utils::parse_prop_modelindex(&entity_data, "model", usize::MAX);
-                    let submodel = &map_data.map.submodel_lump.submodels[model_idx + 1];
+                    let (model_idx, submodel) = match entity_submodel(&map_data.map, &entity_data) {
+                        Some(v) => v,
+                        None => return
+                    };
                     let pos = submodel.origin;
                     
                     world.spawn((
@@ -216,8 +651,10 @@ impl GameState {
                     ));
                 }
                 "func_object" => {
-                    let model_idx = parse_utils::parse_prop_modelindex(&entity_data, "model", usize::MAX);
-                    let submodel = &map_data.map.submodel_lump.submodels[model_idx + 1];
+                    let (model_idx, submodel) = match entity_submodel(&map_data.map, &entity_data) {
+                        Some(v) => v,
+                        None => return
+                    };
                     let pos = submodel.origin;
                     
                     world.spawn((
@@ -226,8 +663,10 @@ impl GameState {
                     ));
                 }
                 "func_plat" => {
-                    let model_idx = parse_utils::parse_prop_modelindex(&entity_data, "model", usize::MAX);
-                    let submodel = &map_data.map.submodel_lump.submodels[model_idx + 1];
+                    let (model_idx, submodel) = match entity_submodel(&map_data.map, &entity_data) {
+                        Some(v) => v,
+                        None => return
+                    };
                     let pos = submodel.origin;
                     
                     world.spawn((
@@ -236,8 +675,10 @@ impl GameState {
                     ));
                 }
                 "func_rotating" => {
-                    let model_idx = parse_utils::parse_prop_modelindex(&entity_data, "model", usize::MAX);
-                    let submodel = &map_data.map.submodel_lump.submodels[model_idx + 1];
+                    let (model_idx, submodel) = match entity_submodel(&map_data.map, &entity_data) {
+                        Some(v) => v,
+                        None => return
+                    };
                     let spawn_flags = parse_utils::parse_prop::<u32>(&entity_data, "spawnflags", 0);
                     let pos = parse_utils::parse_prop_vec3(&entity_data, "origin", submodel.origin);
                     let speed = parse_utils::parse_prop::<f32>(&entity_data, "speed", 0.0);
@@ -254,13 +695,16 @@ impl GameState {
                     
                     world.spawn((
                         Transform3D::default().with_position(pos),
+                        Interpolated::new(&Transform3D::default().with_position(pos)),
                         Rotator { rot_axis: axis, rot_speed: speed },
                         MapModel { model_idx }
                     ));
                 }
                 "func_train" => {
-                    let model_idx = parse_utils::parse_prop_modelindex(&entity_data, "model", usize::MAX);
-                    let submodel = &map_data.map.submodel_lump.submodels[model_idx + 1];
+                    let (model_idx, submodel) = match entity_submodel(&map_data.map, &entity_data) {
+                        Some(v) => v,
+                        None => return
+                    };
                     let pos = submodel.origin;
                     
                     world.spawn((
@@ -275,18 +719,22 @@ impl GameState {
 
         // resolve triggerable entity targets
         let mut cmd_buf = CommandBuffer::new();
-        for (e, targetname) in pending_resolve_targets {
-            if !targetmap.contains_key(&targetname) {
-                logfmt!("Couldn't find trigger target: {}", &targetname);
-            }
-            else {
-                let target_ent = targetmap[&targetname];
-                cmd_buf.insert_one(e, TriggerLink {
-                    target: target_ent
-                });
+        for (e, targetname, delay) in pending_resolve_targets {
+            match targetmap.get(&targetname) {
+                Some(target_ents) => {
+                    cmd_buf.insert_one(e, TriggerLink {
+                        targets: target_ents.clone(),
+                        delay,
+                        prev_triggered: false
+                    });
+                }
+                None => {
+                    logfmt!("Couldn't find trigger target: {}", &targetname);
+                }
             }
         }
         cmd_buf.run_on(&mut world);
+        map_data.target_index = targetmap;
 
         // link doors together if they are touching
         let mut pending_door_links = Vec::new();
@@ -308,31 +756,24 @@ impl GameState {
 
         cmd_buf.run_on(&mut world);
 
-        // player & camera
-        let player_entity = world.spawn((
-            Transform3D::default().with_position(player_start_pos),
-            FPView::new(-player_start_rot, 0.0, 40.0),
-            CharacterController::default(),
-            PlayerInput::new(),
-            DoorOpener {},
-            // Light { max_radius: 200.0, color: Vector3::new(1.0, 1.0, 1.0) }
-        ));
+        // players & cameras
+        // pick the start matching the requested spawnpoint, falling back to the default (unnamed) start
+        map_data.spawn_points = spawn_points;
+        let (player_start_pos, player_start_rot) = match map_data.choose_spawn(spawnpoint, false) {
+            Some(v) => (v.position, v.yaw),
+            None => {
+                logfmt!("No info_player_start found in map {}", map_data.map_name);
+                (Vector3::zero(), 0.0)
+            }
+        };
 
-        world.spawn((
-            Transform3D::default(),
-            Camera::default(),
-            FPCamera::new(player_entity)
-        ));
+        spawn_players(&mut world, NUM_PLAYERS, player_start_pos, player_start_rot);
 
         // test mesh
         world.spawn((
             Transform3D::default().with_scale(Vector3::new(20.0, 20.0, 20.0)).with_rotation(Quaternion::from_euler(Vector3::new(90.0_f32.to_radians(), 0.0, 0.0))),
-            Mesh {
-                mesh: load_mesh("/cd/content/model/leigh/leigh.dbm").unwrap(),
-                bounds_offset: Vector3::new(0.0, 1.25, 0.0),
-                bounds_extents: Vector3::new(1.0, 1.5, 1.0),
-            },
-            MeshAnim { anim: load_mesh_anim("/cd/content/model/leigh/leigh_idle.dba").unwrap(), loop_mode: AnimationCurveLoopMode::Repeat, time: 0.0 },
+            Mesh::from(load_mesh("/cd/content/model/leigh/leigh.dbm").unwrap()).with_bounds_margin(0.25),
+            MeshAnim::new(load_mesh_anim("/cd/content/model/leigh/leigh_idle.dba").unwrap(), AnimationCurveLoopMode::Repeat),
             // CharacterController::default(),
             ColliderBounds { bounds_offset: Vector3::new(0.0, 0.5, 0.0), bounds_extents: Vector3::new(1.0, 2.0, 1.0) }
         ));
@@ -345,16 +786,99 @@ impl GameState {
             }
         ));
 
-        // let music_player = MusicPlayer::new("/cd/content/mus/b8d_toys.qoa", false).unwrap();
+        // looping sounds belong to the old map's speakers
+        sfx::stop_all_loops();
 
-        GameState {
-            gamepad: Gamepad::new(gamepad::GamepadSlot::SlotA),
-            world,
-            time_data: TimeData::default(),
-            map_data: Some(map_data),
-            env: Some(env),
-            music_player: None, //Some(music_player),
-        }
+        self.world = world;
+        self.map_data = Some(map_data);
+        self.env = env;
+    }
+
+    // step the map being loaded in the background, & switch over to it once it's ready
+    fn update_map_loader(self: &mut Self) {
+        let (loader, spawnpoint) = match &mut self.map_loader {
+            Some(v) => (&mut v.0, &v.1),
+            None => return
+        };
+
+        match loader.step() {
+            Ok(Some(map_data)) => {
+                let spawnpoint = spawnpoint.clone();
+                self.map_loader = None;
+                self.enter_map(map_data, &spawnpoint);
+
+                // don't try to catch up on time spent loading
+                self.sim_accumulator = 0.0;
+            }
+            Ok(None) => {
+                loader.draw_progress();
+            }
+            Err(e) => {
+                let next_map = loader.map_name().to_owned();
+                self.map_loader = None;
+
+                logfmt!("Failed changing level to {}: {:?}", next_map, e);
+
+                // drop the broken trigger(s) so that we don't retry every frame
+                let broken = self.world.query::<&ChangeLevel>()
+                    .iter()
+                    .filter(|(_, x)| x.next_map == next_map)
+                    .map(|(e, _)| e)
+                    .collect::<Vec<_>>();
+
+                for e in broken {
+                    self.world.despawn(e).unwrap();
+                }
+            }
+        };
+    }
+
+    /// Write the current map, player, door, trigger & light layer state to a save file
+    pub fn save_state(self: &Self, path: &str) -> Result<(), SaveError> {
+        let map_data = match &self.map_data {
+            Some(v) => v,
+            None => return Ok(())
+        };
+
+        let save_data = SaveData::capture(map_data, &self.world);
+
+        let mut writer = match FileStream::open(path, FileMode::Write) {
+            Ok(v) => v,
+            Err(e) => return Err(SaveError::FileError(e))
+        };
+
+        save_data.write(&mut writer)?;
+        logfmt!("Saved game to {}", path);
+
+        Ok(())
+    }
+
+    /// Restore state from a save file, reloading the saved map first
+    pub fn load_state(self: &mut Self, path: &str) -> Result<(), SaveError> {
+        let mut reader = match FileStream::open(path, FileMode::Read) {
+            Ok(v) => v,
+            Err(e) => return Err(SaveError::FileError(e))
+        };
+
+        let save_data = SaveData::read(&mut reader)?;
+
+        // always reload so that entities are back in their spawn state before the save is applied
+        match self.change_map(&save_data.map_name, "") {
+            Ok(_) => {}
+            Err(e) => return Err(SaveError::MapLoadError(e))
+        };
+
+        match &mut self.map_data {
+            Some(v) => {
+                save_data.apply(v, &mut self.world);
+            }
+            _ => {
+            }
+        };
+
+        logfmt!("Loaded game from {}", path);
+
+        Ok(())
     }
 
     pub fn tick(self: &mut Self) {
@@ -365,44 +889,225 @@ impl GameState {
             music_player.update();
         }
 
-        // update input state
-        let gp_state = self.gamepad.read_state();
-        let input_state = InputState {
+        // update input state for each player
+        let gp_states = self.gamepads.iter_mut().map(|x| x.read_state()).collect::<Vec<_>>();
+        let input_states = gp_states.iter().map(|gp_state| InputState {
             move_x: gp_state.left_stick_x as f32 / i16::MAX as f32,
             move_y: gp_state.left_stick_y as f32 / i16::MAX as f32,
             look_x: gp_state.right_stick_x as f32 / i16::MAX as f32,
             look_y: gp_state.right_stick_y as f32 / i16::MAX as f32,
             crouch: gp_state.is_pressed(gamepad::GamepadButton::B),
-            jump: gp_state.is_pressed(gamepad::GamepadButton::A)
-        };
+            jump: gp_state.is_pressed(gamepad::GamepadButton::A),
+            walk: gp_state.is_pressed(gamepad::GamepadButton::X)
+        }).collect::<Vec<_>>();
+
+        // debug combos are read from the first player's gamepad
+        let gp_state = &gp_states[0];
+
+        // debug: hold Select + Start to hot-reload assets
+        let reload_combo = gp_state.is_pressed(gamepad::GamepadButton::Select) && gp_state.is_pressed(gamepad::GamepadButton::Start);
+        if reload_combo && !self.reload_combo_held {
+            reload_all();
+        }
+        self.reload_combo_held = reload_combo;
+
+        // debug: hold Select + X to toggle noclip
+        let noclip_combo = gp_state.is_pressed(gamepad::GamepadButton::Select) && gp_state.is_pressed(gamepad::GamepadButton::X);
+        if noclip_combo && !self.noclip_combo_held {
+            flycam_toggle_noclip(&mut self.world);
+        }
+        self.noclip_combo_held = noclip_combo;
+
+        // debug: hold Select + L1 to quicksave, Select + R1 to quickload
+        let save_combo = gp_state.is_pressed(gamepad::GamepadButton::Select) && gp_state.is_pressed(gamepad::GamepadButton::L1);
+        if save_combo && !self.save_combo_held {
+            match self.save_state(QUICKSAVE_PATH) {
+                Ok(_) => {}
+                Err(e) => {
+                    logfmt!("Failed saving game: {:?}", e);
+                }
+            };
+        }
+        self.save_combo_held = save_combo;
+
+        let load_combo = gp_state.is_pressed(gamepad::GamepadButton::Select) && gp_state.is_pressed(gamepad::GamepadButton::R1);
+        if load_combo && !self.load_combo_held {
+            match self.load_state(QUICKSAVE_PATH) {
+                Ok(_) => {}
+                Err(e) => {
+                    logfmt!("Failed loading game: {:?}", e);
+                }
+            };
+        }
+        self.load_combo_held = load_combo;
+
+        // debug: hold Select + Y to toggle the frame timing overlay
+        let overlay_combo = gp_state.is_pressed(gamepad::GamepadButton::Select) && gp_state.is_pressed(gamepad::GamepadButton::Y);
+        if overlay_combo && !self.overlay_combo_held {
+            self.debug_overlay.enabled = !self.debug_overlay.enabled;
+        }
+        self.overlay_combo_held = overlay_combo;
+
+        // debug: hold Select + B to cycle through debug visualizations
+        let debug_draw_combo = gp_state.is_pressed(gamepad::GamepadButton::Select) && gp_state.is_pressed(gamepad::GamepadButton::B);
+        if debug_draw_combo && !self.debug_draw_combo_held {
+            self.debug_draw.cycle();
+        }
+        self.debug_draw_combo_held = debug_draw_combo;
+
+        // debug: hold Select + A to toggle between first & third person cameras
+        let tpcam_combo = gp_state.is_pressed(gamepad::GamepadButton::Select) && gp_state.is_pressed(gamepad::GamepadButton::A);
+        if tpcam_combo && !self.tpcam_combo_held {
+            tpcam_toggle(&mut self.world);
+        }
+        self.tpcam_combo_held = tpcam_combo;
 
-        // update time
-        self.time_data.delta_time = DELTA;
-        self.time_data.total_time += DELTA;
+        self.debug_overlay.update();
+
+        // accumulate real elapsed time, so that the simulation runs at a fixed rate regardless of display rate
+        // elapsed time is capped so that a long hitch doesn't make us try to catch up all at once
+        let now = audio::get_time();
+        let elapsed = ((now - self.last_frame_time) as f32).clamp(0.0, DELTA * MAX_SIM_STEPS as f32);
+        self.last_frame_time = now;
+        self.sim_accumulator += elapsed;
+
+        // while the next map loads, the current one is paused & a loading bar is drawn instead
+        if self.map_loader.is_some() {
+            self.update_map_loader();
+            return;
+        }
 
         // update & render
         match &mut self.map_data {
             Some(v) => {
-                rotator_system_update(&self.time_data, &mut self.world);
-                door_system_update(&self.time_data, v, &mut self.world);
-                trigger_link_system_update(&mut self.world);
-                fpview_input_system_update(&input_state, &self.time_data, &mut self.world);
-                character_init(&mut self.world);
-                character_rotation_update(&mut self.world);
-                character_input_update(&input_state, &mut self.world);
-                fpview_eye_update(&self.time_data, &mut self.world);
-                character_apply_input_update(&self.time_data, v, &mut self.world);
-                character_update(&self.time_data, v, &mut self.world);
-                sk_anim_system_update(&self.time_data, &mut self.world);
-                flycam_system_update(&input_state, &self.time_data, &v.map, &mut self.world);
+                while self.sim_accumulator >= DELTA {
+                    self.sim_accumulator -= DELTA;
+
+                    // update time
+                    self.time_data.delta_time = DELTA;
+                    self.time_data.total_time += DELTA;
+
+                    interpolation_snapshot(&mut self.world);
+                    rotator_system_update(&self.time_data, &mut self.world);
+                    door_system_update(&self.time_data, v, &mut self.world);
+                    trigger_link_system_update(&self.time_data, &mut self.world);
+                    delayed_trigger_system_update(&self.time_data, &mut self.world);
+                    explosive_system_update(v, &mut self.world);
+                    fpview_input_system_update(&input_states, &self.time_data, &mut self.world);
+                    character_init(&mut self.world);
+                    character_rotation_update(&mut self.world);
+                    character_input_update(&input_states, &mut self.world);
+                    fpview_eye_update(&self.time_data, &mut self.world);
+                    character_apply_input_update(&self.time_data, v, &mut self.world);
+                    character_update(&self.time_data, v, &mut self.world);
+                    footstep_system_update(&self.time_data, &v.map, &mut self.footstep_sounds, &mut self.world);
+                    v.update_light_layers(self.time_data.total_time);
+                    light_switch_system_update(v, &mut self.world);
+                    areaportal_system_update(v, &mut self.world);
+                    sk_anim_system_update(&self.time_data, &mut self.world);
+                    flycam_system_update(&input_states, &self.time_data, &v.map, &mut self.world);
+                    camera_shake_decay(&self.time_data, &mut self.world);
+                }
+
+                // render interpolated between the last two simulation steps
+                interpolation_apply(self.sim_accumulator / DELTA, &mut self.world);
+
+                attachment_system_update(&mut self.world);
                 fpcam_update(&mut self.world);
-                render_system(&self.time_data, v, &self.env, &mut self.world);
+                tpcam_update(&v.map, &mut self.world);
+                portal_system_update(&mut self.world);
+                ambient_sound_system_update(&v.map, &mut self.world);
+                camera_shake_apply(&self.time_data, &mut self.world);
+                render_system(&self.time_data, v, &self.env, &mut self.post_process, &mut self.debug_overlay, &mut self.debug_draw, &mut self.world);
+
+                camera_shake_restore(&mut self.world);
+                interpolation_restore(&mut self.world);
             }
             _ => {
             }
         };
 
+        // level transitions are deferred until the end of the frame, since they replace the whole world
+        // the next map is loaded over the following frames, so that the game doesn't freeze up while it loads
+        if let Some((next_map, spawnpoint)) = changelevel_system_update(&mut self.world) {
+            self.map_loader = Some((MapLoader::new(&next_map), spawnpoint));
+        }
+
+    }
+}
+
+/// Look up the brush model referenced by an entity's "model" key, logging & returning None if it's missing or out of range
+fn entity_submodel<'a>(map: &'a BspFile, entity_data: &HashMap<&str, &str>) -> Option<(usize, &'a SubModel)> {
+    let model_idx = match parse_utils::parse_prop_modelindex(entity_data, "model") {
+        Some(v) => v,
+        None => {
+            logfmt!("{} has no valid model, ignoring", entity_data["classname"]);
+            return None;
+        }
+    };
+
+    match map.submodel_lump.submodels.get(model_idx + 1) {
+        Some(v) => Some((model_idx, v)),
+        None => {
+            logfmt!("{} references model *{}, but the map only has {} models, ignoring", entity_data["classname"], model_idx + 1, map.submodel_lump.submodels.len());
+            None
+        }
+    }
+}
+
+fn open_gamepads(num_players: usize) -> Vec<Gamepad> {
+    (0..num_players.clamp(1, 4)).map(|i| {
+        let slot = match i {
+            0 => gamepad::GamepadSlot::SlotA,
+            1 => gamepad::GamepadSlot::SlotB,
+            2 => gamepad::GamepadSlot::SlotC,
+            _ => gamepad::GamepadSlot::SlotD,
+        };
+
+        Gamepad::new(slot)
+    }).collect()
+}
+
+/// Calculate the viewport rect for a given player when the screen is split between num_players
+fn splitscreen_viewport(player_index: usize, num_players: usize) -> Option<Rectangle> {
+    match num_players {
+        0 | 1 => None,
+        // top/bottom split
+        2 => Some(Rectangle::new(0, 240 * player_index as i32, 640, 240)),
+        // quadrants
+        _ => Some(Rectangle::new(320 * (player_index % 2) as i32, 240 * (player_index / 2) as i32, 320, 240)),
+    }
+}
+
+/// Spawn a number of local players, each with their own camera & viewport
+pub fn spawn_players(world: &mut World, num_players: usize, start_pos: Vector3, start_rot: f32) -> Vec<Entity> {
+    let mut players = Vec::new();
+
+    for i in 0..num_players {
+        let player_entity = world.spawn((
+            Transform3D::default().with_position(start_pos),
+            Interpolated::new(&Transform3D::default().with_position(start_pos)),
+            FPView::new(-start_rot, 0.0, 40.0),
+            CharacterController::default(),
+            PlayerInput::new(i),
+            DoorOpener {},
+            Footsteps::new(),
+            // Light { max_radius: 200.0, color: Vector3::new(1.0, 1.0, 1.0), dynamic: true }
+        ));
+
+        let mut camera = Camera::default();
+        camera.viewport_rect = splitscreen_viewport(i, num_players);
+
+        world.spawn((
+            Transform3D::default(),
+            camera,
+            FPCamera::new(player_entity)
+        ));
+
+        players.push(player_entity);
     }
+
+    players
 }
 
 fn tick() {
@@ -414,4 +1119,147 @@ pub fn main(_: i32, _: i32) -> i32 {
     db::register_panic();
     vdp::set_vsync_handler(Some(tick));
     return 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_players_split_the_screen_top_and_bottom() {
+        let mut world = World::new();
+        let players = spawn_players(&mut world, 2, Vector3::zero(), 0.0);
+        assert_eq!(players.len(), 2);
+
+        let mut cameras = world.query::<(&Camera, &FPCamera)>()
+            .iter()
+            .map(|(_, (camera, fpcam))| (fpcam.follow_entity, camera.viewport_rect.unwrap()))
+            .collect::<Vec<_>>();
+        cameras.sort_by_key(|(_, rect)| rect.y);
+
+        // each player gets their own camera, covering half of the screen
+        assert_eq!(cameras.len(), 2);
+        assert_eq!(cameras[0].0, players[0]);
+        assert_eq!(cameras[1].0, players[1]);
+        assert_eq!((cameras[0].1.x, cameras[0].1.y, cameras[0].1.width, cameras[0].1.height), (0, 0, 640, 240));
+        assert_eq!((cameras[1].1.x, cameras[1].1.y, cameras[1].1.width, cameras[1].1.height), (0, 240, 640, 240));
+    }
+
+    #[test]
+    fn single_player_uses_the_whole_screen() {
+        let mut world = World::new();
+        spawn_players(&mut world, 1, Vector3::zero(), 0.0);
+
+        let viewports = world.query::<&Camera>()
+            .iter()
+            .map(|(_, camera)| camera.viewport_rect.is_none())
+            .collect::<Vec<_>>();
+
+        assert_eq!(viewports, vec![true]);
+    }
+
+    fn spawn(target_name: &str, x: f32, deathmatch: bool) -> SpawnPoint {
+        SpawnPoint { target_name: target_name.to_owned(), position: Vector3::new(x, 0.0, 0.0), yaw: 0.0, deathmatch }
+    }
+
+    #[test]
+    fn named_spawn_point_is_chosen() {
+        let spawns = [spawn("", 0.0, false), spawn("start_b", 1.0, false), spawn("", 2.0, true)];
+        let mut next_spawn = 0;
+
+        assert_eq!(choose_spawn_index(&spawns, &mut next_spawn, "start_b", false), Some(1));
+        assert_eq!(choose_spawn_index(&spawns, &mut next_spawn, "start_b", true), Some(1));
+
+        // unknown names fall back to the default start
+        assert_eq!(choose_spawn_index(&spawns, &mut next_spawn, "missing", false), Some(0));
+    }
+
+    #[test]
+    fn deathmatch_cycles_through_deathmatch_spawns() {
+        let spawns = [spawn("", 0.0, false), spawn("", 1.0, true), spawn("", 2.0, true)];
+        let mut next_spawn = 0;
+
+        let chosen = (0..4)
+            .map(|_| choose_spawn_index(&spawns, &mut next_spawn, "", true).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(chosen, vec![1, 2, 1, 2]);
+        assert_eq!(choose_spawn_index(&spawns, &mut next_spawn, "", false), Some(0));
+    }
+
+    #[test]
+    fn spawn_falls_back_when_no_default_start() {
+        let mut next_spawn = 0;
+
+        let spawns = [spawn("start_b", 0.0, false), spawn("", 1.0, true)];
+        assert_eq!(choose_spawn_index(&spawns, &mut next_spawn, "", false), Some(0));
+
+        // deathmatch-only maps can still be played single-player
+        let spawns = [spawn("", 0.0, true)];
+        assert_eq!(choose_spawn_index(&spawns, &mut next_spawn, "", false), Some(0));
+
+        assert_eq!(choose_spawn_index(&[], &mut next_spawn, "", true), None);
+    }
+
+    #[test]
+    fn entities_sharing_a_targetname_are_all_found() {
+        let mut test_map = test_map::TestMap::new();
+        test_map.add_box(Vector3::new(-64.0, -64.0, -64.0), Vector3::new(64.0, 64.0, 0.0));
+        let mut map_data = MapLoader::from_bsp(test_map.build()).finish().unwrap();
+
+        let mut world = World::new();
+        let door_a = world.spawn((Transform3D::default(),));
+        let door_b = world.spawn((Transform3D::default(),));
+        let button = world.spawn((Transform3D::default(),));
+
+        // filled in the same way as when the map's entities are spawned
+        for (target_name, e) in [("doors", door_a), ("doors", door_b), ("button", button)] {
+            map_data.target_index.entry(target_name.to_owned()).or_insert_with(Vec::new).push(e);
+        }
+
+        assert_eq!(map_data.entities_named("doors"), &[door_a, door_b]);
+        assert_eq!(map_data.entities_named("button"), &[button]);
+        assert!(map_data.entities_named("missing").is_empty());
+    }
+
+    #[test]
+    fn light_layers_can_be_set_and_pulsed() {
+        let mut test_map = test_map::TestMap::new();
+        test_map.add_box(Vector3::new(-64.0, -64.0, -64.0), Vector3::new(64.0, 64.0, 0.0));
+        let mut map_data = MapLoader::from_bsp(test_map.build()).finish().unwrap();
+
+        assert!(map_data.light_layers.iter().all(|x| *x == 0.0));
+
+        map_data.set_light_layer(3, 0.75);
+        assert_eq!(map_data.light_layers[3], 0.75);
+
+        // a pulse oscillates between zero & its amplitude, starting halfway up
+        map_data.pulse_light_layer(5, 2.0, 0.5);
+        map_data.update_light_layers(0.0);
+        assert!((map_data.light_layers[5] - 1.0).abs() < 0.001);
+        map_data.update_light_layers(0.5);
+        assert!((map_data.light_layers[5] - 2.0).abs() < 0.001);
+        map_data.update_light_layers(1.5);
+        assert!(map_data.light_layers[5].abs() < 0.001);
+
+        for i in 0..100 {
+            map_data.update_light_layers(i as f32 * 0.037);
+            assert!(map_data.light_layers[5] >= 0.0 && map_data.light_layers[5] <= 2.0);
+        }
+
+        // layers which aren't pulsing are left alone
+        assert_eq!(map_data.light_layers[3], 0.75);
+
+        // setting a layer cancels its pulse
+        map_data.set_light_layer(5, 0.25);
+        map_data.update_light_layers(0.5);
+        assert_eq!(map_data.light_layers[5], 0.25);
+
+        // indices past the last custom layer are ignored
+        map_data.set_light_layer(NUM_CUSTOM_LIGHT_LAYERS, 1.0);
+        map_data.pulse_light_layer(NUM_CUSTOM_LIGHT_LAYERS, 1.0, 1.0);
+        map_data.update_light_layers(0.25);
+        assert_eq!(map_data.light_layers[3], 0.75);
+        assert_eq!(map_data.light_layers[5], 0.25);
+    }
 }
\ No newline at end of file
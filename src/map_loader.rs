@@ -0,0 +1,433 @@
+use dbsdk_rs::{db::log, logfmt, math::{Matrix4x4, Vector2, Vector3, Vector4}, vdp::{self, Color32, Rectangle, Texture, TextureUnit}};
+
+use crate::{archive, bsp_file::BspFile, bsp_renderer::{self, BspMapModelRenderer, BspMapTextures, FogMode, FogSettings, LightmapSettings, MapVertex}, parse_utils, MapData, MapLoadError, DEFAULT_GRAVITY, DEFAULT_SKY};
+
+// how many texinfos are loaded per step
+const TEXTURES_PER_STEP: usize = 8;
+
+// how many map models are built per step
+const MODELS_PER_STEP: usize = 4;
+
+// fraction of the progress bar given to each stage. model building fills the rest
+const BSP_PROGRESS: f32 = 0.1;
+const TEXTURE_PROGRESS: f32 = 0.6;
+
+/// Per-map settings, read from worldspawn
+pub struct MapSettings {
+    pub lightmap_settings: LightmapSettings,
+    pub sky_name: String,
+    pub sky_rotate: f32,
+    pub sky_axis: Vector3,
+    pub gravity: f32,
+    pub fog: FogSettings,
+    pub texture_filter: vdp::TextureFilter,
+    pub stream_textures: bool,
+    pub sort_opaque_front_to_back: bool,
+}
+
+enum LoadStage {
+    OpenBsp,
+    Settings,
+    Textures(usize),
+    Models,
+    Done,
+}
+
+/// Loads a map a little at a time, so that the caller can keep drawing (for example, a loading bar) while the map loads
+pub struct MapLoader {
+    map_name: String,
+    stage: LoadStage,
+    bsp: Option<BspFile>,
+    settings: Option<MapSettings>,
+    textures: Option<BspMapTextures>,
+    models: Option<BspMapModelRenderer>,
+}
+
+impl MapSettings {
+    /// Read settings from the map's worldspawn entity
+    pub fn parse(bsp: &BspFile) -> MapSettings {
+        // sky & lightmap brightness can be tuned per-map via worldspawn keys
+        let mut lightmap_settings = LightmapSettings::default();
+        let mut sky_name = DEFAULT_SKY.to_owned();
+        let mut sky_rotate = 0.0;
+        let mut sky_axis = Vector3::new(0.0, 0.0, 1.0);
+        let mut gravity = DEFAULT_GRAVITY;
+        let mut fog = FogSettings::default();
+        let mut texture_filter = vdp::TextureFilter::Linear;
+        let mut stream_textures = false;
+        let mut sort_opaque_front_to_back = false;
+
+        bsp.entity_lump.parse(|entity_data| {
+            if entity_data["classname"] == "worldspawn" {
+                lightmap_settings.overbright = parse_utils::parse_prop::<f32>(&entity_data, "_lm_overbright", lightmap_settings.overbright);
+                lightmap_settings.gamma = parse_utils::parse_prop::<f32>(&entity_data, "_lm_gamma", lightmap_settings.gamma);
+                sky_name = parse_utils::get_prop_str(&entity_data, "sky", DEFAULT_SKY).to_owned();
+                sky_rotate = parse_utils::parse_prop::<f32>(&entity_data, "skyrotate", 0.0);
+                sky_axis = parse_utils::parse_prop_vec3(&entity_data, "skyaxis", sky_axis);
+                gravity = parse_utils::parse_prop::<f32>(&entity_data, "gravity", DEFAULT_GRAVITY);
+
+                // "fog" gives a "start end" distance range for linear fog, while "fogdensity" enables exponential fog instead
+                fog.color = parse_utils::parse_prop_color(&entity_data, "fogcolor", fog.color);
+
+                if entity_data.contains_key("fog") {
+                    let range: Vec<f32> = entity_data["fog"].split_whitespace().filter_map(|x| x.parse().ok()).collect();
+                    if range.len() == 2 && range[1] > range[0] {
+                        fog.mode = FogMode::Linear;
+                        fog.start = range[0];
+                        fog.end = range[1];
+                    }
+                    else {
+                        logfmt!("Malformed value for fog: {}", entity_data["fog"]);
+                    }
+                }
+
+                fog.density = parse_utils::parse_prop::<f32>(&entity_data, "fogdensity", 0.0);
+                if fog.density > 0.0 {
+                    fog.mode = FogMode::Exponential;
+                }
+
+                // large maps can stream textures in & out instead of loading them all up front
+                stream_textures = parse_utils::parse_prop::<i32>(&entity_data, "_stream_textures", 0) != 0;
+
+                // maps with heavy overdraw may draw faster sorted front to back than batched by texture
+                sort_opaque_front_to_back = parse_utils::parse_prop::<i32>(&entity_data, "_sort_opaque", 0) != 0;
+
+                texture_filter = match parse_utils::get_prop_str(&entity_data, "_texture_filter", "linear") {
+                    "nearest" => vdp::TextureFilter::Nearest,
+                    "linear" => vdp::TextureFilter::Linear,
+                    v => {
+                        logfmt!("Malformed value for _texture_filter: {}", v);
+                        vdp::TextureFilter::Linear
+                    }
+                };
+            }
+        });
+
+        if sky_axis.length_sq() <= f32::EPSILON {
+            sky_axis = Vector3::new(0.0, 0.0, 1.0);
+        }
+
+        MapSettings {
+            lightmap_settings,
+            sky_name,
+            sky_rotate,
+            sky_axis: sky_axis.normalized(),
+            gravity,
+            fog,
+            texture_filter,
+            stream_textures,
+            sort_opaque_front_to_back,
+        }
+    }
+}
+
+impl MapLoader {
+    /// Start loading the map with the given name
+    pub fn new(map_name: &str) -> MapLoader {
+        MapLoader {
+            map_name: map_name.to_owned(),
+            stage: LoadStage::OpenBsp,
+            bsp: None,
+            settings: None,
+            textures: None,
+            models: None,
+        }
+    }
+
+    /// Start loading a map from an already-loaded BSP file
+    pub fn from_bsp(bsp: BspFile) -> MapLoader {
+        MapLoader {
+            map_name: String::new(),
+            stage: LoadStage::Settings,
+            bsp: Some(bsp),
+            settings: None,
+            textures: None,
+            models: None,
+        }
+    }
+
+    /// Name of the map being loaded, or an empty string if loading from an already-loaded BSP file
+    pub fn map_name(self: &Self) -> &str {
+        &self.map_name
+    }
+
+    /// How far along loading is, from 0 to 1
+    pub fn progress(self: &Self) -> f32 {
+        match self.stage {
+            LoadStage::OpenBsp => 0.0,
+            LoadStage::Settings => BSP_PROGRESS,
+            LoadStage::Textures(i) => {
+                let num_textures = self.bsp.as_ref().unwrap().tex_info_lump.textures.len().max(1);
+                BSP_PROGRESS + (TEXTURE_PROGRESS * (i as f32 / num_textures as f32))
+            }
+            LoadStage::Models => {
+                let num_models = (self.bsp.as_ref().unwrap().submodel_lump.submodels.len().max(2) - 1) as f32;
+                let built = self.models.as_ref().unwrap().model_count() as f32;
+                BSP_PROGRESS + TEXTURE_PROGRESS + ((1.0 - BSP_PROGRESS - TEXTURE_PROGRESS) * (built / num_models))
+            }
+            LoadStage::Done => 1.0,
+        }
+    }
+
+    /// Do the next chunk of loading work. Returns the finished map data once everything has been loaded, & an error if stepped again after that
+    pub fn step(self: &mut Self) -> Result<Option<MapData>, MapLoadError> {
+        match self.stage {
+            LoadStage::OpenBsp => {
+                logfmt!("Loading map: {}", self.map_name);
+                let mut bsp_file = match archive::open_file(format!("/cd/content/maps/{}.bsp", self.map_name).as_str()) {
+                    Ok(v) => v,
+                    Err(e) => return Err(MapLoadError::IOError(e))
+                };
+                let bsp = match BspFile::new(&mut bsp_file) {
+                    Ok(v) => v,
+                    Err(e) => return Err(MapLoadError::BspError(e))
+                };
+
+                self.bsp = Some(bsp);
+                self.stage = LoadStage::Settings;
+            }
+            LoadStage::Settings => {
+                let bsp = self.bsp.as_ref().unwrap();
+                let settings = MapSettings::parse(bsp);
+
+                let mut textures = BspMapTextures::new_deferred(bsp, settings.stream_textures);
+                textures.filter = settings.texture_filter;
+
+                self.textures = Some(textures);
+                self.settings = Some(settings);
+                self.stage = LoadStage::Textures(0);
+            }
+            LoadStage::Textures(start) => {
+                let bsp = self.bsp.as_ref().unwrap();
+                let textures = self.textures.as_mut().unwrap();
+                let end = (start + TEXTURES_PER_STEP).min(bsp.tex_info_lump.textures.len());
+
+                for i in start..end {
+                    textures.load_step(bsp, i);
+                }
+
+                if end < bsp.tex_info_lump.textures.len() {
+                    self.stage = LoadStage::Textures(end);
+                }
+                else {
+                    self.models = Some(BspMapModelRenderer::new_deferred(&self.settings.as_ref().unwrap().lightmap_settings));
+                    self.stage = LoadStage::Models;
+                }
+            }
+            LoadStage::Models => {
+                let bsp = self.bsp.as_ref().unwrap();
+                let textures = self.textures.as_ref().unwrap();
+                let models = self.models.as_mut().unwrap();
+
+                for _ in 0..MODELS_PER_STEP {
+                    if !models.build_step(bsp, textures) {
+                        self.stage = LoadStage::Done;
+                        break;
+                    }
+                }
+
+                if let LoadStage::Done = self.stage {
+                    let mut map_data = MapData::from_parts(self.bsp.take().unwrap(), self.settings.take().unwrap(), self.textures.take().unwrap(), self.models.take().unwrap());
+                    map_data.map_name = self.map_name.clone();
+
+                    logfmt!("Map loaded");
+                    return Ok(Some(map_data));
+                }
+            }
+            LoadStage::Done => {
+                return Err(MapLoadError::AlreadyLoaded);
+            }
+        };
+
+        Ok(None)
+    }
+
+    /// Step the loader until the map has finished loading
+    pub fn finish(self: &mut Self) -> Result<MapData, MapLoadError> {
+        loop {
+            match self.step()? {
+                Some(v) => return Ok(v),
+                None => {}
+            };
+        }
+    }
+
+    /// Draw a full-screen loading bar showing current progress. Sets up its own VU program & render state
+    pub fn draw_progress(self: &Self) {
+        let progress = self.progress().clamp(0.0, 1.0);
+
+        // bar is drawn in clip space, centered near the bottom of the screen
+        let x0 = -0.5;
+        let x1 = 0.5;
+        let y0 = -0.6;
+        let y1 = -0.65;
+        let xp = x0 + ((x1 - x0) * progress);
+
+        let back_col = Color32::new(64, 64, 64, 255);
+        let fill_col = Color32::new(255, 255, 255, 255);
+
+        let mut geo = Vec::with_capacity(12);
+        for (l, r, col) in [(x0, x1, back_col), (x0, xp, fill_col)] {
+            let v0 = MapVertex::new(Vector4::new(l, y0, 0.0, 1.0), Vector2::zero(), Vector2::zero(), col);
+            let v1 = MapVertex::new(Vector4::new(r, y0, 0.0, 1.0), Vector2::zero(), Vector2::zero(), col);
+            let v2 = MapVertex::new(Vector4::new(l, y1, 0.0, 1.0), Vector2::zero(), Vector2::zero(), col);
+            let v3 = MapVertex::new(Vector4::new(r, y1, 0.0, 1.0), Vector2::zero(), Vector2::zero(), col);
+
+            geo.extend_from_slice(&[v0, v1, v2, v2, v1, v3]);
+        }
+
+        vdp::viewport(Rectangle::new(0, 0, 640, 480));
+        vdp::clear_color(Color32::new(0, 0, 0, 255));
+        vdp::clear_depth(1.0);
+
+        // vertices are already in clip space, so just use the map VU program with an identity transform
+        bsp_renderer::setup_vu();
+        bsp_renderer::load_cdata_matrix(0, &Matrix4x4::identity());
+        vdp::set_vu_cdata(4, &Vector4::zero());
+
+        vdp::set_culling(false);
+        vdp::depth_func(vdp::Compare::Always);
+        vdp::depth_write(false);
+        vdp::blend_equation(vdp::BlendEquation::Add);
+        vdp::blend_func(vdp::BlendFactor::One, vdp::BlendFactor::Zero);
+        vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, None);
+        vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, None);
+
+        vdp::submit_vu(vdp::Topology::TriangleList, geo.as_slice());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_map::TestMap;
+
+    fn settings_for(entities: &str) -> MapSettings {
+        let mut test_map = TestMap::new().with_entities(entities);
+        test_map.add_box(Vector3::new(-64.0, -64.0, -64.0), Vector3::new(64.0, 64.0, 0.0));
+        MapSettings::parse(&test_map.build())
+    }
+
+    #[test]
+    fn gravity_is_read_from_worldspawn() {
+        let settings = settings_for("{\n\"classname\" \"worldspawn\"\n\"gravity\" \"150\"\n}\n");
+        assert_eq!(settings.gravity, 150.0);
+    }
+
+    #[test]
+    fn sky_is_read_from_worldspawn() {
+        let settings = settings_for("{\n\"classname\" \"worldspawn\"\n}\n");
+        assert_eq!(settings.sky_name, DEFAULT_SKY);
+        assert_eq!(settings.sky_rotate, 0.0);
+        assert_eq!((settings.sky_axis.x, settings.sky_axis.y, settings.sky_axis.z), (0.0, 0.0, 1.0));
+
+        let settings = settings_for("{\n\"classname\" \"worldspawn\"\n\"sky\" \"unit1_\"\n\"skyrotate\" \"15\"\n\"skyaxis\" \"0 2 0\"\n}\n");
+        assert_eq!(settings.sky_name, "unit1_");
+        assert_eq!(settings.sky_rotate, 15.0);
+
+        // the axis is normalized
+        assert_eq!((settings.sky_axis.x, settings.sky_axis.y, settings.sky_axis.z), (0.0, 1.0, 0.0));
+
+        // a zero axis can't be rotated around, so it falls back to straight up
+        let settings = settings_for("{\n\"classname\" \"worldspawn\"\n\"skyrotate\" \"15\"\n\"skyaxis\" \"0 0 0\"\n}\n");
+        assert_eq!((settings.sky_axis.x, settings.sky_axis.y, settings.sky_axis.z), (0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn fog_is_read_from_worldspawn() {
+        let settings = settings_for("{\n\"classname\" \"worldspawn\"\n}\n");
+        assert!(settings.fog.mode == FogMode::None);
+
+        let settings = settings_for("{\n\"classname\" \"worldspawn\"\n\"fog\" \"256 1024\"\n\"fogcolor\" \"0.5 0.25 1\"\n}\n");
+        assert!(settings.fog.mode == FogMode::Linear);
+        assert_eq!((settings.fog.start, settings.fog.end), (256.0, 1024.0));
+        assert_eq!((settings.fog.color.x, settings.fog.color.y, settings.fog.color.z), (0.5, 0.25, 1.0));
+
+        // density switches to exponential fog, even if a linear range is also given
+        let settings = settings_for("{\n\"classname\" \"worldspawn\"\n\"fog\" \"256 1024\"\n\"fogdensity\" \"0.002\"\n}\n");
+        assert!(settings.fog.mode == FogMode::Exponential);
+        assert_eq!(settings.fog.density, 0.002);
+
+        // a range which ends before it starts is ignored
+        let settings = settings_for("{\n\"classname\" \"worldspawn\"\n\"fog\" \"1024 256\"\n}\n");
+        assert!(settings.fog.mode == FogMode::None);
+    }
+
+    #[test]
+    fn texture_filter_is_read_from_worldspawn() {
+        let settings = settings_for("{\n\"classname\" \"worldspawn\"\n}\n");
+        assert!(matches!(settings.texture_filter, vdp::TextureFilter::Linear));
+
+        let settings = settings_for("{\n\"classname\" \"worldspawn\"\n\"_texture_filter\" \"nearest\"\n}\n");
+        assert!(matches!(settings.texture_filter, vdp::TextureFilter::Nearest));
+
+        let settings = settings_for("{\n\"classname\" \"worldspawn\"\n\"_texture_filter\" \"blurry\"\n}\n");
+        assert!(matches!(settings.texture_filter, vdp::TextureFilter::Linear));
+    }
+
+    #[test]
+    fn gravity_defaults_when_missing_or_malformed() {
+        assert_eq!(settings_for("{\n\"classname\" \"worldspawn\"\n}\n").gravity, DEFAULT_GRAVITY);
+        assert_eq!(settings_for("{\n\"classname\" \"worldspawn\"\n\"gravity\" \"heavy\"\n}\n").gravity, DEFAULT_GRAVITY);
+    }
+
+    fn loader_test_map() -> TestMap {
+        let mut test_map = TestMap::new().with_entities("{\n\"classname\" \"worldspawn\"\n\"gravity\" \"150\"\n}\n");
+        test_map.add_box(Vector3::new(-64.0, -64.0, -64.0), Vector3::new(64.0, 64.0, 0.0));
+
+        // enough textures & models that each of those stages takes more than one step
+        for i in 0..(TEXTURES_PER_STEP + 2) {
+            test_map.add_texture(format!("tex{}", i).as_str(), 0, 0);
+        }
+
+        for i in 0..(MODELS_PER_STEP + 2) {
+            let x = i as f32 * 32.0;
+            test_map.add_model_box(Vector3::new(x, 0.0, 0.0), Vector3::new(x + 16.0, 16.0, 16.0));
+        }
+
+        test_map
+    }
+
+    #[test]
+    fn stepwise_load_matches_blocking_load() {
+        let test_map = loader_test_map();
+
+        let mut stepwise = MapLoader::from_bsp(test_map.build());
+        let mut steps = 0;
+        let mut progress = stepwise.progress();
+
+        let stepped = loop {
+            steps += 1;
+            let result = stepwise.step().unwrap();
+
+            assert!(stepwise.progress() >= progress);
+            progress = stepwise.progress();
+
+            if let Some(v) = result {
+                break v;
+            }
+        };
+
+        let blocking = MapLoader::from_bsp(test_map.build()).finish().unwrap();
+
+        // settings, 2 texture steps, & 2 model steps
+        assert_eq!(steps, 5);
+        assert_eq!(progress, 1.0);
+
+        assert_eq!(stepped.gravity, blocking.gravity);
+        assert_eq!(stepped.sky_name, blocking.sky_name);
+        assert_eq!(stepped.map_models.model_count(), blocking.map_models.model_count());
+        assert_eq!(stepped.map.tex_info_lump.textures.len(), blocking.map.tex_info_lump.textures.len());
+        assert_eq!(stepped.areaportal_states, blocking.areaportal_states);
+        assert_eq!(stepped.gravity, 150.0);
+    }
+
+    #[test]
+    fn stepping_after_finishing_is_an_error() {
+        let mut loader = MapLoader::from_bsp(loader_test_map().build());
+        assert!(loader.finish().is_ok());
+
+        assert!(matches!(loader.step(), Err(MapLoadError::AlreadyLoaded)));
+        assert!(matches!(loader.finish(), Err(MapLoadError::AlreadyLoaded)));
+    }
+}
\ No newline at end of file
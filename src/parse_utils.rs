@@ -1,14 +1,14 @@
 use std::{collections::HashMap, fmt::Debug, str::FromStr};
 
-use dbsdk_rs::math::Vector3;
+use dbsdk_rs::{db::log, logfmt, math::{Quaternion, Vector3}};
 
-fn parse_vec3(src: &str) -> Vector3 {
+fn parse_vec3(src: &str) -> Option<Vector3> {
     let mut split = src.split_whitespace();
-    let x = split.nth(0).unwrap().parse::<f32>().unwrap();
-    let y = split.nth(0).unwrap().parse::<f32>().unwrap();
-    let z = split.nth(0).unwrap().parse::<f32>().unwrap();
+    let x = split.next()?.parse::<f32>().ok()?;
+    let y = split.next()?.parse::<f32>().ok()?;
+    let z = split.next()?.parse::<f32>().ok()?;
 
-    return Vector3::new(x, y, z);
+    return Some(Vector3::new(x, y, z));
 }
 
 pub fn parse_prop<T: FromStr>(props: &HashMap<&str, &str>, prop_name: &str, default_value: T) -> T
@@ -17,7 +17,13 @@ pub fn parse_prop<T: FromStr>(props: &HashMap<&str, &str>, prop_name: &str, defa
         return default_value;
     }
 
-    return props[prop_name].parse::<T>().unwrap();
+    match props[prop_name].parse::<T>() {
+        Ok(v) => v,
+        Err(_) => {
+            logfmt!("Malformed value for {}: {}", prop_name, props[prop_name]);
+            default_value
+        }
+    }
 }
 
 pub fn parse_prop_vec3(props: &HashMap<&str, &str>, prop_name: &str, default_value: Vector3) -> Vector3 {
@@ -25,15 +31,82 @@ pub fn parse_prop_vec3(props: &HashMap<&str, &str>, prop_name: &str, default_val
         return default_value;
     }
 
-    return parse_vec3(props[prop_name]);
+    match parse_vec3(props[prop_name]) {
+        Some(v) => v,
+        None => {
+            logfmt!("Malformed value for {}: {}", prop_name, props[prop_name]);
+            default_value
+        }
+    }
 }
 
-pub fn parse_prop_modelindex(props: &HashMap<&str, &str>, prop_name: &str, default_value: usize) -> usize {
+/// Parse a color authored either as normalized floats ("1 0.5 0.25") or as 0-255 ints ("255 128 64"). Returns the default if missing or malformed
+/// If any component is greater than 1, every component is treated as 0-255. This means overbright float colors (such as "2 1 1") are also divided down by 255
+pub fn parse_prop_color(props: &HashMap<&str, &str>, prop_name: &str, default_value: Vector3) -> Vector3 {
     if !props.contains_key(prop_name) {
         return default_value;
     }
 
-    return props[prop_name][1..].parse::<i32>().unwrap() as usize - 1;
+    let col = match parse_vec3(props[prop_name]) {
+        Some(v) => v,
+        None => {
+            logfmt!("Malformed value for {}: {}", prop_name, props[prop_name]);
+            return default_value;
+        }
+    };
+
+    // colors may be authored either as normalized floats or as 0-255 ints depending on the tool
+    if col.x > 1.0 || col.y > 1.0 || col.z > 1.0 {
+        return col / 255.0;
+    }
+
+    return col;
+}
+
+/// Parse a "pitch yaw roll" triple in degrees (as used by "angles" & "mangle") into a rotation. Returns identity if missing or malformed
+pub fn parse_prop_angles(props: &HashMap<&str, &str>, prop_name: &str) -> Quaternion {
+    if !props.contains_key(prop_name) {
+        return Quaternion::identity();
+    }
+
+    let angles = match parse_vec3(props[prop_name]) {
+        Some(v) => v,
+        None => return Quaternion::identity()
+    };
+
+    // pitch rotates around X, roll around the forward (Y) axis, yaw around Z
+    return Quaternion::from_euler(Vector3::new(angles.x.to_radians(), angles.z.to_radians(), angles.y.to_radians()));
+}
+
+/// Check whether the given flag is set in the entity's "spawnflags". Malformed spawnflags are treated as zero
+pub fn has_spawnflag(props: &HashMap<&str, &str>, flag: u32) -> bool {
+    let spawn_flags = match props.get("spawnflags") {
+        Some(v) => v.parse::<u32>().unwrap_or(0),
+        None => 0
+    };
+
+    return spawn_flags & flag != 0;
+}
+
+/// Parse a brush model reference ("*N") into an index into the map's submodels, excluding the world. Returns None if missing or malformed
+pub fn parse_prop_modelindex(props: &HashMap<&str, &str>, prop_name: &str) -> Option<usize> {
+    if !props.contains_key(prop_name) {
+        return None;
+    }
+
+    // model indices are written as "*N", where *0 is the world
+    let index = match props[prop_name].strip_prefix('*') {
+        Some(v) => v.parse::<usize>().ok(),
+        None => None
+    };
+
+    match index {
+        Some(v) if v > 0 => Some(v - 1),
+        _ => {
+            logfmt!("Malformed value for {}: {}", prop_name, props[prop_name]);
+            None
+        }
+    }
 }
 
 pub fn get_prop_str<'a>(props: &'a HashMap<&str, &str>, prop_name: &str, default_value: &'a str) -> &'a str {
@@ -42,4 +115,108 @@ pub fn get_prop_str<'a>(props: &'a HashMap<&str, &str>, prop_name: &str, default
     }
 
     return props[prop_name];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn props<'a>(pairs: &[(&'a str, &'a str)]) -> HashMap<&'a str, &'a str> {
+        pairs.iter().cloned().collect()
+    }
+
+    #[test]
+    fn malformed_number_returns_default() {
+        let p = props(&[("speed", "100abc"), ("lip", "")]);
+
+        assert_eq!(parse_prop::<f32>(&p, "speed", 50.0), 50.0);
+        assert_eq!(parse_prop::<f32>(&p, "lip", 8.0), 8.0);
+        assert_eq!(parse_prop::<i32>(&p, "missing", 3), 3);
+    }
+
+    #[test]
+    fn well_formed_number_is_parsed() {
+        let p = props(&[("speed", "150")]);
+        assert_eq!(parse_prop::<f32>(&p, "speed", 50.0), 150.0);
+    }
+
+    #[test]
+    fn malformed_vec3_returns_default() {
+        let p = props(&[("short", "1 2"), ("junk", "1 two 3"), ("ok", "1 2 3")]);
+        let default = Vector3::new(4.0, 5.0, 6.0);
+
+        let v = parse_prop_vec3(&p, "short", default);
+        assert_eq!((v.x, v.y, v.z), (4.0, 5.0, 6.0));
+
+        let v = parse_prop_vec3(&p, "junk", default);
+        assert_eq!((v.x, v.y, v.z), (4.0, 5.0, 6.0));
+
+        let v = parse_prop_vec3(&p, "ok", default);
+        assert_eq!((v.x, v.y, v.z), (1.0, 2.0, 3.0));
+    }
+
+    #[test]
+    fn color_is_parsed_from_either_range() {
+        let p = props(&[("normalized", "1 0.5 0.25"), ("bytes", "255 127.5 0"), ("dark", "0 0 1"), ("overbright", "2 1 1"), ("junk", "1 0.5")]);
+        let default = Vector3::new(1.0, 1.0, 1.0);
+
+        let v = parse_prop_color(&p, "normalized", default);
+        assert_eq!((v.x, v.y, v.z), (1.0, 0.5, 0.25));
+
+        let v = parse_prop_color(&p, "bytes", default);
+        assert!((v.x - 1.0).abs() < 0.0001 && (v.y - 0.5).abs() < 0.0001 && v.z == 0.0);
+
+        // components of exactly 1 are still read as normalized
+        let v = parse_prop_color(&p, "dark", default);
+        assert_eq!((v.x, v.y, v.z), (0.0, 0.0, 1.0));
+
+        // a component over 1 makes the whole color read as 0-255
+        let v = parse_prop_color(&p, "overbright", default);
+        assert!((v.x - (2.0 / 255.0)).abs() < 0.0001 && (v.y - (1.0 / 255.0)).abs() < 0.0001 && (v.z - (1.0 / 255.0)).abs() < 0.0001);
+
+        let v = parse_prop_color(&p, "junk", default);
+        assert_eq!((v.x, v.y, v.z), (1.0, 1.0, 1.0));
+
+        let v = parse_prop_color(&p, "missing", default);
+        assert_eq!((v.x, v.y, v.z), (1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn malformed_modelindex_returns_none() {
+        let p = props(&[("a", "*abc"), ("b", "3"), ("c", "*0"), ("d", "*"), ("e", "*2")]);
+
+        assert_eq!(parse_prop_modelindex(&p, "a"), None);
+        assert_eq!(parse_prop_modelindex(&p, "b"), None);
+        assert_eq!(parse_prop_modelindex(&p, "c"), None);
+        assert_eq!(parse_prop_modelindex(&p, "d"), None);
+        assert_eq!(parse_prop_modelindex(&p, "missing"), None);
+        assert_eq!(parse_prop_modelindex(&p, "e"), Some(1));
+    }
+
+    fn assert_quat_eq(a: Quaternion, b: Quaternion) {
+        assert!((a.x - b.x).abs() < 0.0001 && (a.y - b.y).abs() < 0.0001 && (a.z - b.z).abs() < 0.0001 && (a.w - b.w).abs() < 0.0001);
+    }
+
+    #[test]
+    fn angles_map_pitch_yaw_roll_onto_axes() {
+        let p = props(&[("angles", "10 20 30"), ("short", "10 20")]);
+
+        let expected = Quaternion::from_euler(Vector3::new(10f32.to_radians(), 30f32.to_radians(), 20f32.to_radians()));
+        assert_quat_eq(parse_prop_angles(&p, "angles"), expected);
+
+        assert_quat_eq(parse_prop_angles(&p, "short"), Quaternion::identity());
+        assert_quat_eq(parse_prop_angles(&p, "missing"), Quaternion::identity());
+    }
+
+    #[test]
+    fn spawnflags_are_tested_bitwise() {
+        let p = props(&[("spawnflags", "5")]);
+
+        assert!(has_spawnflag(&p, 1));
+        assert!(!has_spawnflag(&p, 2));
+        assert!(has_spawnflag(&p, 4));
+
+        assert!(!has_spawnflag(&props(&[("spawnflags", "junk")]), 1));
+        assert!(!has_spawnflag(&props(&[]), 1));
+    }
 }
\ No newline at end of file
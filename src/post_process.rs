@@ -0,0 +1,120 @@
+use dbsdk_rs::{math::{Matrix4x4, Vector2, Vector4}, vdp::{self, Color32, Rectangle, Texture, TextureUnit}};
+
+use crate::bsp_renderer::{self, MapVertex};
+
+// framebuffer copies are made into a power-of-two texture, so only the top-left region is used
+const FB_TEX_WIDTH: i32 = 1024;
+const FB_TEX_HEIGHT: i32 = 512;
+
+/// Full-screen gamma & brightness adjustment, applied after all cameras have rendered
+pub struct PostProcess {
+    /// Values above 1 darken midtones, values below 1 brighten them
+    pub gamma: f32,
+    /// Multiplier applied to the final image, from 0 to 2
+    pub brightness: f32,
+    fb_tex: Option<Texture>,
+}
+
+// draw a full-screen quad in clip space with the given vertex color & texture coordinate scale
+fn draw_fullscreen_quad(col: Color32, uv_scale: Vector2) {
+    let quad = [
+        MapVertex::new(Vector4::new(-1.0,  1.0, 0.0, 1.0), Vector2::new(0.0, 0.0), Vector2::zero(), col),
+        MapVertex::new(Vector4::new( 1.0,  1.0, 0.0, 1.0), Vector2::new(uv_scale.x, 0.0), Vector2::zero(), col),
+        MapVertex::new(Vector4::new(-1.0, -1.0, 0.0, 1.0), Vector2::new(0.0, uv_scale.y), Vector2::zero(), col),
+
+        MapVertex::new(Vector4::new(-1.0, -1.0, 0.0, 1.0), Vector2::new(0.0, uv_scale.y), Vector2::zero(), col),
+        MapVertex::new(Vector4::new( 1.0,  1.0, 0.0, 1.0), Vector2::new(uv_scale.x, 0.0), Vector2::zero(), col),
+        MapVertex::new(Vector4::new( 1.0, -1.0, 0.0, 1.0), Vector2::new(uv_scale.x, uv_scale.y), Vector2::zero(), col),
+    ];
+
+    vdp::submit_vu(vdp::Topology::TriangleList, &quad);
+}
+
+fn unorm_color(v: f32) -> Color32 {
+    let c = (v.clamp(0.0, 1.0) * 255.0) as u8;
+    Color32::new(c, c, c, 255)
+}
+
+impl PostProcess {
+    pub fn new() -> PostProcess {
+        PostProcess {
+            gamma: 1.0,
+            brightness: 1.0,
+            fb_tex: None,
+        }
+    }
+
+    /// Whether the current settings leave the image unchanged, in which case drawing is skipped entirely
+    pub fn is_identity(self: &Self) -> bool {
+        (self.gamma - 1.0).abs() <= f32::EPSILON && (self.brightness - 1.0).abs() <= f32::EPSILON
+    }
+
+    /// Apply gamma & brightness to the whole screen. Sets up its own VU program & render state
+    pub fn draw(self: &mut Self) {
+        if self.is_identity() {
+            return;
+        }
+
+        let screen = Rectangle::new(0, 0, 640, 480);
+
+        // vertices are already in clip space, so just use the map VU program with an identity transform
+        bsp_renderer::setup_vu();
+        bsp_renderer::load_cdata_matrix(0, &Matrix4x4::identity());
+        vdp::set_vu_cdata(4, &Vector4::zero());
+
+        vdp::viewport(screen);
+        vdp::set_culling(false);
+        vdp::depth_func(vdp::Compare::Always);
+        vdp::depth_write(false);
+        vdp::blend_equation(vdp::BlendEquation::Add);
+        vdp::set_tex_combine(vdp::TexCombine::None, vdp::TexCombine::Mul);
+        vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, None);
+
+        // the blend unit can only multiply & add, so gamma is approximated by blending the image towards either
+        // itself squared (darken) or its screen blend with itself (brighten), which closely match gamma 2 & 0.5
+        if (self.gamma - 1.0).abs() > f32::EPSILON {
+            if self.fb_tex.is_none() {
+                self.fb_tex = Some(Texture::new(FB_TEX_WIDTH, FB_TEX_HEIGHT, false, vdp::TextureFormat::RGBA8888).unwrap());
+            }
+
+            let fb_tex = self.fb_tex.as_ref().unwrap();
+            vdp::copy_fb_to_texture(&screen, &screen, fb_tex);
+
+            vdp::bind_texture_slot(TextureUnit::TU0, Some(fb_tex));
+            vdp::set_sample_params_slot(TextureUnit::TU0, vdp::TextureFilter::Nearest, vdp::TextureWrap::Clamp, vdp::TextureWrap::Clamp);
+
+            let uv_scale = Vector2::new(640.0 / FB_TEX_WIDTH as f32, 480.0 / FB_TEX_HEIGHT as f32);
+
+            if self.gamma > 1.0 {
+                // dst * (t * src + (1 - t)) = lerp(x, x^2, t)
+                let t = (self.gamma - 1.0).min(1.0);
+                vdp::set_vu_cdata(4, &Vector4::new(1.0 - t, 1.0 - t, 1.0 - t, 0.0));
+                vdp::blend_func(vdp::BlendFactor::DstColor, vdp::BlendFactor::Zero);
+                draw_fullscreen_quad(unorm_color(t), uv_scale);
+                vdp::set_vu_cdata(4, &Vector4::zero());
+            }
+            else {
+                // t * src + dst * (1 - t * src) = lerp(x, 1 - (1 - x)^2, t)
+                let t = ((1.0 / self.gamma.max(0.01)) - 1.0).min(1.0);
+                vdp::blend_func(vdp::BlendFactor::One, vdp::BlendFactor::OneMinusSrcColor);
+                draw_fullscreen_quad(unorm_color(t), uv_scale);
+            }
+        }
+
+        // brightness only needs the destination color, so no copy is needed
+        vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, None);
+
+        if self.brightness > 1.0 {
+            // dst * src + dst = dst * (1 + src)
+            vdp::blend_func(vdp::BlendFactor::DstColor, vdp::BlendFactor::One);
+            draw_fullscreen_quad(unorm_color(self.brightness - 1.0), Vector2::zero());
+        }
+        else if self.brightness < 1.0 {
+            vdp::blend_func(vdp::BlendFactor::DstColor, vdp::BlendFactor::Zero);
+            draw_fullscreen_quad(unorm_color(self.brightness.max(0.0)), Vector2::zero());
+        }
+
+        vdp::blend_func(vdp::BlendFactor::One, vdp::BlendFactor::Zero);
+        vdp::set_tex_combine(vdp::TexCombine::Mul, vdp::TexCombine::Mul);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,427 @@
+use std::io::{Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use dbsdk_rs::{io::IOError, math::{Quaternion, Vector3}};
+use hecs::{CommandBuffer, World};
+
+use crate::{bsp_file::NUM_CUSTOM_LIGHT_LAYERS, component::{charactercontroller::{CharacterInputState, CharacterState}, explosive::Explosive, fpview::FPView, light::LightSwitch, mapmodel::MapModel, playerinput::PlayerInput, transform3d::Transform3D, triggerable::TriggerState}, LightLayerPulse, MapData, MapLoadError};
+
+const SAVE_MAGIC: u32 = 0x56535652; // "RVSV"
+const SAVE_VERSION: u32 = 1;
+
+// longest map name a save file may contain
+const MAX_MAP_NAME_LEN: usize = 256;
+
+/// Enumeration of errors which can result from saving or restoring game state
+#[derive(Debug)]
+pub enum SaveError {
+    FileError(IOError),
+    IOError(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    MapNameTooLong(usize),
+    MapLoadError(MapLoadError),
+}
+
+impl From<std::io::Error> for SaveError {
+    fn from(value: std::io::Error) -> Self {
+        SaveError::IOError(value)
+    }
+}
+
+/// Saved state of a single player, keyed by their input slot
+pub struct PlayerSave {
+    pub slot: usize,
+    pub position: Vector3,
+    pub rotation: Quaternion,
+    pub view: FPView,
+    pub state: CharacterState,
+}
+
+/// Saved state of a brush entity with a trigger state (doors, buttons, etc), keyed by its submodel index
+pub struct MapModelSave {
+    pub model_idx: usize,
+    pub triggered: bool,
+    pub position: Vector3,
+}
+
+/// Saved state of a switchable light, keyed by its light layer
+pub struct LightSwitchSave {
+    pub layer: usize,
+    pub triggered: bool,
+}
+
+/// A snapshot of game state which can be written to & read back from a save file
+pub struct SaveData {
+    pub map_name: String,
+    pub players: Vec<PlayerSave>,
+    pub map_models: Vec<MapModelSave>,
+    pub light_switches: Vec<LightSwitchSave>,
+    pub light_layers: [f32;NUM_CUSTOM_LIGHT_LAYERS],
+    pub light_layer_pulses: [LightLayerPulse;NUM_CUSTOM_LIGHT_LAYERS],
+    pub areaportal_states: Vec<bool>,
+}
+
+fn write_vec3<W: Write>(writer: &mut W, v: Vector3) -> Result<(), std::io::Error> {
+    writer.write_f32::<LittleEndian>(v.x)?;
+    writer.write_f32::<LittleEndian>(v.y)?;
+    writer.write_f32::<LittleEndian>(v.z)?;
+    Ok(())
+}
+
+fn read_vec3<R: Read>(reader: &mut R) -> Result<Vector3, std::io::Error> {
+    let x = reader.read_f32::<LittleEndian>()?;
+    let y = reader.read_f32::<LittleEndian>()?;
+    let z = reader.read_f32::<LittleEndian>()?;
+    Ok(Vector3::new(x, y, z))
+}
+
+impl SaveData {
+    /// Capture the current state of the given map & world
+    pub fn capture(map_data: &MapData, world: &World) -> SaveData {
+        let players = world.query::<(&PlayerInput, &Transform3D, &FPView, &CharacterState)>()
+            .iter()
+            .map(|(_, (input, transform, view, state))| PlayerSave {
+                slot: input.slot,
+                position: transform.position,
+                rotation: transform.rotation,
+                view: *view,
+                state: *state,
+            })
+            .collect::<Vec<_>>();
+
+        let map_models = world.query::<(&MapModel, &TriggerState, &Transform3D)>()
+            .iter()
+            .map(|(_, (mapmodel, trigger, transform))| MapModelSave {
+                model_idx: mapmodel.model_idx,
+                triggered: trigger.triggered,
+                position: transform.position,
+            })
+            .collect::<Vec<_>>();
+
+        let light_switches = world.query::<(&LightSwitch, &TriggerState)>()
+            .iter()
+            .map(|(_, (switch, trigger))| LightSwitchSave {
+                layer: switch.layer,
+                triggered: trigger.triggered,
+            })
+            .collect::<Vec<_>>();
+
+        SaveData {
+            map_name: map_data.map_name.clone(),
+            players,
+            map_models,
+            light_switches,
+            light_layers: map_data.light_layers,
+            light_layer_pulses: map_data.light_layer_pulses,
+            areaportal_states: map_data.areaportal_states.clone(),
+        }
+    }
+
+    /// Serialize save data to the given writer
+    pub fn write<W: Write>(self: &Self, writer: &mut W) -> Result<(), SaveError> {
+        writer.write_u32::<LittleEndian>(SAVE_MAGIC)?;
+        writer.write_u32::<LittleEndian>(SAVE_VERSION)?;
+
+        if self.map_name.len() > MAX_MAP_NAME_LEN {
+            return Err(SaveError::MapNameTooLong(self.map_name.len()));
+        }
+
+        writer.write_u32::<LittleEndian>(self.map_name.len() as u32)?;
+        writer.write_all(self.map_name.as_bytes())?;
+
+        writer.write_u32::<LittleEndian>(self.players.len() as u32)?;
+        for player in &self.players {
+            writer.write_u32::<LittleEndian>(player.slot as u32)?;
+            write_vec3(writer, player.position)?;
+            writer.write_f32::<LittleEndian>(player.rotation.x)?;
+            writer.write_f32::<LittleEndian>(player.rotation.y)?;
+            writer.write_f32::<LittleEndian>(player.rotation.z)?;
+            writer.write_f32::<LittleEndian>(player.rotation.w)?;
+            writer.write_f32::<LittleEndian>(player.view.yaw)?;
+            writer.write_f32::<LittleEndian>(player.view.pitch)?;
+            writer.write_f32::<LittleEndian>(player.view.eye_offset)?;
+            writer.write_f32::<LittleEndian>(player.state.height)?;
+            write_vec3(writer, player.state.velocity)?;
+            writer.write_u8(player.state.grounded as u8)?;
+            writer.write_u8(player.state.crouched as u8)?;
+        }
+
+        writer.write_u32::<LittleEndian>(self.map_models.len() as u32)?;
+        for mapmodel in &self.map_models {
+            writer.write_u32::<LittleEndian>(mapmodel.model_idx as u32)?;
+            writer.write_u8(mapmodel.triggered as u8)?;
+            write_vec3(writer, mapmodel.position)?;
+        }
+
+        writer.write_u32::<LittleEndian>(self.light_switches.len() as u32)?;
+        for switch in &self.light_switches {
+            writer.write_u32::<LittleEndian>(switch.layer as u32)?;
+            writer.write_u8(switch.triggered as u8)?;
+        }
+
+        for i in 0..NUM_CUSTOM_LIGHT_LAYERS {
+            writer.write_f32::<LittleEndian>(self.light_layers[i])?;
+            writer.write_f32::<LittleEndian>(self.light_layer_pulses[i].amplitude)?;
+            writer.write_f32::<LittleEndian>(self.light_layer_pulses[i].hz)?;
+        }
+
+        writer.write_u32::<LittleEndian>(self.areaportal_states.len() as u32)?;
+        for state in &self.areaportal_states {
+            writer.write_u8(*state as u8)?;
+        }
+
+        Ok(())
+    }
+
+    /// Deserialize save data from the given reader
+    pub fn read<R: Read>(reader: &mut R) -> Result<SaveData, SaveError> {
+        let magic = reader.read_u32::<LittleEndian>()?;
+        if magic != SAVE_MAGIC {
+            return Err(SaveError::BadMagic);
+        }
+
+        let version = reader.read_u32::<LittleEndian>()?;
+        if version != SAVE_VERSION {
+            return Err(SaveError::UnsupportedVersion(version));
+        }
+
+        let map_name_len = reader.read_u32::<LittleEndian>()? as usize;
+        if map_name_len > MAX_MAP_NAME_LEN {
+            return Err(SaveError::MapNameTooLong(map_name_len));
+        }
+
+        let mut map_name = vec![0;map_name_len];
+        reader.read_exact(&mut map_name)?;
+        let map_name = match String::from_utf8(map_name) {
+            Ok(v) => v,
+            Err(_) => return Err(SaveError::IOError(std::io::Error::new(std::io::ErrorKind::InvalidData, "map name is not valid UTF-8")))
+        };
+
+        let num_players = reader.read_u32::<LittleEndian>()?;
+        let mut players = Vec::new();
+        for _ in 0..num_players {
+            let slot = reader.read_u32::<LittleEndian>()? as usize;
+            let position = read_vec3(reader)?;
+            let rx = reader.read_f32::<LittleEndian>()?;
+            let ry = reader.read_f32::<LittleEndian>()?;
+            let rz = reader.read_f32::<LittleEndian>()?;
+            let rw = reader.read_f32::<LittleEndian>()?;
+            let yaw = reader.read_f32::<LittleEndian>()?;
+            let pitch = reader.read_f32::<LittleEndian>()?;
+            let eye_offset = reader.read_f32::<LittleEndian>()?;
+            let height = reader.read_f32::<LittleEndian>()?;
+            let velocity = read_vec3(reader)?;
+            let grounded = reader.read_u8()? != 0;
+            let crouched = reader.read_u8()? != 0;
+
+            let mut state = CharacterState::new(height);
+            state.velocity = velocity;
+            state.grounded = grounded;
+            state.crouched = crouched;
+
+            players.push(PlayerSave {
+                slot,
+                position,
+                rotation: Quaternion::new(rx, ry, rz, rw),
+                view: FPView::new(yaw, pitch, eye_offset),
+                state,
+            });
+        }
+
+        let num_map_models = reader.read_u32::<LittleEndian>()?;
+        let mut map_models = Vec::new();
+        for _ in 0..num_map_models {
+            let model_idx = reader.read_u32::<LittleEndian>()? as usize;
+            let triggered = reader.read_u8()? != 0;
+            let position = read_vec3(reader)?;
+
+            map_models.push(MapModelSave { model_idx, triggered, position });
+        }
+
+        let num_light_switches = reader.read_u32::<LittleEndian>()?;
+        let mut light_switches = Vec::new();
+        for _ in 0..num_light_switches {
+            let layer = reader.read_u32::<LittleEndian>()? as usize;
+            let triggered = reader.read_u8()? != 0;
+
+            light_switches.push(LightSwitchSave { layer, triggered });
+        }
+
+        let mut light_layers = [0.0;NUM_CUSTOM_LIGHT_LAYERS];
+        let mut light_layer_pulses = [LightLayerPulse::default();NUM_CUSTOM_LIGHT_LAYERS];
+        for i in 0..NUM_CUSTOM_LIGHT_LAYERS {
+            light_layers[i] = reader.read_f32::<LittleEndian>()?;
+            light_layer_pulses[i].amplitude = reader.read_f32::<LittleEndian>()?;
+            light_layer_pulses[i].hz = reader.read_f32::<LittleEndian>()?;
+        }
+
+        let num_areaportal_states = reader.read_u32::<LittleEndian>()?;
+        let mut areaportal_states = Vec::new();
+        for _ in 0..num_areaportal_states {
+            areaportal_states.push(reader.read_u8()? != 0);
+        }
+
+        Ok(SaveData {
+            map_name,
+            players,
+            map_models,
+            light_switches,
+            light_layers,
+            light_layer_pulses,
+            areaportal_states,
+        })
+    }
+
+    /// Apply saved state to a freshly loaded map & world. The map should match the one the save was captured from
+    pub fn apply(self: &Self, map_data: &mut MapData, world: &mut World) {
+        let mut cmd_buf = CommandBuffer::new();
+
+        for (eid, (input, transform, view)) in world.query_mut::<(&PlayerInput, &mut Transform3D, &mut FPView)>() {
+            if let Some(player) = self.players.iter().find(|x| x.slot == input.slot) {
+                transform.position = player.position;
+                transform.rotation = player.rotation;
+                *view = player.view;
+
+                // character state may not have been initialized yet on a fresh map
+                cmd_buf.insert(eid, (player.state, CharacterInputState::default()));
+            }
+        }
+
+        cmd_buf.run_on(world);
+
+        for (_, (mapmodel, trigger, transform)) in world.query_mut::<(&MapModel, &mut TriggerState, &mut Transform3D)>() {
+            if let Some(saved) = self.map_models.iter().find(|x| x.model_idx == mapmodel.model_idx) {
+                trigger.triggered = saved.triggered;
+                transform.position = saved.position;
+            }
+        }
+
+        // explosives missing from the save had already been destroyed
+        let destroyed = world.query::<(&Explosive, &MapModel)>()
+            .iter()
+            .filter(|(_, (_, mapmodel))| !self.map_models.iter().any(|x| x.model_idx == mapmodel.model_idx))
+            .map(|(e, _)| e)
+            .collect::<Vec<_>>();
+
+        for e in destroyed {
+            world.despawn(e).unwrap();
+        }
+
+        // the saved layer values already reflect each switch's state, so the switches shouldn't write over them (or any pulse) again
+        for (_, (switch, trigger)) in world.query_mut::<(&mut LightSwitch, &mut TriggerState)>() {
+            if let Some(saved) = self.light_switches.iter().find(|x| x.layer == switch.layer) {
+                trigger.triggered = saved.triggered;
+            }
+
+            switch.applied = Some(trigger.triggered != switch.start_on);
+        }
+
+        map_data.light_layers = self.light_layers;
+        map_data.light_layer_pulses = self.light_layer_pulses;
+
+        // a mismatched portal count means the map has changed since the save was made
+        if self.areaportal_states.len() == map_data.areaportal_states.len() {
+            map_data.areaportal_states.copy_from_slice(&self.areaportal_states);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_save() -> SaveData {
+        let mut state = CharacterState::new(56.0);
+        state.velocity = Vector3::new(10.0, -20.0, 30.0);
+        state.grounded = true;
+
+        let mut light_layers = [0.0;NUM_CUSTOM_LIGHT_LAYERS];
+        light_layers[3] = 0.5;
+
+        let mut light_layer_pulses = [LightLayerPulse::default();NUM_CUSTOM_LIGHT_LAYERS];
+        light_layer_pulses[3] = LightLayerPulse { amplitude: 0.25, hz: 2.0 };
+
+        SaveData {
+            map_name: "base1".to_owned(),
+            players: vec![PlayerSave {
+                slot: 0,
+                position: Vector3::new(128.0, -64.0, 24.0),
+                rotation: Quaternion::new(0.0, 0.0, 0.5, 0.5),
+                view: FPView::new(90.0, -10.0, 48.0),
+                state,
+            }],
+            map_models: vec![
+                MapModelSave { model_idx: 2, triggered: true, position: Vector3::new(0.0, 0.0, 96.0) },
+                MapModelSave { model_idx: 5, triggered: false, position: Vector3::new(32.0, 0.0, 0.0) },
+            ],
+            light_switches: vec![LightSwitchSave { layer: 3, triggered: true }],
+            light_layers,
+            light_layer_pulses,
+            areaportal_states: vec![true, false, true],
+        }
+    }
+
+    fn write_bytes(save: &SaveData) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        save.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn save_data_round_trips() {
+        let save = test_save();
+        let loaded = SaveData::read(&mut write_bytes(&save).as_slice()).unwrap();
+
+        assert_eq!(loaded.map_name, "base1");
+
+        assert_eq!(loaded.players.len(), 1);
+        let player = &loaded.players[0];
+        assert_eq!(player.slot, 0);
+        assert_eq!((player.position.x, player.position.y, player.position.z), (128.0, -64.0, 24.0));
+        assert_eq!((player.rotation.z, player.rotation.w), (0.5, 0.5));
+        assert_eq!((player.view.yaw, player.view.pitch, player.view.eye_offset), (90.0, -10.0, 48.0));
+        assert_eq!(player.state.height, 56.0);
+        assert_eq!((player.state.velocity.x, player.state.velocity.y, player.state.velocity.z), (10.0, -20.0, 30.0));
+        assert!(player.state.grounded);
+        assert!(!player.state.crouched);
+
+        let doors: Vec<(usize, bool, f32)> = loaded.map_models.iter().map(|x| (x.model_idx, x.triggered, x.position.z)).collect();
+        assert_eq!(doors, vec![(2, true, 96.0), (5, false, 0.0)]);
+
+        assert_eq!(loaded.light_switches.len(), 1);
+        assert_eq!((loaded.light_switches[0].layer, loaded.light_switches[0].triggered), (3, true));
+        assert_eq!(loaded.light_layers[3], 0.5);
+        assert_eq!((loaded.light_layer_pulses[3].amplitude, loaded.light_layer_pulses[3].hz), (0.25, 2.0));
+        assert_eq!(loaded.areaportal_states, vec![true, false, true]);
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let mut bytes = write_bytes(&test_save());
+        bytes[0] ^= 0xFF;
+
+        assert!(matches!(SaveData::read(&mut bytes.as_slice()), Err(SaveError::BadMagic)));
+    }
+
+    #[test]
+    fn unsupported_version_is_rejected() {
+        let mut bytes = write_bytes(&test_save());
+        bytes[4..8].copy_from_slice(&(SAVE_VERSION + 1).to_le_bytes());
+
+        assert!(matches!(SaveData::read(&mut bytes.as_slice()), Err(SaveError::UnsupportedVersion(v)) if v == SAVE_VERSION + 1));
+    }
+
+    #[test]
+    fn oversized_map_name_is_rejected() {
+        let mut bytes = write_bytes(&test_save());
+        bytes[8..12].copy_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+
+        assert!(matches!(SaveData::read(&mut bytes.as_slice()), Err(SaveError::MapNameTooLong(_))));
+
+        let mut save = test_save();
+        save.map_name = "a".repeat(MAX_MAP_NAME_LEN + 1);
+        assert!(matches!(save.write(&mut Vec::new()), Err(SaveError::MapNameTooLong(_))));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,114 @@
+use std::sync::{Arc, Mutex};
+
+use dbsdk_rs::{audio, math::Vector3};
+use lazy_static::lazy_static;
+
+use crate::{asset_loader::SoundClip, bsp_file::BspFile};
+
+// voices 0 & 1 are reserved for music playback
+const SFX_VOICE_START: i32 = 2;
+const SFX_NUM_VOICES: usize = 10;
+
+// the last few voices are reserved for looping sounds, so one-shots never cut them off
+const SFX_LOOP_VOICE_START: i32 = SFX_VOICE_START + SFX_NUM_VOICES as i32;
+const SFX_NUM_LOOP_VOICES: usize = 4;
+
+lazy_static! {
+    static ref SFX_STATE: Mutex<SfxState> = Mutex::new(SfxState::new());
+}
+
+struct SfxState {
+    voices: [Option<Arc<SoundClip>>;SFX_NUM_VOICES],
+    loop_voices: [Option<Arc<SoundClip>>;SFX_NUM_LOOP_VOICES],
+    next_voice: usize,
+}
+
+impl SfxState {
+    fn new() -> SfxState {
+        SfxState {
+            voices: [const {None};SFX_NUM_VOICES],
+            loop_voices: [const {None};SFX_NUM_LOOP_VOICES],
+            next_voice: 0,
+        }
+    }
+}
+
+fn start_voice(slot: i32, clip: &Arc<SoundClip>, looping: bool, volume: f32, pan: f32, pitch: f32) {
+    let t = audio::get_time();
+
+    audio::queue_set_voice_param_i(slot, audio::AudioVoiceParam::SampleData, clip.sample.handle, t);
+    audio::queue_set_voice_param_i(slot, audio::AudioVoiceParam::Samplerate, clip.samplerate, t);
+    audio::queue_set_voice_param_i(slot, audio::AudioVoiceParam::LoopEnabled, if looping { 1 } else { 0 }, t);
+    audio::queue_set_voice_param_i(slot, audio::AudioVoiceParam::LoopStart, 0, t);
+    audio::queue_set_voice_param_i(slot, audio::AudioVoiceParam::LoopEnd, clip.length as i32, t);
+    audio::queue_set_voice_param_i(slot, audio::AudioVoiceParam::Reverb, 0, t);
+    audio::queue_set_voice_param_f(slot, audio::AudioVoiceParam::Volume, volume, t);
+    audio::queue_set_voice_param_f(slot, audio::AudioVoiceParam::Pitch, pitch, t);
+    audio::queue_set_voice_param_f(slot, audio::AudioVoiceParam::Detune, 0.0, t);
+    audio::queue_set_voice_param_f(slot, audio::AudioVoiceParam::Pan, pan, t);
+    audio::queue_set_voice_param_f(slot, audio::AudioVoiceParam::FadeInDuration, 0.0, t);
+    audio::queue_set_voice_param_f(slot, audio::AudioVoiceParam::FadeOutDuration, 0.0, t);
+
+    audio::queue_stop_voice(slot, t);
+    audio::queue_start_voice(slot, t);
+}
+
+/// Play a one-shot sound effect. Voices are handed out round-robin, so if all voices are busy the oldest sound is cut off
+pub fn play_sound(clip: &Arc<SoundClip>, volume: f32, pan: f32, pitch: f32) {
+    let mut state = SFX_STATE.lock().unwrap();
+
+    let voice = state.next_voice;
+    state.next_voice = (voice + 1) % SFX_NUM_VOICES;
+
+    start_voice(SFX_VOICE_START + voice as i32, clip, false, volume, pan, pitch);
+
+    // keep the clip alive until its voice is reused, so the sample isn't freed while it's still playing
+    state.voices[voice] = Some(clip.clone());
+}
+
+/// Play a one-shot sound effect emitted from a point in the world. Sounds which can't be heard from the listener's position (according to the map's PHS) are skipped entirely, saving a voice
+/// Returns whether the sound was played
+pub fn play_sound_at(map: &BspFile, listener: &Vector3, origin: &Vector3, clip: &Arc<SoundClip>, volume: f32, pitch: f32) -> bool {
+    if !map.is_hearable(listener, origin) {
+        return false;
+    }
+
+    play_sound(clip, volume, 0.0, pitch);
+    true
+}
+
+/// Start playing a looping sound effect, returning a handle to the voice it's playing on. Returns None if all looping voices are busy
+pub fn start_loop(clip: &Arc<SoundClip>, volume: f32, pan: f32, pitch: f32) -> Option<usize> {
+    let mut state = SFX_STATE.lock().unwrap();
+
+    let voice = state.loop_voices.iter().position(|x| x.is_none())?;
+    start_voice(SFX_LOOP_VOICE_START + voice as i32, clip, true, volume, pan, pitch);
+
+    state.loop_voices[voice] = Some(clip.clone());
+    Some(voice)
+}
+
+/// Update volume & pan of a looping sound started with start_loop
+pub fn update_loop(voice: usize, volume: f32, pan: f32) {
+    let slot = SFX_LOOP_VOICE_START + voice as i32;
+    let t = audio::get_time();
+
+    audio::queue_set_voice_param_f(slot, audio::AudioVoiceParam::Volume, volume, t);
+    audio::queue_set_voice_param_f(slot, audio::AudioVoiceParam::Pan, pan, t);
+}
+
+/// Stop a looping sound started with start_loop, freeing its voice
+pub fn stop_loop(voice: usize) {
+    let mut state = SFX_STATE.lock().unwrap();
+
+    if state.loop_voices[voice].take().is_some() {
+        audio::queue_stop_voice(SFX_LOOP_VOICE_START + voice as i32, audio::get_time());
+    }
+}
+
+/// Stop all looping sounds (for example, when changing maps)
+pub fn stop_all_loops() {
+    for voice in 0..SFX_NUM_LOOP_VOICES {
+        stop_loop(voice);
+    }
+}
\ No newline at end of file
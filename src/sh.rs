@@ -32,6 +32,26 @@ impl SphericalHarmonics {
         self.coeff.m[3][2] += color.z;
     }
 
+    /// Get the direction & color of the strongest directional light stored in the SH, if there's any directional light at all
+    /// Directional terms from several lights blend together, so this is only an approximation of the dominant light
+    pub fn dominant_light(&self) -> Option<(Vector3, Vector3)> {
+        let channel = |c: usize| Vector3::new(self.coeff.m[0][c], self.coeff.m[1][c], self.coeff.m[2][c]);
+
+        let r = channel(0);
+        let g = channel(1);
+        let b = channel(2);
+
+        let mut direction = (r + g + b) * (1.0 / 3.0);
+        if direction.length() <= f32::EPSILON {
+            return None;
+        }
+
+        direction.normalize();
+
+        let color = Vector3::new(r.length(), g.length(), b.length()) * (1.0 / BASIS_WEIGHT_LINEAR);
+        return Some((direction, color));
+    }
+
     pub fn add_directional_light(&mut self, dir: Vector3, color: Vector3) {
         let mut direction = dir;
         direction.normalize();
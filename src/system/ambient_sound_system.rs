@@ -0,0 +1,82 @@
+use dbsdk_rs::math::{Matrix4x4, Vector3, Vector4};
+use hecs::World;
+
+use crate::{bsp_file::BspFile, component::{ambientsound::AmbientSound, camera::Camera, transform3d::Transform3D, triggerable::TriggerState}, sfx::{play_sound, start_loop, stop_loop, update_loop}};
+
+// calculate volume & pan of a sound as heard by the listener
+fn spatialize(sound: &AmbientSound, listener_pos: &Vector3, listener_right: &Vector3) -> (f32, f32) {
+    let offset = sound.origin - *listener_pos;
+    let dist = offset.length();
+
+    let volume = if sound.radius.is_finite() {
+        sound.volume * (1.0 - (dist / sound.radius)).clamp(0.0, 1.0)
+    }
+    else {
+        sound.volume
+    };
+
+    let pan = if dist > f32::EPSILON {
+        Vector3::dot(&(offset / dist), listener_right).clamp(-1.0, 1.0)
+    }
+    else {
+        0.0
+    };
+
+    (volume, pan)
+}
+
+/// System which plays & stops ambient sounds based on trigger state, & attenuates them by distance to the active camera
+pub fn ambient_sound_system_update(map: &BspFile, world: &mut World) {
+    // the first camera drawing to the screen is the listener
+    let listener = world.query::<(&Transform3D, &Camera)>()
+        .iter()
+        .filter(|(_, (_, camera))| camera.render_target.is_none())
+        .map(|(_, (transform, _))| {
+            let right = Matrix4x4::rotation(transform.rotation) * Vector4::new(1.0, 0.0, 0.0, 0.0);
+            (transform.position, Vector3::new(right.x, right.y, right.z))
+        })
+        .next();
+
+    for (_, (sound, trigger)) in world.query_mut::<(&mut AmbientSound, Option<&TriggerState>)>() {
+        let triggered = match trigger {
+            Some(v) => v.triggered,
+            None => false
+        };
+
+        let just_triggered = triggered && !sound.prev_triggered;
+        sound.prev_triggered = triggered;
+
+        let (listener_pos, listener_right) = match &listener {
+            Some(v) => v,
+            None => {
+                if let Some(voice) = sound.voice.take() {
+                    stop_loop(voice);
+                }
+                continue;
+            }
+        };
+
+        let hearable = (sound.origin - *listener_pos).length() < sound.radius && map.is_hearable(listener_pos, &sound.origin);
+        let (volume, pan) = spatialize(sound, listener_pos, listener_right);
+
+        if !sound.looping {
+            // one-shot speakers play each time they're triggered
+            if just_triggered && hearable {
+                play_sound(&sound.sample, volume, pan, 1.0);
+            }
+            continue;
+        }
+
+        let active = sound.start_on != triggered;
+
+        if active && hearable {
+            match sound.voice {
+                Some(voice) => update_loop(voice, volume, pan),
+                None => sound.voice = start_loop(&sound.sample, volume, pan, 1.0)
+            }
+        }
+        else if let Some(voice) = sound.voice.take() {
+            stop_loop(voice);
+        }
+    }
+}
\ No newline at end of file
@@ -1,14 +1,15 @@
 use dbsdk_rs::math::{Matrix4x4, Quaternion, Vector3};
 use hecs::{CommandBuffer, World};
 
-use crate::{component::mesh::{Mesh, MeshAnim, SkeletalPoseState}, dbanim::{AnimationCurveLoopMode, DBAnimationClip}, dbmesh::{DBSkelNode, DBSkeleton}, TimeData};
+use crate::{common::decompose_matrix, component::{charactercontroller::CharacterState, mesh::{Mesh, MeshAnim, MeshAnimAdditive, SkeletalPoseState}, transform3d::Transform3D}, dbanim::{AnimationCurveLoopMode, DBAnimationClip, Lerp}, dbmesh::{DBSkelNode, DBSkeleton}, TimeData};
 
-fn sample_anim_node(node: &DBSkelNode, anim: &DBAnimationClip, time: f32, loopmode: AnimationCurveLoopMode, parent_mat: Matrix4x4, bonepalette: &mut [Matrix4x4]) {
+// sample the local translation, rotation, & scale of a single bone, falling back to identity for any channels the clip doesn't have
+fn sample_bone_local(anim: &DBAnimationClip, bone_index: u32, time: f32, loopmode: AnimationCurveLoopMode) -> (Vector3, Quaternion, Vector3) {
     let mut local_pos = Vector3::zero();
     let mut local_rot = Quaternion::identity();
     let mut local_scale = Vector3::new(1.0, 1.0, 1.0);
 
-    match anim.get_channel_vec3(node.bone_index as u32, 0) {
+    match anim.get_channel_vec3(bone_index, 0) {
         Some(channel) => {
             local_pos = match channel.sample(time, loopmode) {
                 Ok(v) => { v }
@@ -19,7 +20,7 @@ fn sample_anim_node(node: &DBSkelNode, anim: &DBAnimationClip, time: f32, loopmo
         }
     };
 
-    match anim.get_channel_quat(node.bone_index as u32, 1) {
+    match anim.get_channel_quat(bone_index, 1) {
         Some(channel) => {
             local_rot = match channel.sample(time, loopmode) {
                 Ok(v) => { v }
@@ -30,7 +31,7 @@ fn sample_anim_node(node: &DBSkelNode, anim: &DBAnimationClip, time: f32, loopmo
         }
     };
 
-    match anim.get_channel_vec3(node.bone_index as u32, 2) {
+    match anim.get_channel_vec3(bone_index, 2) {
         Some(channel) => {
             local_scale = match channel.sample(time, loopmode) {
                 Ok(v) => { v }
@@ -41,6 +42,64 @@ fn sample_anim_node(node: &DBSkelNode, anim: &DBAnimationClip, time: f32, loopmo
         }
     };
 
+    (local_pos, local_rot, local_scale)
+}
+
+// ratio of two scale components, treating a zero reference scale as no change
+fn scale_ratio(scale: f32, reference: f32) -> f32 {
+    if reference.abs() <= f32::EPSILON { 1.0 } else { scale / reference }
+}
+
+// sample the root bone's translation & rotation at the given (unfolded) time. translation accumulates across loops, so that looping clips keep moving forward
+fn sample_root_motion(anim: &DBAnimationClip, bone_index: u32, time: f32, loopmode: AnimationCurveLoopMode) -> (Vector3, Quaternion) {
+    let duration = anim.duration();
+    let (pos, rot, _) = sample_bone_local(anim, bone_index, loopmode.fold_time(time, duration), AnimationCurveLoopMode::Clamp);
+
+    match loopmode {
+        AnimationCurveLoopMode::Repeat if duration > 0.0 => {
+            let (start_pos, _, _) = sample_bone_local(anim, bone_index, 0.0, AnimationCurveLoopMode::Clamp);
+            let (end_pos, _, _) = sample_bone_local(anim, bone_index, duration, AnimationCurveLoopMode::Clamp);
+            let cycles = (time / duration).floor();
+
+            (pos + ((end_pos - start_pos) * cycles), rot)
+        }
+        _ => (pos, rot)
+    }
+}
+
+fn sample_anim_node(node: &DBSkelNode, anim: &DBAnimationClip, time: f32, loopmode: AnimationCurveLoopMode, additive: Option<&MeshAnimAdditive>, extract_root: bool, parent_mat: Matrix4x4, bonepalette: &mut [Matrix4x4], bonematrices: &mut [Matrix4x4]) {
+    let (mut local_pos, mut local_rot, mut local_scale) = sample_bone_local(anim, node.bone_index as u32, time, loopmode);
+
+    // root motion moves the entity instead, so it's left out of the pose
+    if extract_root {
+        local_pos = Vector3::zero();
+        local_rot = Quaternion::identity();
+    }
+
+    // layer the additive clip's offset from its own first frame on top, in bone local space
+    if let Some(additive) = additive {
+        if additive.weight != 0.0 {
+            let (ref_pos, ref_rot, ref_scale) = sample_bone_local(&additive.clip, node.bone_index as u32, 0.0, AnimationCurveLoopMode::Clamp);
+            let (add_pos, add_rot, add_scale) = sample_bone_local(&additive.clip, node.bone_index as u32, additive.base_pose_time, AnimationCurveLoopMode::Clamp);
+
+            let mut ref_rot_inv = ref_rot;
+            ref_rot_inv.invert();
+
+            let delta_rot = ref_rot_inv * add_rot;
+            let delta_scale = Vector3::new(
+                scale_ratio(add_scale.x, ref_scale.x),
+                scale_ratio(add_scale.y, ref_scale.y),
+                scale_ratio(add_scale.z, ref_scale.z)
+            );
+
+            local_pos = local_pos + ((add_pos - ref_pos) * additive.weight);
+            local_rot = local_rot * <Quaternion as Lerp<Quaternion>>::lerp(Quaternion::identity(), delta_rot, additive.weight);
+
+            let delta_scale = Vector3::new(1.0, 1.0, 1.0) + ((delta_scale - Vector3::new(1.0, 1.0, 1.0)) * additive.weight);
+            local_scale = Vector3::new(local_scale.x * delta_scale.x, local_scale.y * delta_scale.y, local_scale.z * delta_scale.z);
+        }
+    }
+
     // compute skinning matrix
     // in order, this matrix:
     // - transforms vertex into bone local space
@@ -63,37 +122,122 @@ fn sample_anim_node(node: &DBSkelNode, anim: &DBAnimationClip, time: f32, loopmo
     // write result to bone matrix palette
     bonepalette[node.bone_index as usize] = skin_mat;
 
+    // also keep bone's object space transform around (used for attachments)
+    bonematrices[node.bone_index as usize] = bone_to_object;
+
     // iterate children
     for child in &node.children {
-        sample_anim_node(child, anim, time, loopmode, bone_to_object, bonepalette);
+        sample_anim_node(child, anim, time, loopmode, additive, false, bone_to_object, bonepalette, bonematrices);
     }
 }
 
-fn sample_anim(skeleton: &DBSkeleton, anim: &DBAnimationClip, time: f32, loopmode: AnimationCurveLoopMode, bonepalette: &mut [Matrix4x4]) {
+fn sample_anim(skeleton: &DBSkeleton, anim: &DBAnimationClip, time: f32, loopmode: AnimationCurveLoopMode, additive: Option<&MeshAnimAdditive>, extract_root: bool, bonepalette: &mut [Matrix4x4], bonematrices: &mut [Matrix4x4]) {
+    // fold time by the length of the whole clip rather than each channel's own length, so channels which end early hold their last pose instead of looping out of sync
+    let time = loopmode.fold_time(time, anim.duration());
+
     for root in skeleton.nodes.as_slice() {
-        sample_anim_node(root, anim, time, loopmode, Matrix4x4::identity(), bonepalette);
+        sample_anim_node(root, anim, time, AnimationCurveLoopMode::Clamp, additive, extract_root, Matrix4x4::identity(), bonepalette, bonematrices);
     }
 }
 
 // initialize skeletal animation state
 fn sk_anim_init(world: &mut World) {
     let mut cmd_buf = CommandBuffer::new();
-    for (e, (_mesh_anim, mesh)) in world.query_mut::<(&MeshAnim, &Mesh)>() {
-        let bone_palette: Vec<Matrix4x4> = vec![Matrix4x4::identity();mesh.mesh.skeleton.as_ref().unwrap().bone_count as usize];
-        cmd_buf.insert_one(e, SkeletalPoseState {
-            bone_palette
-        });
+    for (e, (_mesh_anim, mesh)) in world.query_mut::<(&MeshAnim, &Mesh)>().without::<&SkeletalPoseState>() {
+        let bone_count = mesh.mesh.skeleton.as_ref().unwrap().bone_count as usize;
+        cmd_buf.insert_one(e, SkeletalPoseState::new(bone_count));
     }
     cmd_buf.run_on(world);
 }
 
 // update skeletal animation
 fn sk_anim_update(time: &TimeData, world: &mut World) {
-    for (_, (mesh_anim, mesh, pose_state)) in world.query_mut::<(&mut MeshAnim, &Mesh, &mut SkeletalPoseState)>() {
-        // sample animation
-        sample_anim(mesh.mesh.skeleton.as_ref().unwrap(), &mesh_anim.anim, mesh_anim.time, mesh_anim.loop_mode, &mut pose_state.bone_palette);
+    for (_, (mesh_anim, mesh, pose_state, additive, transform)) in world.query_mut::<(&mut MeshAnim, &Mesh, &mut SkeletalPoseState, Option<&MeshAnimAdditive>, Option<&Transform3D>)>() {
+        let skeleton = mesh.mesh.skeleton.as_ref().unwrap();
+
+        // sample animation (skipped if nothing has changed since the last sample, so paused & idle meshes keep their cached palette)
+        if pose_state.needs_update(mesh_anim, additive) {
+            sample_anim(skeleton, &mesh_anim.anim, mesh_anim.time, mesh_anim.loop_mode, additive, mesh_anim.extract_root_motion, &mut pose_state.bone_palette, &mut pose_state.bone_matrices);
+            pose_state.mark_sampled(mesh_anim, additive);
+        }
+
+        mesh_anim.root_motion_translation = Vector3::zero();
+        mesh_anim.root_motion_rotation = Quaternion::identity();
+
+        if !mesh_anim.paused {
+            let prev_time = mesh_anim.time;
+            mesh_anim.advance(time.delta_time);
+
+            if mesh_anim.extract_root_motion && skeleton.nodes.len() > 0 {
+                let root = &skeleton.nodes[0];
+                let bone_index = root.bone_index as u32;
+
+                let (p0, r0) = sample_root_motion(&mesh_anim.anim, bone_index, prev_time, mesh_anim.loop_mode);
+                let (p1, r1) = sample_root_motion(&mesh_anim.anim, bone_index, mesh_anim.time, mesh_anim.loop_mode);
+
+                let mut r0_inv = r0;
+                r0_inv.invert();
+
+                // when a looping clip wraps around, the rotation from the end of the clip carries over into the next loop
+                let duration = mesh_anim.anim.duration();
+                let wrapped = match mesh_anim.loop_mode {
+                    AnimationCurveLoopMode::Repeat if duration > 0.0 => (prev_time / duration).floor() != (mesh_anim.time / duration).floor(),
+                    _ => false
+                };
+
+                mesh_anim.root_motion_rotation = if wrapped {
+                    let (_, start_rot) = sample_root_motion(&mesh_anim.anim, bone_index, 0.0, AnimationCurveLoopMode::Clamp);
+                    let (_, end_rot) = sample_root_motion(&mesh_anim.anim, bone_index, duration, AnimationCurveLoopMode::Clamp);
+
+                    let mut start_rot_inv = start_rot;
+                    start_rot_inv.invert();
+
+                    r0_inv * end_rot * start_rot_inv * r1
+                }
+                else {
+                    r0_inv * r1
+                };
+
+                // bring the translation from the root bone's space into world space (ignoring the entity's position)
+                let bone_to_world = match transform {
+                    Some(t) => root.local_rest_pose * Matrix4x4::scale(t.scale) * Matrix4x4::rotation(t.rotation),
+                    None => root.local_rest_pose
+                };
+
+                let (origin, _, _) = decompose_matrix(&bone_to_world);
+                let (moved, _, _) = decompose_matrix(&(Matrix4x4::translation(p1 - p0) * bone_to_world));
+
+                mesh_anim.root_motion_translation = moved - origin;
+            }
+
+            // queue up any events passed this frame
+            let events = &mut mesh_anim.events;
+            mesh_anim.anim.for_each_event_crossed(prev_time, mesh_anim.time, mesh_anim.loop_mode, |event| {
+                events.push(event.name.clone());
+            });
+        }
+    }
+}
+
+// move entities by the root motion extracted from their animation this frame
+fn root_motion_apply(time: &TimeData, world: &mut World) {
+    for (_, (mesh_anim, transform, cstate)) in world.query_mut::<(&MeshAnim, &mut Transform3D, Option<&mut CharacterState>)>() {
+        if !mesh_anim.extract_root_motion {
+            continue;
+        }
 
-        mesh_anim.time += time.delta_time;
+        match cstate {
+            // characters are moved through their velocity instead, so they still collide with the world
+            Some(cstate) if time.delta_time > 0.0 => {
+                cstate.velocity.x = mesh_anim.root_motion_translation.x / time.delta_time;
+                cstate.velocity.y = mesh_anim.root_motion_translation.y / time.delta_time;
+            }
+            _ => {
+                transform.position = transform.position + mesh_anim.root_motion_translation;
+            }
+        };
+
+        transform.rotation = transform.rotation * mesh_anim.root_motion_rotation;
     }
 }
 
@@ -101,4 +245,5 @@ fn sk_anim_update(time: &TimeData, world: &mut World) {
 pub fn sk_anim_system_update(time: &TimeData, world: &mut World) {
     sk_anim_init(world);
     sk_anim_update(time, world);
+    root_motion_apply(time, world);
 }
\ No newline at end of file
@@ -0,0 +1,12 @@
+use hecs::World;
+
+use crate::{component::{areaportal::AreaPortal, triggerable::TriggerState}, MapData};
+
+/// System which opens areaportals while they are triggered (for example, by a linked door)
+pub fn areaportal_system_update(map_data: &mut MapData, world: &mut World) {
+    for (_, (portal, state)) in world.query_mut::<(&AreaPortal, &TriggerState)>() {
+        if portal.portal_num < map_data.areaportal_states.len() {
+            map_data.areaportal_states[portal.portal_num] = state.triggered;
+        }
+    }
+}
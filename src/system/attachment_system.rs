@@ -0,0 +1,41 @@
+use dbsdk_rs::math::Matrix4x4;
+use hecs::World;
+
+use crate::{common::decompose_matrix, component::{attachment::Attachment, mesh::SkeletalPoseState, transform3d::Transform3D}};
+
+/// System which moves entities with an Attachment component to follow their parent's bone
+pub fn attachment_system_update(world: &mut World) {
+    let mut results = Vec::new();
+
+    for (e, attachment) in world.query::<&Attachment>().iter() {
+        let parent_transform = match world.get::<&Transform3D>(attachment.parent) {
+            Ok(v) => *v,
+            Err(_) => continue
+        };
+
+        let bone_mat = match world.get::<&SkeletalPoseState>(attachment.parent) {
+            Ok(v) => v.bone_world_matrix(attachment.bone_index),
+            Err(_) => Matrix4x4::identity()
+        };
+
+        let offset = attachment.local_offset;
+
+        let offset_mat = Matrix4x4::scale(offset.scale)
+            * Matrix4x4::rotation(offset.rotation)
+            * Matrix4x4::translation(offset.position);
+
+        let parent_local2world = Matrix4x4::scale(parent_transform.scale)
+            * Matrix4x4::rotation(parent_transform.rotation)
+            * Matrix4x4::translation(parent_transform.position);
+
+        results.push((e, decompose_matrix(&(offset_mat * bone_mat * parent_local2world))));
+    }
+
+    for (e, (position, rotation, scale)) in results {
+        if let Ok(mut transform) = world.get::<&mut Transform3D>(e) {
+            transform.position = position;
+            transform.rotation = rotation;
+            transform.scale = scale;
+        }
+    }
+}
\ No newline at end of file
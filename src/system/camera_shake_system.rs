@@ -0,0 +1,146 @@
+use dbsdk_rs::math::{Quaternion, Vector3};
+use hecs::{Entity, World};
+
+use crate::{component::{camera::{Camera, CameraShake}, transform3d::Transform3D}, TimeData};
+
+const SHAKE_DEFAULT_DECAY: f32 = 1.0;
+const SHAKE_MAX_ANGLE: f32 = 5.0;
+const SHAKE_MAX_OFFSET: f32 = 4.0;
+const SHAKE_FREQUENCY: f32 = 15.0;
+
+// smooth pseudo-random noise in the range -1..1, built from a few incommensurate sine waves
+fn shake_noise(t: f32, seed: f32) -> f32 {
+    let a = (t * SHAKE_FREQUENCY + seed).sin();
+    let b = (t * SHAKE_FREQUENCY * 2.31 + seed * 1.7).sin();
+    let c = (t * SHAKE_FREQUENCY * 0.57 + seed * 3.1).sin();
+
+    (a + (b * 0.5) + (c * 0.25)) / 1.75
+}
+
+/// Add trauma to a camera, making it shake. Trauma is clamped to 0..1
+pub fn add_trauma(world: &mut World, camera_entity: Entity, amount: f32) {
+    if let Ok(mut shake) = world.get::<&mut CameraShake>(camera_entity) {
+        shake.trauma = (shake.trauma + amount).clamp(0.0, 1.0);
+        return;
+    }
+
+    if world.contains(camera_entity) {
+        world.insert_one(camera_entity, CameraShake::new(amount.clamp(0.0, 1.0), SHAKE_DEFAULT_DECAY)).unwrap();
+    }
+}
+
+/// Add trauma to every camera, scaled down with distance from a point (for explosions & the like)
+pub fn add_trauma_at(world: &mut World, position: Vector3, amount: f32, radius: f32) {
+    let targets = world.query::<(&Camera, &Transform3D)>()
+        .iter()
+        .filter_map(|(e, (_, transform))| {
+            let falloff = 1.0 - ((transform.position - position).length() / radius);
+            if falloff > 0.0 { Some((e, amount * falloff)) } else { None }
+        })
+        .collect::<Vec<_>>();
+
+    for (e, trauma) in targets {
+        add_trauma(world, e, trauma);
+    }
+}
+
+/// System which decays camera trauma over time. Run once per simulation step
+pub fn camera_shake_decay(time: &TimeData, world: &mut World) {
+    for (_, shake) in world.query_mut::<&mut CameraShake>() {
+        shake.trauma = (shake.trauma - (shake.decay * time.delta_time)).max(0.0);
+    }
+}
+
+/// Call after cameras have been positioned & before rendering to offset shaking cameras
+pub fn camera_shake_apply(time: &TimeData, world: &mut World) {
+    for (e, (shake, transform)) in world.query_mut::<(&mut CameraShake, &mut Transform3D)>() {
+        shake.base_position = transform.position;
+        shake.base_rotation = transform.rotation;
+
+        if shake.trauma <= 0.0 {
+            continue;
+        }
+
+        // each camera gets its own noise offset so that splitscreen cameras don't shake in lockstep
+        let seed = e.id() as f32 * 13.7;
+        let t = time.total_time;
+        let amount = shake.trauma * shake.trauma;
+
+        let pitch = (SHAKE_MAX_ANGLE * amount * shake_noise(t, seed)).to_radians();
+        let roll = (SHAKE_MAX_ANGLE * amount * shake_noise(t, seed + 1.0)).to_radians();
+        let yaw = (SHAKE_MAX_ANGLE * amount * shake_noise(t, seed + 2.0)).to_radians();
+
+        let offset = Vector3::new(
+            shake_noise(t, seed + 3.0),
+            shake_noise(t, seed + 4.0),
+            shake_noise(t, seed + 5.0)) * (SHAKE_MAX_OFFSET * amount);
+
+        transform.position = transform.position + offset;
+        transform.rotation = transform.rotation * Quaternion::from_euler(Vector3::new(pitch, roll, yaw));
+    }
+}
+
+/// Call after rendering to remove shake offsets, so that they never accumulate into the camera's transform
+pub fn camera_shake_restore(world: &mut World) {
+    for (_, (shake, transform)) in world.query_mut::<(&CameraShake, &mut Transform3D)>() {
+        transform.position = shake.base_position;
+        transform.rotation = shake.base_rotation;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trauma_is_added_and_clamped() {
+        let mut world = World::new();
+        let cam = world.spawn((Camera::default(), Transform3D::default()));
+
+        add_trauma(&mut world, cam, 0.75);
+        assert_eq!(world.get::<&CameraShake>(cam).unwrap().trauma, 0.75);
+
+        add_trauma(&mut world, cam, 0.75);
+        assert_eq!(world.get::<&CameraShake>(cam).unwrap().trauma, 1.0);
+    }
+
+    #[test]
+    fn trauma_falls_off_with_distance() {
+        let mut world = World::new();
+        let near_cam = world.spawn((Camera::default(), Transform3D::default().with_position(Vector3::new(25.0, 0.0, 0.0))));
+        let far_cam = world.spawn((Camera::default(), Transform3D::default().with_position(Vector3::new(200.0, 0.0, 0.0))));
+
+        add_trauma_at(&mut world, Vector3::zero(), 1.0, 100.0);
+
+        assert!((world.get::<&CameraShake>(near_cam).unwrap().trauma - 0.75).abs() < 0.001);
+        assert!(world.get::<&CameraShake>(far_cam).is_err());
+    }
+
+    #[test]
+    fn trauma_decays_to_zero() {
+        let mut world = World::new();
+        let cam = world.spawn((Camera::default(), Transform3D::default(), CameraShake::new(1.0, 2.0)));
+
+        camera_shake_decay(&TimeData { delta_time: 0.25, total_time: 0.25 }, &mut world);
+        assert_eq!(world.get::<&CameraShake>(cam).unwrap().trauma, 0.5);
+
+        camera_shake_decay(&TimeData { delta_time: 1.0, total_time: 1.25 }, &mut world);
+        assert_eq!(world.get::<&CameraShake>(cam).unwrap().trauma, 0.0);
+    }
+
+    #[test]
+    fn shake_is_undone_after_rendering() {
+        let mut world = World::new();
+        let start = Vector3::new(10.0, 20.0, 30.0);
+        let cam = world.spawn((Camera::default(), Transform3D::default().with_position(start), CameraShake::new(1.0, 1.0)));
+
+        camera_shake_apply(&TimeData { delta_time: 0.0, total_time: 0.3 }, &mut world);
+        let shaken = world.get::<&Transform3D>(cam).unwrap().position;
+        assert!((shaken - start).length() > 0.0);
+        assert!((shaken - start).length() <= SHAKE_MAX_OFFSET * 3.0_f32.sqrt());
+
+        camera_shake_restore(&mut world);
+        let restored = world.get::<&Transform3D>(cam).unwrap().position;
+        assert_eq!((restored.x, restored.y, restored.z), (10.0, 20.0, 30.0));
+    }
+}
\ No newline at end of file
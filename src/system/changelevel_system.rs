@@ -0,0 +1,68 @@
+use dbsdk_rs::math::Vector3;
+use hecs::World;
+
+use crate::{common::aabb_aabb_intersects, component::{changelevel::ChangeLevel, charactercontroller::CharacterController, playerinput::PlayerInput, transform3d::Transform3D}};
+
+/// System which checks whether any player has entered a level change volume
+/// Returns the map & spawnpoint to change to, if any
+pub fn changelevel_system_update(world: &mut World) -> Option<(String, String)> {
+    let mut player_iter = world.query::<(&PlayerInput, &CharacterController, &Transform3D)>();
+    let players = player_iter
+        .iter()
+        .collect::<Vec<_>>();
+
+    for (_, changelevel) in world.query::<&ChangeLevel>().iter() {
+        for (_, (_, cc, transform)) in &players {
+            let center = transform.position + (Vector3::unit_z() * cc.height_offset);
+            let extents = Vector3::new(cc.radius, cc.radius, cc.main_height * 0.5);
+
+            if aabb_aabb_intersects(center - extents, center + extents, changelevel.mins, changelevel.maxs) {
+                return Some((changelevel.next_map.clone(), changelevel.spawnpoint.clone()));
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn_changelevel(world: &mut World) {
+        world.spawn((ChangeLevel {
+            next_map: String::from("e1m2"),
+            spawnpoint: String::from("start_b"),
+            mins: Vector3::new(100.0, -32.0, 0.0),
+            maxs: Vector3::new(164.0, 32.0, 128.0),
+        },));
+    }
+
+    #[test]
+    fn player_inside_volume_changes_level() {
+        let mut world = World::new();
+        spawn_changelevel(&mut world);
+        world.spawn((PlayerInput::new(0), CharacterController::default(), Transform3D::default().with_position(Vector3::new(120.0, 0.0, 0.0))));
+
+        let result = changelevel_system_update(&mut world);
+        assert_eq!(result, Some((String::from("e1m2"), String::from("start_b"))));
+    }
+
+    #[test]
+    fn player_outside_volume_does_not_change_level() {
+        let mut world = World::new();
+        spawn_changelevel(&mut world);
+        world.spawn((PlayerInput::new(0), CharacterController::default(), Transform3D::default().with_position(Vector3::new(0.0, 0.0, 0.0))));
+
+        assert_eq!(changelevel_system_update(&mut world), None);
+    }
+
+    #[test]
+    fn non_player_inside_volume_does_not_change_level() {
+        let mut world = World::new();
+        spawn_changelevel(&mut world);
+        world.spawn((CharacterController::default(), Transform3D::default().with_position(Vector3::new(120.0, 0.0, 0.0))));
+
+        assert_eq!(changelevel_system_update(&mut world), None);
+    }
+}
\ No newline at end of file
@@ -1,19 +1,20 @@
 use dbsdk_rs::math::{Matrix4x4, Quaternion, Vector3, Vector4};
 use hecs::{CommandBuffer, World};
-use lazy_static::lazy_static;
 
-use crate::{bsp_file::{BspFile, MASK_SOLID}, common::transform_aabb, component::{charactercontroller::{CharacterController, CharacterInputState, CharacterState}, collider::ColliderBounds, fpview::FPView, mapmodel::MapModel, playerinput::PlayerInput, transform3d::Transform3D}, InputState, MapData, TimeData};
+use crate::{bsp_collision::TraceShape, bsp_file::{BspFile, MASK_SOLID, MASK_WATER}, common::{aabb_aabb_intersects, transform_aabb}, component::{charactercontroller::{CharacterController, CharacterInputState, CharacterState}, collider::ColliderBounds, flycam::FlyCam, fpview::FPView, gravityvolume::GravityVolume, ladder::LadderVolume, mapmodel::MapModel, playerinput::PlayerInput, transform3d::Transform3D}, InputState, MapData, TimeData};
 
-const GROUND_SLOPE_ANGLE: f32 = 45.0;
-const STEP_HEIGHT: f32 = 20.0;
-const GRAVITY: f32 = 300.0;
-const FRICTION: f32 = 0.2;
-const MAX_ACCEL: f32 = 10.0;
-const AIR_ACCEL: f32 = 1.0;
+const CLIMB_SPEED: f32 = 0.75;
+const LADDER_REACH: f32 = 2.0;
+const COYOTE_TIME: f32 = 0.1;
+const JUMP_BUFFER_TIME: f32 = 0.1;
+const WATER_GRAVITY: f32 = 60.0;
+const WATER_BUOYANCY: f32 = 80.0;
+const WATER_FRICTION: f32 = 0.1;
+const WATER_ACCEL: f32 = 4.0;
+const WATER_SPEED: f32 = 0.6;
+const WATER_EXIT_SPEED: f32 = 225.0;
+const WATER_EXIT_REACH: f32 = 24.0;
 
-lazy_static! {
-    static ref GROUND_SLOPE_COS_ANGLE: f32 = GROUND_SLOPE_ANGLE.to_radians().cos();
-}
 /// System which initializes characters
 pub fn character_init(world: &mut World) {
     // initialize character state
@@ -27,14 +28,19 @@ pub fn character_init(world: &mut World) {
 
 /// System which rotates characters according to an attached FPView
 pub fn character_rotation_update(world: &mut World) {
-    for (_, (_, transform, fpview)) in world.query_mut::<(&CharacterController, &mut Transform3D, &FPView)>() {
+    for (_, (_, transform, fpview)) in world.query_mut::<(&CharacterController, &mut Transform3D, &FPView)>().without::<&FlyCam>() {
         transform.rotation = Quaternion::from_euler(Vector3::new(0.0, 0.0, fpview.yaw.to_radians()));
     }
 }
 
 /// System which allows characters with a PlayerInput component to receive input
-pub fn character_input_update(input: &InputState, world: &mut World) {
-    for (_, (state, transform, _)) in world.query_mut::<(&mut CharacterInputState, &Transform3D, &PlayerInput)>() {
+pub fn character_input_update(inputs: &[InputState], world: &mut World) {
+    for (_, (state, transform, player_input, fpview)) in world.query_mut::<(&mut CharacterInputState, &Transform3D, &PlayerInput, Option<&FPView>)>() {
+        let input = match inputs.get(player_input.slot) {
+            Some(v) => v,
+            None => continue
+        };
+
         let rot_matrix = Matrix4x4::rotation(transform.rotation);
 
         let fwd = rot_matrix * Vector4::new(0.0, 1.0, 0.0, 0.0);
@@ -46,32 +52,216 @@ pub fn character_input_update(input: &InputState, world: &mut World) {
         let input_velocity = (fwd * input.move_y)
             + (right * input.move_x);
 
+        // while swimming, forward input moves in the direction the character is looking
+        let swim_velocity = match fpview {
+            Some(fpview) => {
+                let pitch = fpview.pitch.to_radians();
+                (fwd * (pitch.cos() * input.move_y))
+                    + (Vector3::unit_z() * (pitch.sin() * input.move_y))
+                    + (right * input.move_x)
+            }
+            None => input_velocity
+        };
+
         state.input_move_dir = input_velocity;
+        state.input_swim_dir = swim_velocity;
         state.input_crouch = input.crouch;
         state.input_jump = input.jump;
+        state.input_walk = input.walk;
+    }
+}
+
+/// Gravity multiplier of the first gravity volume the given box overlaps, or 1 if none
+fn gravity_scale_at(volumes: &[GravityVolume], center: Vector3, extents: Vector3) -> f32 {
+    match volumes.iter().find(|x| aabb_aabb_intersects(center - extents, center + extents, x.mins, x.maxs)) {
+        Some(v) => v.gravity,
+        None => 1.0
+    }
+}
+
+/// Tick the coyote time & jump buffer timers
+fn update_jump_timers(state: &mut CharacterState, jump: bool, delta_time: f32) {
+    // remember jump presses for a short time, so that a jump pressed just before landing still fires
+    if jump && !state.jump_held {
+        state.jump_buffer_timer = JUMP_BUFFER_TIME;
+    }
+    else {
+        state.jump_buffer_timer = (state.jump_buffer_timer - delta_time).max(0.0);
+    }
+    state.jump_held = jump;
+
+    // keep allowing jumps for a short time after walking off a ledge
+    if state.grounded {
+        state.coyote_timer = COYOTE_TIME;
+    }
+    else {
+        state.coyote_timer = (state.coyote_timer - delta_time).max(0.0);
+    }
+}
+
+/// Jump if a jump press is buffered while the character is (or just was) on the ground
+fn try_jump(state: &mut CharacterState, cc: &CharacterController) -> bool {
+    if state.coyote_timer > 0.0 && state.jump_buffer_timer > 0.0 {
+        // consume both timers so the jump can't fire twice
+        state.grounded = false;
+        state.coyote_timer = 0.0;
+        state.jump_buffer_timer = 0.0;
+        state.velocity.z = cc.jump_force;
+        return true;
+    }
+
+    false
+}
+
+/// Whether a character's bounds, widened slightly so that it can reach, touch any ladder volume
+fn touching_ladder(ladders: &[LadderVolume], center: Vector3, radius: f32, height: f32) -> bool {
+    let extents = Vector3::new(radius + LADDER_REACH, radius + LADDER_REACH, height * 0.5);
+    ladders.iter().any(|x| aabb_aabb_intersects(center - extents, center + extents, x.mins, x.maxs))
+}
+
+/// Velocity of a character climbing a ladder
+fn climb_velocity(cc: &CharacterController, move_dir: Vector3, rotation: Quaternion) -> Vector3 {
+    // forward/back input moves up & down the ladder. forward input also still pushes into the ladder, so that the character steps off at the top
+    let rot_matrix = Matrix4x4::rotation(rotation);
+    let fwd = rot_matrix * Vector4::new(0.0, 1.0, 0.0, 0.0);
+    let fwd = Vector3::new(fwd.x, fwd.y, 0.0);
+
+    let climb_input = Vector3::dot(&move_dir, &fwd);
+    let move_xy = Vector3::new(move_dir.x, move_dir.y, 0.0) * (cc.move_speed * CLIMB_SPEED);
+
+    Vector3::new(move_xy.x, move_xy.y, climb_input * cc.move_speed * CLIMB_SPEED)
+}
+
+/// How deep a character is submerged, checking water depth at its feet, midpoint, & eyes
+fn water_level(map: &BspFile, position: Vector3, center: Vector3, height: f32) -> u8 {
+    let feet_pos = position + Vector3::new(0.0, 0.0, 1.0);
+    let eye_pos = position + Vector3::new(0.0, 0.0, height - 4.0);
+
+    if map.point_contents(&feet_pos) & MASK_WATER == 0 {
+        0
+    }
+    else if map.point_contents(&center) & MASK_WATER == 0 {
+        1
+    }
+    else if map.point_contents(&eye_pos) & MASK_WATER == 0 {
+        2
+    }
+    else {
+        3
+    }
+}
+
+/// Apply water friction & swimming input to a submerged character's velocity
+fn swim_velocity(cc: &CharacterController, velocity: Vector3, input: &CharacterInputState, delta_time: f32) -> Vector3 {
+    // apply water friction
+    let mut velocity = velocity - (velocity * WATER_FRICTION);
+
+    // jump swims straight up
+    let mut wish_dir = input.input_swim_dir;
+    if input.input_jump {
+        wish_dir.z += 1.0;
+    }
+
+    if wish_dir.length_sq() > 0.1 {
+        let wish_speed = cc.move_speed * WATER_SPEED * wish_dir.length().min(1.0);
+        let wish_dir = wish_dir.normalized();
+        let current_speed = Vector3::dot(&wish_dir, &velocity);
+        let add_speed = (wish_speed - current_speed).clamp(0.0, WATER_ACCEL * cc.move_speed * delta_time);
+
+        velocity = velocity + (wish_dir * add_speed);
+    }
+
+    velocity
+}
+
+/// Apply friction & movement input to a walking or falling character's velocity
+fn move_velocity(cc: &CharacterController, velocity: Vector3, input: &CharacterInputState, grounded: bool, delta_time: f32) -> Vector3 {
+    let mut velocity = velocity;
+
+    if grounded {
+        // apply friction
+        velocity = velocity - (velocity * cc.friction);
+    }
+
+    let wish_dir = Vector3::new(input.input_move_dir.x, input.input_move_dir.y, 0.0);
+    let accel = if grounded { cc.max_accel } else { cc.air_accel };
+    let move_speed = if input.input_walk { cc.walk_speed } else { cc.move_speed };
+    
+    if wish_dir.length_sq() > 0.1 {
+        let wish_speed = move_speed * wish_dir.length();
+        let wish_dir = wish_dir.normalized();
+        let current_speed = Vector3::dot(&wish_dir, &velocity);
+        let add_speed = (wish_speed - current_speed).clamp(0.0, accel * cc.move_speed * delta_time);
+        
+        velocity = velocity + (wish_dir * add_speed);
     }
+
+    velocity
 }
 
 /// System which applies input to characters
 pub fn character_apply_input_update(time: &TimeData, map_data: &MapData, world: &mut World) {
-    for (_, (state, cc, input, transform)) in world.query_mut::<(&mut CharacterState, &mut CharacterController, &CharacterInputState, &Transform3D)>() {
-        if state.grounded {
-            // apply friction
-            state.velocity = state.velocity - (state.velocity * FRICTION);
+    // gather ladders
+    let ladders = world.query::<&LadderVolume>()
+        .iter()
+        .map(|(_, ladder)| *ladder)
+        .collect::<Vec<_>>();
+
+    for (_, (state, cc, input, transform)) in world.query_mut::<(&mut CharacterState, &mut CharacterController, &CharacterInputState, &Transform3D)>().without::<&FlyCam>() {
+        update_jump_timers(state, input.input_jump, time.delta_time);
+
+        // characters climb while touching a ladder volume, & can let go by jumping
+        let center = transform.position + Vector3::new(0.0, 0.0, cc.height_offset);
+        let on_ladder = touching_ladder(&ladders, center, cc.radius, state.height);
+
+        if on_ladder && state.climbing && input.input_jump {
+            // push away from the ladder
+            let rot_matrix = Matrix4x4::rotation(transform.rotation);
+            let fwd = rot_matrix * Vector4::new(0.0, 1.0, 0.0, 0.0);
+
+            state.climbing = false;
+            state.velocity = (Vector3::new(fwd.x, fwd.y, 0.0) * (cc.move_speed * -0.5)) + Vector3::new(0.0, 0.0, cc.jump_force * 0.5);
+            continue;
         }
 
-        let wish_dir = Vector3::new(input.input_move_dir.x, input.input_move_dir.y, 0.0);
-        let accel = if state.grounded { MAX_ACCEL } else { AIR_ACCEL };
-        
-        if wish_dir.length_sq() > 0.1 {
-            let wish_speed = cc.move_speed * wish_dir.length();
-            let wish_dir = wish_dir.normalized();
-            let current_speed = Vector3::dot(&wish_dir, &state.velocity);
-            let add_speed = (wish_speed - current_speed).clamp(0.0, accel * cc.move_speed * time.delta_time);
-            
-            state.velocity = state.velocity + (wish_dir * add_speed);
+        state.climbing = on_ladder && !input.input_jump;
+
+        state.water_level = water_level(&map_data.map, transform.position, center, state.height);
+
+        if state.water_level >= 2 && !state.climbing {
+            state.grounded = false;
+            state.crouched = false;
+
+            state.velocity = swim_velocity(cc, state.velocity, input, time.delta_time);
+
+            // if swimming forward into a wall near the surface with open space above it, hop out onto the ledge
+            let move_xy = Vector3::new(input.input_move_dir.x, input.input_move_dir.y, 0.0);
+            if state.water_level == 2 && move_xy.length_sq() > 0.1 {
+                let reach = move_xy.normalized() * WATER_EXIT_REACH;
+                let ledge_pos = transform.position + Vector3::new(0.0, 0.0, state.height + 4.0);
+
+                let wall_trace = map_data.map.linetrace(0, MASK_SOLID, &center, &(center + reach));
+                let ledge_trace = map_data.map.linetrace(0, MASK_SOLID, &ledge_pos, &(ledge_pos + reach));
+
+                if wall_trace.fraction < 1.0 && ledge_trace.fraction == 1.0 {
+                    state.velocity.z = WATER_EXIT_SPEED;
+                }
+            }
+
+            state.height = cc.main_height;
+            cc.height_offset = state.height * 0.5;
+            continue;
         }
 
+        if state.climbing {
+            state.velocity = climb_velocity(cc, input.input_move_dir, transform.rotation);
+            state.height = if state.crouched { cc.crouch_height } else { cc.main_height };
+            cc.height_offset = state.height * 0.5;
+            continue;
+        }
+
+        state.velocity = move_velocity(cc, state.velocity, input, state.grounded, time.delta_time);
+
         if state.crouched && !input.input_crouch {
             // make sure we have enough room to uncrouch before doing so
             let box_extents = Vector3::new(cc.radius, cc.radius, cc.main_height * 0.5);
@@ -86,10 +276,7 @@ pub fn character_apply_input_update(time: &TimeData, map_data: &MapData, world:
             state.crouched = input.input_crouch;
         }
 
-        if state.grounded && input.input_jump {
-            state.grounded = false;
-            state.velocity.z = cc.jump_force;
-        }
+        try_jump(state, cc);
 
         state.height = if state.crouched { cc.crouch_height } else { cc.main_height };
         cc.height_offset = state.height * 0.5;
@@ -105,11 +292,17 @@ pub fn character_update(time: &TimeData, map_data: &MapData, world: &mut World)
         .collect::<Vec<_>>();
 
     // gather characters
-    let mut character_iter = world.query::<(&CharacterController, &mut CharacterState, &mut Transform3D)>();
+    let mut character_iter = world.query::<(&CharacterController, &mut CharacterState, &mut Transform3D, Option<&mut FPView>)>().without::<&FlyCam>();
     let characters = character_iter
         .iter()
         .collect::<Vec<_>>();
 
+    // gather gravity volumes
+    let gravity_volumes = world.query::<&GravityVolume>()
+        .iter()
+        .map(|(_, volume)| *volume)
+        .collect::<Vec<_>>();
+
     // gather colliders
     let mut collider_iter = world.query::<(&ColliderBounds, &Transform3D)>();
     let colliders = collider_iter
@@ -118,7 +311,7 @@ pub fn character_update(time: &TimeData, map_data: &MapData, world: &mut World)
 
     // gather list of collidable entity bounds
     let mut collider_bounds = Vec::with_capacity(characters.len());
-    for (ent, (cc, cstate, transform)) in &characters {
+    for (ent, (cc, cstate, transform, _)) in &characters {
         let center = transform.position + Vector3::new(0.0, 0.0, cc.height_offset);
         let extents = Vector3::new(cc.radius, cc.radius, cstate.height);
 
@@ -134,10 +327,21 @@ pub fn character_update(time: &TimeData, map_data: &MapData, world: &mut World)
     }
 
     // update character physics
-    for (self_ent, (cc, cstate, transform)) in characters {
+    for (self_ent, (cc, cstate, transform, fpview)) in characters {
+        cstate.pinned_by = None;
+
+        let shape = |box_extents: &Vector3| {
+            if cc.cylinder {
+                TraceShape::Cylinder { radius: box_extents.x, half_height: box_extents.z }
+            }
+            else {
+                TraceShape::Box(*box_extents)
+            }
+        };
+
         // trace function which also checks against each map model entity & against other characters
         let trace_fn = |mask: u32, start: &Vector3, end: &Vector3, box_extents: &Vector3| {
-            let mut trace = map_data.map.boxtrace(0, mask, start, end, *box_extents);
+            let mut trace = map_data.map.shapetrace(0, mask, start, end, shape(box_extents));
 
             for (e, (mapmodel, transform)) in &mapmodels {
                 // transform trace start + end into model's local space
@@ -154,9 +358,10 @@ pub fn character_update(time: &TimeData, map_data: &MapData, world: &mut World)
                 let local_start = Vector3::new(local_start.x, local_start.y, local_start.z);
                 let local_end = Vector3::new(local_end.x, local_end.y, local_end.z);
 
-                let tr = map_data.map.boxtrace(mapmodel.model_idx + 1, mask, &local_start, &local_end, *box_extents);
+                let tr = map_data.map.shapetrace(mapmodel.model_idx + 1, mask, &local_start, &local_end, shape(box_extents));
 
-                if tr.fraction < trace.fraction {
+                // a map model we started inside of takes priority, so that getting pinned by a moving model can be detected
+                if (tr.all_solid && !trace.all_solid) || tr.fraction < trace.fraction {
                     // transform trace results back into world space
 
                     let local2world = Matrix4x4::scale(transform.scale)
@@ -188,62 +393,73 @@ pub fn character_update(time: &TimeData, map_data: &MapData, world: &mut World)
 
         let box_extents = Vector3::new(cc.radius, cc.radius, cstate.height * 0.5);
         let box_offset = Vector3::unit_z() * cc.height_offset;
+        let ground_slope_cos_angle = cc.ground_slope_angle.to_radians().cos();
         
         let box_pos = transform.position + box_offset;
+        let start_z = box_pos.z;
 
         // sweep character sideways
         let move_vec_xy = Vector3::new(cstate.velocity.x, cstate.velocity.y, 0.0);
 
-        let (box_pos, move_vec_xy) = if cstate.grounded && move_vec_xy.length_sq() > f32::EPSILON {
+        let (box_pos, move_vec_xy, stepped) = if cstate.grounded && move_vec_xy.length_sq() > f32::EPSILON {
             let original_pos = box_pos;
             let original_move_vec_xy = move_vec_xy;
 
             // while on the ground, sweep up by step height, sweep sideways, then sweep back down by step height.
-            let (box_pos, _, _) = map_data.map.trace_move(&box_pos, &Vector3::new(0.0, 0.0, STEP_HEIGHT), 1.0, false, box_extents, trace_fn);
-            let (box_pos, move_vec_xy, _) = map_data.map.trace_move(&box_pos, &move_vec_xy, time.delta_time, true, box_extents, trace_fn);
-            let (box_pos, _, trace) = map_data.map.trace_move(&box_pos, &Vector3::new(0.0, 0.0, -STEP_HEIGHT), 1.0, false, box_extents, trace_fn);
+            let (box_pos, move_vec_xy, trace) = map_data.map.trace_step(&box_pos, &move_vec_xy, time.delta_time, MASK_SOLID, box_extents, cc.step_height, trace_fn);
 
             // if we leave the ground, see if the ground is still close enough to step down
-            let (box_pos, move_vec_xy) = if trace.fraction == 1.0 {
-                let (new_pos, _, trace) = map_data.map.trace_move(&box_pos, &Vector3::new(0.0, 0.0, -STEP_HEIGHT), 1.0, false, box_extents, trace_fn);
-
-                if trace.fraction < 1.0 {
-                    (new_pos, move_vec_xy)
-                }
-                else {
-                    (box_pos, move_vec_xy)
+            let (box_pos, move_vec_xy, landed) = if trace.fraction == 1.0 {
+                match map_data.map.ground_snap_with(&box_pos, box_extents, cc.step_height, ground_slope_cos_angle, MASK_SOLID, trace_fn) {
+                    Some((new_pos, _)) => (new_pos, move_vec_xy, true),
+                    None => (box_pos, move_vec_xy, false)
                 }
             }
             else {
                 // if we stepped onto ground that's too steep, reset back to original pos and just do a normal sweep instead
-                if trace.hit_normal.z < *GROUND_SLOPE_COS_ANGLE {
-                    let (box_pos, move_vec_xy, _) = map_data.map.trace_move(&original_pos, &original_move_vec_xy, time.delta_time, true, box_extents, trace_fn);
-                    (box_pos, move_vec_xy)
+                if trace.hit_normal.z < ground_slope_cos_angle {
+                    let (box_pos, move_vec_xy, _) = map_data.map.trace_move(&original_pos, &original_move_vec_xy, time.delta_time, true, MASK_SOLID, box_extents, trace_fn);
+                    (box_pos, move_vec_xy, false)
                 }
                 else {
-                    (box_pos, move_vec_xy)
+                    (box_pos, move_vec_xy, true)
                 }
             };
 
-            (box_pos, Vector3::new(move_vec_xy.x, move_vec_xy.y, f32::min(move_vec_xy.z, 0.0)))
+            // walking up a slope also raises the character, but it only counts as a step if moving straight sideways would have run into a wall
+            let stepped = landed && box_pos.z > original_pos.z && {
+                let (_, _, flat_trace) = map_data.map.trace_move(&original_pos, &original_move_vec_xy, time.delta_time, false, MASK_SOLID, box_extents, trace_fn);
+                flat_trace.fraction < 1.0 && flat_trace.hit_normal.z < ground_slope_cos_angle
+            };
+
+            (box_pos, Vector3::new(move_vec_xy.x, move_vec_xy.y, f32::min(move_vec_xy.z, 0.0)), stepped)
         }
         else {
-            let (box_pos, move_vec_xy, _) = map_data.map.trace_move(&box_pos, &move_vec_xy, time.delta_time, true, box_extents, trace_fn);
-            (box_pos, move_vec_xy)
+            let (box_pos, move_vec_xy, _) = map_data.map.trace_move(&box_pos, &move_vec_xy, time.delta_time, true, MASK_SOLID, box_extents, trace_fn);
+            (box_pos, move_vec_xy, false)
         };
 
+        // how far stepping up a ledge carried the character upwards, used to smooth out the view
+        let step_up = if stepped { box_pos.z - start_z } else { 0.0 };
+
         // sweep character down
         let move_vec_z = Vector3::unit_z() * cstate.velocity.z;
-        let (box_pos, mut move_vec_z, trace) = map_data.map.trace_move(&box_pos, &move_vec_z, time.delta_time, !cstate.grounded, box_extents, trace_fn);
+        let (box_pos, mut move_vec_z, trace) = map_data.map.trace_move(&box_pos, &move_vec_z, time.delta_time, !cstate.grounded, MASK_SOLID, box_extents, trace_fn);
 
         // if we hit something while moving down, & slope is within threshold, set character to grounded state
         if trace.all_solid {
             // stuck, don't accumulate velocity
             cstate.velocity.z = 0.0;
+
+            // if a map model is what we're stuck in, remember which one so gameplay can react (crush damage, etc)
+            if trace.hit_submodel.is_some() {
+                cstate.pinned_by = trace.entity;
+            }
+
             continue;
         }
         else if cstate.velocity.z < 0.0 && trace.fraction < 1.0 {
-            if trace.hit_normal.z >= *GROUND_SLOPE_COS_ANGLE {
+            if trace.hit_normal.z >= ground_slope_cos_angle {
                 cstate.grounded = true;
             }
             else {
@@ -261,17 +477,262 @@ pub fn character_update(time: &TimeData, map_data: &MapData, world: &mut World)
         // update transform & character state
         transform.position = box_pos - box_offset;
 
+        // only upward steps are smoothed, & the offset never exceeds a single step so fast vertical movement doesn't lag behind
+        if let Some(fpview) = fpview {
+            if step_up > 0.0 {
+                fpview.step_offset = (fpview.step_offset - step_up).max(-cc.step_height);
+            }
+        }
+
         let prev_velocity = cstate.velocity;
         cstate.velocity = move_vec_xy + move_vec_z;
 
         cstate.velocity.z = f32::min(cstate.velocity.z, prev_velocity.z);
         
-        // apply gravity
-        if !cstate.grounded {
-            cstate.velocity.z -= GRAVITY * time.delta_time;
+        // apply gravity (climbing characters ignore gravity, their vertical velocity comes purely from input)
+        // fully submerged characters float back up towards the surface, & partially submerged characters sink slowly
+        if cstate.water_level == 3 && !cstate.climbing {
+            cstate.velocity.z += WATER_BUOYANCY * time.delta_time;
         }
-        else {
+        else if cstate.water_level == 2 && !cstate.climbing {
+            cstate.velocity.z -= WATER_GRAVITY * time.delta_time;
+        }
+        else if !cstate.grounded && !cstate.climbing {
+            // gravity volumes scale the map's gravity while the character is inside of them
+            let center = transform.position + box_offset;
+            let gravity_scale = gravity_scale_at(&gravity_volumes, center, box_extents);
+
+            cstate.velocity.z -= map_data.gravity * gravity_scale * cc.gravity_scale * time.delta_time;
+        }
+        else if cstate.grounded {
             cstate.velocity.z = -1.0;
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp_file::CONTENTS_WATER;
+    use crate::test_map::TestMap;
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 0.01, "expected {} to be near {}", b, a);
+    }
+
+    #[test]
+    fn characters_touch_ladders_within_reach() {
+        let ladders = [LadderVolume { mins: Vector3::new(32.0, -32.0, 0.0), maxs: Vector3::new(40.0, 32.0, 256.0) }];
+
+        // radius 16 + reach 2 reaches x = 32 from x = 14, but not from x = 12
+        assert!(touching_ladder(&ladders, Vector3::new(14.0, 0.0, 24.0), 16.0, 48.0));
+        assert!(!touching_ladder(&ladders, Vector3::new(12.0, 0.0, 24.0), 16.0, 48.0));
+        assert!(!touching_ladder(&[], Vector3::new(36.0, 0.0, 24.0), 16.0, 48.0));
+    }
+
+    #[test]
+    fn forward_input_climbs_ladder() {
+        let cc = CharacterController::default();
+        let climb_speed = cc.move_speed * CLIMB_SPEED;
+
+        let up = climb_velocity(&cc, Vector3::new(0.0, 1.0, 0.0), Quaternion::identity());
+        assert_near(up.z, climb_speed);
+        assert_near(up.y, climb_speed);
+
+        let down = climb_velocity(&cc, Vector3::new(0.0, -1.0, 0.0), Quaternion::identity());
+        assert_near(down.z, -climb_speed);
+
+        // strafing moves sideways without climbing
+        let strafe = climb_velocity(&cc, Vector3::new(1.0, 0.0, 0.0), Quaternion::identity());
+        assert_near(strafe.x, climb_speed);
+        assert_near(strafe.z, 0.0);
+    }
+
+    #[test]
+    fn water_level_measures_submersion() {
+        let mut test_map = TestMap::new();
+        test_map.add_box_contents(Vector3::new(-256.0, -256.0, -256.0), Vector3::new(256.0, 256.0, 0.0), CONTENTS_WATER, u16::MAX);
+        let bsp = test_map.build();
+
+        let level_at = |z: f32| {
+            let position = Vector3::new(0.0, 0.0, z);
+            water_level(&bsp, position, position + Vector3::new(0.0, 0.0, 24.0), 48.0)
+        };
+
+        assert_eq!(level_at(16.0), 0);
+        assert_eq!(level_at(-8.0), 1);
+        assert_eq!(level_at(-32.0), 2);
+        assert_eq!(level_at(-64.0), 3);
+    }
+
+    #[test]
+    fn swimming_accelerates_toward_input_up_to_water_speed() {
+        let cc = CharacterController::default();
+        let mut input = CharacterInputState::default();
+        input.input_swim_dir = Vector3::new(0.0, 1.0, 0.0);
+
+        let mut velocity = Vector3::zero();
+        for _ in 0..100 {
+            velocity = swim_velocity(&cc, velocity, &input, 1.0 / 60.0);
+        }
+
+        assert_near(velocity.y, cc.move_speed * WATER_SPEED);
+        assert_near(velocity.x, 0.0);
+        assert_near(velocity.z, 0.0);
+    }
+
+    #[test]
+    fn jump_swims_up() {
+        let cc = CharacterController::default();
+        let mut input = CharacterInputState::default();
+        input.input_jump = true;
+
+        let velocity = swim_velocity(&cc, Vector3::zero(), &input, 1.0 / 60.0);
+        assert!(velocity.z > 0.0);
+        assert_near(velocity.y, 0.0);
+    }
+
+    #[test]
+    fn water_friction_slows_idle_swimmer() {
+        let cc = CharacterController::default();
+        let velocity = swim_velocity(&cc, Vector3::new(100.0, 0.0, 0.0), &CharacterInputState::default(), 1.0 / 60.0);
+        assert_near(velocity.x, 100.0 * (1.0 - WATER_FRICTION));
+    }
+
+    fn run(cc: &CharacterController, input: &CharacterInputState, grounded: bool, steps: usize) -> Vector3 {
+        let mut velocity = Vector3::zero();
+        for _ in 0..steps {
+            velocity = move_velocity(cc, velocity, input, grounded, 1.0 / 60.0);
+        }
+        velocity
+    }
+
+    #[test]
+    fn walk_input_moves_at_walk_speed() {
+        let cc = CharacterController::default();
+        let mut input = CharacterInputState::default();
+        input.input_move_dir = Vector3::new(1.0, 0.0, 0.0);
+
+        let run_speed = run(&cc, &input, true, 120).x;
+
+        input.input_walk = true;
+        let walk_speed = run(&cc, &input, true, 120).x;
+
+        assert_near(walk_speed, cc.walk_speed);
+        assert!(run_speed > walk_speed + 50.0);
+        assert!(run_speed <= cc.move_speed);
+    }
+
+    #[test]
+    fn air_control_is_weaker_than_ground_control() {
+        let cc = CharacterController::default();
+        let mut input = CharacterInputState::default();
+        input.input_move_dir = Vector3::new(0.0, 1.0, 0.0);
+
+        let ground = move_velocity(&cc, Vector3::zero(), &input, true, 1.0 / 60.0);
+        let air = move_velocity(&cc, Vector3::zero(), &input, false, 1.0 / 60.0);
+
+        assert_near(ground.y, cc.max_accel * cc.move_speed / 60.0);
+        assert_near(air.y, cc.air_accel * cc.move_speed / 60.0);
+    }
+
+    #[test]
+    fn friction_only_applies_on_ground() {
+        let mut cc = CharacterController::default();
+        cc.friction = 0.5;
+        let input = CharacterInputState::default();
+
+        assert_near(move_velocity(&cc, Vector3::new(100.0, 0.0, 0.0), &input, true, 1.0 / 60.0).x, 50.0);
+        assert_near(move_velocity(&cc, Vector3::new(100.0, 0.0, 0.0), &input, false, 1.0 / 60.0).x, 100.0);
+    }
+
+    #[test]
+    fn walk_button_reaches_character_input() {
+        let mut world = World::new();
+        let e = world.spawn((CharacterInputState::default(), Transform3D::default(), PlayerInput::new(0)));
+
+        let inputs = [InputState { walk: true, ..Default::default() }];
+        character_input_update(&inputs, &mut world);
+
+        assert!(world.get::<&CharacterInputState>(e).unwrap().input_walk);
+    }
+
+    const STEP: f32 = 1.0 / 60.0;
+
+    // tick a character's jump timers for one step, returning whether it jumped
+    fn jump_step(state: &mut CharacterState, cc: &CharacterController, jump: bool) -> bool {
+        update_jump_timers(state, jump, STEP);
+        try_jump(state, cc)
+    }
+
+    #[test]
+    fn can_jump_shortly_after_leaving_ground() {
+        let cc = CharacterController::default();
+        let mut state = CharacterState::new(cc.main_height);
+
+        state.grounded = true;
+        assert!(!jump_step(&mut state, &cc, false));
+
+        // walked off a ledge a couple of steps ago
+        state.grounded = false;
+        assert!(!jump_step(&mut state, &cc, false));
+        assert!(jump_step(&mut state, &cc, true));
+        assert_eq!(state.velocity.z, cc.jump_force);
+    }
+
+    #[test]
+    fn cannot_jump_after_coyote_time() {
+        let cc = CharacterController::default();
+        let mut state = CharacterState::new(cc.main_height);
+
+        state.grounded = true;
+        jump_step(&mut state, &cc, false);
+
+        state.grounded = false;
+        for _ in 0..((COYOTE_TIME / STEP) as usize + 2) {
+            assert!(!jump_step(&mut state, &cc, false));
+        }
+        assert!(!jump_step(&mut state, &cc, true));
+    }
+
+    #[test]
+    fn jump_pressed_before_landing_fires_on_landing() {
+        let cc = CharacterController::default();
+        let mut state = CharacterState::new(cc.main_height);
+
+        // pressed while still in the air
+        assert!(!jump_step(&mut state, &cc, true));
+        assert!(!jump_step(&mut state, &cc, true));
+
+        state.grounded = true;
+        assert!(jump_step(&mut state, &cc, true));
+
+        // holding the button doesn't jump again upon the next landing
+        state.grounded = true;
+        assert!(!jump_step(&mut state, &cc, true));
+    }
+
+    #[test]
+    fn stale_jump_press_is_forgotten() {
+        let cc = CharacterController::default();
+        let mut state = CharacterState::new(cc.main_height);
+
+        assert!(!jump_step(&mut state, &cc, true));
+        for _ in 0..((JUMP_BUFFER_TIME / STEP) as usize + 2) {
+            assert!(!jump_step(&mut state, &cc, false));
+        }
+
+        state.grounded = true;
+        assert!(!jump_step(&mut state, &cc, false));
+    }
+
+    #[test]
+    fn gravity_volumes_scale_gravity_inside() {
+        let volumes = [GravityVolume { mins: Vector3::new(0.0, 0.0, 0.0), maxs: Vector3::new(128.0, 128.0, 128.0), gravity: 0.25 }];
+        let extents = Vector3::new(16.0, 16.0, 24.0);
+
+        assert_eq!(gravity_scale_at(&volumes, Vector3::new(64.0, 64.0, 64.0), extents), 0.25);
+        assert_eq!(gravity_scale_at(&volumes, Vector3::new(-64.0, 64.0, 64.0), extents), 1.0);
+        assert_eq!(gravity_scale_at(&[], Vector3::new(64.0, 64.0, 64.0), extents), 1.0);
+    }
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
 use hecs::{CommandBuffer, World};
 
-use crate::{component::{door::{Door, DoorLink, DoorOpener}, mapmodel::MapModel, transform3d::Transform3D, triggerable::TriggerState}, MapData, TimeData};
+use crate::{common::move_toward, component::{door::{Door, DoorLink, DoorOpener}, mapmodel::MapModel, transform3d::Transform3D, triggerable::TriggerState}, MapData, TimeData};
 
 const DOOR_OPEN_RADIUS: f32 = 150.0;
 
@@ -57,17 +57,7 @@ fn door_system_pass2(world: &mut World) {
 fn door_system_pass3(time: &TimeData, world: &mut World) {
     for (_, (door, state, transform)) in world.query_mut::<(&Door, &TriggerState, &mut Transform3D)>() {
         let target_pos = if state.triggered { door.open_pos } else { door.close_pos };
-        let delta = target_pos - transform.position;
-        let max_delta = door.move_speed * time.delta_time;
-
-        let delta = if delta.length_sq() > max_delta * max_delta {
-            delta.normalized() * max_delta
-        }
-        else {
-            delta
-        };
-
-        transform.position = transform.position + delta;
+        transform.position = move_toward(transform.position, target_pos, door.move_speed * time.delta_time);
     }
 }
 
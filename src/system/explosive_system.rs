@@ -0,0 +1,62 @@
+use hecs::{Entity, World};
+
+use crate::{asset_loader::load_sound, component::{camera::Camera, explosive::Explosive, mapmodel::MapModel, transform3d::Transform3D, triggerable::TriggerState}, sfx::{play_sound, play_sound_at}, system::camera_shake_system::add_trauma_at, MapData};
+
+const EXPLOSION_SOUND: &str = "/cd/content/sound/world/explod2.wav";
+const EXPLOSION_TRAUMA: f32 = 0.6;
+const EXPLOSION_TRAUMA_RADIUS: f32 = 512.0;
+
+/// Apply damage to an explosive entity. Has no effect on entities which aren't damageable explosives
+pub fn explosive_damage(world: &mut World, entity: Entity, amount: f32) {
+    if let Ok(mut explosive) = world.get::<&mut Explosive>(entity) {
+        if explosive.damageable {
+            explosive.health -= amount;
+        }
+    }
+}
+
+/// System which destroys triggered or depleted explosives, removing their brush model & triggering them so that their targets fire
+pub fn explosive_system_update(map: &MapData, world: &mut World) {
+    let destroyed = world.query::<(&Explosive, &TriggerState, &MapModel, &Transform3D)>()
+        .iter()
+        .filter(|(_, (explosive, trigger, _, _))| trigger.triggered || (explosive.damageable && explosive.health <= 0.0))
+        .map(|(e, (_, _, mapmodel, transform))| {
+            let center = match map.map.submodel_lump.submodels.get(mapmodel.model_idx + 1) {
+                Some(v) => transform.position + ((v.mins + v.maxs) * 0.5),
+                None => transform.position
+            };
+
+            (e, center)
+        })
+        .collect::<Vec<_>>();
+
+    if destroyed.len() == 0 {
+        return;
+    }
+
+    let listener = world.query::<(&Transform3D, &Camera)>()
+        .iter()
+        .filter(|(_, (_, camera))| camera.render_target.is_none())
+        .map(|(_, (transform, _))| transform.position)
+        .next();
+
+    let clip = load_sound(EXPLOSION_SOUND).ok();
+
+    for (e, center) in destroyed {
+        // the brush goes away, but the entity is kept so that its trigger link fires its targets as usual (respecting any delay)
+        world.remove::<(Explosive, MapModel)>(e).unwrap();
+
+        if let Ok(mut state) = world.get::<&mut TriggerState>(e) {
+            state.triggered = true;
+        }
+
+        add_trauma_at(world, center, EXPLOSION_TRAUMA, EXPLOSION_TRAUMA_RADIUS);
+
+        if let Some(clip) = &clip {
+            match &listener {
+                Some(listener) => { play_sound_at(&map.map, listener, &center, clip, 1.0, 1.0); }
+                None => play_sound(clip, 1.0, 0.0, 1.0)
+            }
+        }
+    }
+}
\ No newline at end of file
@@ -1,13 +1,18 @@
 use dbsdk_rs::math::{Matrix4x4, Quaternion, Vector3, Vector4};
-use hecs::World;
+use hecs::{CommandBuffer, World};
 
-use crate::{bsp_file::BspFile, component::{flycam::FlyCam, fpview::FPView, playerinput::PlayerInput, transform3d::Transform3D}, InputState, TimeData};
+use crate::{bsp_file::BspFile, component::{charactercontroller::{CharacterController, CharacterState}, flycam::FlyCam, fpview::FPView, playerinput::PlayerInput, transform3d::Transform3D}, InputState, TimeData};
 
 /// System which allows player to control a FlyCam
-pub fn flycam_system_update(input: &InputState, time: &TimeData, map: &BspFile, world: &mut World) {
+pub fn flycam_system_update(inputs: &[InputState], time: &TimeData, map: &BspFile, world: &mut World) {
     let collider_bounds = Vector3::new(15.0, 15.0, 15.0);
 
-    for (_, (transform, fpview, _, _)) in world.query_mut::<(&mut Transform3D, &FPView, &PlayerInput, &FlyCam)>() {
+    for (_, (transform, fpview, player_input, flycam)) in world.query_mut::<(&mut Transform3D, &FPView, &PlayerInput, &FlyCam)>() {
+        let input = match inputs.get(player_input.slot) {
+            Some(v) => v,
+            None => continue
+        };
+
         transform.rotation = Quaternion::from_euler(Vector3::new(fpview.pitch.to_radians(), 0.0, fpview.yaw.to_radians()));
         let rot_matrix = Matrix4x4::rotation(transform.rotation);
 
@@ -20,10 +25,38 @@ pub fn flycam_system_update(input: &InputState, time: &TimeData, map: &BspFile,
         let camera_velocity = (camera_fwd * 100.0 * input.move_y)
             + (camera_right * 100.0 * input.move_x);
 
-        let (new_pos, _, _) = map.trace_move(&transform.position, &camera_velocity, time.delta_time, true, collider_bounds,
+        if flycam.content_mask == 0 {
+            transform.position = transform.position + (camera_velocity * time.delta_time);
+            continue;
+        }
+
+        let (new_pos, _, _) = map.trace_move(&transform.position, &camera_velocity, time.delta_time, true, flycam.content_mask, collider_bounds,
             |mask, start, end, box_extents| {
                 return map.boxtrace(0, mask, start, end, *box_extents);
             });
         transform.position = new_pos;
     }
+}
+
+/// Toggle player-controlled characters between regular movement and noclip flight
+/// Character physics is suspended while a character has a FlyCam attached
+pub fn flycam_toggle_noclip(world: &mut World) {
+    let mut cmd_buf = CommandBuffer::new();
+
+    for (eid, (_, _, flycam, state)) in world.query_mut::<(&PlayerInput, &CharacterController, Option<&FlyCam>, Option<&mut CharacterState>)>() {
+        if flycam.is_some() {
+            // character resumes from wherever the flycam left it
+            if let Some(state) = state {
+                state.velocity = Vector3::zero();
+                state.grounded = false;
+            }
+
+            cmd_buf.remove_one::<FlyCam>(eid);
+        }
+        else {
+            cmd_buf.insert_one(eid, FlyCam { content_mask: 0 });
+        }
+    }
+
+    cmd_buf.run_on(world);
 }
\ No newline at end of file
@@ -0,0 +1,126 @@
+use std::{collections::HashMap, sync::Arc};
+
+use dbsdk_rs::math::Vector3;
+use hecs::World;
+
+use crate::{asset_loader::{load_sound, SoundClip}, bsp_file::{BspFile, MASK_SOLID}, component::{charactercontroller::CharacterState, footsteps::Footsteps, transform3d::Transform3D}, sfx::play_sound, TimeData};
+
+const FOOTSTEP_STRIDE: f32 = 72.0;
+const FOOTSTEP_MIN_SPEED: f32 = 20.0;
+const FOOTSTEP_TRACE_DIST: f32 = 24.0;
+const FOOTSTEP_VARIANTS: usize = 4;
+const FOOTSTEP_VOLUME: f32 = 0.5;
+const DEFAULT_FOOTSTEP_SET: &str = "step";
+
+/// Maps floor texture name prefixes to footstep sound sets
+const FOOTSTEP_MATERIALS: &[(&str, &str)] = &[
+    ("metal", "metal"),
+    ("wood", "wood"),
+    ("water", "water"),
+    ("dirt", "dirt"),
+];
+
+/// Lazily loaded footstep sounds, keyed by sound set name
+pub struct FootstepSounds {
+    sets: HashMap<&'static str, Vec<Arc<SoundClip>>>,
+}
+
+impl FootstepSounds {
+    pub fn new() -> FootstepSounds {
+        FootstepSounds {
+            sets: HashMap::new()
+        }
+    }
+
+    fn get_set(self: &mut Self, set_name: &'static str) -> &[Arc<SoundClip>] {
+        self.sets.entry(set_name).or_insert_with(|| {
+            // missing variants are logged by the sound cache & skipped
+            (1..=FOOTSTEP_VARIANTS).filter_map(|i| {
+                load_sound(format!("/cd/content/sound/player/footsteps/{}{}.wav", set_name, i).as_str()).ok()
+            }).collect()
+        })
+    }
+}
+
+/// Pick the footstep sound set for a floor texture
+pub fn footstep_set_for_texture(texture_name: &str) -> &'static str {
+    // texture names include their directory (e.g. "e1u1/metal3_1"), so only the base name is matched
+    let base_name = texture_name.rsplit('/').next().unwrap_or(texture_name).to_lowercase();
+
+    match FOOTSTEP_MATERIALS.iter().find(|(prefix, _)| base_name.starts_with(prefix)) {
+        Some((_, set)) => *set,
+        None => DEFAULT_FOOTSTEP_SET
+    }
+}
+
+/// Pick the footstep sound set for whatever is beneath the given position, or None if there's nothing to stand on
+pub fn footstep_set_at(map: &BspFile, position: &Vector3) -> Option<&'static str> {
+    let start = *position + Vector3::new(0.0, 0.0, 8.0);
+
+    match map.raycast(&start, &Vector3::new(0.0, 0.0, -1.0), FOOTSTEP_TRACE_DIST, MASK_SOLID) {
+        Some(hit) => match hit.tex_info {
+            Some(v) => Some(footstep_set_for_texture(&v.texture_name)),
+            None => Some(DEFAULT_FOOTSTEP_SET)
+        },
+        None => None
+    }
+}
+
+/// System which plays footstep sounds for grounded, moving characters based on the surface they're standing on
+pub fn footstep_system_update(time: &TimeData, map: &BspFile, sounds: &mut FootstepSounds, world: &mut World) {
+    for (_, (footsteps, state, transform)) in world.query_mut::<(&mut Footsteps, &CharacterState, &Transform3D)>() {
+        let speed = Vector3::new(state.velocity.x, state.velocity.y, 0.0).length();
+
+        if !state.grounded || speed < FOOTSTEP_MIN_SPEED {
+            // take a step as soon as the character lands or starts moving again
+            footsteps.stride_progress = FOOTSTEP_STRIDE;
+            continue;
+        }
+
+        // steps are spaced by distance travelled, so faster movement means more frequent steps
+        footsteps.stride_progress += speed * time.delta_time;
+        if footsteps.stride_progress < FOOTSTEP_STRIDE {
+            continue;
+        }
+
+        footsteps.stride_progress = 0.0;
+
+        // find what the character is standing on
+        let set_name = match footstep_set_at(map, &transform.position) {
+            Some(v) => v,
+            None => continue
+        };
+
+        let clips = sounds.get_set(set_name);
+        if clips.len() > 0 {
+            play_sound(&clips[footsteps.step_index % clips.len()], FOOTSTEP_VOLUME, 0.0, 1.0);
+            footsteps.step_index += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bsp_file::CONTENTS_SOLID, test_map::TestMap};
+
+    #[test]
+    fn texture_names_map_to_footstep_sets() {
+        assert_eq!(footstep_set_for_texture("e1u1/metal3_1"), "metal");
+        assert_eq!(footstep_set_for_texture("e1u1/WOOD1_1"), "wood");
+        assert_eq!(footstep_set_for_texture("e1u1/floor1_1"), DEFAULT_FOOTSTEP_SET);
+    }
+
+    #[test]
+    fn metal_floor_uses_metal_footsteps() {
+        let mut test_map = TestMap::new();
+        let metal = test_map.add_texture("e1u1/metal3_1", 0, 0);
+        test_map.add_box_contents(Vector3::new(-64.0, -64.0, -16.0), Vector3::new(64.0, 64.0, 0.0), CONTENTS_SOLID, metal);
+        let bsp = test_map.build();
+
+        assert_eq!(footstep_set_at(&bsp, &Vector3::new(0.0, 0.0, 1.0)), Some("metal"));
+
+        // nothing to stand on
+        assert_eq!(footstep_set_at(&bsp, &Vector3::new(0.0, 0.0, 100.0)), None);
+    }
+}
\ No newline at end of file
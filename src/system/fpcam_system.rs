@@ -15,6 +15,6 @@ pub fn fpcam_update(world: &mut World) {
         let target_transform = world.get::<&Transform3D>(fpcam.follow_entity).unwrap();
 
         cam_transform.rotation = Quaternion::from_euler(Vector3::new(target_fpview.pitch.to_radians(), 0.0, target_fpview.yaw.to_radians()));
-        cam_transform.position = target_transform.position + Vector3::new(0.0, 0.0, target_fpview.eye_offset);
+        cam_transform.position = target_transform.position + Vector3::new(0.0, 0.0, target_fpview.eye_offset + target_fpview.step_offset);
     }
 }
\ No newline at end of file
@@ -4,10 +4,16 @@ use crate::{component::{charactercontroller::{CharacterController, CharacterStat
 
 const LOOK_SPEED: f32 = 90.0;
 const CROUCH_SPEED: f32 = 120.0;
+const STEP_SMOOTH_SPEED: f32 = 80.0;
 
 /// System which allows player to control yaw/pitch of FPView
-pub fn fpview_input_system_update(input: &InputState, time: &TimeData, world: &mut World) {
-    for (_, (fpview, _)) in world.query_mut::<(&mut FPView, &PlayerInput)>() {
+pub fn fpview_input_system_update(inputs: &[InputState], time: &TimeData, world: &mut World) {
+    for (_, (fpview, player_input)) in world.query_mut::<(&mut FPView, &PlayerInput)>() {
+        let input = match inputs.get(player_input.slot) {
+            Some(v) => v,
+            None => continue
+        };
+
         if input.look_x.abs() >= 0.1 {
             fpview.yaw -= ((input.look_x - 0.1) * 1.1111) * LOOK_SPEED * time.delta_time;
         }
@@ -42,5 +48,6 @@ pub fn fpview_eye_update(time: &TimeData, world: &mut World) {
         let height_delta = height_delta.abs().clamp(0.0, CROUCH_SPEED * time.delta_time) * height_delta.signum();
 
         fpview.eye_offset = cur_height + height_delta;
+        fpview.step_offset = (fpview.step_offset + (STEP_SMOOTH_SPEED * time.delta_time)).min(0.0);
     }
 }
\ No newline at end of file
@@ -0,0 +1,71 @@
+use dbsdk_rs::math::{Quaternion, Vector3};
+use hecs::World;
+
+use crate::{component::{interpolated::Interpolated, transform3d::Transform3D}, dbanim::Lerp};
+
+/// Call before each simulation step to remember where interpolated entities were
+pub fn interpolation_snapshot(world: &mut World) {
+    for (_, (transform, interp)) in world.query_mut::<(&Transform3D, &mut Interpolated)>() {
+        interp.prev_position = transform.position;
+        interp.prev_rotation = transform.rotation;
+    }
+}
+
+/// Call before rendering to blend interpolated entities between the previous & current simulation step
+pub fn interpolation_apply(alpha: f32, world: &mut World) {
+    for (_, (transform, interp)) in world.query_mut::<(&mut Transform3D, &mut Interpolated)>() {
+        interp.sim_position = transform.position;
+        interp.sim_rotation = transform.rotation;
+
+        transform.position = Vector3::lerp(interp.prev_position, interp.sim_position, alpha);
+        transform.rotation = Quaternion::lerp(interp.prev_rotation, interp.sim_rotation, alpha);
+    }
+}
+
+/// Call after rendering to put back the simulated transforms
+pub fn interpolation_restore(world: &mut World) {
+    for (_, (transform, interp)) in world.query_mut::<(&mut Transform3D, &Interpolated)>() {
+        transform.position = interp.sim_position;
+        transform.rotation = interp.sim_rotation;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_blends_between_steps_and_restore_undoes_it() {
+        let mut world = World::new();
+        let transform = Transform3D::default();
+        let e = world.spawn((transform, Interpolated::new(&transform)));
+
+        interpolation_snapshot(&mut world);
+        world.get::<&mut Transform3D>(e).unwrap().position = Vector3::new(10.0, -20.0, 4.0);
+
+        interpolation_apply(0.25, &mut world);
+        let blended = world.get::<&Transform3D>(e).unwrap().position;
+        assert!((blended.x - 2.5).abs() < 0.001);
+        assert!((blended.y + 5.0).abs() < 0.001);
+        assert!((blended.z - 1.0).abs() < 0.001);
+
+        interpolation_restore(&mut world);
+        let restored = world.get::<&Transform3D>(e).unwrap().position;
+        assert_eq!((restored.x, restored.y, restored.z), (10.0, -20.0, 4.0));
+    }
+
+    #[test]
+    fn snapshot_tracks_latest_step() {
+        let mut world = World::new();
+        let transform = Transform3D::default();
+        let e = world.spawn((transform, Interpolated::new(&transform)));
+
+        world.get::<&mut Transform3D>(e).unwrap().position = Vector3::new(8.0, 0.0, 0.0);
+        interpolation_snapshot(&mut world);
+        world.get::<&mut Transform3D>(e).unwrap().position = Vector3::new(16.0, 0.0, 0.0);
+
+        interpolation_apply(0.0, &mut world);
+        assert_eq!(world.get::<&Transform3D>(e).unwrap().position.x, 8.0);
+        interpolation_restore(&mut world);
+    }
+}
\ No newline at end of file
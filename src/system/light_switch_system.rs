@@ -0,0 +1,17 @@
+use hecs::World;
+
+use crate::{component::{light::LightSwitch, triggerable::TriggerState}, MapData};
+
+/// System which sets the custom light layers of switchable lights based on their trigger state
+/// Layers are only written when a switch changes state, so anything else driving the layer (such as a pulse) isn't overridden in between
+pub fn light_switch_system_update(map_data: &mut MapData, world: &mut World) {
+    for (_, (switch, state)) in world.query_mut::<(&mut LightSwitch, &TriggerState)>() {
+        // triggering a light toggles it away from its starting state
+        let on = state.triggered != switch.start_on;
+
+        if switch.applied != Some(on) {
+            map_data.set_light_layer(switch.layer, if on { 1.0 } else { 0.0 });
+            switch.applied = Some(on);
+        }
+    }
+}
@@ -6,4 +6,15 @@ pub mod fpcam_system;
 pub mod rotator_system;
 pub mod door_system;
 pub mod triggerable_system;
-pub mod anim_system;
\ No newline at end of file
+pub mod anim_system;
+pub mod attachment_system;
+pub mod light_switch_system;
+pub mod tpcam_system;
+pub mod areaportal_system;
+pub mod changelevel_system;
+pub mod interpolation_system;
+pub mod footstep_system;
+pub mod camera_shake_system;
+pub mod explosive_system;
+pub mod ambient_sound_system;
+pub mod portal_system;
\ No newline at end of file
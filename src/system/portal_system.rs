@@ -0,0 +1,70 @@
+use dbsdk_rs::math::{Quaternion, Vector3};
+use hecs::{Entity, World};
+
+use crate::component::{camera::Camera, portal::{Portal, PortalCamera}, transform3d::Transform3D};
+
+// every portal redraws the whole scene from its own viewpoint, so only this many may render at once
+const MAX_ACTIVE_PORTALS: usize = 2;
+
+/// System which spawns, updates, & removes the render target cameras behind Portal components
+pub fn portal_system_update(world: &mut World) {
+    // clean up cameras whose portal no longer exists
+    let orphans = world.query_mut::<&PortalCamera>().into_iter()
+        .map(|(e, portal_cam)| (e, portal_cam.portal))
+        .collect::<Vec<_>>();
+
+    for (e, portal) in orphans {
+        if world.get::<&Portal>(portal).is_err() {
+            world.despawn(e).unwrap();
+        }
+    }
+
+    let portals = world.query_mut::<&Portal>().into_iter()
+        .map(|(e, portal)| (e, portal.camera))
+        .collect::<Vec<_>>();
+
+    for (i, (e, camera)) in portals.into_iter().enumerate() {
+        let active = i < MAX_ACTIVE_PORTALS;
+
+        match (camera, active) {
+            (Some(camera), false) => {
+                let _ = world.despawn(camera);
+                world.get::<&mut Portal>(e).unwrap().camera = None;
+            }
+            (None, true) => {
+                let camera = world.spawn((Transform3D::default(), Camera::default(), PortalCamera { portal: e }));
+                world.get::<&mut Portal>(e).unwrap().camera = Some(camera);
+                update_portal_camera(world, e, camera);
+            }
+            (Some(camera), true) => {
+                update_portal_camera(world, e, camera);
+            }
+            (None, false) => {
+            }
+        };
+    }
+}
+
+// copy the portal's view into its camera
+fn update_portal_camera(world: &mut World, portal: Entity, camera: Entity) {
+    let (position, rotation, target_texture, fov) = {
+        let portal = world.get::<&Portal>(portal).unwrap();
+        let angles = portal.view_angles;
+
+        // pitch rotates around X, roll around the forward (Y) axis, yaw around Z
+        let rotation = Quaternion::from_euler(Vector3::new(angles.x.to_radians(), angles.z.to_radians(), angles.y.to_radians()));
+        (portal.view_origin, rotation, portal.target_texture.clone(), portal.fov)
+    };
+
+    let mut query = match world.query_one::<(&mut Transform3D, &mut Camera)>(camera) {
+        Ok(v) => v,
+        Err(_) => return
+    };
+
+    if let Some((transform, cam)) = query.get() {
+        transform.position = position;
+        transform.rotation = rotation;
+        cam.fov = fov;
+        cam.render_target = Some(target_texture);
+    }
+}
\ No newline at end of file
@@ -1,11 +1,26 @@
 use std::sync::Arc;
 
-use dbsdk_rs::{math::{Matrix4x4, Quaternion, Vector2, Vector3, Vector4}, vdp::{self, Color32, PackedVertex, Rectangle, Texture, TextureUnit, VertexSlotFormat}, vu_asm::vu_asm};
+use dbsdk_rs::{math::{Matrix4x4, Quaternion, Vector2, Vector3, Vector4}, vdp::{self, Color32, Rectangle, Texture, TextureUnit, VertexSlotFormat}, vu_asm::vu_asm};
 use hecs::World;
 
-use crate::{MapData, TimeData, bsp_file::{BspFile, MASK_SOLID}, bsp_renderer::{self, MapVertex}, common::{self, aabb_frustum, coord_space_transform, extract_frustum, transform_aabb}, component::{camera::Camera, light::Light, mapmodel::MapModel, mesh::{FPMesh, Mesh, SkeletalPoseState}, transform3d::Transform3D}, dbmesh::DBMeshPart, sh::SphericalHarmonics};
+use crate::{MapData, TimeData, bsp_file::{BspFile, EmissiveSurface, MASK_OPAQUE, NUM_CUSTOM_LIGHT_LAYERS}, bsp_renderer::{self, FogSettings, LightmapSettings, MapVertex}, common::{self, aabb_frustum, coord_space_transform, extract_frustum, transform_aabb}, component::{camera::Camera, light::Light, mapmodel::MapModel, mesh::{FPMesh, Mesh, SkeletalPoseState}, transform3d::Transform3D}, dbmesh::{DBMaterialInfo, DBMeshPart, DBMeshVertex, MaterialShading, ModelVertex, MAX_BONE_INFLUENCES}, debug_draw::DebugDraw, debug_overlay::{DebugOverlay, FrameStats}, post_process::PostProcess, sh::SphericalHarmonics};
 
-// VU program which multiplies input vertex positions against a transform matrix, and input normals against a lighting matrix
+// how far below a mesh to search for a floor to sample baked lighting from
+const AMBIENT_SAMPLE_DIST: f32 = 512.0;
+
+// max number of light-emitting surfaces which can contribute to a single mesh's lighting
+const MAX_EMISSIVE_LIGHTS: usize = 2;
+
+// meshes switch to the next LOD level each time their projected size halves, starting from this fraction of the screen height
+const LOD_SCREEN_SIZE: f32 = 0.25;
+
+// fraction a mesh's projected size must cross a LOD threshold by before switching levels
+const LOD_HYSTERESIS: f32 = 0.1;
+
+// static meshes whose local bounds are larger than this (diagonally) have each part frustum culled separately
+const PART_CULL_SIZE: f32 = 256.0;
+
+// VU program which multiplies input vertex positions against a transform matrix, and input normals against a lighting matrix, adds a specular highlight, and applies fog (see VU_BASIC_TRANSFORM)
 const VU_TRANSFORM_AND_LIGHT: &[u32] = &vu_asm!{
     ld r0 0     // input position in r0
     ld r1 1     // input normal in r1
@@ -19,7 +34,11 @@ const VU_TRANSFORM_AND_LIGHT: &[u32] = &vu_asm!{
     ldc r9 5    // lighting matrix column 1 in r9
     ldc r10 6   // lighting matrix column 2 in r10
     ldc r11 7   // lighting matrix column 3 in r11
-    ldc r12 8   // ocol in r12
+    ldc r12 8   // specular color in r12
+
+    // view depth (clip space w) in r13
+    ld r13 0
+    dot r13 r7
 
     // transform position with MVP
     mulm r0 r4
@@ -27,6 +46,44 @@ const VU_TRANSFORM_AND_LIGHT: &[u32] = &vu_asm!{
     // transform normal with SH lighting matrix & multiply with vertex color
     mulm r1 r8
     mul r1 r3
+
+    // specular ramp (N.H against the dominant light's half vector, see load_cdata_material) in r14
+    ld r14 1
+    ldc r8 15
+    dot r14 r8
+
+    // clamp ramp to 0..1 (fog constants 13 & 14 hold one & zero) & sharpen
+    ldc r8 13
+    min r14 r8
+    ldc r8 14
+    max r14 r8
+    mul r14 r14
+    mul r14 r14
+
+    // scale specular color by ramp to get ocol, so the highlight is added on top of the texture
+    mul r12 r14
+
+    // fog visibility in r13 (matrix registers are free to reuse from here)
+    ldc r4 11
+    mul r4 r13
+    exp r4
+    ldc r5 9
+    mul r13 r5
+    ldc r5 10
+    add r13 r5
+    ldc r5 13
+    min r13 r5
+    ldc r6 14
+    max r13 r6
+    mul r13 r4
+
+    // fade vertex color & ocol out, and fade fog color in
+    sub r5 r13
+    ldc r6 12
+    mul r5 r6
+    mul r1 r13
+    mul r12 r13
+    add r12 r5
     
     // output
     st pos r0
@@ -35,90 +92,11 @@ const VU_TRANSFORM_AND_LIGHT: &[u32] = &vu_asm!{
     st ocol r12
 };
 
-#[derive(Clone, Copy)]
-pub struct ModelVertex {
-    pub position: Vector4,
-    pub normal: Vector4,
-    pub texcoord: Vector2,
-    pub color: Color32
-}
-
-impl ModelVertex {
-    pub fn new(position: Vector4, normal: Vector4, texcoord: Vector2, color: Color32) -> ModelVertex {
-        ModelVertex { position, normal, texcoord, color }
-    }
-}
-
-fn _draw_aabb(center: Vector3, extents: Vector3, camera_view: &Matrix4x4, camera_proj: &Matrix4x4, col: Color32) {
-    let c0 = center + Vector3::new(-extents.x, -extents.y, -extents.z);
-    let c1 = center + Vector3::new( extents.x, -extents.y, -extents.z);
-    let c2 = center + Vector3::new(-extents.x,  extents.y, -extents.z);
-    let c3 = center + Vector3::new( extents.x,  extents.y, -extents.z);
-    let c4 = center + Vector3::new(-extents.x, -extents.y,  extents.z);
-    let c5 = center + Vector3::new( extents.x, -extents.y,  extents.z);
-    let c6 = center + Vector3::new(-extents.x,  extents.y,  extents.z);
-    let c7 = center + Vector3::new( extents.x,  extents.y,  extents.z);
-
-    let c0 = Vector4::new(c0.x, c0.y, c0.z, 1.0);
-    let c1 = Vector4::new(c1.x, c1.y, c1.z, 1.0);
-    let c2 = Vector4::new(c2.x, c2.y, c2.z, 1.0);
-    let c3 = Vector4::new(c3.x, c3.y, c3.z, 1.0);
-    let c4 = Vector4::new(c4.x, c4.y, c4.z, 1.0);
-    let c5 = Vector4::new(c5.x, c5.y, c5.z, 1.0);
-    let c6 = Vector4::new(c6.x, c6.y, c6.z, 1.0);
-    let c7 = Vector4::new(c7.x, c7.y, c7.z, 1.0);
-
-    let ocol = Color32::new(0, 0, 0, 0);
-
-    let mut geo = vec![
-        PackedVertex::new(c0, Vector2::zero(), col, ocol),
-        PackedVertex::new(c1, Vector2::zero(), col, ocol),
-        PackedVertex::new(c2, Vector2::zero(), col, ocol),
-        PackedVertex::new(c3, Vector2::zero(), col, ocol),
-        PackedVertex::new(c0, Vector2::zero(), col, ocol),
-        PackedVertex::new(c2, Vector2::zero(), col, ocol),
-        PackedVertex::new(c1, Vector2::zero(), col, ocol),
-        PackedVertex::new(c3, Vector2::zero(), col, ocol),
-
-        PackedVertex::new(c4, Vector2::zero(), col, ocol),
-        PackedVertex::new(c5, Vector2::zero(), col, ocol),
-        PackedVertex::new(c6, Vector2::zero(), col, ocol),
-        PackedVertex::new(c7, Vector2::zero(), col, ocol),
-        PackedVertex::new(c4, Vector2::zero(), col, ocol),
-        PackedVertex::new(c6, Vector2::zero(), col, ocol),
-        PackedVertex::new(c5, Vector2::zero(), col, ocol),
-        PackedVertex::new(c7, Vector2::zero(), col, ocol),
-
-        PackedVertex::new(c0, Vector2::zero(), col, ocol),
-        PackedVertex::new(c4, Vector2::zero(), col, ocol),
-        PackedVertex::new(c1, Vector2::zero(), col, ocol),
-        PackedVertex::new(c5, Vector2::zero(), col, ocol),
-        PackedVertex::new(c2, Vector2::zero(), col, ocol),
-        PackedVertex::new(c6, Vector2::zero(), col, ocol),
-        PackedVertex::new(c3, Vector2::zero(), col, ocol),
-        PackedVertex::new(c7, Vector2::zero(), col, ocol),
-    ];
-
-    vdp::depth_func(vdp::Compare::Always);
-    vdp::depth_write(false);
-    vdp::blend_func(vdp::BlendFactor::One, vdp::BlendFactor::Zero);
-    vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, None);
-
-    let trs = (*camera_view) * common::coord_space_transform() * (*camera_proj);
-
-    for vtx in &mut geo {
-        vtx.position = trs * vtx.position;
-    }
-
-    vdp::submit_vu(vdp::Topology::LineList, &geo);
-
-    todo!("draw_aabb will break if a non-default VU is loaded")
-}
-
-fn draw_env_quad(tex: &Texture, rotation: &Quaternion, camera_view: &Matrix4x4, camera_proj: &Matrix4x4) {
+fn draw_env_quad(tex: &Texture, rotation: &Quaternion, sky_rotation: &Quaternion, camera_view: &Matrix4x4, camera_proj: &Matrix4x4) {
     // build view + projection matrix
     let trs = Matrix4x4::scale(Vector3::new(100.0, 100.0, 100.0))
         * Matrix4x4::rotation(*rotation)
+        * Matrix4x4::rotation(*sky_rotation)
         * (*camera_view)
         * common::coord_space_transform()
         * (*camera_proj);
@@ -146,7 +124,32 @@ fn draw_env_quad(tex: &Texture, rotation: &Quaternion, camera_view: &Matrix4x4,
     vdp::submit_vu(vdp::Topology::TriangleList, &quad);
 }
 
-fn setup_vu_lit_mesh() {
+// fill the current viewport with a solid color. clearing the color buffer can't be used for this, since it would also wipe out other cameras' viewports
+fn fill_viewport(color: Color32) {
+    bsp_renderer::load_cdata_matrix(0, &Matrix4x4::identity());
+    vdp::set_vu_cdata(4, &Vector4::zero());
+
+    let quad = [
+        MapVertex::new(Vector4::new(-1.0,  1.0, 0.0, 1.0), Vector2::zero(), Vector2::zero(), color),
+        MapVertex::new(Vector4::new( 1.0,  1.0, 0.0, 1.0), Vector2::zero(), Vector2::zero(), color),
+        MapVertex::new(Vector4::new(-1.0, -1.0, 0.0, 1.0), Vector2::zero(), Vector2::zero(), color),
+
+        MapVertex::new(Vector4::new(-1.0, -1.0, 0.0, 1.0), Vector2::zero(), Vector2::zero(), color),
+        MapVertex::new(Vector4::new( 1.0,  1.0, 0.0, 1.0), Vector2::zero(), Vector2::zero(), color),
+        MapVertex::new(Vector4::new( 1.0, -1.0, 0.0, 1.0), Vector2::zero(), Vector2::zero(), color),
+    ];
+
+    vdp::blend_func(vdp::BlendFactor::One, vdp::BlendFactor::Zero);
+    vdp::depth_func(vdp::Compare::Always);
+    vdp::depth_write(false);
+    vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, None);
+    vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, None);
+    vdp::set_culling(false);
+
+    vdp::submit_vu(vdp::Topology::TriangleList, &quad);
+}
+
+fn setup_vu_lit_mesh(fog: &FogSettings) {
     // set up VU program
     vdp::upload_vu_program(VU_TRANSFORM_AND_LIGHT);
 
@@ -156,45 +159,41 @@ fn setup_vu_lit_mesh() {
     vdp::set_vu_layout(1, 16, VertexSlotFormat::FLOAT4);
     vdp::set_vu_layout(2, 32, VertexSlotFormat::FLOAT2);
     vdp::set_vu_layout(3, 40, VertexSlotFormat::UNORM4);
-}
 
-fn draw_static_meshpart(vtx_buffer: &mut Vec<ModelVertex>, meshpart: &DBMeshPart, mvp: &Matrix4x4, normal2world: &Matrix4x4, light: &SphericalHarmonics) {
-    vtx_buffer.clear();
-
-    // unpack mesh part vertices into GPU vertices
-    for vertex in meshpart.vertices.as_slice() {
-        let vtx = Vector4::new(vertex.pos[0].to_f32(), vertex.pos[1].to_f32(), vertex.pos[2].to_f32(), 1.0);
-        let nrm = Vector4::new(vertex.nrm[0].to_f32(), vertex.nrm[1].to_f32(), vertex.nrm[2].to_f32(), 1.0);
+    bsp_renderer::load_cdata_fog(9, fog);
+}
 
-        vtx_buffer.push(ModelVertex::new(
-            vtx,
-            nrm,
-            Vector2::new(vertex.tex[0].to_f32(), vertex.tex[1].to_f32()),
-            Color32::new(vertex.col[0], vertex.col[1], vertex.col[2], vertex.col[3])));
+// direction from a mesh towards the camera
+fn view_direction(mesh_pos: &Vector3, camera_pos: &Vector3) -> Vector3 {
+    let mut dir = *camera_pos - *mesh_pos;
+    if dir.length() > f32::EPSILON {
+        dir.normalize();
     }
 
-    // load cdata
-    let trs = meshpart.transform * (*mvp);
-    bsp_renderer::load_cdata_matrix(0, &trs);
+    dir
+}
 
-    let lightmat = meshpart.transform * (*normal2world) * light.coeff;
-    bsp_renderer::load_cdata_matrix(4, &lightmat);
+// get a plain triangle list for the mesh part, expanding unique vertices through the index list if it has one
+fn expand_meshpart<'a>(vtx_buffer: &'a [ModelVertex], idx_vtx_buffer: &'a mut Vec<ModelVertex>, meshpart: &DBMeshPart) -> &'a [ModelVertex] {
+    if meshpart.indices.len() > 0 {
+        idx_vtx_buffer.clear();
+        for idx in &meshpart.indices {
+            idx_vtx_buffer.push(vtx_buffer[*idx as usize]);
+        }
 
-    vdp::set_vu_cdata(8, &Vector4::zero());
+        idx_vtx_buffer.as_slice()
+    }
+    else {
+        vtx_buffer
+    }
+}
 
-    // set render state
+// set culling, blending, & texture state for a mesh part
+fn set_meshpart_state(meshpart: &DBMeshPart) {
     vdp::depth_func(vdp::Compare::LessOrEqual);
     vdp::set_culling(meshpart.material.enable_cull);
     vdp::set_winding(vdp::WindingOrder::CounterClockwise);
-    match &meshpart.material.texture {
-        Some(v) => {
-            vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, Some(v.as_ref()));
-        },
-        None => {
-            vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, None);
-        }
-    };
-    vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, None);
+    bind_material_texture(&meshpart.material);
 
     vdp::blend_equation(vdp::BlendEquation::Add);
 
@@ -205,101 +204,285 @@ fn draw_static_meshpart(vtx_buffer: &mut Vec<ModelVertex>, meshpart: &DBMeshPart
         vdp::blend_func(vdp::BlendFactor::One, vdp::BlendFactor::Zero);
         vdp::depth_write(true);
     }
+}
 
-    // draw
-    vdp::submit_vu(vdp::Topology::TriangleList, vtx_buffer.as_slice());
+// bind the material's texture & always set its sample params, so meshes don't inherit whatever the previous draw left behind
+fn bind_material_texture(material: &DBMaterialInfo) {
+    match &material.texture {
+        Some(v) => {
+            vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, Some(v.as_ref()));
+            vdp::set_sample_params_slot(TextureUnit::TU0, material.filter, material.wrap_u, material.wrap_v);
+        },
+        None => {
+            vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, None);
+        }
+    };
+    vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, None);
 }
 
-fn draw_skinned_meshpart(vtx_buffer: &mut Vec<ModelVertex>, meshpart: &DBMeshPart, mvp: &Matrix4x4, normal2world: &Matrix4x4, bonepalette: &[Matrix4x4], light: &SphericalHarmonics) {
-    vtx_buffer.clear();
-    
-    // unpack mesh part vertices into GPU vertices
-    for vertex in meshpart.vertices.as_slice() {
-        let vtx = Vector4::new(vertex.pos[0].to_f32(), vertex.pos[1].to_f32(), vertex.pos[2].to_f32(), 1.0);
-        let nrm = Vector4::new(vertex.nrm[0].to_f32(), vertex.nrm[1].to_f32(), vertex.nrm[2].to_f32(), 1.0);
-        
-        let mut sk0 = vtx;
-        let mut sk1 = vtx;
-        let mut nrm0 = nrm;
-        let mut nrm1 = nrm;
-
-        if vertex.bweight[0] > 0 {
-            sk0 = bonepalette[vertex.bidx[0] as usize] * sk0;
-            nrm0 = bonepalette[vertex.bidx[0] as usize] * nrm0;
+// load lighting matrix, ocol, & specular constants for the given material into cdata
+// view_dir points from the mesh towards the camera, in world space
+fn load_cdata_material(material: &DBMaterialInfo, local2world: &Matrix4x4, light: &SphericalHarmonics, view_dir: &Vector3) {
+    // tint lighting by the material's diffuse color (alpha included, so blended materials can fade)
+    let d = material.diffuse_color;
+    let diffuse = Matrix4x4 {m: [
+        [d.x, 0.0, 0.0, 0.0],
+        [0.0, d.y, 0.0, 0.0],
+        [0.0, 0.0, d.z, 0.0],
+        [0.0, 0.0, 0.0, d.w],
+    ]};
+
+    let lightmat = match material.shading {
+        MaterialShading::Unlit => {
+            // constant term only, so every normal gets full brightness
+            let unlit = Matrix4x4 {m: [
+                [0.0, 0.0, 0.0, 0.0],
+                [0.0, 0.0, 0.0, 0.0],
+                [0.0, 0.0, 0.0, 0.0],
+                [1.0, 1.0, 1.0, 1.0],
+            ]};
+
+            unlit * diffuse
         }
+        _ => (*local2world) * light.coeff * diffuse
+    };
+
+    bsp_renderer::load_cdata_matrix(4, &lightmat);
+
+    let specular = match (material.shading, light.dominant_light()) {
+        (MaterialShading::Specular, Some((light_dir, light_color))) => {
+            let mut half_dir = light_dir + *view_dir;
+            if half_dir.length() > f32::EPSILON {
+                half_dir.normalize();
+
+                // approximate a specular lobe with a ramp from N.H = threshold up to 1, which is clamped & sharpened in the VU. rougher materials get a wider & dimmer highlight
+                let roughness = material.roughness.max(0.0).min(1.0);
+                let threshold = 0.5 + (0.45 * (1.0 - roughness));
+                let scale = 1.0 / (1.0 - threshold);
+
+                // bring the half vector into object space, so it can be dotted with untransformed normals. the bias goes in w, since normals have w = 1
+                let m = local2world.m;
+                let h = half_dir * scale;
+                let ramp = Vector4::new(
+                    (m[0][0] * h.x) + (m[0][1] * h.y) + (m[0][2] * h.z),
+                    (m[1][0] * h.x) + (m[1][1] * h.y) + (m[1][2] * h.z),
+                    (m[2][0] * h.x) + (m[2][1] * h.y) + (m[2][2] * h.z),
+                    -threshold * scale);
+
+                let intensity = 1.0 - (roughness * 0.5);
+                let color = material.spec_color;
+
+                Some((ramp, Vector4::new(color.x * light_color.x * intensity, color.y * light_color.y * intensity, color.z * light_color.z * intensity, 0.0)))
+            }
+            else {
+                None
+            }
+        }
+        _ => None
+    };
 
-        if vertex.bweight[1] > 0 {
-            sk1 = bonepalette[vertex.bidx[1] as usize] * sk1;
-            nrm1 = bonepalette[vertex.bidx[1] as usize] * nrm1;
+    match specular {
+        Some((ramp, color)) => {
+            vdp::set_vu_cdata(8, &color);
+            vdp::set_vu_cdata(15, &ramp);
         }
+        None => {
+            // no highlight
+            vdp::set_vu_cdata(8, &Vector4::zero());
+            vdp::set_vu_cdata(15, &Vector4::zero());
+        }
+    };
+}
+
+fn draw_static_meshpart(idx_vtx_buffer: &mut Vec<ModelVertex>, meshpart: &DBMeshPart, mvp: &Matrix4x4, normal2world: &Matrix4x4, light: &SphericalHarmonics, view_dir: &Vector3) {
+    draw_static_meshpart_instances(idx_vtx_buffer, meshpart, &[(*mvp, normal2world, light, view_dir)]);
+}
+
+// draw several instances of the same static mesh part. render state & vertices are set up once, & only each instance's transform & lighting are loaded between submissions
+fn draw_static_meshpart_instances(idx_vtx_buffer: &mut Vec<ModelVertex>, meshpart: &DBMeshPart, instances: &[(Matrix4x4, &Matrix4x4, &SphericalHarmonics, &Vector3)]) {
+    // set render state
+    set_meshpart_state(meshpart);
+
+    // static mesh vertices are unpacked once at load time, only the transform changes per frame
+    let vertices = expand_meshpart(meshpart.gpu_vertices.as_slice(), idx_vtx_buffer, meshpart);
 
-        let weight0 = (vertex.bweight[0] as f32) / 255.0;
-        let weight1 = (vertex.bweight[1] as f32) / 255.0;
+    for (mvp, normal2world, light, view_dir) in instances {
+        // load cdata
+        let trs = meshpart.transform * (*mvp);
+        bsp_renderer::load_cdata_matrix(0, &trs);
 
-        let vtx = (sk0 * weight0) + (sk1 * weight1);
-        let nrm = (nrm0 * weight0) + (nrm1 * weight1);
+        load_cdata_material(&meshpart.material, &(meshpart.transform * (**normal2world)), light, view_dir);
 
-        vtx_buffer.push(ModelVertex::new(
-            vtx,
-            nrm,
-            Vector2::new(vertex.tex[0].to_f32(), vertex.tex[1].to_f32()),
-            Color32::new(vertex.col[0], vertex.col[1], vertex.col[2], vertex.col[3])));
+        // draw
+        vdp::submit_vu(vdp::Topology::TriangleList, vertices);
     }
+}
 
-    // load cdata
-    let trs = meshpart.transform * (*mvp);
-    bsp_renderer::load_cdata_matrix(0, &trs);
+// blend a vertex's position & normal between the bones which influence it, weighted by each bone's influence
+fn skin_vertex(vertex: &DBMeshVertex, gpu_vertex: &ModelVertex, bonepalette: &[Matrix4x4]) -> ModelVertex {
+    let vtx = gpu_vertex.position;
+    let nrm = gpu_vertex.normal;
 
-    let lightmat = meshpart.transform * (*normal2world) * light.coeff;
-    bsp_renderer::load_cdata_matrix(4, &lightmat);
+    let mut sk_vtx = Vector4::zero();
+    let mut sk_nrm = Vector4::zero();
 
-    vdp::set_vu_cdata(8, &Vector4::zero());
+    // accumulate all bone influences
+    for i in 0..MAX_BONE_INFLUENCES {
+        if vertex.bweight[i] == 0 {
+            continue;
+        }
 
-    // set render state
-    vdp::depth_func(vdp::Compare::LessOrEqual);
-    vdp::set_culling(meshpart.material.enable_cull);
-    vdp::set_winding(vdp::WindingOrder::CounterClockwise);
-    match &meshpart.material.texture {
-        Some(v) => {
-            vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, Some(v.as_ref()));
-        },
-        None => {
-            vdp::bind_texture_slot::<Texture>(TextureUnit::TU0, None);
+        let weight = (vertex.bweight[i] as f32) / 255.0;
+        let bone_idx = vertex.bidx[i] as usize;
+
+        // guard against out of range bone indices (treat as unskinned)
+        if bone_idx < bonepalette.len() {
+            sk_vtx = sk_vtx + ((bonepalette[bone_idx] * vtx) * weight);
+            sk_nrm = sk_nrm + ((bonepalette[bone_idx] * nrm) * weight);
         }
-    };
-    vdp::bind_texture_slot::<Texture>(TextureUnit::TU1, None);
+        else {
+            sk_vtx = sk_vtx + (vtx * weight);
+            sk_nrm = sk_nrm + (nrm * weight);
+        }
+    }
 
-    vdp::blend_equation(vdp::BlendEquation::Add);
+    ModelVertex::new(sk_vtx, sk_nrm, gpu_vertex.texcoord, gpu_vertex.color)
+}
 
-    if meshpart.material.blend_enable {
-        vdp::blend_func(vdp::BlendFactor::SrcAlpha, vdp::BlendFactor::OneMinusSrcAlpha);
-        vdp::depth_write(false);
-    } else {
-        vdp::blend_func(vdp::BlendFactor::One, vdp::BlendFactor::Zero);
-        vdp::depth_write(true);
+fn draw_skinned_meshpart(vtx_buffer: &mut Vec<ModelVertex>, idx_vtx_buffer: &mut Vec<ModelVertex>, meshpart: &DBMeshPart, mvp: &Matrix4x4, normal2world: &Matrix4x4, bonepalette: &[Matrix4x4], light: &SphericalHarmonics, view_dir: &Vector3) {
+    vtx_buffer.clear();
+    
+    // skin the pre-unpacked GPU vertices
+    for (vertex, gpu_vertex) in meshpart.vertices.iter().zip(&meshpart.gpu_vertices) {
+        vtx_buffer.push(skin_vertex(vertex, gpu_vertex, bonepalette));
     }
 
+    // load cdata
+    let trs = meshpart.transform * (*mvp);
+    bsp_renderer::load_cdata_matrix(0, &trs);
+
+    load_cdata_material(&meshpart.material, &(meshpart.transform * (*normal2world)), light, view_dir);
+
+    // set render state
+    set_meshpart_state(meshpart);
+
     // draw
-    vdp::submit_vu(vdp::Topology::TriangleList, vtx_buffer.as_slice());
+    let vertices = expand_meshpart(vtx_buffer.as_slice(), idx_vtx_buffer, meshpart);
+    vdp::submit_vu(vdp::Topology::TriangleList, vertices);
 }
 
-fn gather_lighting(light: &mut SphericalHarmonics, pos: &Vector3, lights: &[(Vector3, Vector3, f32)], bsp: &BspFile) {
+fn gather_lighting(light: &mut SphericalHarmonics, pos: &Vector3, lights: &[(Vector3, Vector3, f32)], emissive: &[EmissiveSurface], bsp: &BspFile) {
     for (light_pos, light_color, light_radius) in lights {
         let dir = *light_pos - *pos;
         let dist = dir.length();
 
         if dist > 0.0 && dist < *light_radius {
-            if bsp.linetrace(0, MASK_SOLID, pos, light_pos).fraction == 1.0 {
+            if bsp.linetrace(0, MASK_OPAQUE, pos, light_pos).fraction == 1.0 {
                 let dir = dir / dist;
                 let falloff = 1.0 - (dist / *light_radius);
                 light.add_directional_light(dir, *light_color * falloff);
             }
         }
     }
+
+    // light-emitting surfaces act as lights whose radius is their light value, but only light things in front of them.
+    // only the brightest few are used, since each costs a linetrace
+    let mut surfaces = emissive.iter().filter_map(|surface| {
+        let dir = surface.center - *pos;
+        let dist = dir.length();
+
+        if dist <= 0.0 || dist >= surface.intensity {
+            return None;
+        }
+
+        let facing = -Vector3::dot(&(dir / dist), &surface.normal);
+        if facing <= 0.0 {
+            return None;
+        }
+
+        let falloff = (1.0 - (dist / surface.intensity)) * facing;
+        Some((surface, dir / dist, falloff))
+    }).collect::<Vec<_>>();
+
+    surfaces.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+    for (surface, dir, falloff) in surfaces.into_iter().take(MAX_EMISSIVE_LIGHTS) {
+        // trace to just in front of the surface, so the trace doesn't hit the surface itself
+        let target = surface.center + surface.normal;
+        if bsp.linetrace(0, MASK_OPAQUE, pos, &target).fraction == 1.0 {
+            light.add_directional_light(dir, Vector3::new(1.0, 1.0, 1.0) * falloff);
+        }
+    }
+}
+
+// pick a LOD level from a mesh's projected size on screen. the threshold is pushed away from the current level, so meshes near a threshold don't flicker between levels
+fn select_lod(current_lod: usize, lod_count: usize, screen_size: f32) -> usize {
+    let mut lod = 0;
+
+    while lod + 1 < lod_count {
+        let threshold = LOD_SCREEN_SIZE * 0.5f32.powi(lod as i32);
+        let threshold = if current_lod > lod { threshold * (1.0 + LOD_HYSTERESIS) } else { threshold * (1.0 - LOD_HYSTERESIS) };
+
+        if screen_size >= threshold {
+            break;
+        }
+
+        lod += 1;
+    }
+
+    lod
+}
+
+// calculate the size of a bounding box projected on screen, as a fraction of the screen height
+fn projected_size(bounds_center: &Vector3, bounds_extents: &Vector3, cam_pos: &Vector3, fov: f32) -> f32 {
+    let radius = bounds_extents.length();
+    let dist = (*bounds_center - *cam_pos).length();
+
+    if dist <= radius {
+        return f32::INFINITY;
+    }
+
+    radius / (dist * (fov.to_radians() * 0.5).tan())
+}
+
+// add baked lighting at the given position. maps with a light grid get directional light from the grid,
+// otherwise lighting is taken from the floor beneath the given position, falling back to a constant ambient term if there's no floor
+// floor samples are cached per-leaf, so the lightmap is only read the first time a mesh enters each leaf
+fn gather_ambient(light: &mut SphericalHarmonics, pos: &Vector3, bsp: &BspFile, lm_settings: &LightmapSettings, light_layers: &[f32;NUM_CUSTOM_LIGHT_LAYERS], leaf_ambient: &mut [Option<Vector3>]) {
+    if let Some((dir, color)) = bsp.sample_light_grid(pos) {
+        // split grid light between a directional term & ambient fill, so the side facing away from the light isn't black
+        let color = lm_settings.sample_to_color(color);
+        light.add_directional_light(dir, color * 0.5);
+        light.add_ambient_light(color * 0.5);
+        return;
+    }
+
+    let leaf_index = bsp.calc_leaf_index(pos) as usize;
+
+    let ambient = match leaf_ambient[leaf_index] {
+        Some(v) => v,
+        None => {
+            let ambient = match bsp.sample_lightmap(pos, AMBIENT_SAMPLE_DIST, light_layers) {
+                Some(v) => lm_settings.sample_to_color(v),
+                None => Vector3::new(0.25, 0.1, 0.0)
+            };
+
+            leaf_ambient[leaf_index] = Some(ambient);
+            ambient
+        }
+    };
+
+    light.add_ambient_light(ambient);
 }
 
 /// System which performs all rendering (world + entities)
-pub fn render_system(time: &TimeData, map_data: &mut MapData, env_data: &Option<[Arc<Texture>;6]>, world: &mut World) {
+pub fn render_system(time: &TimeData, map_data: &mut MapData, env_data: &Option<[Arc<Texture>;6]>, post_process: &mut PostProcess, overlay: &mut DebugOverlay, debug_draw: &mut DebugDraw, world: &mut World) {
+    // cached floor samples depend on the light layers, so they're resampled whenever a layer changes
+    if map_data.leaf_ambient_layers != map_data.light_layers {
+        map_data.leaf_ambient.fill(None);
+        map_data.leaf_ambient_layers = map_data.light_layers;
+    }
+
     // gather map models
     let mut mapmodel_iter = world.query::<(&MapModel, &Transform3D)>();
     let mapmodels = mapmodel_iter
@@ -307,14 +490,14 @@ pub fn render_system(time: &TimeData, map_data: &mut MapData, env_data: &Option<
         .collect::<Vec<_>>();
 
     // gather static meshes
-    let mut mesh_iter = world.query::<(&Mesh, &Transform3D)>().without::<&SkeletalPoseState>();
-    let meshes = mesh_iter
+    let mut mesh_iter = world.query::<(&mut Mesh, &Transform3D)>().without::<&SkeletalPoseState>();
+    let mut meshes = mesh_iter
         .iter()
         .collect::<Vec<_>>();
 
     // gather skinned meshes
-    let mut sk_mesh_iter = world.query::<(&Mesh, &Transform3D, &SkeletalPoseState)>();
-    let sk_meshes = sk_mesh_iter
+    let mut sk_mesh_iter = world.query::<(&mut Mesh, &Transform3D, &SkeletalPoseState)>();
+    let mut sk_meshes = sk_mesh_iter
         .iter()
         .collect::<Vec<_>>();
 
@@ -332,14 +515,44 @@ pub fn render_system(time: &TimeData, map_data: &mut MapData, env_data: &Option<
 
     // gather cameras
     let mut camera_iter = world.query::<(&Transform3D, &Camera)>();
-    let cameras = camera_iter
+    let mut cameras = camera_iter
         .iter()
         .collect::<Vec<_>>();
 
+    // render target cameras are drawn first, since they use the framebuffer as scratch space before the screen is drawn
+    cameras.sort_by_key(|(_, (_, camera))| camera.render_target.is_none());
+
     let mut light_data = Vec::with_capacity(lights.len());
+    let mut dynamic_light_data = Vec::with_capacity(lights.len());
 
+    let mut stats = FrameStats::default();
+    stats.total_leaves = map_data.map.leaf_lump.leaves.len();
+
+    // the first screen camera's clear color is used to clear the whole screen, other cameras fill their own viewport if their color differs
+    let screen_clear_color = match cameras.iter().find(|(_, (_, camera))| camera.render_target.is_none()) {
+        Some((_, (_, camera))) => camera.clear_color,
+        None => Color32::new(0, 0, 0, 255)
+    };
+
+    let mut screen_cleared = false;
     let mut camera_index = 0;
-    for (_, (transform, camera)) in cameras {
+    for (camera_entity, (transform, camera)) in cameras {
+        let needs_fill = match &camera.render_target {
+            Some(_) => {
+                vdp::clear_color(camera.clear_color);
+                false
+            }
+            None if !screen_cleared => {
+                vdp::clear_color(screen_clear_color);
+                screen_cleared = true;
+                false
+            }
+            None => {
+                let c = camera.clear_color;
+                c.r != screen_clear_color.r || c.g != screen_clear_color.g || c.b != screen_clear_color.b || c.a != screen_clear_color.a
+            }
+        };
+
         // build view & projection matrices
         let mut cam_rot_inv = transform.rotation;
         cam_rot_inv.invert();
@@ -349,19 +562,23 @@ pub fn render_system(time: &TimeData, map_data: &mut MapData, env_data: &Option<
 
         let cam_env_view = Matrix4x4::rotation(cam_rot_inv);
 
-        let cam_proj = Matrix4x4::projection_perspective(640.0 / 480.0, camera.fov.to_radians(), camera.near, camera.far);
+        // render targets are drawn into the corner of the framebuffer, & then copied out into the texture
+        let viewport = match (&camera.render_target, camera.viewport_rect) {
+            (Some(tex), _) => Rectangle::new(0, 0, tex.width.min(640), tex.height.min(480)),
+            (None, Some(v)) => v,
+            (None, None) => Rectangle::new(0, 0, 640, 480)
+        };
+
+        let cam_proj = Matrix4x4::projection_perspective(viewport.width as f32 / viewport.height as f32, camera.fov.to_radians(), camera.near, camera.far);
 
         // calculate camera frustum planes
         let viewproj = cam_view * common::coord_space_transform() * cam_proj;
 
         let frustum = extract_frustum(&viewproj);
 
-        match camera.viewport_rect {
-            Some(v) => vdp::viewport(v),
-            None => vdp::viewport(Rectangle::new(0, 0, 640, 480))
-        };
-        
-        vdp::clear_color(Color32::new(0, 0, 0, 255));
+        vdp::viewport(viewport);
+
+        // note: color is cleared once up front, since clearing here would also wipe out other cameras' viewports
         vdp::clear_depth(1.0);
 
         // retrieve map renderer for camera
@@ -369,42 +586,76 @@ pub fn render_system(time: &TimeData, map_data: &mut MapData, env_data: &Option<
         let renderer = &mut map_data.map_renderers[camera_index];
 
         // update with new camera position
-        renderer.update(&frustum, time.total_time, &map_data.light_layers, &map_data.map, &map_data.map_textures, &transform.position);
+        renderer.update(&frustum, time.total_time, &map_data.light_layers, &map_data.areaportal_states, &map_data.map, &map_data.map_textures, &transform.position);
+        map_data.map_textures.mark_visible(&map_data.map, renderer);
+
+        stats.visible_leaves += renderer.visible_leaf_count();
+        stats.node_tests += renderer.node_test_count();
+        stats.triangles += renderer.triangle_count();
+        stats.lightmap_atlases += 1;
+        stats.lightmap_memory += renderer.lightmap_memory();
+        stats.lightmap_usage = stats.lightmap_usage.max(renderer.lightmap_usage());
+        stats.build_time = stats.build_time.max(renderer.build_time());
 
         // set up map VU layout & program
         bsp_renderer::setup_vu();
 
+        if needs_fill {
+            fill_viewport(camera.clear_color);
+        }
+
         // draw skybox
         match env_data {
-            Some(v) => {
-                draw_env_quad(&v[0], &Quaternion::identity(), &cam_env_view, &cam_proj);
-                draw_env_quad(&v[1], &Quaternion::from_euler(Vector3::new(0.0, 0.0, 180.0_f32.to_radians())), &cam_env_view, &cam_proj);
-                draw_env_quad(&v[2], &Quaternion::from_euler(Vector3::new(0.0, 0.0, 90.0_f32.to_radians())), &cam_env_view, &cam_proj);
-                draw_env_quad(&v[3], &Quaternion::from_euler(Vector3::new(0.0, 0.0, -90.0_f32.to_radians())), &cam_env_view, &cam_proj);
-                draw_env_quad(&v[4], &Quaternion::from_euler(Vector3::new(-90.0_f32.to_radians(), 0.0, -90.0_f32.to_radians())), &cam_env_view, &cam_proj);
-                draw_env_quad(&v[5], &Quaternion::from_euler(Vector3::new(90.0_f32.to_radians(), 0.0, -90.0_f32.to_radians())), &cam_env_view, &cam_proj);
+            Some(v) if camera.draw_sky => {
+                // slowly rotate sky around axis specified by map (skyrotate is in degrees per second)
+                let a = (map_data.sky_rotate * time.total_time).to_radians() * 0.5;
+                let sa = a.sin();
+                let ca = a.cos();
+                let sky_rot = Quaternion::new(map_data.sky_axis.x * sa, map_data.sky_axis.y * sa, map_data.sky_axis.z * sa, ca);
+
+                draw_env_quad(&v[0], &Quaternion::identity(), &sky_rot, &cam_env_view, &cam_proj);
+                draw_env_quad(&v[1], &Quaternion::from_euler(Vector3::new(0.0, 0.0, 180.0_f32.to_radians())), &sky_rot, &cam_env_view, &cam_proj);
+                draw_env_quad(&v[2], &Quaternion::from_euler(Vector3::new(0.0, 0.0, 90.0_f32.to_radians())), &sky_rot, &cam_env_view, &cam_proj);
+                draw_env_quad(&v[3], &Quaternion::from_euler(Vector3::new(0.0, 0.0, -90.0_f32.to_radians())), &sky_rot, &cam_env_view, &cam_proj);
+                draw_env_quad(&v[4], &Quaternion::from_euler(Vector3::new(-90.0_f32.to_radians(), 0.0, -90.0_f32.to_radians())), &sky_rot, &cam_env_view, &cam_proj);
+                draw_env_quad(&v[5], &Quaternion::from_euler(Vector3::new(90.0_f32.to_radians(), 0.0, -90.0_f32.to_radians())), &sky_rot, &cam_env_view, &cam_proj);
             }
             _ => {
             }
         };
 
+        // the sky is left unfogged, everything else in the world fades into the fog
+        bsp_renderer::load_cdata_fog(5, &map_data.fog);
+
         // draw opaque geometry
-        renderer.draw_opaque(&map_data.map, &map_data.map_textures, time.total_time, &cam_view, &cam_proj);
+        renderer.draw_opaque(&map_data.map, &map_data.map_textures, time.total_time, map_data.sort_opaque_front_to_back, &cam_view, &cam_proj);
 
         // cull light sources
         light_data.clear();
+        dynamic_light_data.clear();
         for (_, (light_transform, light)) in &lights {
             let light_bounds_extents = Vector3::new(light.max_radius, light.max_radius, light.max_radius);
 
             if renderer.check_vis(&map_data.map, light_transform.position, light_bounds_extents) {
                 light_data.push((light_transform.position, light.color, light.max_radius));
+
+                if light.dynamic {
+                    dynamic_light_data.push((light_transform.position, light.color, light.max_radius));
+                }
             }
         }
 
+        // project dynamic lights onto map geometry
+        renderer.draw_dynamic_lights(&map_data.map, &map_data.map_textures, &dynamic_light_data, &cam_view, &cam_proj);
+
         // gather visible models
         let mut visible_models = Vec::new();
         for (_, (model_info, model_transform)) in &mapmodels {
-            let submodel = &map_data.map.submodel_lump.submodels[model_info.model_idx + 1];
+            // skip models whose submodel doesn't exist (for example, a stale index from a destroyed entity)
+            let submodel = match map_data.map.submodel_lump.submodels.get(model_info.model_idx + 1) {
+                Some(v) => v,
+                None => continue
+            };
             let bounds_extents = (submodel.maxs - submodel.mins) * 0.5;
             let bounds_center = model_transform.position + ((submodel.maxs + submodel.mins) * 0.5);
 
@@ -415,13 +666,14 @@ pub fn render_system(time: &TimeData, map_data: &mut MapData, env_data: &Option<
                     * Matrix4x4::rotation(model_transform.rotation)
                     * Matrix4x4::translation(model_transform.position);
 
+                map_data.map_textures.mark_model_visible(&map_data.map, &map_data.map_models, model_info.model_idx);
                 visible_models.push((model_mat, model_info.model_idx));
             }
         }
 
         // gather visible meshes
         let mut visible_meshes = Vec::new();
-        for (_, (mesh, mesh_transform)) in &meshes {
+        for (_, (mesh, mesh_transform)) in &mut meshes {
             let model_mat = Matrix4x4::scale(mesh_transform.scale)
                 * Matrix4x4::rotation(mesh_transform.rotation)
                 * Matrix4x4::translation(mesh_transform.position);
@@ -430,20 +682,24 @@ pub fn render_system(time: &TimeData, map_data: &mut MapData, env_data: &Option<
 
             // calculate lighting
             let mut light = SphericalHarmonics::new();
-            light.add_ambient_light(Vector3::new(0.25, 0.1, 0.0));
-            gather_lighting(&mut light, &bounds_center, &light_data, &map_data.map);
+            gather_ambient(&mut light, &bounds_center, &map_data.map, &map_data.lightmap_settings, &map_data.light_layers, &mut map_data.leaf_ambient);
+            gather_lighting(&mut light, &bounds_center, &light_data, &map_data.emissive_surfaces, &map_data.map);
 
             let vis = aabb_frustum(bounds_center - bounds_extents, bounds_center + bounds_extents, &frustum) && renderer.check_vis(&map_data.map, bounds_center, bounds_extents);
 
             if vis {
+                let lod = select_lod(mesh.lod_for(camera_entity), mesh.mesh.lod_count, projected_size(&bounds_center, &bounds_extents, &transform.position, camera.fov));
+                mesh.set_lod_for(camera_entity, lod);
+
                 let normal2world = Matrix4x4::rotation(mesh_transform.rotation);
-                visible_meshes.push((model_mat, light, normal2world, &mesh.mesh));
+                let view_dir = view_direction(&bounds_center, &transform.position);
+                visible_meshes.push((model_mat, light, normal2world, view_dir, lod, &mesh.mesh));
             }
         }
 
         // gather visible skinned meshes
         let mut visible_skinned_meshes = Vec::new();
-        for (_, (mesh, mesh_transform, pose_state)) in &sk_meshes {
+        for (_, (mesh, mesh_transform, pose_state)) in &mut sk_meshes {
             let model_mat = Matrix4x4::scale(mesh_transform.scale)
                 * Matrix4x4::rotation(mesh_transform.rotation)
                 * Matrix4x4::translation(mesh_transform.position);
@@ -452,14 +708,18 @@ pub fn render_system(time: &TimeData, map_data: &mut MapData, env_data: &Option<
 
             // calculate lighting
             let mut light = SphericalHarmonics::new();
-            light.add_ambient_light(Vector3::new(0.25, 0.1, 0.0));
-            gather_lighting(&mut light, &bounds_center, &light_data, &map_data.map);
+            gather_ambient(&mut light, &bounds_center, &map_data.map, &map_data.lightmap_settings, &map_data.light_layers, &mut map_data.leaf_ambient);
+            gather_lighting(&mut light, &bounds_center, &light_data, &map_data.emissive_surfaces, &map_data.map);
 
             let vis = aabb_frustum(bounds_center - bounds_extents, bounds_center + bounds_extents, &frustum) && renderer.check_vis(&map_data.map, bounds_center, bounds_extents);
 
             if vis {
+                let lod = select_lod(mesh.lod_for(camera_entity), mesh.mesh.lod_count, projected_size(&bounds_center, &bounds_extents, &transform.position, camera.fov));
+                mesh.set_lod_for(camera_entity, lod);
+
                 let normal2world = Matrix4x4::rotation(mesh_transform.rotation);
-                visible_skinned_meshes.push((model_mat, light, normal2world, &mesh.mesh, &pose_state.bone_palette));
+                let view_dir = view_direction(&bounds_center, &transform.position);
+                visible_skinned_meshes.push((model_mat, light, normal2world, view_dir, lod, &mesh.mesh, &pose_state.bone_palette));
             }
         }
 
@@ -469,52 +729,101 @@ pub fn render_system(time: &TimeData, map_data: &mut MapData, env_data: &Option<
         }
 
         let mut vtx_buffer = Vec::with_capacity(1024);
+        let mut idx_vtx_buffer = Vec::with_capacity(1024);
 
         // setup VU for drawing lit meshes
-        setup_vu_lit_mesh();
-
-        // draw static meshes
-        for (local2world, light, normal2world, mesh) in &visible_meshes {
-            let mvp = (*local2world) * cam_view * coord_space_transform() * cam_proj;
-
-            for part in &mesh.mesh_parts {
-                draw_static_meshpart(&mut vtx_buffer, part, &mvp, &normal2world, &light);
+        setup_vu_lit_mesh(&map_data.fog);
+
+        // draw static meshes, batching entities which share the same mesh & LOD so that each part is only set up once per batch
+        visible_meshes.sort_by_key(|(_, _, _, _, lod, mesh)| (Arc::as_ptr(mesh) as usize, *lod));
+
+        let mut instances = Vec::with_capacity(visible_meshes.len());
+        let mut batch_start = 0;
+        while batch_start < visible_meshes.len() {
+            let (_, _, _, _, lod, mesh) = &visible_meshes[batch_start];
+            let batch_len = visible_meshes[batch_start..].iter()
+                .take_while(|(_, _, _, _, other_lod, other_mesh)| *other_lod == *lod && Arc::ptr_eq(other_mesh, mesh))
+                .count();
+
+            let batch = &visible_meshes[batch_start..batch_start + batch_len];
+            let batch_mvps = batch.iter()
+                .map(|(local2world, _, _, _, _, _)| (*local2world) * cam_view * coord_space_transform() * cam_proj)
+                .collect::<Vec<_>>();
+
+            // small meshes were already culled as a whole, but large ones (a whole building, say) also skip parts which are out of view
+            let cull_parts = mesh.mesh_parts.len() > 1 && (mesh.bounds_max - mesh.bounds_min).length() > PART_CULL_SIZE;
+
+            for part in mesh.mesh_parts.iter().filter(|x| x.lod == *lod) {
+                // lighting still differs per instance, so it's loaded for each one
+                instances.clear();
+                for ((local2world, light, normal2world, view_dir, _, _), mvp) in batch.iter().zip(&batch_mvps) {
+                    if cull_parts {
+                        let part_offset = (part.bounds_min + part.bounds_max) * 0.5;
+                        let part_extents = (part.bounds_max - part.bounds_min) * 0.5;
+                        let (part_center, part_extents) = transform_aabb(part_offset, part_extents, local2world);
+
+                        if !aabb_frustum(part_center - part_extents, part_center + part_extents, &frustum) {
+                            continue;
+                        }
+                    }
+
+                    instances.push((*mvp, normal2world, light, view_dir));
+                }
+
+                if instances.len() > 0 {
+                    draw_static_meshpart_instances(&mut idx_vtx_buffer, part, &instances);
+                }
             }
+
+            stats.mesh_instances += batch_len;
+            stats.mesh_batches += 1;
+            batch_start += batch_len;
         }
 
         // draw skinned meshes
-        for (local2world, light, normal2world, mesh, pose_state) in &visible_skinned_meshes {
+        for (local2world, light, normal2world, view_dir, lod, mesh, pose_state) in &visible_skinned_meshes {
             let mvp = (*local2world) * cam_view * coord_space_transform() * cam_proj;
 
-            for part in &mesh.mesh_parts {
-                draw_skinned_meshpart(&mut vtx_buffer, part, &mvp, &normal2world, &pose_state, &light);
+            for part in mesh.mesh_parts.iter().filter(|x| x.lod == *lod) {
+                draw_skinned_meshpart(&mut vtx_buffer, &mut idx_vtx_buffer, part, &mvp, &normal2world, &pose_state, &light, &view_dir);
             }
         }
 
         // setup VU for map rendering
         bsp_renderer::setup_vu();
+        bsp_renderer::load_cdata_fog(5, &map_data.fog);
+
+        // draw decals
+        map_data.decals.draw(time.total_time, &cam_view, &cam_proj);
 
         // draw transparent geometry
-        renderer.draw_transparent(&map_data.map, &map_data.map_textures, time.total_time, &cam_view, &cam_proj);
+        renderer.draw_transparent(&map_data.map, &map_data.map_textures, time.total_time, &transform.position, &cam_view, &cam_proj);
 
         // draw models (transparent)
         for (transform, id) in &visible_models {
             map_data.map_models.draw_model_transparent(&map_data.map, time.total_time, &map_data.map_textures, *id, transform, &cam_view, &cam_proj);
         }
 
+        // draw debug visualization (sets up its own VU program)
+        debug_draw.draw(&map_data.map, renderer, &light_data, &transform.position, &cam_view, &cam_proj);
+
         // setup VU for drawing lit meshes
-        setup_vu_lit_mesh();
+        setup_vu_lit_mesh(&map_data.fog);
 
         // clear depth
         vdp::clear_depth(1.0);
 
         // calculate lighting for first-person meshes
         let mut fplight = SphericalHarmonics::new();
-        fplight.add_ambient_light(Vector3::new(0.25, 0.1, 0.0));
-        gather_lighting(&mut fplight, &transform.position, &light_data, &map_data.map);
+        gather_ambient(&mut fplight, &transform.position, &map_data.map, &map_data.lightmap_settings, &map_data.light_layers, &mut map_data.leaf_ambient);
+        gather_lighting(&mut fplight, &transform.position, &light_data, &map_data.emissive_surfaces, &map_data.map);
+
+        // first-person meshes are viewed from straight ahead
+        let cam_back = Matrix4x4::rotation(transform.rotation) * Vector4::new(0.0, -1.0, 0.0, 0.0);
+        let fp_view_dir = Vector3::new(cam_back.x, cam_back.y, cam_back.z);
 
-        // draw FP meshes
-        for (_, (mesh, mesh_transform)) in &fp_meshes {
+        // draw FP meshes (only for cameras drawing to the screen)
+        for (_, (mesh, mesh_transform)) in fp_meshes.iter().filter(|_| camera.render_target.is_none()) {
             let local2world = Matrix4x4::scale(mesh_transform.scale)
                 * Matrix4x4::rotation(mesh_transform.rotation)
                 * Matrix4x4::translation(mesh_transform.position);
@@ -522,11 +831,89 @@ pub fn render_system(time: &TimeData, map_data: &mut MapData, env_data: &Option<
             let normal2world = Matrix4x4::rotation(mesh_transform.rotation) * Matrix4x4::rotation(transform.rotation);
             let mvp = local2world * coord_space_transform() * cam_proj;
 
-            for part in &mesh.mesh.mesh_parts {
-                draw_static_meshpart(&mut vtx_buffer, part, &mvp, &normal2world, &fplight);
+            // first-person meshes are always close to the camera, so always draw at full detail
+            for part in mesh.mesh.mesh_parts.iter().filter(|x| x.lod == 0) {
+                draw_static_meshpart(&mut idx_vtx_buffer, part, &mvp, &normal2world, &fplight, &fp_view_dir);
             }
         }
 
+        if let Some(tex) = &camera.render_target {
+            vdp::copy_fb_to_texture(&viewport, &viewport, tex);
+        }
+
         camera_index += 1;
     }
+
+    // make sure the screen is cleared even if there were only render target cameras
+    if !screen_cleared {
+        vdp::clear_color(screen_clear_color);
+    }
+
+    // unload map textures which have been out of view for a while
+    map_data.map_textures.end_frame();
+
+    // apply gamma & brightness to the final image (the overlay is left unaffected)
+    post_process.draw();
+
+    // draw debug overlay on top of all cameras
+    overlay.draw(&stats);
+}
+
+#[cfg(test)]
+mod tests {
+    use half::f16;
+
+    use super::*;
+
+    #[test]
+    fn far_meshes_select_lower_detail() {
+        let near = select_lod(0, 3, projected_size(&Vector3::zero(), &Vector3::new(16.0, 16.0, 16.0), &Vector3::new(64.0, 0.0, 0.0), 60.0));
+        let far = select_lod(0, 3, projected_size(&Vector3::zero(), &Vector3::new(16.0, 16.0, 16.0), &Vector3::new(4096.0, 0.0, 0.0), 60.0));
+
+        assert_eq!(near, 0);
+        assert_eq!(far, 2);
+    }
+
+    #[test]
+    fn single_lod_meshes_always_select_lod_zero() {
+        assert_eq!(select_lod(0, 1, 0.0), 0);
+    }
+
+    #[test]
+    fn lod_switches_with_hysteresis() {
+        // just below the first threshold, the mesh stays at whichever level it was already at
+        let size = LOD_SCREEN_SIZE * (1.0 - (LOD_HYSTERESIS * 0.5));
+        assert_eq!(select_lod(0, 2, size), 0);
+        assert_eq!(select_lod(1, 2, size), 1);
+
+        // past the hysteresis band it switches
+        assert_eq!(select_lod(0, 2, LOD_SCREEN_SIZE * (1.0 - (LOD_HYSTERESIS * 2.0))), 1);
+        assert_eq!(select_lod(1, 2, LOD_SCREEN_SIZE * (1.0 + (LOD_HYSTERESIS * 2.0))), 0);
+    }
+
+    #[test]
+    fn many_lod_levels_do_not_overflow() {
+        assert_eq!(select_lod(0, 100, 0.0), 99);
+    }
+
+    #[test]
+    fn equally_weighted_vertex_is_averaged_between_bones() {
+        // four equal weights, normalized to sum to 255
+        let vertex = DBMeshVertex {
+            pos: [f16::from_f32(1.0), f16::from_f32(2.0), f16::from_f32(0.0)],
+            nrm: [f16::from_f32(0.0), f16::from_f32(0.0), f16::from_f32(1.0)],
+            col: [255, 255, 255, 255],
+            tex: [f16::from_f32(0.0), f16::from_f32(0.0)],
+            bweight: [64, 64, 64, 63],
+            bidx: [0, 1, 2, 3],
+        };
+
+        let bonepalette: Vec<Matrix4x4> = (1..=4).map(|i| Matrix4x4::scale(Vector3::new(i as f32, i as f32, i as f32))).collect();
+        let skinned = skin_vertex(&vertex, &ModelVertex::unpack(&vertex), &bonepalette);
+
+        // the average of the bones scaling by 1, 2, 3, & 4
+        assert!((skinned.position.x - 2.5).abs() < 0.02, "{}", skinned.position.x);
+        assert!((skinned.position.y - 5.0).abs() < 0.04, "{}", skinned.position.y);
+        assert!((skinned.position.w - 1.0).abs() < 0.001);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,61 @@
+use dbsdk_rs::math::{Matrix4x4, Quaternion, Vector3, Vector4};
+use hecs::{CommandBuffer, World};
+
+use crate::{bsp_file::{BspFile, MASK_SOLID}, component::{camera::{Camera, FPCamera, ThirdPersonCamera}, fpview::FPView, transform3d::Transform3D}};
+
+const TPCAM_DISTANCE: f32 = 100.0;
+const TPCAM_HEIGHT: f32 = 40.0;
+
+/// System which allows a ThirdPersonCamera to orbit behind an entity with an FPView attached, pulling in to avoid walls
+pub fn tpcam_update(map: &BspFile, world: &mut World) {
+    let mut camera_iter = world.query::<(&ThirdPersonCamera, &Camera, &mut Transform3D)>();
+    let cameras = camera_iter
+        .iter()
+        .collect::<Vec<_>>();
+
+    for (_, (tpcam, camera, cam_transform)) in cameras {
+        let target_fpview = world.get::<&FPView>(tpcam.follow_entity).unwrap();
+        let target_transform = world.get::<&Transform3D>(tpcam.follow_entity).unwrap();
+
+        let pitch = target_fpview.pitch + tpcam.pitch_offset;
+        cam_transform.rotation = Quaternion::from_euler(Vector3::new(pitch.to_radians(), 0.0, target_fpview.yaw.to_radians()));
+
+        let rot_matrix = Matrix4x4::rotation(cam_transform.rotation);
+        let camera_fwd = rot_matrix * Vector4::new(0.0, -1.0, 0.0, 0.0);
+        let camera_fwd = Vector3::new(camera_fwd.x, camera_fwd.y, camera_fwd.z);
+
+        let pivot = target_transform.position + Vector3::new(0.0, 0.0, tpcam.height);
+        let desired_pos = pivot - (camera_fwd * tpcam.distance);
+
+        // spring arm: pull camera in towards the pivot if there's a wall in the way
+        let trace = map.linetrace(0, MASK_SOLID, &pivot, &desired_pos);
+        let distance = if trace.fraction < 1.0 {
+            // keep the near plane from poking through the wall
+            ((tpcam.distance * trace.fraction) - camera.near).max(0.0)
+        }
+        else {
+            tpcam.distance
+        };
+
+        cam_transform.position = pivot - (camera_fwd * distance);
+    }
+}
+
+
+/// Toggle cameras between following their entity in first person & orbiting behind it in third person
+pub fn tpcam_toggle(world: &mut World) {
+    let mut cmd_buf = CommandBuffer::new();
+
+    for (eid, (fpcam, tpcam)) in world.query_mut::<(Option<&FPCamera>, Option<&ThirdPersonCamera>)>() {
+        if let Some(fpcam) = fpcam {
+            cmd_buf.remove_one::<FPCamera>(eid);
+            cmd_buf.insert_one(eid, ThirdPersonCamera::new(fpcam.follow_entity, TPCAM_DISTANCE, TPCAM_HEIGHT));
+        }
+        else if let Some(tpcam) = tpcam {
+            cmd_buf.remove_one::<ThirdPersonCamera>(eid);
+            cmd_buf.insert_one(eid, FPCamera::new(tpcam.follow_entity));
+        }
+    }
+
+    cmd_buf.run_on(world);
+}
\ No newline at end of file
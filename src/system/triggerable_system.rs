@@ -1,15 +1,184 @@
 use hecs::{CommandBuffer, World};
 
-use crate::component::triggerable::{TriggerLink, TriggerState};
+use crate::{component::triggerable::{DelayedTrigger, TriggerFollow, TriggerLink, TriggerState}, TimeData};
 
-/// System which propagates state of triggerable entities to linked targets, if any
-pub fn trigger_link_system_update(world: &mut World) {
+/// System which triggers the linked targets of triggerable entities, if any, when they become triggered
+pub fn trigger_link_system_update(time: &TimeData, world: &mut World) {
     let mut cmd_buf = CommandBuffer::new();
-    for (_, (triggerable, link)) in world.query_mut::<(&mut TriggerState, &TriggerLink)>() {
-        cmd_buf.insert_one(link.target, TriggerState {
-            triggered: triggerable.triggered
-        });
+    for (_, (triggerable, link)) in world.query::<(&TriggerState, &mut TriggerLink)>().iter() {
+        // only the rising edge is passed on, so targets are free to reset themselves afterwards
+        if triggerable.triggered == link.prev_triggered {
+            continue;
+        }
+
+        for target in &link.targets {
+            // targets may have been destroyed (e.g. an exploded func_explosive)
+            if !world.contains(*target) {
+                continue;
+            }
+
+            if !triggerable.triggered && world.get::<&TriggerFollow>(*target).is_err() {
+                continue;
+            }
+
+            if link.delay > 0.0 {
+                cmd_buf.spawn((DelayedTrigger {
+                    target: *target,
+                    triggered: triggerable.triggered,
+                    fire_time: time.total_time + link.delay
+                },));
+            }
+            else {
+                cmd_buf.insert_one(*target, TriggerState {
+                    triggered: triggerable.triggered
+                });
+            }
+        }
+
+        link.prev_triggered = triggerable.triggered;
+    }
+
+    cmd_buf.run_on(world);
+}
+
+/// System which applies scheduled trigger state changes once their delay has elapsed
+pub fn delayed_trigger_system_update(time: &TimeData, world: &mut World) {
+    let mut cmd_buf = CommandBuffer::new();
+    for (e, delayed) in world.query::<&DelayedTrigger>().iter() {
+        if time.total_time < delayed.fire_time {
+            continue;
+        }
+
+        if world.contains(delayed.target) {
+            cmd_buf.insert_one(delayed.target, TriggerState {
+                triggered: delayed.triggered
+            });
+        }
+
+        cmd_buf.despawn(e);
     }
 
     cmd_buf.run_on(world);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_frame_pulse_triggers_target_once() {
+        let mut world = World::new();
+        let time = TimeData::default();
+
+        let target = world.spawn((TriggerState { triggered: false },));
+        let source = world.spawn((TriggerState { triggered: false }, TriggerLink { targets: vec![target], delay: 0.0, prev_triggered: false }));
+
+        let mut activations = 0;
+        for frame in 0..4 {
+            world.get::<&mut TriggerState>(source).unwrap().triggered = frame == 1;
+            trigger_link_system_update(&time, &mut world);
+
+            // the target consumes & resets its own trigger, like a door or button would
+            let mut state = world.get::<&mut TriggerState>(target).unwrap();
+            if state.triggered {
+                activations += 1;
+                state.triggered = false;
+            }
+        }
+
+        assert_eq!(activations, 1);
+    }
+
+    #[test]
+    fn only_following_targets_are_untriggered() {
+        let mut world = World::new();
+        let time = TimeData::default();
+
+        let latch = world.spawn((TriggerState { triggered: false },));
+        let follower = world.spawn((TriggerState { triggered: false }, TriggerFollow));
+        let source = world.spawn((TriggerState { triggered: true }, TriggerLink { targets: vec![latch, follower], delay: 0.0, prev_triggered: false }));
+
+        trigger_link_system_update(&time, &mut world);
+        assert!(world.get::<&TriggerState>(latch).unwrap().triggered);
+        assert!(world.get::<&TriggerState>(follower).unwrap().triggered);
+
+        world.get::<&mut TriggerState>(source).unwrap().triggered = false;
+        trigger_link_system_update(&time, &mut world);
+        assert!(world.get::<&TriggerState>(latch).unwrap().triggered);
+        assert!(!world.get::<&TriggerState>(follower).unwrap().triggered);
+    }
+
+    #[test]
+    fn link_fires_every_target() {
+        let mut world = World::new();
+        let time = TimeData::default();
+
+        let a = world.spawn((TriggerState { triggered: false },));
+        let b = world.spawn((TriggerState { triggered: false },));
+        let source = world.spawn((TriggerState { triggered: false }, TriggerLink { targets: vec![a, b], delay: 0.0, prev_triggered: false }));
+
+        world.get::<&mut TriggerState>(source).unwrap().triggered = true;
+        trigger_link_system_update(&time, &mut world);
+
+        assert!(world.get::<&TriggerState>(a).unwrap().triggered);
+        assert!(world.get::<&TriggerState>(b).unwrap().triggered);
+    }
+
+    fn step(world: &mut World, total_time: f32) {
+        let time = TimeData { delta_time: 0.0, total_time };
+        trigger_link_system_update(&time, world);
+        delayed_trigger_system_update(&time, world);
+    }
+
+    #[test]
+    fn delayed_link_fires_after_delay() {
+        let mut world = World::new();
+
+        let target = world.spawn((TriggerState { triggered: false },));
+        let source = world.spawn((TriggerState { triggered: true }, TriggerLink { targets: vec![target], delay: 1.5, prev_triggered: false }));
+
+        step(&mut world, 1.0);
+        assert!(!world.get::<&TriggerState>(target).unwrap().triggered);
+        assert_eq!(world.query::<&DelayedTrigger>().iter().count(), 1);
+
+        step(&mut world, 2.0);
+        assert!(!world.get::<&TriggerState>(target).unwrap().triggered);
+
+        step(&mut world, 2.5);
+        assert!(world.get::<&TriggerState>(target).unwrap().triggered);
+        assert_eq!(world.query::<&DelayedTrigger>().iter().count(), 0);
+        assert!(world.get::<&TriggerState>(source).unwrap().triggered);
+    }
+
+    #[test]
+    fn relay_forwards_to_its_target() {
+        let mut world = World::new();
+
+        // source -> relay (delayed) -> target
+        let target = world.spawn((TriggerState { triggered: false },));
+        let relay = world.spawn((TriggerState { triggered: false }, TriggerLink { targets: vec![target], delay: 0.5, prev_triggered: false }));
+        world.spawn((TriggerState { triggered: true }, TriggerLink { targets: vec![relay], delay: 0.0, prev_triggered: false }));
+
+        step(&mut world, 0.0);
+        assert!(world.get::<&TriggerState>(relay).unwrap().triggered);
+
+        step(&mut world, 0.1);
+        assert!(!world.get::<&TriggerState>(target).unwrap().triggered);
+
+        step(&mut world, 0.6);
+        assert!(world.get::<&TriggerState>(target).unwrap().triggered);
+    }
+
+    #[test]
+    fn delayed_trigger_for_destroyed_target_is_dropped() {
+        let mut world = World::new();
+
+        let target = world.spawn((TriggerState { triggered: false },));
+        world.spawn((DelayedTrigger { target, triggered: true, fire_time: 1.0 },));
+        world.despawn(target).unwrap();
+
+        step(&mut world, 1.0);
+        assert_eq!(world.query::<&DelayedTrigger>().iter().count(), 0);
+        assert!(!world.contains(target));
+    }
 }
\ No newline at end of file
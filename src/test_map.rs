@@ -0,0 +1,539 @@
+//! Builds small synthetic BSP files in memory, so that map loading, collision, & visibility can be tested without any map assets
+//!
+//! Each volume (a brush or an empty room) is given a chain of BSP nodes, one per bounding plane. A point inside every plane of a volume ends up in that volume's leaf, and a point outside any of them moves on to the next volume's chain.
+//! This is far from an optimal tree, but traces & point queries only care that every leaf a volume could touch is reachable
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use dbsdk_rs::{math::Vector3, vdp::Color32};
+
+use crate::bsp_file::{BspFile, CONTENTS_SOLID};
+
+const BSP_MAGIC: u32 = 0x50534249;
+const BSP_VERSION: u32 = 38;
+
+const NUM_LUMPS: usize = 19;
+
+struct TestVolume {
+    planes: Vec<(Vector3, f32)>,
+    bounds: Option<(Vector3, Vector3)>,
+    contents: u32,
+    cluster: u16,
+    area: u16,
+    /// Whether the volume is a brush which traces collide with, or just an empty region of space
+    brush: bool,
+    tex: u16,
+}
+
+struct TestFace {
+    verts: Vec<Vector3>,
+    tex: u16,
+    /// Samples of the face's single lightmap style, if it has one
+    lightmap: Option<Vec<Color32>>,
+}
+
+struct TestTexInfo {
+    name: String,
+    flags: u32,
+    value: u32,
+}
+
+pub struct TestMap {
+    entities: String,
+    /// Volumes of each model. The first model is the world
+    models: Vec<Vec<TestVolume>>,
+    textures: Vec<TestTexInfo>,
+    /// Faces of the world model
+    faces: Vec<TestFace>,
+    pvs: Vec<Vec<bool>>,
+    phs: Option<Vec<Vec<bool>>>,
+    /// Portals leading out of each area, as (portal number, other area)
+    areas: Vec<Vec<(u32, u32)>>,
+    version: u32,
+}
+
+fn box_planes(mins: Vector3, maxs: Vector3) -> Vec<(Vector3, f32)> {
+    vec![
+        (Vector3::unit_x(), maxs.x),
+        (Vector3::unit_x() * -1.0, -mins.x),
+        (Vector3::unit_y(), maxs.y),
+        (Vector3::unit_y() * -1.0, -mins.y),
+        (Vector3::unit_z(), maxs.z),
+        (Vector3::unit_z() * -1.0, -mins.z),
+    ]
+}
+
+fn plane_type(normal: &Vector3) -> u32 {
+    // the collision code expects axial plane types to face along the positive axis
+    if normal.x == 1.0 {
+        0
+    }
+    else if normal.y == 1.0 {
+        1
+    }
+    else if normal.z == 1.0 {
+        2
+    }
+    else {
+        3
+    }
+}
+
+fn child_leaf(leaf_idx: usize) -> i32 {
+    -(leaf_idx as i32) - 1
+}
+
+// run-length encode a row of cluster bits the way the vis compiler does: a zero byte followed by how many zero bytes it stands for
+fn compress_vis_row(row: &[bool]) -> Vec<u8> {
+    let mut bytes = vec![0u8;(row.len() + 7) / 8];
+    for (i, visible) in row.iter().enumerate() {
+        if *visible {
+            bytes[i / 8] |= 1 << (i % 8);
+        }
+    }
+
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != 0 {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        let mut run = 0;
+        while i < bytes.len() && bytes[i] == 0 && run < 255 {
+            run += 1;
+            i += 1;
+        }
+
+        out.push(0);
+        out.push(run);
+    }
+
+    out
+}
+
+fn write_vec3f(out: &mut Vec<u8>, v: &Vector3) {
+    out.write_f32::<LittleEndian>(v.x).unwrap();
+    out.write_f32::<LittleEndian>(v.y).unwrap();
+    out.write_f32::<LittleEndian>(v.z).unwrap();
+}
+
+fn write_vec3s(out: &mut Vec<u8>, v: &Vector3) {
+    out.write_i16::<LittleEndian>(v.x.clamp(i16::MIN as f32, i16::MAX as f32) as i16).unwrap();
+    out.write_i16::<LittleEndian>(v.y.clamp(i16::MIN as f32, i16::MAX as f32) as i16).unwrap();
+    out.write_i16::<LittleEndian>(v.z.clamp(i16::MIN as f32, i16::MAX as f32) as i16).unwrap();
+}
+
+impl TestMap {
+    pub fn new() -> TestMap {
+        TestMap {
+            entities: String::new(),
+            models: vec![Vec::new()],
+            textures: Vec::new(),
+            faces: Vec::new(),
+            pvs: Vec::new(),
+            phs: None,
+            areas: vec![Vec::new()],
+            version: BSP_VERSION,
+        }
+    }
+
+    /// Set the contents of the entity lump
+    pub fn with_entities(mut self: Self, entities: &str) -> Self {
+        self.entities = entities.to_owned();
+        self
+    }
+
+    /// Write the given version number into the header instead of the supported one
+    pub fn with_version(mut self: Self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Add a texinfo, returning its index
+    pub fn add_texture(self: &mut Self, name: &str, flags: u32, value: u32) -> u16 {
+        self.textures.push(TestTexInfo { name: name.to_owned(), flags, value });
+        (self.textures.len() - 1) as u16
+    }
+
+    /// Add an axis-aligned solid box to the world
+    pub fn add_box(self: &mut Self, mins: Vector3, maxs: Vector3) -> &mut Self {
+        self.add_box_contents(mins, maxs, CONTENTS_SOLID, u16::MAX)
+    }
+
+    /// Add an axis-aligned box brush with the given contents & texinfo to the world
+    pub fn add_box_contents(self: &mut Self, mins: Vector3, maxs: Vector3, contents: u32, tex: u16) -> &mut Self {
+        self.models[0].push(TestVolume { planes: box_planes(mins, maxs), bounds: Some((mins, maxs)), contents, cluster: u16::MAX, area: 0, brush: true, tex });
+        self
+    }
+
+    /// Add a solid convex brush to the world, bounded by the given outward facing planes (normal & distance)
+    pub fn add_brush(self: &mut Self, planes: &[(Vector3, f32)]) -> &mut Self {
+        self.models[0].push(TestVolume { planes: planes.to_vec(), bounds: None, contents: CONTENTS_SOLID, cluster: u16::MAX, area: 0, brush: true, tex: u16::MAX });
+        self
+    }
+
+    /// Add an empty region of space belonging to the given cluster & area
+    pub fn add_room(self: &mut Self, mins: Vector3, maxs: Vector3, cluster: u16, area: u16) -> &mut Self {
+        while self.areas.len() <= area as usize {
+            self.areas.push(Vec::new());
+        }
+
+        while self.pvs.len() <= cluster as usize {
+            self.pvs.push(Vec::new());
+        }
+
+        self.models[0].push(TestVolume { planes: box_planes(mins, maxs), bounds: Some((mins, maxs)), contents: 0, cluster, area, brush: false, tex: u16::MAX });
+        self
+    }
+
+    /// Add a face to the world with the given vertices (clockwise, seen from the front) & texinfo, plus one style of lightmap samples if given.
+    /// The face is listed in the leaf of every room whose bounds contain it
+    pub fn add_face(self: &mut Self, verts: &[Vector3], tex: u16, lightmap: Option<&[Color32]>) -> &mut Self {
+        self.faces.push(TestFace { verts: verts.to_vec(), tex, lightmap: lightmap.map(|x| x.to_vec()) });
+        self
+    }
+
+    /// Add a brush model containing a single solid box, returning the model's index into the submodel lump
+    pub fn add_model_box(self: &mut Self, mins: Vector3, maxs: Vector3) -> usize {
+        self.models.push(vec![TestVolume { planes: box_planes(mins, maxs), bounds: Some((mins, maxs)), contents: CONTENTS_SOLID, cluster: u16::MAX, area: 0, brush: true, tex: u16::MAX }]);
+        self.models.len() - 1
+    }
+
+    /// Set which clusters are potentially visible from each cluster. Clusters default to seeing every other cluster
+    pub fn set_pvs(self: &mut Self, pvs: Vec<Vec<bool>>) -> &mut Self {
+        self.pvs = pvs;
+        self
+    }
+
+    /// Set which clusters are potentially hearable from each cluster. Defaults to the PVS
+    pub fn set_phs(self: &mut Self, phs: Vec<Vec<bool>>) -> &mut Self {
+        self.phs = Some(phs);
+        self
+    }
+
+    /// Connect two areas with the given areaportal
+    pub fn add_area_portal(self: &mut Self, portal_num: u32, area_a: u16, area_b: u16) -> &mut Self {
+        while self.areas.len() <= area_a.max(area_b) as usize {
+            self.areas.push(Vec::new());
+        }
+
+        self.areas[area_a as usize].push((portal_num, area_b as u32));
+        self.areas[area_b as usize].push((portal_num, area_a as u32));
+        self
+    }
+
+    /// Serialize the map into the same format the map compiler writes
+    pub fn to_bytes(self: &Self) -> Vec<u8> {
+        let mut planes = Vec::new();
+        let mut nodes: Vec<(u32, i32, i32)> = Vec::new();
+        let mut leaves: Vec<(u32, u16, u16, Vector3, Vector3, u16, u16, u16, u16)> = Vec::new();
+        let mut leaf_faces: Vec<u16> = Vec::new();
+        let mut leaf_brushes: Vec<u16> = Vec::new();
+        let mut brushes: Vec<(u32, u32, u32)> = Vec::new();
+        let mut brush_sides: Vec<(u16, u16)> = Vec::new();
+        let mut models: Vec<(Vector3, Vector3, u32, u32)> = Vec::new();
+
+        // leaf 0 is the empty space outside of every volume
+        leaves.push((0, u16::MAX, 0, Vector3::zero(), Vector3::zero(), 0, 0, 0, 0));
+
+        for (model_idx, volumes) in self.models.iter().enumerate() {
+            let first_node = nodes.len();
+            let mut model_bounds: Option<(Vector3, Vector3)> = None;
+
+            // every node index in the chain is known up front, so the chain can be built in one pass
+            let mut chain_start = first_node;
+
+            for (vol_idx, vol) in volumes.iter().enumerate() {
+                let next_chain = chain_start + vol.planes.len();
+                let outside = if vol_idx == volumes.len() - 1 { child_leaf(0) } else { next_chain as i32 };
+
+                let (mins, maxs) = vol.bounds.unwrap_or((Vector3::zero(), Vector3::zero()));
+                if vol.bounds.is_some() {
+                    model_bounds = match model_bounds {
+                        Some((a, b)) => Some((
+                            Vector3::new(a.x.min(mins.x), a.y.min(mins.y), a.z.min(mins.z)),
+                            Vector3::new(b.x.max(maxs.x), b.y.max(maxs.y), b.z.max(maxs.z))
+                        )),
+                        None => Some((mins, maxs))
+                    };
+                }
+
+                let leaf_idx = leaves.len();
+                if vol.brush {
+                    let brush_idx = brushes.len();
+                    brushes.push((brush_sides.len() as u32, vol.planes.len() as u32, vol.contents));
+                    leaves.push((vol.contents, vol.cluster, vol.area, mins, maxs, 0, 0, leaf_brushes.len() as u16, 1));
+                    leaf_brushes.push(brush_idx as u16);
+                }
+                else {
+                    // rooms list the world faces lying on or inside their bounds
+                    let first_face = leaf_faces.len();
+                    if model_idx == 0 && vol.bounds.is_some() {
+                        for (face_idx, face) in self.faces.iter().enumerate() {
+                            let inside = face.verts.iter().all(|v| {
+                                v.x >= mins.x - 0.01 && v.y >= mins.y - 0.01 && v.z >= mins.z - 0.01 &&
+                                v.x <= maxs.x + 0.01 && v.y <= maxs.y + 0.01 && v.z <= maxs.z + 0.01
+                            });
+
+                            if inside {
+                                leaf_faces.push(face_idx as u16);
+                            }
+                        }
+                    }
+
+                    leaves.push((vol.contents, vol.cluster, vol.area, mins, maxs, first_face as u16, (leaf_faces.len() - first_face) as u16, 0, 0));
+                }
+
+                for (plane_idx, (normal, dist)) in vol.planes.iter().enumerate() {
+                    planes.push((*normal, *dist, plane_type(normal)));
+
+                    if vol.brush {
+                        brush_sides.push(((planes.len() - 1) as u16, vol.tex));
+                    }
+
+                    // in front of any plane is outside the volume, behind all of them is inside
+                    let inside = if plane_idx == vol.planes.len() - 1 { child_leaf(leaf_idx) } else { (chain_start + plane_idx + 1) as i32 };
+                    nodes.push(((planes.len() - 1) as u32, outside, inside));
+                }
+
+                chain_start = next_chain;
+            }
+
+            // the point lookup always starts at node 0, so the world needs at least one node
+            let headnode = if nodes.len() > first_node {
+                first_node as u32
+            }
+            else if model_idx == 0 {
+                planes.push((Vector3::unit_x(), 0.0, 0));
+                nodes.push(((planes.len() - 1) as u32, child_leaf(0), child_leaf(0)));
+                first_node as u32
+            }
+            else {
+                child_leaf(0) as u32
+            };
+
+            let (model_mins, model_maxs) = model_bounds.unwrap_or((Vector3::zero(), Vector3::zero()));
+            let num_faces = if model_idx == 0 { self.faces.len() as u32 } else { 0 };
+            models.push((model_mins, model_maxs, headnode, num_faces));
+        }
+
+        // faces. edge 0 can't be referenced (its sign would be lost), so it's left unused
+        let mut vertices: Vec<Vector3> = Vec::new();
+        let mut edges: Vec<(u16, u16)> = vec![(0, 0)];
+        let mut face_edges: Vec<i32> = Vec::new();
+        let mut faces: Vec<(u16, u32, u16, u16, bool, u32)> = Vec::new();
+        let mut lightmap: Vec<Color32> = Vec::new();
+
+        for face in &self.faces {
+            let v = &face.verts;
+            let mut normal = Vector3::cross(&(v[2] - v[0]), &(v[1] - v[0]));
+            normal.normalize();
+
+            planes.push((normal, Vector3::dot(&normal, &v[0]), plane_type(&normal)));
+
+            let first_edge = face_edges.len() as u32;
+            let first_vertex = vertices.len();
+            vertices.extend_from_slice(v);
+
+            for i in 0..v.len() {
+                face_edges.push(edges.len() as i32);
+                edges.push(((first_vertex + i) as u16, (first_vertex + ((i + 1) % v.len())) as u16));
+            }
+
+            let lightmap_offset = (lightmap.len() * 3) as u32;
+            if let Some(samples) = &face.lightmap {
+                lightmap.extend_from_slice(samples);
+            }
+
+            faces.push(((planes.len() - 1) as u16, first_edge, v.len() as u16, face.tex, face.lightmap.is_some(), lightmap_offset));
+        }
+
+        let mut lumps: Vec<Vec<u8>> = vec![Vec::new();NUM_LUMPS];
+
+        // entities
+        lumps[0].extend_from_slice(self.entities.as_bytes());
+        lumps[0].push(0);
+
+        // planes
+        for (normal, dist, plane_type) in &planes {
+            write_vec3f(&mut lumps[1], normal);
+            lumps[1].write_f32::<LittleEndian>(*dist).unwrap();
+            lumps[1].write_u32::<LittleEndian>(*plane_type).unwrap();
+        }
+
+        // vertices
+        for v in &vertices {
+            write_vec3f(&mut lumps[2], v);
+        }
+
+        // vis. clusters which weren't given explicit visibility can see everything
+        let num_clusters = self.pvs.len();
+        let full_row = vec![true;num_clusters];
+        let pvs_rows: Vec<&[bool]> = self.pvs.iter().map(|x| if x.len() == num_clusters { x.as_slice() } else { full_row.as_slice() }).collect();
+        let phs_rows: Vec<&[bool]> = match &self.phs {
+            Some(v) => v.iter().map(|x| x.as_slice()).collect(),
+            None => pvs_rows.clone()
+        };
+
+        let hdr_size = 4 + (num_clusters * 8);
+        let mut vis_data = Vec::new();
+        let mut vis_offsets = Vec::new();
+
+        for i in 0..num_clusters {
+            let pvs_offs = hdr_size + vis_data.len();
+            vis_data.extend(compress_vis_row(pvs_rows[i]));
+            let phs_offs = hdr_size + vis_data.len();
+            vis_data.extend(compress_vis_row(phs_rows[i]));
+
+            vis_offsets.push((pvs_offs as u32, phs_offs as u32));
+        }
+
+        lumps[3].write_u32::<LittleEndian>(num_clusters as u32).unwrap();
+        for (pvs_offs, phs_offs) in &vis_offsets {
+            lumps[3].write_u32::<LittleEndian>(*pvs_offs).unwrap();
+            lumps[3].write_u32::<LittleEndian>(*phs_offs).unwrap();
+        }
+        lumps[3].extend(vis_data);
+
+        // nodes
+        for (plane, front, back) in &nodes {
+            lumps[4].write_u32::<LittleEndian>(*plane).unwrap();
+            lumps[4].write_i32::<LittleEndian>(*front).unwrap();
+            lumps[4].write_i32::<LittleEndian>(*back).unwrap();
+            write_vec3s(&mut lumps[4], &Vector3::zero());
+            write_vec3s(&mut lumps[4], &Vector3::zero());
+            lumps[4].write_u16::<LittleEndian>(0).unwrap();
+            lumps[4].write_u16::<LittleEndian>(0).unwrap();
+        }
+
+        // texinfo
+        for tex in &self.textures {
+            write_vec3f(&mut lumps[5], &Vector3::unit_x());
+            lumps[5].write_f32::<LittleEndian>(0.0).unwrap();
+            write_vec3f(&mut lumps[5], &Vector3::unit_y());
+            lumps[5].write_f32::<LittleEndian>(0.0).unwrap();
+            lumps[5].write_u32::<LittleEndian>(tex.flags).unwrap();
+            lumps[5].write_u32::<LittleEndian>(tex.value).unwrap();
+
+            let mut name = [0u8;32];
+            let len = tex.name.len().min(31);
+            name[0..len].copy_from_slice(&tex.name.as_bytes()[0..len]);
+            lumps[5].extend_from_slice(&name);
+            lumps[5].write_u32::<LittleEndian>(0).unwrap();
+        }
+
+        // faces & their lightmaps
+        for (plane, first_edge, num_edges, tex, has_lightmap, lightmap_offset) in &faces {
+            lumps[6].write_u16::<LittleEndian>(*plane).unwrap();
+            lumps[6].write_u16::<LittleEndian>(0).unwrap();
+            lumps[6].write_u32::<LittleEndian>(*first_edge).unwrap();
+            lumps[6].write_u16::<LittleEndian>(*num_edges).unwrap();
+            lumps[6].write_u16::<LittleEndian>(*tex).unwrap();
+            lumps[6].extend_from_slice(if *has_lightmap { &[0, 255, 255, 255] } else { &[255, 255, 255, 255] });
+            lumps[6].write_u32::<LittleEndian>(*lightmap_offset).unwrap();
+        }
+
+        for c in &lightmap {
+            lumps[7].extend_from_slice(&[c.r, c.g, c.b]);
+        }
+
+        // leaves
+        for (contents, cluster, area, mins, maxs, first_face, num_faces, first_brush, num_brushes) in &leaves {
+            lumps[8].write_u32::<LittleEndian>(*contents).unwrap();
+            lumps[8].write_u16::<LittleEndian>(*cluster).unwrap();
+            lumps[8].write_u16::<LittleEndian>(*area).unwrap();
+            write_vec3s(&mut lumps[8], mins);
+            write_vec3s(&mut lumps[8], maxs);
+            lumps[8].write_u16::<LittleEndian>(*first_face).unwrap();
+            lumps[8].write_u16::<LittleEndian>(*num_faces).unwrap();
+            lumps[8].write_u16::<LittleEndian>(*first_brush).unwrap();
+            lumps[8].write_u16::<LittleEndian>(*num_brushes).unwrap();
+        }
+
+        // leaf faces & leaf brushes
+        for face in &leaf_faces {
+            lumps[9].write_u16::<LittleEndian>(*face).unwrap();
+        }
+
+        for brush in &leaf_brushes {
+            lumps[10].write_u16::<LittleEndian>(*brush).unwrap();
+        }
+
+        // edges & face edges
+        for (a, b) in &edges {
+            lumps[11].write_u16::<LittleEndian>(*a).unwrap();
+            lumps[11].write_u16::<LittleEndian>(*b).unwrap();
+        }
+
+        for edge in &face_edges {
+            lumps[12].write_i32::<LittleEndian>(*edge).unwrap();
+        }
+
+        // models. the world model owns every face
+        for (mins, maxs, headnode, num_faces) in &models {
+            write_vec3f(&mut lumps[13], mins);
+            write_vec3f(&mut lumps[13], maxs);
+            write_vec3f(&mut lumps[13], &((*mins + *maxs) * 0.5));
+            lumps[13].write_u32::<LittleEndian>(*headnode).unwrap();
+            lumps[13].write_u32::<LittleEndian>(0).unwrap();
+            lumps[13].write_u32::<LittleEndian>(*num_faces).unwrap();
+        }
+
+        // brushes & brush sides
+        for (first_side, num_sides, contents) in &brushes {
+            lumps[14].write_u32::<LittleEndian>(*first_side).unwrap();
+            lumps[14].write_u32::<LittleEndian>(*num_sides).unwrap();
+            lumps[14].write_u32::<LittleEndian>(*contents).unwrap();
+        }
+
+        for (plane, tex) in &brush_sides {
+            lumps[15].write_u16::<LittleEndian>(*plane).unwrap();
+            lumps[15].write_u16::<LittleEndian>(*tex).unwrap();
+        }
+
+        // areas & areaportals
+        let mut area_portals = Vec::new();
+        for portals in &self.areas {
+            lumps[17].write_u32::<LittleEndian>(portals.len() as u32).unwrap();
+            lumps[17].write_u32::<LittleEndian>(area_portals.len() as u32).unwrap();
+            area_portals.extend(portals.iter().cloned());
+        }
+
+        for (portal_num, other_area) in &area_portals {
+            lumps[18].write_u32::<LittleEndian>(*portal_num).unwrap();
+            lumps[18].write_u32::<LittleEndian>(*other_area).unwrap();
+        }
+
+        // header, followed by each lump aligned to 4 bytes
+        let mut out = Vec::new();
+        out.write_u32::<LittleEndian>(BSP_MAGIC).unwrap();
+        out.write_u32::<LittleEndian>(self.version).unwrap();
+
+        let mut offset = 8 + (NUM_LUMPS * 8);
+        for lump in &lumps {
+            out.write_u32::<LittleEndian>(offset as u32).unwrap();
+            out.write_u32::<LittleEndian>(lump.len() as u32).unwrap();
+            offset = (offset + lump.len() + 3) & !3;
+        }
+
+        for lump in &lumps {
+            out.extend_from_slice(lump);
+            while out.len() % 4 != 0 {
+                out.push(0);
+            }
+        }
+
+        out
+    }
+
+    /// Serialize & load the map
+    pub fn build(self: &Self) -> BspFile {
+        match BspFile::from_bytes(&self.to_bytes()) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed loading test map: {:?}", e)
+        }
+    }
+}
\ No newline at end of file